@@ -0,0 +1,142 @@
+//本机回环 tcp 上的收发吞吐对比
+//  legacy: 每条消息单独编码到 Vec, 分别写消息头和消息体并 flush, 读端为每个消息体分配 vec![0; body_len]
+//  frame:  写端合并为 FrameBatch 一次 writev, 读端从 BytesMut 中切出 Bytes
+//运行: cargo bench -p cable --bench frame_bench
+use bytes::BytesMut;
+use cable::message::Frame;
+use cable::network::frame::FrameBatch;
+use cable::network::tcp::PROTO_HEADER_LEN;
+use cable::protos::{self, C2sInventoryReq, Item, ProtoType};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+const MSG_NUM: usize = 200_000;
+const BATCH_MAX: usize = 256;
+
+fn sample_proto() -> ProtoType {
+    let items = (0..20)
+        .map(|i| Item {
+            uid: i,
+            id: i as u32 * 10,
+        })
+        .collect();
+    ProtoType::C2sInventoryReq(C2sInventoryReq { tag: 1, items })
+}
+
+async fn connect_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (client.unwrap(), server.unwrap().0)
+}
+
+async fn legacy_path() -> Duration {
+    let (client, server) = connect_pair().await;
+    let start = Instant::now();
+    let writer = tokio::spawn(async move {
+        let mut stream = BufWriter::new(client);
+        for _ in 0..MSG_NUM {
+            let pto = sample_proto();
+            let (proto_id, _) = pto.inner_info();
+            let body = protos::encode(pto).unwrap();
+            stream.write_u32_le(proto_id).await.unwrap();
+            stream.write_u32_le(body.len() as u32).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    });
+    let mut stream = BufReader::new(server);
+    for _ in 0..MSG_NUM {
+        let proto_id = stream.read_u32_le().await.unwrap();
+        let body_len = stream.read_u32_le().await.unwrap() as usize;
+        let mut body = vec![0; body_len];
+        stream.read_exact(&mut body).await.unwrap();
+        protos::decode(proto_id, &body).unwrap();
+    }
+    writer.await.unwrap();
+    start.elapsed()
+}
+
+async fn frame_path() -> Duration {
+    let (mut client, mut server) = connect_pair().await;
+    let start = Instant::now();
+    let writer = tokio::spawn(async move {
+        let mut batch = FrameBatch::new();
+        let mut sent = 0;
+        while sent < MSG_NUM {
+            while sent < MSG_NUM && batch.frames() < BATCH_MAX {
+                batch.push(Frame::from_proto(sample_proto()).unwrap());
+                sent += 1;
+            }
+            client.write_all_buf(&mut batch).await.unwrap();
+            batch.clear();
+        }
+    });
+    let mut buffer = BytesMut::with_capacity(8 * 1024);
+    let mut received = 0;
+    while received < MSG_NUM {
+        match Frame::parse(&mut buffer).unwrap() {
+            Some(frame) => {
+                frame.decode().unwrap();
+                received += 1;
+            }
+            None => {
+                assert!(server.read_buf(&mut buffer).await.unwrap() > 0);
+            }
+        }
+    }
+    writer.await.unwrap();
+    start.elapsed()
+}
+
+//预先编码好的帧, 广播时只增加引用计数
+async fn forward_path() -> Duration {
+    let (mut client, mut server) = connect_pair().await;
+    let frame = Frame::from_proto(sample_proto()).unwrap();
+    let start = Instant::now();
+    let writer = tokio::spawn(async move {
+        let mut batch = FrameBatch::new();
+        let mut sent = 0;
+        while sent < MSG_NUM {
+            while sent < MSG_NUM && batch.frames() < BATCH_MAX {
+                batch.push(frame.clone());
+                sent += 1;
+            }
+            client.write_all_buf(&mut batch).await.unwrap();
+            batch.clear();
+        }
+    });
+    let mut buffer = BytesMut::with_capacity(8 * 1024);
+    let mut received = 0;
+    while received < MSG_NUM {
+        match Frame::parse(&mut buffer).unwrap() {
+            Some(_) => received += 1,
+            None => {
+                assert!(server.read_buf(&mut buffer).await.unwrap() > 0);
+            }
+        }
+    }
+    writer.await.unwrap();
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let frame_len = PROTO_HEADER_LEN + Frame::from_proto(sample_proto()).unwrap().body.len();
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "{name:<8} {:>8.1} ms  {:>10.0} msg/s  {:>8.1} MB/s",
+        secs * 1000.0,
+        MSG_NUM as f64 / secs,
+        (MSG_NUM * frame_len) as f64 / secs / 1024.0 / 1024.0,
+    );
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        report("legacy", legacy_path().await);
+        report("frame", frame_path().await);
+        report("forward", forward_path().await);
+    });
+}
@@ -1,14 +1,36 @@
-use cable::config::Config;
-use cable::logger::{init, LogLevel};
+use cable::cli::{self, Cli};
+use cable::config::{Reloader, Schema};
+use cable::logger::{self, init};
 use cable::services;
-use std::env;
+use cable::states::Handlers;
+use std::{env, process};
 
 fn main() {
+    let cli = Cli::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}\n{}", cli::usage("server"));
+        process::exit(2);
+    });
     let pwd = env::current_dir().unwrap();
-    let config_path = format!("{}/etc/sysconfig.conf", pwd.to_str().unwrap());
-    let conf = Config::new(&config_path).with("workdir", pwd.to_str().unwrap());
-    let log_level: LogLevel = conf.get_int("log_level").unwrap().into();
-    let log_chan_size = conf.get_int("log_chan_size").unwrap() as usize;
-    init(log_level, log_chan_size);
-    services::start(conf);
+    //配置文件 < 环境变量 < 命令行 --set k=v, 有错误时全部打印后退出; 收到 SIGHUP 时重新加载
+    let loader = cli.loader(Schema::server(), pwd.to_str().unwrap(), env::vars());
+    if let Some(code) = cli.run_offline("server", env!("CARGO_PKG_VERSION"), &loader) {
+        process::exit(code);
+    }
+    for warning in loader.warnings() {
+        eprintln!("warning: {warning}");
+    }
+    let (reloader, sys) = match Reloader::new(loader) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}: {err}", cli.config_path(pwd.to_str().unwrap()));
+            process::exit(1);
+        }
+    };
+    init(sys.log_level, sys.log_chan_size);
+    logger::set_log_format(sys.log_format);
+    logger::set_log_overflow(sys.log_overflow);
+    logger::set_host_id(sys.host_id);
+    services::start_with_reloader(reloader, Handlers::new());
+    //退出前把队列中的日志都写到文件
+    logger::shutdown();
 }
@@ -0,0 +1,193 @@
+//server 和 robot 的命令行
+//
+//  除了启动服务, 其他动作只加载配置或者脚本, 不打开任何监听
+//  退出码: 0 成功, 1 配置或者脚本有错误, 2 命令行有错误
+use crate::config::{Config, ConfigLoader, Schema};
+use crate::luautil;
+use crate::message::ServiceType;
+use crate::protos;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Run,
+    CheckConfig,
+    PrintConfig,
+    //检查脚本, exec 时执行入口脚本
+    CheckLua { exec: bool },
+    Version,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    pub config: Option<String>, //没有指定时为 ./etc/sysconfig.conf
+    pub sets: Vec<String>,      //--set 的 k=v, 按出现的顺序
+    pub action: Action,
+}
+
+pub fn usage(name: &str) -> String {
+    format!(
+        "usage: {name} [options]            start the service
+       {name} check-lua [--exec]  compile every lua script under logic_path, --exec also runs main.lua
+options:
+  -c, --config <path>         config file, defaults to ./etc/sysconfig.conf (.toml for toml)
+  --set <key=value>           override a config key, can be repeated
+  --check-config              validate the config and exit
+  --print-effective-config    print the config after defaults and overrides and exit
+  -V, --version               print the version and the protocol version
+  -h, --help                  print this message"
+    )
+}
+
+pub fn version(name: &str, pkg_version: &str) -> String {
+    format!("{name} {pkg_version} (protocol {})", protos::version())
+}
+
+impl Cli {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut cli = Cli {
+            config: None,
+            sets: Vec::new(),
+            action: Action::Run,
+        };
+        let set_action = |cli: &mut Cli, action: Action, arg: &str| {
+            if cli.action != Action::Run && cli.action != action {
+                return Err(format!("{arg}: only one action can be given"));
+            }
+            cli.action = action;
+            Ok(())
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            //--key value 和 --key=value 两种写法
+            let (key, inline) = match arg.split_once('=') {
+                Some((k, v)) if k.starts_with("--") => (k, Some(v.to_owned())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{key}: missing value"))
+            };
+            match key {
+                "-c" | "--config" => cli.config = Some(value()?),
+                "--set" => {
+                    let kv = value()?;
+                    if kv.split_once('=').is_none_or(|(k, _)| k.trim().is_empty()) {
+                        return Err(format!("--set {kv}: expected key=value"));
+                    }
+                    cli.sets.push(kv);
+                }
+                "--check-config" => set_action(&mut cli, Action::CheckConfig, key)?,
+                "--print-effective-config" => set_action(&mut cli, Action::PrintConfig, key)?,
+                "check-lua" => set_action(&mut cli, Action::CheckLua { exec: false }, key)?,
+                "--exec" => match cli.action {
+                    Action::CheckLua { .. } => cli.action = Action::CheckLua { exec: true },
+                    _ => return Err("--exec: only valid after check-lua".into()),
+                },
+                "-V" | "--version" => set_action(&mut cli, Action::Version, key)?,
+                "-h" | "--help" => set_action(&mut cli, Action::Help, key)?,
+                _ => return Err(format!("unknown argument: {arg}")),
+            }
+        }
+        Ok(cli)
+    }
+
+    pub fn config_path(&self, pwd: &str) -> String {
+        self.config
+            .clone()
+            .unwrap_or_else(|| format!("{pwd}/etc/sysconfig.conf"))
+    }
+
+    //配置文件 < 环境变量 < --set, workdir 为启动时的目录
+    pub fn loader<I>(&self, schema: Schema, pwd: &str, vars: I) -> ConfigLoader
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let sets = self.sets.iter().flat_map(|kv| ["--set".into(), kv.clone()]);
+        ConfigLoader::new(&self.config_path(pwd), schema)
+            .with_env(vars)
+            .with_args(sets)
+            .with_override("workdir", pwd)
+    }
+
+    //完成启动服务以外的动作, 返回退出码; 需要启动服务时返回 None
+    pub fn run_offline(&self, name: &str, pkg_version: &str, loader: &ConfigLoader) -> Option<i32> {
+        let load = || {
+            loader.load().map_err(|err| {
+                eprintln!("{}: {err}", loader.path());
+            })
+        };
+        let code = match self.action {
+            Action::Run => return None,
+            Action::Version => {
+                println!("{}", version(name, pkg_version));
+                0
+            }
+            Action::Help => {
+                println!("{}", usage(name));
+                0
+            }
+            Action::CheckConfig => match load() {
+                Ok(_) => {
+                    println!("{}: ok", loader.path());
+                    0
+                }
+                Err(()) => 1,
+            },
+            Action::PrintConfig => match load() {
+                Ok((conf, _)) => {
+                    print!("{}", effective(&conf, loader.schema()));
+                    0
+                }
+                Err(()) => 1,
+            },
+            Action::CheckLua { exec } => match load() {
+                Ok((conf, sys)) => {
+                    let errors = match lua_service(sys.service_type) {
+                        Some(service_type) => luautil::check_scripts(service_type, &conf, exec),
+                        None => vec![format!(
+                            "service_type = {}: does not run lua scripts",
+                            String::from(sys.service_type)
+                        )],
+                    };
+                    match errors.is_empty() {
+                        true => {
+                            println!("{}: ok", conf.get_string("logic_path").unwrap());
+                            0
+                        }
+                        false => {
+                            for err in errors {
+                                eprintln!("{err}");
+                            }
+                            1
+                        }
+                    }
+                }
+                Err(()) => 1,
+            },
+        };
+        Some(code)
+    }
+}
+
+//运行脚本的服务; 机器人的配置中没有 service_type
+fn lua_service(service_type: ServiceType) -> Option<ServiceType> {
+    match service_type {
+        ServiceType::TCP => Some(ServiceType::TCP),
+        ServiceType::UNKNOW => Some(ServiceType::TCPROBOT),
+        _ => None,
+    }
+}
+
+//生效的配置, 每行 k = v, 按键排序; 标记为 secret 的值隐藏
+pub fn effective(conf: &Config, schema: &Schema) -> String {
+    conf.entries()
+        .into_iter()
+        .map(|(k, v)| match schema.field(k).is_some_and(|f| f.secret) {
+            true => format!("{k} = ******\n"),
+            false => format!("{k} = {v}\n"),
+        })
+        .collect()
+}
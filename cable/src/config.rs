@@ -1,67 +1,172 @@
+//系统配置
+//
+//  配置文件支持两种格式: 传统的 k = v 行格式, 以及扩展名为 .toml 的 toml 格式
+//  toml 中的子表展开为带前缀的键, 例如 [rpc_acl] 下的 _db = "game_service" 即 rpc_acl._db; 数组以逗号连接
+//  启动时由 ConfigLoader 依次叠加配置文件, 环境变量, 命令行, 再按 schema 补全默认值并校验, 所有错误一次报告
+//  运行中由 Reloader 重新加载, 标记为 hot 的配置经 watch 通知到持有 Config 的各个模块
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
 
-#[derive(Debug, Clone)]
+pub mod loader;
+pub use loader::{ConfigLoader, ENV_PREFIX};
+pub mod reload;
+pub use reload::{reload_blocking, Change, Reloader};
+pub mod schema;
+pub use schema::{Field, Kind, Schema, SysConf};
+
+//重新加载后的配置
+pub type ConfigWatch = watch::Receiver<Arc<Config>>;
+
+//配置中所有的错误, 一行一个
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "config has {} error(s):", self.errors.len())?;
+        for err in self.errors.iter() {
+            write!(f, "\n  {err}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Config {
     values: HashMap<String, String>,
+    live: Option<ConfigWatch>, //由 Reloader 创建时, 所有的克隆都能收到重新加载的配置
 }
 
 impl Config {
+    //读取配置文件, 有错误时 panic; 启动时应使用 ConfigLoader 校验
     pub fn new(fpath: &str) -> Self {
-        let file =
-            File::open(fpath).expect(format!("open config file:{fpath} should be ok").as_str());
-        let reader = BufReader::new(file);
+        Self::load(fpath).unwrap_or_else(|err| panic!("{fpath}: {err}"))
+    }
 
-        let mut values: HashMap<String, String> = HashMap::new();
+    //按扩展名选择格式读取配置文件, 不做 schema 校验
+    pub fn load(fpath: &str) -> Result<Self, ConfigError> {
+        let (conf, errors) = Self::read(fpath);
+        if errors.is_empty() {
+            Ok(conf)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    //k = v 行格式
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let (conf, errors) = Self::parse_lines(text);
+        if errors.is_empty() {
+            Ok(conf)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
 
-        for line in reader.lines() {
-            let ln: String = line.unwrap();
+    pub fn parse_toml(text: &str) -> Result<Self, ConfigError> {
+        let (conf, errors) = Self::parse_table(text);
+        if errors.is_empty() {
+            Ok(conf)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    //读取失败时返回空配置, 错误和之后的校验错误一起报告
+    pub(crate) fn read(fpath: &str) -> (Self, Vec<String>) {
+        match std::fs::read_to_string(fpath) {
+            Ok(text) if fpath.ends_with(".toml") => Self::parse_table(&text),
+            Ok(text) => Self::parse_lines(&text),
+            Err(err) => (Config::default(), vec![format!("{fpath}: {err}")]),
+        }
+    }
+
+    fn parse_lines(text: &str) -> (Self, Vec<String>) {
+        let mut values: HashMap<String, String> = HashMap::new();
+        let mut errors = Vec::new();
+        for (n, ln) in text.lines().enumerate() {
             //移除注释文本, 注释文本约定为以 '#' 号开始的文本， 例如 k = v #这后面是注释
             //移除后配置行是 k = v 的格式
-            let target = if let Some(pos) = ln.find('#') {
-                let (target, _s2) = ln.split_at(pos);
-                target
-            } else {
-                ln.as_str()
-            };
+            let target = strip_comment(ln);
 
-            //如果是空行,或不是 k = v 的格式，就过滤掉
-            if target.len() == 0 {
+            //空行过滤掉
+            if target.trim().is_empty() {
                 continue;
             }
 
-            //此时,target 就剩下 k = v
-            let kv: Vec<&str> = target.split('=').collect();
-            if kv.len() != 2 {
-                panic!("this line content: [{}] is not a k=v format", target);
+            //只按第一个 '=' 切分, 值中可以带 '=', 例如 base64 编码的密钥
+            let Some((k, v)) = target.split_once('=') else {
+                errors.push(format!(
+                    "line {}: [{}] is not a k = v format",
+                    n + 1,
+                    target.trim()
+                ));
+                continue;
+            };
+            let k = k.trim();
+            if k.is_empty() {
+                errors.push(format!(
+                    "line {}: [{}] has an empty key",
+                    n + 1,
+                    target.trim()
+                ));
+                continue;
+            }
+            //值两边的双引号去掉, 与 toml 的写法一致
+            let v = v.trim();
+            let v = v
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(v);
+            if values.insert(k.to_owned(), v.to_owned()).is_some() {
+                errors.push(format!("line {}: {k} is duplicated", n + 1));
             }
-            //k,v 字符串必须是前后都没有空格的
-            let k: String = kv[0].trim().into();
-            let v: String = kv[1].trim().into();
-            values.insert(k, v);
         }
-        Config { values }
+        (Config { values, live: None }, errors)
     }
 
-    pub fn get_int(&self, k: &str) -> Option<i32> {
-        if let Some(v) = self.values.get(k) {
-            let res: i32 = v.parse().unwrap();
-            Some(res)
-        } else {
-            None
+    fn parse_table(text: &str) -> (Self, Vec<String>) {
+        let mut conf = Config::default();
+        let mut errors = Vec::new();
+        match text.parse::<toml::Table>() {
+            Ok(table) => conf.flatten("", &table, &mut errors),
+            Err(err) => errors.push(format!("toml: {}", err.message())),
         }
+        (conf, errors)
     }
 
-    pub fn get_float(&self, k: &str) -> Option<f32> {
-        if let Some(v) = self.values.get(k) {
-            let res: f32 = v.parse().unwrap();
-            Some(res)
-        } else {
-            None
+    fn flatten(&mut self, prefix: &str, table: &toml::Table, errors: &mut Vec<String>) {
+        for (k, v) in table.iter() {
+            let key = format!("{prefix}{k}");
+            if let toml::Value::Table(sub) = v {
+                self.flatten(&format!("{key}."), sub, errors);
+                continue;
+            }
+            match toml_to_string(v) {
+                Some(s) => {
+                    self.values.insert(key, s);
+                }
+                None => errors.push(format!(
+                    "{key}: arrays of arrays or tables are not supported"
+                )),
+            }
         }
     }
 
+    pub fn get_int(&self, k: &str) -> Option<i32> {
+        self.values.get(k).and_then(|v| v.parse().ok())
+    }
+
+    pub fn get_float(&self, k: &str) -> Option<f32> {
+        self.values.get(k).and_then(|v| v.parse().ok())
+    }
+
     pub fn get_string(&self, k: &str) -> Option<&String> {
         self.values.get(k)
     }
@@ -72,11 +177,84 @@ impl Config {
                 return true;
             }
         }
-        return false;
+        false
     }
 
-    pub fn with(mut self, k: &str, v: &str) -> Self {
+    //同一前缀的一组配置, 例如 rpc_acl.func = v, 返回去掉前缀后的 (func, v)
+    pub fn get_prefixed(&self, prefix: &str) -> Vec<(&str, &str)> {
+        self.values
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix(prefix).map(|k| (k, v.as_str())))
+            .collect()
+    }
+
+    //所有配置, 按键排序
+    pub fn entries(&self) -> Vec<(&str, &str)> {
+        let mut entries: Vec<(&str, &str)> = self
+            .values
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    pub fn contains(&self, k: &str) -> bool {
+        self.values.contains_key(k)
+    }
+
+    pub fn set(&mut self, k: &str, v: &str) {
         self.values.insert(k.to_string(), v.to_string());
+    }
+
+    pub fn with(mut self, k: &str, v: &str) -> Self {
+        self.set(k, v);
+        self
+    }
+
+    //订阅重新加载的配置, 不支持重新加载时返回 None
+    pub fn watch(&self) -> Option<ConfigWatch> {
+        self.live.clone()
+    }
+
+    pub fn with_watch(mut self, live: ConfigWatch) -> Self {
+        self.live = Some(live);
         self
     }
 }
+
+fn toml_to_string(v: &toml::Value) -> Option<String> {
+    match v {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(d) => Some(d.to_string()),
+        toml::Value::Array(items) => {
+            let items: Option<Vec<String>> = items
+                .iter()
+                .map(|v| match v {
+                    toml::Value::Array(_) | toml::Value::Table(_) => None,
+                    v => toml_to_string(v),
+                })
+                .collect();
+            items.map(|items| items.join(","))
+        }
+        toml::Value::Table(_) => None,
+    }
+}
+
+//'#' 在行首或者空白之后才是注释, 双引号内的不算, 例如 k = a#b 和 k = "a #b" 的值保留 '#'
+fn strip_comment(ln: &str) -> &str {
+    let mut quoted = false;
+    let mut prev = None;
+    for (pos, c) in ln.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted && prev.is_none_or(char::is_whitespace) => return &ln[..pos],
+            _ => {}
+        }
+        prev = Some(c);
+    }
+    ln
+}
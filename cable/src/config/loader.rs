@@ -0,0 +1,117 @@
+//启动时加载配置
+//
+//  优先级从低到高: 配置文件, 环境变量, 命令行
+//  环境变量 CABLE_LOG_LEVEL=4 即 log_level = 4, 键中的 '.' 写作 "__", 例如 CABLE_RPC_ACL___DB 即 rpc_acl._db
+//  命令行 --set k=v 或者 --set=k=v
+//  环境中可能有其他程序的 CABLE_ 变量, schema 中没有的环境变量只警告; 配置文件和命令行中未知的键仍是错误
+use super::{Config, ConfigError, Schema, SysConf};
+
+pub const ENV_PREFIX: &str = "CABLE_";
+
+pub struct ConfigLoader {
+    path: String,
+    schema: Schema,
+    overrides: Vec<(String, String)>,
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl ConfigLoader {
+    pub fn new(path: &str, schema: Schema) -> Self {
+        ConfigLoader {
+            path: path.to_owned(),
+            schema,
+            overrides: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    //只取 CABLE_ 开头的环境变量, 传入 std::env::vars()
+    pub fn with_env<I: IntoIterator<Item = (String, String)>>(mut self, vars: I) -> Self {
+        let mut vars: Vec<(String, String, String)> = vars
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let key = k
+                    .strip_prefix(ENV_PREFIX)?
+                    .to_lowercase()
+                    .replace("__", ".");
+                Some((key, v, k))
+            })
+            .collect();
+        //环境变量的顺序不固定, 按键排序后结果才确定
+        vars.sort();
+        for (key, v, name) in vars {
+            if self.schema.field(&key).is_some() {
+                self.overrides.push((key, v));
+            } else {
+                self.warnings
+                    .push(format!("{name}: unknown key {key}, ignored"));
+            }
+        }
+        self
+    }
+
+    //命令行参数中的 --set k=v, 其他参数视为错误
+    pub fn with_args<I: IntoIterator<Item = String>>(mut self, args: I) -> Self {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let kv = match arg.strip_prefix("--set") {
+                Some("") => args.next(),
+                Some(kv) => kv.strip_prefix('=').map(str::to_owned),
+                None => None,
+            };
+            match kv.as_deref().and_then(|kv| kv.split_once('=')) {
+                Some((k, v)) if !k.trim().is_empty() => {
+                    self.overrides
+                        .push((k.trim().to_owned(), v.trim().to_owned()));
+                }
+                _ => self
+                    .errors
+                    .push(format!("argument [{arg}]: expected --set key=value")),
+            }
+        }
+        self
+    }
+
+    pub fn with_override(mut self, k: &str, v: &str) -> Self {
+        self.overrides.push((k.to_owned(), v.to_owned()));
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    //被忽略的环境变量, 启动时打印
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    //返回叠加后的配置和类型化视图; 有任何错误时返回所有错误
+    //可以多次调用, 重新加载时使用同样的环境变量和命令行
+    pub fn load(&self) -> Result<(Config, SysConf), ConfigError> {
+        let (mut conf, mut errors) = Config::read(&self.path);
+        errors.extend(self.errors.iter().cloned());
+        for (k, v) in self.overrides.iter() {
+            conf.set(k, v);
+        }
+        errors.extend(self.schema.apply(&mut conf));
+        match SysConf::from_config(&conf) {
+            Ok(sys) if errors.is_empty() => Ok((conf, sys)),
+            Ok(_) => Err(ConfigError { errors }),
+            Err(more) => {
+                for err in more {
+                    if !errors.contains(&err) {
+                        errors.push(err);
+                    }
+                }
+                Err(ConfigError { errors })
+            }
+        }
+    }
+}
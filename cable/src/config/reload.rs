@@ -0,0 +1,123 @@
+//运行中重新加载配置
+//
+//  重新读取配置文件(环境变量和命令行与启动时相同)并校验, 与当前配置比较
+//  只有标记为 hot 的键可以修改; 其他键有变化时拒绝整个重新加载, 当前配置不变
+//  日志等级在这里直接生效, 其他配置经 watch 通知: 连接的限流在下一条消息时生效, game_hub 更新帧率并通知脚本层
+use super::{Config, ConfigError, ConfigLoader, SysConf};
+use crate::logger::{self, LogFormat, LogLevel, LogOverflow};
+use crate::network::limit::LimitConf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+//一个键的变化, 新增或者删除时对应的一边为 None
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+//两份配置中值不同的键, 按键排序
+pub fn diff(old: &Config, new: &Config) -> Vec<Change> {
+    let mut keys: Vec<&str> = old
+        .entries()
+        .into_iter()
+        .chain(new.entries())
+        .map(|(k, _)| k)
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter_map(|k| {
+            let (o, n) = (old.get_string(k), new.get_string(k));
+            (o != n).then(|| Change {
+                key: k.to_owned(),
+                old: o.cloned(),
+                new: n.cloned(),
+            })
+        })
+        .collect()
+}
+
+pub struct Reloader {
+    loader: ConfigLoader,
+    current: Config,
+    sender: watch::Sender<Arc<Config>>,
+}
+
+impl Reloader {
+    pub fn new(loader: ConfigLoader) -> Result<(Self, SysConf), ConfigError> {
+        let (conf, sys) = loader.load()?;
+        let (sender, _) = watch::channel(Arc::new(conf.clone()));
+        let reloader = Reloader {
+            loader,
+            current: conf,
+            sender,
+        };
+        Ok((reloader, sys))
+    }
+
+    pub fn path(&self) -> &str {
+        self.loader.path()
+    }
+
+    //当前配置, 各个模块持有的克隆都能收到之后重新加载的配置
+    pub fn config(&self) -> Config {
+        self.current.clone().with_watch(self.sender.subscribe())
+    }
+
+    //返回生效的变化; 校验失败或者修改了不能重新加载的键时返回所有错误
+    pub fn reload(&mut self) -> Result<Vec<Change>, ConfigError> {
+        let (conf, _) = self.loader.load()?;
+        let changes = diff(&self.current, &conf);
+        let schema = self.loader.schema();
+        let mut errors: Vec<String> = changes
+            .iter()
+            .filter(|c| !schema.field(&c.key).is_some_and(|f| f.hot))
+            .map(|c| {
+                //错误会写入日志和管理接口的回复, 不输出密钥
+                let secret = schema.field(&c.key).is_some_and(|f| f.secret);
+                let show = |v: Option<&str>| match v {
+                    None => "(unset)".to_owned(),
+                    Some(_) if secret => "******".to_owned(),
+                    Some(v) => v.to_owned(),
+                };
+                format!(
+                    "{}: {} -> {} requires a restart",
+                    c.key,
+                    show(c.old.as_deref()),
+                    show(c.new.as_deref())
+                )
+            })
+            .collect();
+        //各个模块收到后才解析, 先在这里检查, 避免部分模块生效
+        if let Err(err) = LimitConf::from_config(&conf) {
+            errors.push(err.to_string());
+        }
+        if !errors.is_empty() {
+            return Err(ConfigError { errors });
+        }
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+        if let Some(level) = conf.get_int("log_level") {
+            logger::set_global_log_level(LogLevel::from(level));
+        }
+        if let Some(format) = conf.get_string("log_format") {
+            logger::set_log_format(LogFormat::from(format.as_str()));
+        }
+        if let Some(overflow) = conf.get_string("log_overflow") {
+            logger::set_log_overflow(LogOverflow::from(overflow.as_str()));
+        }
+        self.sender.send_replace(Arc::new(conf.clone()));
+        self.current = conf;
+        Ok(changes)
+    }
+}
+
+//在阻塞线程中读取和校验配置文件, 锁也只在阻塞线程中持有, 不占用 tokio 的工作线程
+pub async fn reload_blocking(reloader: Arc<Mutex<Reloader>>) -> Result<Vec<Change>, ConfigError> {
+    tokio::task::spawn_blocking(move || reloader.lock().unwrap().reload())
+        .await
+        .unwrap()
+}
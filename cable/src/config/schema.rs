@@ -0,0 +1,395 @@
+//配置的 schema: 每个键的类型, 默认值, 是否必须
+//
+//  以 '.' 结尾的键表示一组带前缀的配置, 例如 rpc_acl. 匹配 rpc_acl._db
+//  不在 schema 中的键视为拼写错误; 标记为 hot 的键可以由 Reloader 在运行中修改
+use super::Config;
+use crate::logger::{LogFormat, LogLevel, LogOverflow};
+use crate::message::ServiceType;
+use crate::network::console::ConsoleAddr;
+
+const SERVICE_TYPES: &[&str] = &["game_service", "gate_service", "db_service"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Str,
+    Bool,
+    Int { min: i32, max: i32 },
+    Float,
+    Enum(&'static [&'static str]),
+    //逗号分隔, 每一项都是给定的值之一
+    List(&'static [&'static str]),
+    //逗号分隔的整数
+    IntList,
+    //host:port
+    Addr,
+}
+
+pub const INT: Kind = Kind::Int {
+    min: i32::MIN,
+    max: i32::MAX,
+};
+pub const POSITIVE: Kind = Kind::Int {
+    min: 1,
+    max: i32::MAX,
+};
+pub const NON_NEGATIVE: Kind = Kind::Int {
+    min: 0,
+    max: i32::MAX,
+};
+
+impl Kind {
+    pub fn check(&self, v: &str) -> Result<(), String> {
+        match *self {
+            Kind::Str => Ok(()),
+            Kind::Bool => match v {
+                "true" | "false" => Ok(()),
+                _ => Err("expected true or false".into()),
+            },
+            Kind::Int { min, max } => match v.parse::<i32>() {
+                Ok(n) if n < min || n > max => Err(format!("out of range {min}..={max}")),
+                Ok(_) => Ok(()),
+                Err(_) => Err("expected an integer".into()),
+            },
+            Kind::Float => match v.parse::<f32>() {
+                Ok(_) => Ok(()),
+                Err(_) => Err("expected a number".into()),
+            },
+            Kind::Enum(values) => match values.contains(&v) {
+                true => Ok(()),
+                false => Err(format!("expected one of {}", values.join("|"))),
+            },
+            Kind::List(values) => {
+                for item in v.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    if !values.contains(&item) {
+                        return Err(format!("{item} is not one of {}", values.join("|")));
+                    }
+                }
+                Ok(())
+            }
+            Kind::IntList => {
+                for item in v.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    if item.parse::<i32>().is_err() {
+                        return Err(format!("{item} is not an integer"));
+                    }
+                }
+                Ok(())
+            }
+            Kind::Addr => match v.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+                _ => Err("expected host:port".into()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field {
+    pub key: &'static str,
+    pub kind: Kind,
+    pub default: Option<&'static str>,
+    pub required: bool,
+    pub hot: bool,    //可以在运行中重新加载
+    pub secret: bool, //打印配置时隐藏
+}
+
+impl Field {
+    pub const fn new(key: &'static str, kind: Kind) -> Self {
+        Field {
+            key,
+            kind,
+            default: None,
+            required: false,
+            hot: false,
+            secret: false,
+        }
+    }
+
+    pub const fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub const fn default(mut self, v: &'static str) -> Self {
+        self.default = Some(v);
+        self
+    }
+
+    pub const fn hot(mut self) -> Self {
+        self.hot = true;
+        self
+    }
+
+    pub const fn secret(mut self) -> Self {
+        self.secret = true;
+        self
+    }
+
+    fn is_prefix(&self) -> bool {
+        self.key.ends_with('.')
+    }
+}
+
+//所有服务器共用的配置, 包括机器人
+const COMMON: &[Field] = &[
+    Field::new("host_id", INT).required(),
+    Field::new("host_name", Kind::Str),
+    Field::new("workdir", Kind::Str),
+    Field::new("logic_path", Kind::Str),
+    Field::new("service_addr", Kind::Addr),
+    Field::new("log_level", Kind::Int { min: 1, max: 4 })
+        .default("3")
+        .hot(),
+    Field::new("log_format", Kind::Enum(&["text", "json"]))
+        .default("text")
+        .hot(),
+    Field::new("log_chan_size", POSITIVE).default("2000"),
+    Field::new("log_overflow", Kind::Enum(&["drop_debug", "block", "drop"]))
+        .default("drop_debug")
+        .hot(),
+    Field::new("fps", POSITIVE).default("10").hot(),
+    Field::new("is_ws", Kind::Bool).default("false"),
+    Field::new("max_connection", POSITIVE).default("10000"),
+    Field::new("conn_chan_size", POSITIVE).default("1000"),
+    Field::new("conn_msg_chan_size", POSITIVE).default("2000"),
+    Field::new("tcp_msg_chan_size", POSITIVE).default("20000"),
+    Field::new("is_encrypt", Kind::Bool).default("false"),
+    Field::new("compress", Kind::List(&["lz4", "zstd", "none"])),
+    Field::new("compress_threshold", NON_NEGATIVE),
+    Field::new("is_ssl", Kind::Bool).default("false"),
+    Field::new("tcp_tls", Kind::Bool).default("false"),
+    Field::new("certificate_file", Kind::Str),
+    Field::new("privatekey_file", Kind::Str),
+    Field::new("ca_file", Kind::Str),
+    Field::new("tls_server_name", Kind::Str),
+];
+
+//server 使用的配置
+const SERVER: &[Field] = &[
+    Field::new("service_type", Kind::Enum(SERVICE_TYPES)).required(),
+    Field::new("rpc_service_addr", Kind::Addr),
+    Field::new("rpc_secret", Kind::Str).secret(),
+    Field::new("rpc_acl.", Kind::List(SERVICE_TYPES)),
+    Field::new("rpc_host.", Kind::Addr),
+    Field::new("http_addr", Kind::Addr),
+    Field::new("http_token", Kind::Str).secret(),
+    Field::new("encrypt_key_file", Kind::Str),
+    Field::new("console_addr", Kind::Str),
+    Field::new("console_readonly", Kind::Bool).default("false"),
+    Field::new("rpc_tls", Kind::Bool).default("false"),
+    Field::new("rpc_tls_mutual", Kind::Bool).default("false"),
+    Field::new("rpc_credit_window", NON_NEGATIVE),
+    Field::new("rpc_backlog_max", POSITIVE).default("10000"),
+    Field::new("max_connection_per_ip", NON_NEGATIVE),
+    Field::new("limit_msg_rate", NON_NEGATIVE).hot(),
+    Field::new("limit_msg_burst", NON_NEGATIVE).hot(),
+    Field::new("limit_byte_rate", NON_NEGATIVE).hot(),
+    Field::new("limit_byte_burst", NON_NEGATIVE).hot(),
+    Field::new("limit_proto.", Kind::Str).hot(),
+    Field::new("limit_action", Kind::Enum(&["drop", "delay", "disconnect"])).hot(),
+    Field::new("sched_budget", POSITIVE),
+    Field::new("sched_weight_rpc", POSITIVE),
+    Field::new("sched_weight_login", POSITIVE),
+    Field::new("sched_weight_gameplay", POSITIVE),
+    Field::new("sched_login_protos", Kind::IntList),
+    Field::new("sched_queue_max", POSITIVE),
+    Field::new("sched_rpc_queue_max", POSITIVE),
+    Field::new("logic_shards", POSITIVE),
+    Field::new("shard_policy", Kind::Enum(&["hash", "scene"])),
+    Field::new("shard_lobby", NON_NEGATIVE),
+    Field::new("shard_scene.", NON_NEGATIVE),
+    Field::new("shard_migrate_pending_max", POSITIVE),
+    Field::new("shard_backlog_max", POSITIVE),
+    Field::new("session_migrate_timeout_ms", POSITIVE),
+    Field::new("gate_backends", Kind::IntList),
+    Field::new("gate_login_protos", Kind::IntList),
+    Field::new("db_engine", Kind::Enum(&["sled", "memory"])),
+    Field::new("db_path", Kind::Str),
+    Field::new("db_batch_size", POSITIVE),
+    Field::new("db_write_behind_ms", NON_NEGATIVE),
+    Field::new("db_write_behind_max", POSITIVE),
+    Field::new("data_db_host", INT),
+    Field::new("data_table", Kind::Str),
+    Field::new("data_flush_ms", POSITIVE),
+    Field::new("data_retry_ms", POSITIVE),
+    Field::new("data_save_timeout_ms", POSITIVE),
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    fields: Vec<Field>,
+}
+
+impl Schema {
+    pub fn common() -> Self {
+        Schema {
+            fields: COMMON.to_vec(),
+        }
+    }
+
+    pub fn server() -> Self {
+        Self::common().with(SERVER)
+    }
+
+    //追加或者覆盖字段
+    pub fn with(mut self, fields: &[Field]) -> Self {
+        for field in fields {
+            self.fields.retain(|f| f.key != field.key);
+            self.fields.push(*field);
+        }
+        self
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub fn field(&self, key: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| {
+            if f.is_prefix() {
+                key.strip_prefix(f.key).is_some_and(|k| !k.is_empty())
+            } else {
+                f.key == key
+            }
+        })
+    }
+
+    //补全默认值并校验, 返回所有错误
+    pub fn apply(&self, conf: &mut Config) -> Vec<String> {
+        let mut errors = Vec::new();
+        for field in self.fields.iter().filter(|f| !f.is_prefix()) {
+            if conf.contains(field.key) {
+                continue;
+            }
+            match field.default {
+                Some(v) => conf.set(field.key, v),
+                None if field.required => errors.push(format!("{}: is required", field.key)),
+                None => {}
+            }
+        }
+        for (k, v) in conf.entries() {
+            match self.field(k) {
+                Some(field) => {
+                    if let Err(err) = field.kind.check(v) {
+                        errors.push(format!("{k} = {v}: {err}"));
+                    }
+                }
+                None => errors.push(format!("{k}: unknown key")),
+            }
+        }
+        errors
+    }
+}
+
+//启动时使用的配置, 校验后的类型化视图
+#[derive(Debug, Clone, PartialEq)]
+pub struct SysConf {
+    pub host_id: i32,
+    pub service_type: ServiceType, //机器人没有配置时为 UNKNOW
+    pub service_addr: Option<String>,
+    pub rpc_service_addr: Option<String>,
+    pub logic_path: Option<String>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub log_chan_size: usize,
+    pub log_overflow: LogOverflow,
+    pub fps: i32,
+    pub is_ws: bool,
+    pub is_ssl: bool,
+    pub tcp_tls: bool,
+    pub rpc_tls: bool,
+}
+
+impl SysConf {
+    //按 schema 补全之后调用; 检查键之间的依赖, 返回所有错误
+    pub fn from_config(conf: &Config) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let service_type = conf
+            .get_string("service_type")
+            .map_or(ServiceType::UNKNOW, |s| ServiceType::from(s.as_str()));
+        let sys = SysConf {
+            host_id: conf.get_int("host_id").unwrap_or_default(),
+            service_type,
+            service_addr: conf.get_string("service_addr").cloned(),
+            rpc_service_addr: conf.get_string("rpc_service_addr").cloned(),
+            logic_path: conf.get_string("logic_path").cloned(),
+            log_level: conf.get_int("log_level").unwrap_or(3).into(),
+            log_format: conf
+                .get_string("log_format")
+                .map_or(LogFormat::Text, |s| s.as_str().into()),
+            log_chan_size: conf.get_int("log_chan_size").unwrap_or(2000).max(1) as usize,
+            log_overflow: conf
+                .get_string("log_overflow")
+                .map_or(LogOverflow::DropDebug, |s| s.as_str().into()),
+            fps: conf.get_int("fps").unwrap_or(10),
+            is_ws: conf.get_bool("is_ws"),
+            is_ssl: conf.get_bool("is_ssl"),
+            tcp_tls: conf.get_bool("tcp_tls"),
+            rpc_tls: conf.get_bool("rpc_tls"),
+        };
+
+        //示例配置中的占位值是公开的, 不能作为真正的密钥使用
+        for key in ["http_token", "rpc_secret"] {
+            if conf
+                .get_string(key)
+                .is_some_and(|v| v.starts_with("change_me"))
+            {
+                errors.push(format!("{key}: replace the change_me placeholder"));
+            }
+        }
+        let mut require = |key: &str, reason: &str| {
+            if conf.get_string(key).is_none_or(|v| v.is_empty()) {
+                errors.push(format!("{key}: is required {reason}"));
+            }
+        };
+        //db 服务只接收 rpc, 其他服务都有对外的监听地址; server 的所有服务都有 rpc
+        if service_type != ServiceType::DB {
+            require("service_addr", "to accept or open connections");
+        }
+        if service_type != ServiceType::UNKNOW {
+            require("rpc_service_addr", "by every server");
+            require("rpc_secret", "by every server");
+        }
+        //网关和 db 服务不运行脚本
+        if service_type != ServiceType::GATE && service_type != ServiceType::DB {
+            require("logic_path", "to run lua scripts");
+        }
+        //机器人只作为连接端
+        if sys.is_ssl && service_type != ServiceType::UNKNOW {
+            require("certificate_file", "when is_ssl = true");
+            require("privatekey_file", "when is_ssl = true");
+        }
+        if sys.tcp_tls && service_type == ServiceType::UNKNOW {
+            require("ca_file", "when tcp_tls = true");
+        } else if sys.tcp_tls {
+            require("certificate_file", "when tcp_tls = true");
+            require("privatekey_file", "when tcp_tls = true");
+        }
+        //管理接口可以踢人和执行 gm 命令, 不允许匿名访问
+        if conf.get_string("http_addr").is_some() {
+            require("http_token", "when http_addr is set");
+        }
+        //服务端用静态密钥证明身份, 客户端固定服务端的公钥
+        if conf.get_bool("is_encrypt") && service_type == ServiceType::UNKNOW {
+            require("encrypt_server_key", "when is_encrypt = true");
+        } else if conf.get_bool("is_encrypt") {
+            require("encrypt_key_file", "when is_encrypt = true");
+        }
+        if sys.rpc_tls {
+            require("certificate_file", "when rpc_tls = true");
+            require("privatekey_file", "when rpc_tls = true");
+            require("ca_file", "when rpc_tls = true");
+        }
+        //控制台可以执行任意脚本, 只能在本机访问
+        if let Some(Err(err)) = conf
+            .get_string("console_addr")
+            .map(|a| ConsoleAddr::parse(a))
+        {
+            errors.push(format!("console_addr: {err}"));
+        }
+        if errors.is_empty() {
+            Ok(sys)
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -6,10 +6,12 @@ pub mod services;
 pub mod error;
 pub use error::Error;
 pub type Result<T> = std::result::Result<T, error::Error>;
+pub mod cli;
 pub mod config;
 
 pub mod logger;
 pub mod macros;
+pub mod metrics;
 
 pub mod network;
 pub mod states;
@@ -19,9 +19,9 @@ impl From<i32> for LogLevel {
     }
 }
 
-impl Into<i32> for LogLevel {
-    fn into(self) -> i32 {
-        match self {
+impl From<LogLevel> for i32 {
+    fn from(val: LogLevel) -> Self {
+        match val {
             LogLevel::Debug => 1,
             LogLevel::Warning => 2,
             LogLevel::Info => 3,
@@ -30,6 +30,41 @@ impl Into<i32> for LogLevel {
     }
 }
 
+//日志格式: 默认是文本; json 时每行一个对象, 带上 host_id, vfd, proto_id 和 trace_id 等字段
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl From<&str> for LogFormat {
+    fn from(s: &str) -> Self {
+        match s {
+            "json" => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+//日志队列满时的处理: 先丢弃 debug 日志(默认), 等待, 或者直接丢弃; 丢弃的行数写在 logger.log 中
+//Block 会让写日志的 tokio 工作线程停下来等写线程, 磁盘慢时整个运行时都会卡住
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LogOverflow {
+    DropDebug,
+    Block,
+    Drop,
+}
+
+impl From<&str> for LogOverflow {
+    fn from(s: &str) -> Self {
+        match s {
+            "block" => LogOverflow::Block,
+            "drop" => LogOverflow::Drop,
+            _ => LogOverflow::DropDebug,
+        }
+    }
+}
+
 mod inner;
 pub use inner::Inner;
 
@@ -37,14 +72,18 @@ mod outter;
 pub use outter::Outter;
 
 mod hub;
-pub use hub::init;
+pub use hub::{init, set_log_root, shutdown};
 mod sink;
-pub use sink::clone_sender;
+pub use sink::{
+    channel, clone_sender, dropped, get_global_log_level, get_host_id, get_log_format,
+    get_log_overflow, set_global_log_level, set_host_id, set_log_format, set_log_overflow,
+    LogReceiver, LogSender,
+};
+mod trace;
+pub use trace::{current_trace, new_trace_id, Trace, TraceGuard};
 
+//使用全局日志等级, 重新加载配置后随之变化
 pub fn build_logger(log_name: &str) -> Outter {
-    let log_level = sink::get_global_log_level();
     let sender = clone_sender().unwrap();
-    Outter::new(log_name)
-        .with_level(log_level)
-        .with_sinker(sender)
+    Outter::new(log_name).with_sinker(sender)
 }
@@ -1,10 +1,24 @@
-use super::sink;
-use super::{inner::Inner, LogLevel};
-use sink::LogMsgType;
+use super::sink::{self, LogReceiver, CLOSE_MSG};
+use super::{inner::Inner, LogLevel, Outter};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::mpsc;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::OnceLock;
 use std::thread;
+use std::time::{Duration, Instant};
+
+//文件按大小(Inner 的缓冲区满)或者时间写出
+const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+//丢弃的日志行数写在这个日志中
+const DROPPED_LOG_NAME: &str = "logger.log";
+//日志文件路径的根目录, 没有设置时是相对当前目录的 log/xxx
+static G_LOG_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+//在 init 之前设置, 只有第一次设置生效
+pub fn set_log_root(dir: &Path) {
+    let _ = G_LOG_ROOT.set(dir.to_owned());
+}
 
 //只能初始化一次
 pub fn init(log_level: LogLevel, log_chan_size: usize) {
@@ -17,37 +31,111 @@ pub fn init(log_level: LogLevel, log_chan_size: usize) {
     sink::set_chan(log_chan_size);
     let chan_receiver = sink::take_receiver().unwrap();
 
-    thread::spawn(move || {
-        let mut logfiles: HashMap<String, Inner> = HashMap::new();
+    let writer = thread::spawn(move || Writer::new().run(chan_receiver));
+    sink::set_writer(writer);
+}
+
+//等待写线程把队列中所有的日志写到文件后退出, 进程退出前调用; 之后的日志不再写入
+pub fn shutdown() {
+    let Some(writer) = sink::take_writer() else {
+        return;
+    };
+    if let Some(sender) = sink::clone_sender() {
+        if let Err(err) = sender.send_close() {
+            eprintln!("log failed: {err}");
+        }
+    }
+    if writer.join().is_err() {
+        eprintln!("log failed: writer panicked");
+    }
+}
+
+struct Writer {
+    logfiles: HashMap<String, Inner>,
+    stdout: BufWriter<Stdout>,
+    last_flush: Instant,
+    reported: u64, //已经写到日志中的丢弃行数
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer {
+            logfiles: HashMap::new(),
+            stdout: BufWriter::new(io::stdout()),
+            last_flush: Instant::now(),
+            reported: 0,
+        }
+    }
+
+    fn run(mut self, chan_receiver: LogReceiver) {
         loop {
-            match chan_receiver.recv() {
-                Ok((log_path, logstr)) => {
-                    if logstr == "gm:close" {
-                        eprintln!("gm:close");
-                        break;
-                    }
-                    if let Some(lgr) = logfiles.get_mut(&log_path) {
-                        if let Err(err) = lgr.write(&logstr) {
-                            eprintln!("log failed: {err}");
-                        }
-                    } else {
-                        let fp = PathBuf::from(log_path.clone());
-                        let filename = fp.file_name().unwrap().to_str().unwrap();
-                        let mut lgr = Inner::new(&log_path, filename);
-                        if let Err(err) = lgr.write(&logstr) {
-                            eprintln!("log failed: {err}");
+            match chan_receiver.recv_timeout(FLUSH_INTERVAL) {
+                Ok((_, logstr)) if logstr == CLOSE_MSG => {
+                    //关闭消息之前发出的日志都已经取出
+                    while let Ok((log_path, logstr)) = chan_receiver.try_recv() {
+                        if logstr != CLOSE_MSG {
+                            self.write(log_path, &logstr);
                         }
-                        logfiles.insert(log_path, lgr);
                     }
+                    eprintln!("gm:close");
+                    break;
                 }
-                Err(err) => {
-                    eprintln!("log failed: {err}");
-                }
+                Ok((log_path, logstr)) => self.write(log_path, &logstr),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
             }
         }
-    });
-}
+        self.flush();
+    }
 
-pub fn clone_sender() -> Option<mpsc::Sender<LogMsgType>> {
-    sink::clone_sender()
+    fn write(&mut self, log_path: String, logstr: &str) {
+        //for debug
+        let _ = writeln!(self.stdout, "{logstr}");
+        let lgr = self
+            .logfiles
+            .entry(log_path)
+            .or_insert_with_key(|log_path| {
+                let fp = match G_LOG_ROOT.get() {
+                    Some(root) => root.join(log_path),
+                    None => PathBuf::from(log_path),
+                };
+                let filename = fp.file_name().unwrap().to_str().unwrap();
+                Inner::new(fp.to_str().unwrap(), filename)
+            });
+        if let Err(err) = lgr.write(logstr) {
+            eprintln!("log failed: {err}");
+        }
+    }
+
+    fn flush(&mut self) {
+        self.report_dropped();
+        for lgr in self.logfiles.values_mut() {
+            if let Err(err) = lgr.flush() {
+                eprintln!("log failed: {err}");
+            }
+        }
+        let _ = self.stdout.flush();
+        self.last_flush = Instant::now();
+    }
+
+    //队列满时丢弃的行数, 有新的丢弃时写一行
+    fn report_dropped(&mut self) {
+        let dropped = sink::dropped();
+        if dropped == self.reported {
+            return;
+        }
+        let report = Outter::new(DROPPED_LOG_NAME);
+        let logstr = format!(
+            "[logger]: dropped={},total={},overflow={:?}",
+            dropped - self.reported,
+            dropped,
+            sink::get_log_overflow()
+        );
+        self.reported = dropped;
+        let line = report.format_line("error", &logstr, &[]);
+        self.write(report.get_path().to_owned(), &line);
+    }
 }
@@ -1,22 +1,25 @@
 //正在写文件需要创建 inner 对象
 
 use crate::{error::Error, Result};
-use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
-use std::io::{BufRead, BufReader, Write};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, ParseResult};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::{
     fs::{self, create_dir_all, File, OpenOptions},
     os::unix::prelude::MetadataExt,
 };
 
+//写缓冲区大小, 缓冲区满时写一次文件
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub struct Inner {
-    path: String,           //文件路径
-    name: String,           //文件名
-    handler: Option<File>,  //文件句柄
-    create_date: NaiveDate, //对象创建的时间
-    size: u64,              //当前文件大小, 单位 byte
-    max_size: u64,          //文件大小最大上限, 单位 byte
-    roll_times: i32,        //当天文件滚动次数
+    path: String,                     //文件路径
+    name: String,                     //文件名
+    handler: Option<BufWriter<File>>, //文件句柄, 写满缓冲区或者 flush 时才写到文件
+    create_date: NaiveDate,           //对象创建的时间
+    size: u64,                        //当前文件大小, 单位 byte
+    max_size: u64,                    //文件大小最大上限, 单位 byte
+    roll_times: i32,                  //当天文件滚动次数
 }
 
 impl Inner {
@@ -72,8 +75,17 @@ impl Inner {
         }
     }
 
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(fh) = self.handler.as_mut() {
+            fh.flush()?;
+        }
+        Ok(())
+    }
+
     pub fn roll(&mut self) -> Result<()> {
-        self.handler.take();
+        if let Some(mut fh) = self.handler.take() {
+            fh.flush()?;
+        }
         self.size = 0;
         self.roll_times += 1;
         self.create_date = Local::now().date_naive();
@@ -81,7 +93,7 @@ impl Inner {
         let newname = format!(
             "{}.{}-{}",
             self.path,
-            Local::now().format("%Y-%m-%d %H:%M:%S%.6f").to_string(),
+            Local::now().format("%Y-%m-%d %H:%M:%S%.6f"),
             self.roll_times
         );
         match fs::rename(&self.path, &newname) {
@@ -95,8 +107,6 @@ impl Inner {
         let n: usize = logstr.len();
         writeln!(fh, "{}", logstr)?;
         self.size += n as u64;
-        //fh.flush()?;
-        println!("{logstr}"); // for debug
         Ok(())
     }
 
@@ -106,41 +116,34 @@ impl Inner {
             None => {
                 let now = Local::now();
                 //尝试打开当前文件
-                let mt = fs::metadata(&self.path);
-                if mt.is_ok() {
+                if let Ok(mt) = fs::metadata(&self.path) {
                     //文件存在,读取最后一行，判断文件最后写入时间
                     let tmp: File = File::open(&self.path)?;
                     let reader = BufReader::new(tmp);
                     let mut last_line = String::from("");
-                    if let Some(line) = reader.lines().last() {
-                        if let Ok(lstr) = line {
-                            last_line = lstr;
-                        }
+                    if let Some(Ok(lstr)) = reader.lines().last() {
+                        last_line = lstr;
                     }
                     //println!("Last line: {}==={}", self.path, last_line);
 
-                    if last_line.len() == 0 {
+                    if last_line.is_empty() {
                         return self.create_new_file_and_write(logstr);
                     }
 
-                    //每一行都是以 "[时间][事件][日志等级]: 日志内容" 格式写入日志文件
+                    //文本格式每一行都是以 "[时间][事件][日志等级]: 日志内容" 格式写入日志文件, json 格式以 {"ts":"时间" 开头
                     let mut need_new_file = true; //是否需要重新创建文件
-                    if let Some(epos) = last_line.find(']') {
-                        if epos > 1 {
-                            let timestr = &last_line[1..epos];
-                            match NaiveDateTime::parse_from_str(timestr, "%Y-%m-%d %H:%M:%S%.6f") {
-                                Ok(last_time) => {
-                                    if last_time.date() == now.date_naive() {
-                                        need_new_file = false;
-                                    } else {
-                                        self.roll()?
-                                    }
-                                }
-                                Err(err) => {
-                                    eprintln!("{}", err);
-                                }
+                    match line_date(&last_line) {
+                        Some(Ok(last_date)) => {
+                            if last_date == now.date_naive() {
+                                need_new_file = false;
+                            } else {
+                                self.roll()?
                             }
                         }
+                        Some(Err(err)) => {
+                            eprintln!("{}", err);
+                        }
+                        None => {}
                     }
                     //println!("-----{},{}", self.path, need_new_file);
                     if need_new_file {
@@ -149,8 +152,9 @@ impl Inner {
                         //不需要创建文件，则打开当前文件写入
                         match OpenOptions::new().append(true).open(&self.path) {
                             Ok(fh) => {
-                                self.size = mt.unwrap().size();
-                                self.handler = Some(fh);
+                                self.size = mt.size();
+                                self.handler =
+                                    Some(BufWriter::with_capacity(WRITE_BUFFER_SIZE, fh));
                                 self.dowrite(logstr)
                             }
                             Err(err) => Err(Error::IoError(err)),
@@ -178,7 +182,7 @@ impl Inner {
             .open(&self.path)
         {
             Ok(fh) => {
-                self.handler = Some(fh);
+                self.handler = Some(BufWriter::with_capacity(WRITE_BUFFER_SIZE, fh));
                 self.size = 0;
                 self.roll_times = 0;
 
@@ -188,3 +192,17 @@ impl Inner {
         }
     }
 }
+
+//日志行的写入日期, 不是日志格式时返回 None
+fn line_date(line: &str) -> Option<ParseResult<NaiveDate>> {
+    if let Some(rest) = line.strip_prefix("{\"ts\":\"") {
+        let timestr = &rest[..rest.find('"')?];
+        return Some(DateTime::parse_from_rfc3339(timestr).map(|t| t.date_naive()));
+    }
+    let epos = line.find(']')?;
+    if epos <= 1 {
+        return None;
+    }
+    let timestr = &line[1..epos];
+    Some(NaiveDateTime::parse_from_str(timestr, "%Y-%m-%d %H:%M:%S%.6f").map(|t| t.date()))
+}
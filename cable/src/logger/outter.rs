@@ -1,32 +1,43 @@
 //暴露给用户的 Outter log对象
-//每个 Outter 对象都持有一个文件路径，以及对应的日志等级
-use super::LogLevel;
-use chrono::Local;
-use std::sync::mpsc::Sender;
+//每个 Outter 对象都持有一个文件路径，以及对应的日志等级; 没有单独设置等级时使用全局日志等级
+//日志格式跟随全局设置, 见 LogFormat
+use super::sink::{self, LogSender};
+use super::{trace, LogFormat, LogLevel};
+use chrono::{DateTime, Local};
+use serde_json::Value;
 
 #[derive(Clone)]
 pub struct Outter {
-    log_path: String, //文件路径
-    level: LogLevel,  //当前设置的可写入的日志等级
-    sinker: Option<Sender<(String, String)>>,
+    log_name: String,        //日志名, json 格式中的 logger 字段
+    log_path: String,        //文件路径
+    vfd: Option<u64>,        //连接的读写协程使用的日志, 每一行都带上 vfd
+    level: Option<LogLevel>, //当前设置的可写入的日志等级, None 时跟随全局日志等级
+    sinker: Option<LogSender>,
 }
 
 impl Outter {
     pub fn new(log_name: &str) -> Self {
         let log_path = format!("log/{log_name}");
         Outter {
+            log_name: log_name.to_owned(),
             log_path,
-            level: LogLevel::Debug, //默认当前是最低日志等级
-            sinker: None,           //默认处理日志文本的方法是打印到 stdout
+            vfd: None,
+            level: None,  //默认跟随全局日志等级, 初始化之前是最低日志等级
+            sinker: None, //默认处理日志文本的方法是打印到 stdout
         }
     }
 
     pub fn with_level(mut self, lvl: LogLevel) -> Self {
-        self.level = lvl;
+        self.level = Some(lvl);
         self
     }
 
-    pub fn with_sinker(mut self, sinker: Sender<(String, String)>) -> Self {
+    pub fn with_vfd(mut self, vfd: u64) -> Self {
+        self.vfd = Some(vfd);
+        self
+    }
+
+    pub fn with_sinker(mut self, sinker: LogSender) -> Self {
         self.sinker = Some(sinker);
         self
     }
@@ -36,39 +47,106 @@ impl Outter {
     }
 
     pub fn set_level(&mut self, lvl: LogLevel) {
-        self.level = lvl;
+        self.level = Some(lvl);
     }
 
     pub fn get_level(&self) -> LogLevel {
-        self.level
+        self.level.unwrap_or_else(sink::get_global_log_level)
     }
 
     pub fn can_log_debug(&self) -> bool {
-        LogLevel::Debug >= self.level
+        LogLevel::Debug >= self.get_level()
     }
 
     pub fn can_log_warning(&self) -> bool {
-        LogLevel::Warning >= self.level
+        LogLevel::Warning >= self.get_level()
     }
 
     pub fn can_log_info(&self) -> bool {
-        LogLevel::Info >= self.level
+        LogLevel::Info >= self.get_level()
     }
 
     pub fn can_log_error(&self) -> bool {
-        LogLevel::Error >= self.level
+        LogLevel::Error >= self.get_level()
     }
 
     pub fn log(&mut self, lvl: &str, logstr: &str) {
-        let timestr = Local::now().format("%Y-%m-%d %H:%M:%S%.6f").to_string();
-        let nstr = format!("[{}][{}]{}", timestr, lvl, logstr);
+        self.log_fields(lvl, logstr, &[]);
+    }
+
+    //带有额外字段的日志; 队列满时按 LogOverflow 处理
+    pub fn log_fields(&mut self, lvl: &str, logstr: &str, fields: &[(String, Value)]) {
+        let nstr = self.format_line(lvl, logstr, fields);
         let fp = self.get_path().to_string();
         if let Some(sinker) = &self.sinker {
-            if let Err(err) = sinker.send((fp, nstr)) {
+            if let Err(err) = sinker.send(lvl, (fp, nstr)) {
                 eprintln!("[log]: err={err}, logstr={logstr}");
             }
         } else {
             eprintln!("[log]: no_sinker=true,logstr={logstr}");
         }
     }
+
+    //按全局的日志格式生成一行, 文本格式时额外字段以 ",k=v" 追加在内容后面
+    pub fn format_line(&self, lvl: &str, logstr: &str, fields: &[(String, Value)]) -> String {
+        let now = Local::now();
+        match sink::get_log_format() {
+            LogFormat::Text => {
+                let timestr = now.format("%Y-%m-%d %H:%M:%S%.6f");
+                let mut nstr = format!("[{}][{}]{}", timestr, lvl, logstr);
+                for (k, v) in fields {
+                    //字符串不加引号, 与其他日志的 k=v 写法一致
+                    let v = match v {
+                        Value::String(v) => v.clone(),
+                        v => v.to_string(),
+                    };
+                    nstr.push_str(&format!(",{k}={v}"));
+                }
+                nstr
+            }
+            LogFormat::Json => self.json_line(now, lvl, logstr, fields),
+        }
+    }
+
+    //ts 放在第一个字段, Inner 据此判断文件最后写入的日期
+    //额外字段与固定字段同名时覆盖固定字段, ts 和 level 除外
+    fn json_line(
+        &self,
+        now: DateTime<Local>,
+        lvl: &str,
+        logstr: &str,
+        fields: &[(String, Value)],
+    ) -> String {
+        let mut obj: Vec<(String, Value)> = vec![
+            (
+                "ts".into(),
+                now.format("%Y-%m-%dT%H:%M:%S%.6f%:z").to_string().into(),
+            ),
+            ("level".into(), lvl.into()),
+            ("logger".into(), self.log_name.as_str().into()),
+            ("host_id".into(), sink::get_host_id().into()),
+        ];
+        let trace = trace::current_trace();
+        if let Some(trace) = &trace {
+            obj.push(("trace_id".into(), trace.trace_id.as_str().into()));
+        }
+        if let Some(vfd) = self.vfd.or(trace.as_ref().and_then(|t| t.vfd)) {
+            obj.push(("vfd".into(), vfd.into()));
+        }
+        if let Some(proto_id) = trace.as_ref().and_then(|t| t.proto_id) {
+            obj.push(("proto_id".into(), proto_id.into()));
+        }
+        obj.push(("msg".into(), logstr.into()));
+        for (k, v) in fields.iter().filter(|(k, _)| k != "ts" && k != "level") {
+            match obj.iter_mut().find(|(key, _)| key == k) {
+                Some((_, old)) => *old = v.clone(),
+                None => obj.push((k.clone(), v.clone())),
+            }
+        }
+        let body: Vec<String> = obj
+            .iter()
+            .map(|(k, v)| format!("{}:{}", Value::from(k.as_str()), v))
+            .collect();
+        format!("{{{}}}", body.join(","))
+    }
 }
@@ -1,53 +1,169 @@
-use super::LogLevel;
+use super::{LogFormat, LogLevel, LogOverflow};
+use crate::metrics;
 use lazy_static::lazy_static;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{atomic, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::sync::{atomic, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 lazy_static! {
     static ref G_REMOTER: Mutex<Sink> = Mutex::new(Sink::new());
 }
 
+//全局日志等级, 每条日志都要检查, 不放在锁里; 运行中可以修改
+static G_LEVEL: atomic::AtomicI32 = atomic::AtomicI32::new(1);
+//全局日志格式, 与日志等级一样可以在运行中修改
+static G_JSON: atomic::AtomicBool = atomic::AtomicBool::new(false);
+//json 格式的日志中带上的 host_id
+static G_HOST_ID: atomic::AtomicI32 = atomic::AtomicI32::new(0);
+//队列满时的处理, 见 LogOverflow
+static G_OVERFLOW: atomic::AtomicU8 = atomic::AtomicU8::new(0);
+//因为队列满丢弃的日志行数
+static G_DROPPED: atomic::AtomicU64 = atomic::AtomicU64::new(0);
+
 pub type LogMsgType = (String, String);
 
+//写线程收到后把所有日志写到文件, 然后退出
+pub const CLOSE_MSG: &str = "gm:close";
+
+//有界的日志队列, 记录队列中的行数; drop_debug 时据此给 debug 以外的日志留出空间
+#[derive(Clone)]
+pub struct LogSender {
+    sender: SyncSender<LogMsgType>,
+    queued: Arc<atomic::AtomicUsize>,
+    capacity: usize,
+}
+
+pub struct LogReceiver {
+    receiver: Receiver<LogMsgType>,
+    queued: Arc<atomic::AtomicUsize>,
+}
+
+pub fn channel(capacity: usize) -> (LogSender, LogReceiver) {
+    let capacity = capacity.max(1);
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let queued = Arc::new(atomic::AtomicUsize::new(0));
+    let sender = LogSender {
+        sender,
+        queued: queued.clone(),
+        capacity,
+    };
+    (sender, LogReceiver { receiver, queued })
+}
+
+impl LogSender {
+    //按 LogOverflow 发送, 丢弃的日志只计数; 写线程已经退出时返回错误
+    pub fn send(&self, lvl: &str, msg: LogMsgType) -> Result<(), String> {
+        let block = match get_log_overflow() {
+            LogOverflow::Block => true,
+            LogOverflow::Drop => false,
+            //队列超过 3/4 时丢弃 debug 日志, 队列满时其他日志也丢弃; 日志在 tokio 线程中写, 不能等待
+            LogOverflow::DropDebug => {
+                if lvl == "debug"
+                    && self.queued.load(atomic::Ordering::Relaxed) * 4 >= self.capacity * 3
+                {
+                    drop_line(lvl);
+                    return Ok(());
+                }
+                false
+            }
+        };
+        //先计数再发送, 写线程取出时减一
+        self.queued.fetch_add(1, atomic::Ordering::Relaxed);
+        metrics::log_queued();
+        let res = match block {
+            true => self.sender.send(msg).map_err(|err| err.to_string()),
+            false => match self.sender.try_send(msg) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.queued.fetch_sub(1, atomic::Ordering::Relaxed);
+                    metrics::log_written();
+                    drop_line(lvl);
+                    return Ok(());
+                }
+                Err(err) => Err(err.to_string()),
+            },
+        };
+        if res.is_err() {
+            self.queued.fetch_sub(1, atomic::Ordering::Relaxed);
+            metrics::log_written();
+        }
+        res
+    }
+
+    //关闭消息总是等待队列有空间, 不会被丢弃
+    pub fn send_close(&self) -> Result<(), String> {
+        self.queued.fetch_add(1, atomic::Ordering::Relaxed);
+        metrics::log_queued();
+        self.sender
+            .send((String::new(), CLOSE_MSG.to_owned()))
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl LogReceiver {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<LogMsgType, RecvTimeoutError> {
+        let msg = self.receiver.recv_timeout(timeout)?;
+        self.taken();
+        Ok(msg)
+    }
+
+    pub fn try_recv(&self) -> Result<LogMsgType, TryRecvError> {
+        let msg = self.receiver.try_recv()?;
+        self.taken();
+        Ok(msg)
+    }
+
+    fn taken(&self) {
+        self.queued.fetch_sub(1, atomic::Ordering::Relaxed);
+        metrics::log_written();
+    }
+}
+
+fn drop_line(lvl: &str) {
+    G_DROPPED.fetch_add(1, atomic::Ordering::Relaxed);
+    metrics::log_dropped(lvl);
+}
+
 pub struct Sink {
-    level: LogLevel,
-    sender: Option<Sender<LogMsgType>>,
-    receiver: Option<Receiver<LogMsgType>>,
+    sender: Option<LogSender>,
+    receiver: Option<LogReceiver>,
+    writer: Option<JoinHandle<()>>,
     init: atomic::AtomicBool,
 }
 
 impl Sink {
     pub fn new() -> Self {
         Sink {
-            level: LogLevel::Debug,
             sender: None,
             receiver: None,
+            writer: None,
             init: atomic::AtomicBool::new(false),
         }
     }
 
-    pub fn set_level(&mut self, lvl: LogLevel) {
-        self.level = lvl;
-    }
-
-    pub fn set_chan(&mut self, _log_chan_size: usize) {
-        let (sender, receiver) = mpsc::channel();
+    pub fn set_chan(&mut self, log_chan_size: usize) {
+        let (sender, receiver) = channel(log_chan_size);
         self.sender = Some(sender);
         self.receiver = Some(receiver);
     }
 
-    pub fn get_level(&self) -> LogLevel {
-        self.level
-    }
-
-    pub fn clone_sender(&self) -> Option<Sender<LogMsgType>> {
+    pub fn clone_sender(&self) -> Option<LogSender> {
         self.sender.clone()
     }
 
-    pub fn take_receiver(&mut self) -> Option<Receiver<LogMsgType>> {
+    pub fn take_receiver(&mut self) -> Option<LogReceiver> {
         self.receiver.take()
     }
 
+    pub fn set_writer(&mut self, writer: JoinHandle<()>) {
+        self.writer = Some(writer);
+    }
+
+    pub fn take_writer(&mut self) -> Option<JoinHandle<()>> {
+        self.writer.take()
+    }
+
     pub fn is_init(&self) -> bool {
         self.init.load(atomic::Ordering::SeqCst)
     }
@@ -58,13 +174,51 @@ impl Sink {
 }
 
 pub fn set_global_log_level(lvl: LogLevel) {
-    let mut remote = G_REMOTER.lock().unwrap();
-    (*remote).set_level(lvl);
+    G_LEVEL.store(lvl.into(), atomic::Ordering::Relaxed);
 }
 
 pub fn get_global_log_level() -> LogLevel {
-    let remote = G_REMOTER.lock().unwrap();
-    (*remote).get_level()
+    G_LEVEL.load(atomic::Ordering::Relaxed).into()
+}
+
+pub fn set_log_format(format: LogFormat) {
+    G_JSON.store(format == LogFormat::Json, atomic::Ordering::Relaxed);
+}
+
+pub fn get_log_format() -> LogFormat {
+    match G_JSON.load(atomic::Ordering::Relaxed) {
+        true => LogFormat::Json,
+        false => LogFormat::Text,
+    }
+}
+
+pub fn set_log_overflow(overflow: LogOverflow) {
+    let n = match overflow {
+        LogOverflow::DropDebug => 0,
+        LogOverflow::Block => 1,
+        LogOverflow::Drop => 2,
+    };
+    G_OVERFLOW.store(n, atomic::Ordering::Relaxed);
+}
+
+pub fn get_log_overflow() -> LogOverflow {
+    match G_OVERFLOW.load(atomic::Ordering::Relaxed) {
+        1 => LogOverflow::Block,
+        2 => LogOverflow::Drop,
+        _ => LogOverflow::DropDebug,
+    }
+}
+
+pub fn dropped() -> u64 {
+    G_DROPPED.load(atomic::Ordering::Relaxed)
+}
+
+pub fn set_host_id(host_id: i32) {
+    G_HOST_ID.store(host_id, atomic::Ordering::Relaxed);
+}
+
+pub fn get_host_id() -> i32 {
+    G_HOST_ID.load(atomic::Ordering::Relaxed)
 }
 
 pub fn set_chan(log_chan_size: usize) {
@@ -72,16 +226,26 @@ pub fn set_chan(log_chan_size: usize) {
     (*remote).set_chan(log_chan_size);
 }
 
-pub fn clone_sender() -> Option<Sender<LogMsgType>> {
+pub fn clone_sender() -> Option<LogSender> {
     let remote = G_REMOTER.lock().unwrap();
     (*remote).clone_sender()
 }
 
-pub fn take_receiver() -> Option<Receiver<LogMsgType>> {
+pub fn take_receiver() -> Option<LogReceiver> {
     let mut remote = G_REMOTER.lock().unwrap();
     (*remote).take_receiver()
 }
 
+pub fn set_writer(writer: JoinHandle<()>) {
+    let mut remote = G_REMOTER.lock().unwrap();
+    (*remote).set_writer(writer)
+}
+
+pub fn take_writer() -> Option<JoinHandle<()>> {
+    let mut remote = G_REMOTER.lock().unwrap();
+    (*remote).take_writer()
+}
+
 pub fn is_init() -> bool {
     let remote = G_REMOTER.lock().unwrap();
     (*remote).is_init()
@@ -0,0 +1,79 @@
+//正在处理的消息的上下文, json 格式的日志自动带上这些字段
+//game_hub 在同一个线程中同步地把消息交给脚本层, 所以上下文放在线程局部变量中, 处理完后恢复
+//rpc 协议带上 trace_id, 对端处理时使用同一个 trace_id, 一个请求在各台机器上的日志可以关联起来
+use super::sink;
+use chrono::Local;
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+lazy_static! {
+    //进程启动的时间, 重启后生成的 trace_id 不会与之前的重复
+    static ref START_MS: i64 = Local::now().timestamp_millis();
+}
+
+static SEQ: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub trace_id: String,
+    pub vfd: Option<u64>,
+    pub proto_id: Option<u32>,
+}
+
+impl Trace {
+    pub fn new(trace_id: String) -> Self {
+        Trace {
+            trace_id,
+            vfd: None,
+            proto_id: None,
+        }
+    }
+
+    pub fn with_vfd(mut self, vfd: u64) -> Self {
+        self.vfd = Some(vfd);
+        self
+    }
+
+    pub fn with_proto_id(mut self, proto_id: u32) -> Self {
+        self.proto_id = Some(proto_id);
+        self
+    }
+
+    //设置当前线程的上下文, 返回的 guard 释放时恢复之前的上下文
+    pub fn enter(self) -> TraceGuard {
+        let prev = CURRENT.with(|cur| cur.borrow_mut().replace(self));
+        TraceGuard {
+            prev,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+//不能跨越 await 持有, 否则任务换到其他线程后上下文就错了
+pub struct TraceGuard {
+    prev: Option<Trace>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|cur| *cur.borrow_mut() = prev);
+    }
+}
+
+pub fn current_trace() -> Option<Trace> {
+    CURRENT.with(|cur| cur.borrow().clone())
+}
+
+//格式为 host_id-启动时间-序号, 十六进制
+pub fn new_trace_id() -> String {
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", sink::get_host_id(), *START_MS, seq)
+}
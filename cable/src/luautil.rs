@@ -1,14 +1,23 @@
 use crate::config::Config;
-use crate::logger::{build_logger, Outter};
-use crate::message::{SMSender, ServiceType};
-use crate::states::{Communicate, GameState, TcpState, TimerState};
-use crate::{debug, error, info, warning};
+use crate::error;
+use crate::logger::{build_logger, current_trace, new_trace_id, Outter};
+use crate::message::{Frame, SMSender, ServiceType};
+use crate::modules::data_cache::Load;
+use crate::modules::session::SessionTarget;
+use crate::states::db_state::DbOp;
+use crate::states::game_state::{LuaDataCache, RPC_SHARD_SHIFT};
+use crate::states::{Communicate, GameState, SessionState, ShardState, TcpState, TimerState};
 use crate::{network, protos::*};
 use chrono::Local;
-use rlua::{LightUserData, Lua, Table, ToLua};
+use rlua::{
+    Context, Function, HookTriggers, LightUserData, Lua, MultiValue, RegistryKey, Table, Value,
+};
 use std::collections::HashMap;
 use std::ffi::c_void;
 use std::fs::read_to_string;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub fn init_lua(service_type: ServiceType, conf: Config) -> rlua::Result<Lua> {
     let logic_path = conf.get_string("logic_path").unwrap();
@@ -31,6 +40,13 @@ pub fn init_lua(service_type: ServiceType, conf: Config) -> rlua::Result<Lua> {
 
         let log_level = conf.get_int("log_level").unwrap();
         xlib.set("log_level", log_level)?;
+
+        //逻辑分片, 没有开启分片时只有 0 号分片
+        xlib.set("shard_id", conf.get_int("shard_id").unwrap_or(0))?;
+        xlib.set(
+            "shard_num",
+            conf.get_int("logic_shards").unwrap_or(1).max(1),
+        )?;
         //====================== 注册供脚本层调用的函数 ======================
         //时间相关
         //毫秒 10-3秒
@@ -41,40 +57,51 @@ pub fn init_lua(service_type: ServiceType, conf: Config) -> rlua::Result<Lua> {
         xlib.set("time_ms", time_ms)?;
         //纳秒（10-9秒）
         let time_ns = ctx.create_function(|_, ()| {
-            let ns = Local::now().timestamp_nanos();
+            let ns = Local::now().timestamp_nanos_opt().unwrap_or_default();
             Ok(ns)
         })?;
         xlib.set("time_ns", time_ns)?;
 
         //日志相关
+        //xlib.log(log_name, lvl, log_str, fields), fields 是可选的 table, 作为日志的额外字段
         let mut alllogs: HashMap<String, Outter> = HashMap::new();
         let log = ctx.create_function_mut(
-            move |_, (log_name, lvl, log_str): (String, String, String)| {
+            move |_, (log_name, lvl, log_str, fields): (String, String, String, Option<Table>)| {
                 if !alllogs.contains_key(&log_name) {
                     let newlog = build_logger(&log_name);
                     alllogs.insert(log_name.clone(), newlog);
                 }
                 let log = alllogs.get_mut(&log_name).unwrap();
-                match lvl.as_str() {
-                    "info" => {
-                        info!(log, "{}", log_str);
-                    }
-                    "error" => {
-                        error!(log, "{}", log_str);
-                    }
-                    "warn" => {
-                        warning!(log, "{}", log_str);
-                    }
-                    _ => {
-                        debug!(log, "{}", log_str);
-                    }
+                let (lvl, can_log) = match lvl.as_str() {
+                    "info" => ("info", log.can_log_info()),
+                    "error" => ("error", true),
+                    "warn" => ("warn", log.can_log_warning()),
+                    _ => ("debug", log.can_log_debug()),
+                };
+                if !can_log {
+                    return Ok(());
                 }
+                let fields = match fields.map(|t| lua_to_json(Value::Table(t))) {
+                    Some(serde_json::Value::Object(obj)) => obj.into_iter().collect(),
+                    Some(_) => {
+                        return Err(rlua::Error::RuntimeError(
+                            "xlib.log: fields must be a table with string keys".into(),
+                        ))
+                    }
+                    None => Vec::new(),
+                };
+                log.log_fields(lvl, &log_str, &fields);
                 Ok(())
             },
         )?;
         xlib.set("log", log)?;
+        //当前正在处理的消息的 trace_id, 不在消息处理中时为 nil
+        let trace_id = ctx.create_function(|_, ()| Ok(current_trace().map(|t| t.trace_id)))?;
+        xlib.set("trace_id", trace_id)?;
 
         //加密相关
+        //游戏连接是否加密, 脚本层可以据此拒绝明文传输密码的登录
+        xlib.set("tcp_encrypt", conf.get_bool("is_encrypt"))?;
 
         //宿主层和脚本层协议解码编码相关
         let serialize_table_to_string = ctx.create_function(|ctx, t: Table| {
@@ -104,6 +131,75 @@ pub fn call_entry(lua: Lua, conf: Config) -> Lua {
     lua
 }
 
+//不启动服务检查脚本: 编译 logic_path 下所有的 .lua 文件
+//exec 时再按启动的流程初始化虚拟机并执行入口脚本, 返回所有错误
+pub fn check_scripts(service_type: ServiceType, conf: &Config, exec: bool) -> Vec<String> {
+    let Some(logic_path) = conf.get_string("logic_path") else {
+        return vec!["logic_path: is required to run lua scripts".into()];
+    };
+    let mut files = Vec::new();
+    if let Err(err) = lua_files(std::path::Path::new(logic_path), &mut files) {
+        return vec![format!("{logic_path}: {err}")];
+    }
+    files.sort();
+    let lua = Lua::new();
+    let mut errors: Vec<String> = files
+        .iter()
+        .filter_map(|path| {
+            let name = path.display().to_string();
+            let res = read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|src| {
+                    lua.context(|ctx| {
+                        ctx.load(&src)
+                            .set_name(&name)
+                            .and_then(|chunk| chunk.into_function())
+                            .map(|_| ())
+                            .map_err(|e| e.to_string())
+                    })
+                });
+            res.err().map(|err| format!("{name}: {err}"))
+        })
+        .collect();
+    if !files
+        .iter()
+        .any(|p| p.ends_with("main.lua") && p.parent() == Some(logic_path.as_ref()))
+    {
+        errors.push(format!("{logic_path}/main.lua: not found"));
+    }
+    if !exec || !errors.is_empty() {
+        return errors;
+    }
+
+    //和 GameState 一样注册 tcp 和 timer, 脚本在入口中可以使用
+    let run = || -> rlua::Result<()> {
+        let lua = init_lua(service_type, conf.clone())?;
+        let tcp_state = Box::new(TcpState::new());
+        let timer_state = Box::new(TimerState::new(conf.get_int("fps").unwrap_or(10)));
+        init_tcp_state(&lua, &*tcp_state as *const TcpState as *mut c_void)?;
+        init_timer_state(&lua, &*timer_state as *const TimerState as *mut c_void)?;
+        let entry = format!("{logic_path}/main.lua");
+        let src = read_to_string(&entry).map_err(rlua::Error::external)?;
+        lua.context(|ctx| ctx.load(&src).set_name(&entry)?.exec())
+    };
+    if let Err(err) = run() {
+        errors.push(format!("{logic_path}/main.lua: {err}"));
+    }
+    errors
+}
+
+fn lua_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            lua_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "lua") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
 pub fn init_tcp_state(lua_state: &Lua, tcp_state: *mut c_void) -> rlua::Result<()> {
     lua_state.context(|ctx| {
         let tmpstate = rlua::LightUserData(tcp_state);
@@ -130,8 +226,35 @@ pub fn init_tcp_state(lua_state: &Lua, tcp_state: *mut c_void) -> rlua::Result<(
                 Ok(())
             },
         )?;
+        //广播: 协议只编码一次, 所有连接共享同一份编码结果
+        let tcp_broadcast = ctx.create_function(
+            |ctx, (vfds, proto_id, proto_name, body): (Vec<u64>, i32, String, Table)| {
+                let tcp_state: LightUserData = ctx.globals().get("tcp_state").unwrap();
+                let tcp_state = tcp_state.0 as *mut TcpState;
+                let tcp_state = unsafe { &mut (*tcp_state) };
+                let Some(pto) = ProtoType::from_id(proto_id) else {
+                    println!("[tcp_broadcast]:sender=failed,proto_id={proto_id},proto_name={proto_name}");
+                    return Ok(());
+                };
+                let pto = pto.decode_from_lua(body)?;
+                let frame = match Frame::from_proto(pto) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        println!("[tcp_broadcast]:encode=failed,proto_id={proto_id},proto_name={proto_name},err={err}");
+                        return Ok(());
+                    }
+                };
+                for vfd in vfds {
+                    if let Some(sender) = (*tcp_state).conn_map().get(&vfd) {
+                        let _ = network::try_send_frame(sender, vfd, frame.clone());
+                    }
+                }
+                Ok(())
+            },
+        )?;
         let xlib: Table = globals.get("xlib")?;
         xlib.set("tcp_send", tcp_send)?;
+        xlib.set("tcp_broadcast", tcp_broadcast)?;
         Ok(())
     })?;
     Ok(())
@@ -167,7 +290,10 @@ pub fn init_timer_state(lua_state: &Lua, timer_state: *mut c_void) -> rlua::Resu
     Ok(())
 }
 
-pub fn init_rpc_send(gate_state: &mut GameState, rpc_sender: SMSender) {
+//shard 为本分片在 session 中的高位, 发出的请求带上它, 回复才能回到本分片
+//xlib.rpc_send 的 session 必须小于 2^48, 返回是否交给了 rpc_client_hub; 通道满时不阻塞脚本, 返回 false 由脚本决定稍后重试还是放弃
+//  交给 rpc_client_hub 之后, 对端额度用完时最多暂存 rpc_backlog_max 条, 超过的丢弃并计入 cable_backlog_dropped_total
+pub fn init_rpc_send(gate_state: &mut GameState, rpc_sender: SMSender, shard: Arc<AtomicU64>) {
     let mut log = gate_state.log.clone();
     //初始化 lua 的 rpc_send 函数
     gate_state.lua_state.as_ref().unwrap().context(|ctx| {
@@ -185,54 +311,451 @@ pub fn init_rpc_send(gate_state: &mut GameState, rpc_sender: SMSender) {
                     String,
                     Table,
                 )| {
+                    //带上正在处理的消息的 trace_id, 定时器等发起的 rpc 使用新的 trace_id
+                    let trace_id = current_trace().map_or_else(new_trace_id, |t| t.trace_id);
                     if is_send {
-                        let mut rsend = RpcSend::default();
-                        rsend.from_host = from_host;
-                        rsend.from_addr = from_addr;
-
-                        rsend.to_host = to_host;
-                        rsend.to_addr = to_addr;
-
-                        rsend.session = session;
-                        rsend.func = func;
+                        //高位留给分片, 否则回复会交给错误的分片
+                        if session >> RPC_SHARD_SHIFT != 0 {
+                            return Err(rlua::Error::RuntimeError(format!(
+                                "[rpc_send]: session={session},max={}",
+                                (1u64 << RPC_SHARD_SHIFT) - 1
+                            )));
+                        }
                         let s = serialize_table_to_string(ctx, args)?;
                         let s = match String::from_utf8(s) {
                             Ok(s) => s,
                             Err(err) => return Err(rlua::Error::RuntimeError(err.to_string())),
                         };
-                        rsend.args = s;
+                        let rsend = RpcSend {
+                            from_host,
+                            from_addr,
+                            to_host,
+                            to_addr,
+                            session: session | shard.load(Ordering::Relaxed),
+                            func,
+                            args: s,
+                            trace_id,
+                        };
 
                         let pto = ProtoType::RpcSend(rsend);
                         if let Err(err) = network::try_send_rpc(&rpc_sender, to_host as u64, pto) {
                             error!(log, "{}", err);
+                            return Ok(false);
                         }
                     } else {
-                        let mut rsend = RpcResp::default();
-                        rsend.from_host = from_host;
-                        rsend.from_addr = from_addr;
-
-                        rsend.to_host = to_host;
-                        rsend.to_addr = to_addr;
-
-                        rsend.session = session;
-                        rsend.func = func;
                         let s = serialize_table_to_string(ctx, args)?;
                         let s = match String::from_utf8(s) {
                             Ok(s) => s,
                             Err(err) => return Err(rlua::Error::RuntimeError(err.to_string())),
                         };
-                        rsend.args = s;
+                        let rsend = RpcResp {
+                            from_host,
+                            from_addr,
+                            to_host,
+                            to_addr,
+                            session,
+                            func,
+                            args: s,
+                            trace_id,
+                        };
 
                         let pto = ProtoType::RpcResp(rsend);
                         if let Err(err) = network::try_send_rpc(&rpc_sender, to_host as u64, pto) {
                             error!(log, "{}", err);
+                            return Ok(false);
                         }
                     }
 
-                    Ok(())
+                    Ok(true)
                 },
             )
             .unwrap();
         xlib.set("rpc_send", rpc_send).unwrap();
     });
 }
+
+//分片之间发送消息和迁移连接
+pub fn init_shard(gate_state: &mut GameState, shard: ShardState) {
+    let mut log = gate_state.log.clone();
+    gate_state.lua_state.as_ref().unwrap().context(|ctx| {
+        let xlib: Table = ctx.globals().get("xlib").unwrap();
+
+        let send_shard = shard.clone();
+        let shard_send = ctx
+            .create_function_mut(move |ctx, (to, func, args): (usize, String, Table)| {
+                let s = serialize_table_to_string(ctx, args)?;
+                let s = match String::from_utf8(s) {
+                    Ok(s) => s,
+                    Err(err) => return Err(rlua::Error::RuntimeError(err.to_string())),
+                };
+                if let Err(err) = send_shard.send(to, func, s) {
+                    error!(log, "{}", err);
+                    return Ok(false);
+                }
+                Ok(true)
+            })
+            .unwrap();
+        xlib.set("shard_send", shard_send).unwrap();
+
+        let migrate_shard = shard.clone();
+        let shard_migrate = ctx
+            .create_function(move |ctx, (vfd, to, data): (u64, usize, Table)| {
+                let s = serialize_table_to_string(ctx, data)?;
+                let s = match String::from_utf8(s) {
+                    Ok(s) => s,
+                    Err(err) => return Err(rlua::Error::RuntimeError(err.to_string())),
+                };
+                migrate_shard
+                    .migrate(vfd, to, s)
+                    .map_err(|err| rlua::Error::RuntimeError(err.to_string()))
+            })
+            .unwrap();
+        xlib.set("shard_migrate", shard_migrate).unwrap();
+
+        let shard_of_scene = ctx
+            .create_function(move |_, scene: u64| Ok(shard.shard_of_scene(scene)))
+            .unwrap();
+        xlib.set("shard_of_scene", shard_of_scene).unwrap();
+    });
+}
+
+//会话迁移: 冻结会话, 把玩家数据序列化后交给目标分片或者其他机器
+pub fn init_session(gate_state: &mut GameState, session: SessionState) {
+    gate_state.lua_state.as_ref().unwrap().context(|ctx| {
+        let xlib: Table = ctx.globals().get("xlib").unwrap();
+        let session_migrate = ctx
+            .create_function(
+                move |ctx, (vfd, host, shard, data): (u64, i32, u32, Table)| {
+                    let s = serialize_table_to_string(ctx, data)?;
+                    let s = match String::from_utf8(s) {
+                        Ok(s) => s,
+                        Err(err) => return Err(rlua::Error::RuntimeError(err.to_string())),
+                    };
+                    let target = SessionTarget { host, shard };
+                    let now = Local::now().timestamp_millis();
+                    session
+                        .migrate(vfd, target, s, now)
+                        .map_err(|err| rlua::Error::RuntimeError(err.to_string()))
+                },
+            )
+            .unwrap();
+        xlib.set("session_migrate", session_migrate).unwrap();
+    });
+}
+
+//db 服务的读写: 返回请求 id, 结果由脚本层的 _db_resp(id, ok, reason, rows) 收到
+//  xlib.db_load(host, table, key)
+//  xlib.db_save(host, table, key, value)
+//  xlib.db_delete(host, table, key)
+//  xlib.db_query(host, table, prefix, limit)
+pub fn init_db(gate_state: &mut GameState, rpc_sender: SMSender, seq: Arc<AtomicU64>) {
+    let send = move |host: i32, op: DbOp, table: String, key: String, value: Vec<u8>, limit| {
+        let id = seq.fetch_add(1, Ordering::Relaxed);
+        let req = DbReq {
+            id,
+            host: 0,
+            op: op as u32,
+            table,
+            key,
+            value,
+            limit,
+        };
+        network::try_send_rpc(&rpc_sender, host as u64, ProtoType::DbReq(req))
+            .map(|_| id)
+            .map_err(|err| rlua::Error::RuntimeError(err.to_string()))
+    };
+    gate_state.lua_state.as_ref().unwrap().context(|ctx| {
+        let xlib: Table = ctx.globals().get("xlib").unwrap();
+
+        let load = send.clone();
+        let db_load = ctx
+            .create_function(move |_, (host, table, key): (i32, String, String)| {
+                load(host, DbOp::Load, table, key, Vec::new(), 0)
+            })
+            .unwrap();
+        xlib.set("db_load", db_load).unwrap();
+
+        let save = send.clone();
+        let db_save = ctx
+            .create_function(
+                move |_, (host, table, key, value): (i32, String, String, rlua::String)| {
+                    save(host, DbOp::Save, table, key, value.as_bytes().to_vec(), 0)
+                },
+            )
+            .unwrap();
+        xlib.set("db_save", db_save).unwrap();
+
+        let delete = send.clone();
+        let db_delete = ctx
+            .create_function(move |_, (host, table, key): (i32, String, String)| {
+                delete(host, DbOp::Delete, table, key, Vec::new(), 0)
+            })
+            .unwrap();
+        xlib.set("db_delete", db_delete).unwrap();
+
+        let db_query = ctx
+            .create_function(
+                move |_, (host, table, prefix, limit): (i32, String, String, Option<u32>)| {
+                    send(
+                        host,
+                        DbOp::Query,
+                        table,
+                        prefix,
+                        Vec::new(),
+                        limit.unwrap_or(0),
+                    )
+                },
+            )
+            .unwrap();
+        xlib.set("db_query", db_query).unwrap();
+    });
+}
+
+//玩家数据缓存
+//  xlib.data_load(key, cb): 加载完成后调用 cb(data, err), 已经在缓存中时立即调用
+//  xlib.data_mark_dirty(key): 修改数据后标记为脏, 由定时器提交
+//  xlib.data_flush(key): 立即提交, 返回是否发出了提交
+//  xlib.data_unload(key): 从缓存中移除, 有未提交的修改时提交成功后移除
+pub fn init_data(gate_state: &mut GameState, cache: Arc<Mutex<LuaDataCache>>, rpc: SMSender) {
+    let db_host = cache.lock().unwrap().conf().db_host as u64;
+    gate_state.lua_state.as_ref().unwrap().context(|ctx| {
+        let xlib: Table = ctx.globals().get("xlib").unwrap();
+
+        let load_cache = cache.clone();
+        let load_rpc = rpc.clone();
+        let data_load = ctx
+            .create_function(move |ctx, (key, cb): (String, Function)| {
+                let waiter = ctx.create_registry_value(cb)?;
+                let load = load_cache.lock().unwrap().load(&key, waiter);
+                match load {
+                    Load::Ready(waiter) => {
+                        let doc: Table = {
+                            let cache = load_cache.lock().unwrap();
+                            ctx.registry_value(cache.get(&key).unwrap())?
+                        };
+                        call_waiters(ctx, vec![waiter], Value::Table(doc), Value::Nil)
+                    }
+                    Load::Waiting => Ok(()),
+                    Load::Request(req) => {
+                        let pto = ProtoType::DbReq(req);
+                        if let Err(err) = network::try_send_rpc(&load_rpc, db_host, pto) {
+                            for waiter in load_cache.lock().unwrap().cancel(&key) {
+                                ctx.remove_registry_value(waiter)?;
+                            }
+                            return Err(rlua::Error::RuntimeError(err.to_string()));
+                        }
+                        Ok(())
+                    }
+                }
+            })
+            .unwrap();
+        xlib.set("data_load", data_load).unwrap();
+
+        let dirty_cache = cache.clone();
+        let data_mark_dirty = ctx
+            .create_function(move |_, key: String| {
+                dirty_cache
+                    .lock()
+                    .unwrap()
+                    .mark_dirty(&key)
+                    .map_err(|err| rlua::Error::RuntimeError(err.to_string()))
+            })
+            .unwrap();
+        xlib.set("data_mark_dirty", data_mark_dirty).unwrap();
+
+        let flush_cache = cache.clone();
+        let data_flush = ctx
+            .create_function(move |ctx, key: String| {
+                let now = Local::now().timestamp_millis();
+                save_data(ctx, &flush_cache, &rpc, &key, now)
+                    .map_err(|err| rlua::Error::RuntimeError(err.to_string()))
+            })
+            .unwrap();
+        xlib.set("data_flush", data_flush).unwrap();
+
+        let data_unload = ctx
+            .create_function(move |ctx, key: String| {
+                let released = cache.lock().unwrap().unload(&key);
+                match released {
+                    Some(doc) => ctx.remove_registry_value(doc).map(|_| true),
+                    None => Ok(false),
+                }
+            })
+            .unwrap();
+        xlib.set("data_unload", data_unload).unwrap();
+    });
+}
+
+//序列化缓存中的数据并提交给 db 服务, 不需要提交时返回 false
+pub fn save_data(
+    ctx: Context,
+    cache: &Mutex<LuaDataCache>,
+    rpc: &SMSender,
+    key: &str,
+    now: i64,
+) -> crate::Result<bool> {
+    let mut cache = cache.lock().unwrap();
+    if !cache.can_save(key) {
+        return Ok(false);
+    }
+    let db_host = cache.conf().db_host as u64;
+    let value = cache
+        .get(key)
+        .ok_or_else(|| rlua::Error::RuntimeError(format!("not_loaded={key}")))
+        .and_then(|doc| ctx.registry_value::<Table>(doc))
+        .and_then(|doc| serialize_table_to_string(ctx, doc))
+        .map_err(|err| format!("[save_data]: key={key},err={err}"))?;
+    let Some(req) = cache.save(key, value, now) else {
+        return Ok(false);
+    };
+    if let Err(err) = network::try_send_rpc(rpc, db_host, ProtoType::DbReq(req)) {
+        cache.unsave(key, now);
+        return Err(err);
+    }
+    Ok(true)
+}
+
+//调用等待加载的回调, 回调出错时继续调用其他的
+pub fn call_waiters<'lua>(
+    ctx: Context<'lua>,
+    waiters: Vec<RegistryKey>,
+    doc: Value<'lua>,
+    err: Value<'lua>,
+) -> rlua::Result<()> {
+    let mut res = Ok(());
+    for waiter in waiters {
+        let cb: Function = ctx.registry_value(&waiter)?;
+        ctx.remove_registry_value(waiter)?;
+        if let Err(e) = cb.call::<_, ()>((doc.clone(), err.clone())) {
+            res = Err(e);
+        }
+    }
+    res
+}
+
+//转换为 json 时 table 的最大嵌套层数, 超过的部分输出 null, 也避免循环引用
+const JSON_DEPTH_MAX: usize = 32;
+
+//脚本的值转换为 json, 用于管理接口; 键为 1..n 的 table 为数组, 其他 table 为对象, 键转为字符串
+//函数等不能转换的值输出为类型名
+pub fn lua_to_json(value: Value) -> serde_json::Value {
+    to_json(value, 0)
+}
+
+fn to_json(value: Value, depth: usize) -> serde_json::Value {
+    use serde_json::Value as Json;
+    match value {
+        Value::Nil => Json::Null,
+        Value::Boolean(b) => Json::Bool(b),
+        Value::Integer(i) => Json::from(i),
+        Value::Number(n) => serde_json::Number::from_f64(n).map_or(Json::Null, Json::Number),
+        Value::String(s) => Json::String(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Value::Table(_) if depth >= JSON_DEPTH_MAX => Json::Null,
+        Value::Table(t) => {
+            let len = t.raw_len();
+            let pairs: Vec<(Value, Value)> = t.clone().pairs().filter_map(Result::ok).collect();
+            if len > 0 && pairs.len() as i64 == len {
+                let items = (1..=len)
+                    .map(|i| to_json(t.raw_get(i).unwrap_or(Value::Nil), depth + 1))
+                    .collect();
+                return Json::Array(items);
+            }
+            let mut obj = serde_json::Map::new();
+            for (k, v) in pairs {
+                let key = match k {
+                    Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+                    Value::Integer(i) => i.to_string(),
+                    Value::Number(n) => n.to_string(),
+                    Value::Boolean(b) => b.to_string(),
+                    _ => continue,
+                };
+                obj.insert(key, to_json(v, depth + 1));
+            }
+            Json::Object(obj)
+        }
+        other => Json::String(format!("<{}>", other.type_name())),
+    }
+}
+
+//控制台执行一段脚本的最长时间, 超过时中断, 避免死循环卡住 game_hub
+const CONSOLE_EVAL_MAX: Duration = Duration::from_secs(1);
+//每执行多少条虚拟机指令检查一次是否超时
+const CONSOLE_HOOK_INSTRUCTIONS: u32 = 10000;
+
+//调试控制台执行一段脚本: 先作为表达式求值, 不是表达式时作为语句执行; 多个返回值用 tab 分隔
+//readonly 时在单独的环境中执行, 可以读取全局变量, 给全局变量赋值时报错; 只防止误操作, 不是沙箱
+pub fn console_eval(lua: &Lua, code: &str, readonly: bool) -> Result<String, String> {
+    let start = Instant::now();
+    let triggers = HookTriggers {
+        every_nth_instruction: Some(CONSOLE_HOOK_INSTRUCTIONS),
+        ..Default::default()
+    };
+    lua.set_hook(triggers, move |_, _| {
+        match start.elapsed() > CONSOLE_EVAL_MAX {
+            true => Err(rlua::Error::RuntimeError(format!(
+                "console: interrupted after {:?}",
+                CONSOLE_EVAL_MAX
+            ))),
+            false => Ok(()),
+        }
+    });
+    let res = lua.context(|ctx| {
+        let mut chunk = ctx.load(code).set_name("console")?;
+        if readonly {
+            chunk = chunk.set_environment(readonly_env(ctx)?)?;
+        }
+        let values: MultiValue = chunk.eval()?;
+        let values: Vec<String> = values.into_iter().map(|v| pretty(ctx, v)).collect();
+        Ok(values.join("\t"))
+    });
+    lua.remove_hook();
+    res.map_err(|err: rlua::Error| error_text(&err))
+}
+
+//回调中的错误只显示原因, 不显示 traceback
+fn error_text(err: &rlua::Error) -> String {
+    match err {
+        rlua::Error::CallbackError { cause, .. } => error_text(cause),
+        err => err.to_string(),
+    }
+}
+
+//读取时查找全局变量, 赋值时报错; _G 指向自己, 元表不能取出
+fn readonly_env(ctx: Context) -> rlua::Result<Table> {
+    let deny = ctx.create_function(|_, (_, key, _): (Value, Value, Value)| {
+        let key = match key {
+            Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+            key => key.type_name().to_owned(),
+        };
+        Err::<(), _>(rlua::Error::RuntimeError(format!(
+            "console is read-only: cannot assign global '{key}'"
+        )))
+    })?;
+    let meta = ctx.create_table()?;
+    meta.set("__index", ctx.globals())?;
+    meta.set("__newindex", deny)?;
+    meta.set("__metatable", false)?;
+    let env = ctx.create_table()?;
+    env.raw_set("_G", env.clone())?;
+    env.set_metatable(Some(meta));
+    Ok(env)
+}
+
+//table 使用协议的序列化格式, 字符串加上引号, 其他值与 tostring 相同
+fn pretty<'lua>(ctx: Context<'lua>, value: Value<'lua>) -> String {
+    let tostring = |value: Value<'lua>| {
+        ctx.globals()
+            .get::<_, Function>("tostring")
+            .and_then(|f| f.call::<_, String>(value))
+            .unwrap_or_else(|err| format!("<{err}>"))
+    };
+    match value {
+        Value::Table(t) => match serialize_table_to_string(ctx, t.clone()) {
+            Ok(s) => String::from_utf8_lossy(&s).into_owned(),
+            //含有函数等不能序列化的值
+            Err(_) => tostring(Value::Table(t)),
+        },
+        Value::String(s) => format!("{:?}", String::from_utf8_lossy(s.as_bytes())),
+        value => tostring(value),
+    }
+}
@@ -1,3 +1,4 @@
+pub use crate::network::frame::Frame;
 pub use crate::protos::ProtoType;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -7,6 +8,7 @@ pub enum ServiceType {
     RPCCLIENT,
     TCPROBOT,
     DB,
+    GATE,
     UNKNOW,
 }
 
@@ -18,19 +20,21 @@ impl From<&str> for ServiceType {
             "rpc_client_service" => Self::RPCCLIENT,
             "robot_service" => Self::TCPROBOT,
             "db_service" => Self::DB,
+            "gate_service" => Self::GATE,
             _ => Self::UNKNOW,
         }
     }
 }
 
-impl Into<String> for ServiceType {
-    fn into(self) -> String {
-        match self {
+impl From<ServiceType> for String {
+    fn from(val: ServiceType) -> Self {
+        match val {
             ServiceType::TCP => String::from("game_service"),
             ServiceType::RPC => String::from("rpc_service"),
             ServiceType::RPCCLIENT => String::from("rpc_client_service"),
             ServiceType::TCPROBOT => String::from("robot_service"),
             ServiceType::DB => String::from("db_service"),
+            ServiceType::GATE => String::from("gate_service"),
             ServiceType::UNKNOW => String::from("unknow_service"),
         }
     }
@@ -42,10 +46,56 @@ pub enum MessageType {
     Tcp,          //tcp监听套接字收到的消息
     Rpc,          //rpc监听套接字收到的消息
     RpcClient,    //rpc的客户端接收到的消息
+    Shard,        //逻辑分片之间, 以及分片和路由之间的消息
     SocketClosed, //tcp连接断开
     Dummy,        //占位
 }
-pub type SystemMsg = (MessageType, u64, ProtoType);
+pub type SystemMsg = (MessageType, u64, Packet);
+
+//消息内容: 协议结构, 或者已经编码好的协议帧
+//转发和广播时使用 Encoded, 写协程直接写出, 不需要再次编码
+//ProtoType 原本就是直接放在消息中传递的, 这里不装箱, 避免每条消息多一次分配
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Packet {
+    Proto(ProtoType),
+    Encoded(Frame),
+}
+
+impl Packet {
+    pub fn proto_id(&self) -> u32 {
+        match self {
+            Packet::Proto(pto) => pto.inner_info().0,
+            Packet::Encoded(frame) => frame.proto_id,
+        }
+    }
+
+    pub fn into_proto(self) -> crate::Result<ProtoType> {
+        match self {
+            Packet::Proto(pto) => Ok(pto),
+            Packet::Encoded(frame) => frame.decode(),
+        }
+    }
+
+    pub fn into_frame(self) -> crate::Result<Frame> {
+        match self {
+            Packet::Proto(pto) => Frame::from_proto(pto),
+            Packet::Encoded(frame) => Ok(frame),
+        }
+    }
+}
+
+impl From<ProtoType> for Packet {
+    fn from(pto: ProtoType) -> Self {
+        Packet::Proto(pto)
+    }
+}
+
+impl From<Frame> for Packet {
+    fn from(frame: Frame) -> Self {
+        Packet::Encoded(frame)
+    }
+}
 
 use tokio::sync::mpsc::{Receiver, Sender};
 //发送 SystemMsg 的 channel 类型
@@ -0,0 +1,216 @@
+//运行指标, 以 prometheus 文本格式从 http 服务的 /metrics 输出
+//
+//  所有指标注册在同一个 Registry 中, 名字以 cable_ 开头; 热路径上只是原子加, 按标签查找时有一次读锁
+//  标签只使用取值有限的字段: 服务类型, 方向, proto_id, hub 名字, 脚本回调名
+use crate::message::ServiceType;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new_custom(Some("cable".into()), None).unwrap();
+    static ref CONN_ACCEPTED: IntCounterVec =
+        counter_vec("conn_accepted_total", "accepted connections", &["service"]);
+    static ref CONN_CLOSED: IntCounterVec = counter_vec(
+        "conn_closed_total",
+        "closed connections by reason",
+        &["service", "reason"]
+    );
+    static ref FRAMES: IntCounterVec = counter_vec(
+        "frames_total",
+        "frames read (in) or written (out)",
+        &["service", "dir", "proto_id"]
+    );
+    static ref BYTES: IntCounterVec = counter_vec(
+        "frame_bytes_total",
+        "frame body bytes read (in) or written (out), after compression",
+        &["service", "dir", "proto_id"]
+    );
+    static ref SEND_FAILED: IntCounterVec = counter_vec(
+        "send_failed_total",
+        "try_send failures on internal channels",
+        &["site", "reason"]
+    );
+    static ref HUB_LOOP_SECONDS: HistogramVec = histogram_vec(
+        "hub_loop_seconds",
+        "time spent handling one batch of messages in a hub",
+        &["hub"]
+    );
+    static ref HUB_QUEUED: IntGaugeVec = gauge_vec(
+        "hub_queued",
+        "messages waiting in a hub, channel and scheduler",
+        &["hub"]
+    );
+    static ref LUA_CALLBACK_SECONDS: HistogramVec = histogram_vec(
+        "lua_callback_seconds",
+        "time spent in a lua callback",
+        &["callback"]
+    );
+    static ref TIMERS: IntGauge = gauge("timers", "active lua timers");
+    static ref TIMERS_FIRED: IntCounter = counter("timers_fired_total", "fired lua timers");
+    static ref BACKLOG: IntGaugeVec = gauge_vec(
+        "backlog",
+        "messages held in a hub because the connection's outbox was full",
+        &["site"]
+    );
+    static ref BACKLOG_DROPPED: IntCounterVec = counter_vec(
+        "backlog_dropped_total",
+        "held messages dropped on overflow or when the connection closed",
+        &["site"]
+    );
+    static ref LOG_BACKLOG: IntGauge =
+        gauge("log_backlog", "log lines waiting for the writer thread");
+    static ref LOG_DROPPED: IntCounterVec = counter_vec(
+        "log_dropped_total",
+        "log lines dropped because the log queue was full",
+        &["level"]
+    );
+}
+
+//50us 到 3.3s
+fn buckets() -> Vec<f64> {
+    exponential_buckets(0.00005, 4.0, 9).unwrap()
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+fn counter(name: &str, help: &str) -> IntCounter {
+    register(IntCounter::new(name, help).unwrap())
+}
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(IntCounterVec::new(Opts::new(name, help), labels).unwrap())
+}
+
+fn gauge(name: &str, help: &str) -> IntGauge {
+    register(IntGauge::new(name, help).unwrap())
+}
+
+fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    register(IntGaugeVec::new(Opts::new(name, help), labels).unwrap())
+}
+
+fn histogram_vec(name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help).buckets(buckets());
+    register(HistogramVec::new(opts, labels).unwrap())
+}
+
+fn service(service_type: ServiceType) -> &'static str {
+    match service_type {
+        ServiceType::TCP => "tcp",
+        ServiceType::RPC => "rpc",
+        ServiceType::RPCCLIENT => "rpc_client",
+        ServiceType::TCPROBOT => "robot",
+        ServiceType::DB => "db",
+        ServiceType::GATE => "gate",
+        ServiceType::UNKNOW => "unknow",
+    }
+}
+
+pub fn conn_accepted(service_type: ServiceType) {
+    CONN_ACCEPTED
+        .with_label_values(&[service(service_type)])
+        .inc();
+}
+
+//reason: peer 对端关闭, io 读出错, limit 限流断开, shutdown 服务停止, hub_closed 处理端已关闭, writer_closed 写端先结束(例如被踢下线), error 解码等其他错误
+pub fn conn_closed(service_type: ServiceType, reason: &str) {
+    CONN_CLOSED
+        .with_label_values(&[service(service_type), reason])
+        .inc();
+}
+
+pub fn frame_in(service_type: ServiceType, proto_id: u32, len: usize) {
+    frame(service_type, "in", proto_id, len);
+}
+
+pub fn frame_out(service_type: ServiceType, proto_id: u32, len: usize) {
+    frame(service_type, "out", proto_id, len);
+}
+
+fn frame(service_type: ServiceType, dir: &str, proto_id: u32, len: usize) {
+    let proto_id = proto_id.to_string();
+    let labels = [service(service_type), dir, proto_id.as_str()];
+    FRAMES.with_label_values(&labels).inc();
+    BYTES.with_label_values(&labels).inc_by(len as u64);
+}
+
+//site 为发送的位置, reason 为 full 或者 closed
+pub fn send_failed(site: &str, reason: &str) {
+    SEND_FAILED.with_label_values(&[site, reason]).inc();
+}
+
+//返回的计时器销毁时记录耗时
+pub fn hub_timer(hub: &str) -> HistogramTimer {
+    HUB_LOOP_SECONDS.with_label_values(&[hub]).start_timer()
+}
+
+pub fn hub_queued(hub: &str, num: usize) {
+    HUB_QUEUED.with_label_values(&[hub]).set(num as i64);
+}
+
+pub fn lua_timer(callback: &str) -> HistogramTimer {
+    LUA_CALLBACK_SECONDS
+        .with_label_values(&[callback])
+        .start_timer()
+}
+
+pub fn timers_added(num: usize) {
+    TIMERS.add(num as i64);
+}
+
+pub fn timers_removed(num: usize) {
+    TIMERS.sub(num as i64);
+}
+
+pub fn timers_fired(num: usize) {
+    TIMERS_FIRED.inc_by(num as u64);
+}
+
+pub fn backlog_queued(site: &str, num: usize) {
+    BACKLOG.with_label_values(&[site]).set(num as i64);
+}
+
+pub fn backlog_dropped(site: &str, num: usize) {
+    BACKLOG_DROPPED
+        .with_label_values(&[site])
+        .inc_by(num as u64);
+}
+
+pub fn log_queued() {
+    LOG_BACKLOG.inc();
+}
+
+pub fn log_written() {
+    LOG_BACKLOG.dec();
+}
+
+pub fn log_dropped(level: &str) {
+    LOG_DROPPED.with_label_values(&[level]).inc();
+}
+
+//所有指标的 prometheus 文本格式; 指标在第一次使用时注册, 输出前先全部注册, 没有发生过的也输出 0
+pub fn gather() -> String {
+    lazy_static::initialize(&CONN_ACCEPTED);
+    lazy_static::initialize(&CONN_CLOSED);
+    lazy_static::initialize(&FRAMES);
+    lazy_static::initialize(&BYTES);
+    lazy_static::initialize(&SEND_FAILED);
+    lazy_static::initialize(&HUB_LOOP_SECONDS);
+    lazy_static::initialize(&HUB_QUEUED);
+    lazy_static::initialize(&LUA_CALLBACK_SECONDS);
+    lazy_static::initialize(&TIMERS);
+    lazy_static::initialize(&TIMERS_FIRED);
+    lazy_static::initialize(&BACKLOG);
+    lazy_static::initialize(&BACKLOG_DROPPED);
+    lazy_static::initialize(&LOG_BACKLOG);
+    lazy_static::initialize(&LOG_DROPPED);
+    TextEncoder::new()
+        .encode_to_string(&REGISTRY.gather())
+        .unwrap_or_else(|err| format!("# encode failed: {err}\n"))
+}
@@ -1,5 +1,11 @@
 //modules 对外提供消息进出的接口
+pub mod data_cache;
+pub mod gate;
 pub mod instance;
 pub use instance::Module;
+pub mod scheduler;
+pub mod session;
+pub mod shard;
+pub mod storage;
 // pub mod manager;
 // pub use manager::ModuleManager;
@@ -0,0 +1,351 @@
+//玩家数据缓存
+//
+//  玩家登录时从 db 服务加载数据, 之后脚本层直接修改缓存中的数据, 修改后标记为脏
+//  脏数据按 data_flush_ms 定时提交, 也可以由脚本层立即提交(例如下线时), 服务停止时全部提交
+//  提交只是经 rpc 把序列化后的数据发给 db 服务, 不在逻辑协程中等待 I/O; 结果随 db 的回复到达
+//  提交失败或超时时重新标记为脏, data_retry_ms 之后重试; 提交期间再次修改的数据, 回复后再提交一次
+//  D 为缓存的数据, W 为等待加载完成的回调, 由调用方决定具体类型
+use crate::config::Config;
+use crate::protos::{DbReq, DbResp};
+use crate::states::db_state::DbOp;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataConf {
+    pub db_host: i32,         //db 服务的 host_id
+    pub table: String,        //数据所在的表
+    pub flush_ms: i64,        //定时提交的间隔
+    pub retry_ms: i64,        //提交失败后的重试间隔
+    pub save_timeout_ms: i64, //提交等待回复的超时
+}
+
+impl DataConf {
+    //没有配置 data_db_host 时不开启
+    pub fn from_config(conf: &Config) -> Option<Self> {
+        let db_host = conf.get_int("data_db_host")?;
+        let get = |k: &str, v: i64| conf.get_int(k).map_or(v, |n| n.max(1) as i64);
+        Some(DataConf {
+            db_host,
+            table: conf
+                .get_string("data_table")
+                .cloned()
+                .unwrap_or_else(|| "player".to_owned()),
+            flush_ms: get("data_flush_ms", 5000),
+            retry_ms: get("data_retry_ms", 1000),
+            save_timeout_ms: get("data_save_timeout_ms", 5000),
+        })
+    }
+}
+
+//加载的结果
+pub enum Load<W> {
+    Ready(W),       //已经在缓存中, 原样返回回调, 由调用方立即调用
+    Waiting,        //正在加载, 回调加入等待
+    Request(DbReq), //需要发给 db 服务
+}
+
+//db 回复对应的事件
+pub enum DataEvent<D, W> {
+    Loaded {
+        key: String,
+        value: Option<Vec<u8>>, //None 表示 db 中没有该玩家的数据
+        waiters: Vec<W>,
+    },
+    LoadFailed {
+        key: String,
+        reason: String,
+        waiters: Vec<W>,
+    },
+    Saved {
+        key: String,
+        released: Option<D>, //已经卸载的数据, 提交成功后从缓存中移除
+    },
+    SaveFailed {
+        key: String,
+        reason: String,
+    },
+}
+
+enum Slot<D, W> {
+    Loading(Vec<W>),
+    Ready(D),
+}
+
+struct Entry<D, W> {
+    slot: Slot<D, W>,
+    dirty: bool,
+    saving: Option<(u64, i64)>, //正在提交的请求 id 和超时时间
+    retry_at: i64,
+    unload: bool, //提交完成后卸载
+}
+
+enum DataReq {
+    Load(String),
+    Save(String),
+}
+
+pub struct DataCache<D, W> {
+    conf: DataConf,
+    seq: Arc<AtomicU64>, //db 请求的 id, 与脚本层直接发出的 db 请求共用
+    entries: HashMap<String, Entry<D, W>>,
+    requests: HashMap<u64, DataReq>,
+    next_flush: i64,
+}
+
+impl<D, W> DataCache<D, W> {
+    pub fn new(conf: DataConf, seq: Arc<AtomicU64>) -> Self {
+        DataCache {
+            conf,
+            seq,
+            entries: HashMap::new(),
+            requests: HashMap::new(),
+            next_flush: 0,
+        }
+    }
+
+    pub fn conf(&self) -> &DataConf {
+        &self.conf
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    //已经加载完成的数据
+    pub fn get(&self, key: &str) -> Option<&D> {
+        match self.entries.get(key).map(|e| &e.slot) {
+            Some(Slot::Ready(doc)) => Some(doc),
+            _ => None,
+        }
+    }
+
+    pub fn is_dirty(&self, key: &str) -> bool {
+        self.entries.get(key).is_some_and(|e| e.dirty)
+    }
+
+    pub fn is_saving(&self, key: &str) -> bool {
+        self.entries.get(key).is_some_and(|e| e.saving.is_some())
+    }
+
+    //db 回复是否属于缓存发出的请求
+    pub fn owns(&self, id: u64) -> bool {
+        self.requests.contains_key(&id)
+    }
+
+    pub fn load(&mut self, key: &str, waiter: W) -> Load<W> {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.unload = false;
+            return match &mut entry.slot {
+                Slot::Ready(_) => Load::Ready(waiter),
+                Slot::Loading(waiters) => {
+                    waiters.push(waiter);
+                    Load::Waiting
+                }
+            };
+        }
+        self.entries.insert(
+            key.to_owned(),
+            Entry {
+                slot: Slot::Loading(vec![waiter]),
+                dirty: false,
+                saving: None,
+                retry_at: 0,
+                unload: false,
+            },
+        );
+        Load::Request(self.request(DataReq::Load(key.to_owned()), DbOp::Load, key, Vec::new()))
+    }
+
+    //加载请求没有发出去, 返回等待的回调
+    pub fn cancel(&mut self, key: &str) -> Vec<W> {
+        if !matches!(
+            self.entries.get(key).map(|e| &e.slot),
+            Some(Slot::Loading(_))
+        ) {
+            return Vec::new();
+        }
+        self.requests
+            .retain(|_, req| !matches!(req, DataReq::Load(k) if k == key));
+        match self.entries.remove(key).map(|e| e.slot) {
+            Some(Slot::Loading(waiters)) => waiters,
+            _ => Vec::new(),
+        }
+    }
+
+    //加载完成, 放入数据
+    pub fn ready(&mut self, key: &str, doc: D) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.slot = Slot::Ready(doc);
+        }
+    }
+
+    pub fn mark_dirty(&mut self, key: &str) -> crate::Result<()> {
+        match self.entries.get_mut(key) {
+            Some(Entry {
+                slot: Slot::Ready(_),
+                dirty,
+                ..
+            }) => {
+                *dirty = true;
+                Ok(())
+            }
+            _ => Err(format!("[data_cache]: not_loaded={key}").into()),
+        }
+    }
+
+    //可以立即提交: 脏的, 没有正在提交的
+    pub fn can_save(&self, key: &str) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|e| e.dirty && e.saving.is_none() && matches!(e.slot, Slot::Ready(_)))
+    }
+
+    //还没有保存到 db 服务的数据: 脏的, 和正在提交的; 按键排序
+    pub fn unsaved(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.dirty || e.saving.is_some())
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    //需要提交的数据: 定时到达时返回所有可以提交且已过重试时间的; force 时忽略定时和重试时间
+    pub fn due(&mut self, now: i64, force: bool) -> Vec<String> {
+        if !force {
+            if now < self.next_flush {
+                return Vec::new();
+            }
+            self.next_flush = now + self.conf.flush_ms;
+        }
+        self.entries
+            .iter()
+            .filter(|(k, e)| self.can_save(k) && (force || e.retry_at <= now))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    //提交序列化后的数据, 之后的修改需要重新标记为脏
+    pub fn save(&mut self, key: &str, value: Vec<u8>, now: i64) -> Option<DbReq> {
+        if !self.can_save(key) {
+            return None;
+        }
+        let req = self.request(DataReq::Save(key.to_owned()), DbOp::Save, key, value);
+        let entry = self.entries.get_mut(key)?;
+        entry.dirty = false;
+        entry.saving = Some((req.id, now + self.conf.save_timeout_ms));
+        Some(req)
+    }
+
+    //提交的请求没有发出去, 之后重试
+    pub fn unsave(&mut self, key: &str, now: i64) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            if let Some((id, _)) = entry.saving.take() {
+                self.requests.remove(&id);
+            }
+            entry.dirty = true;
+            entry.retry_at = now + self.conf.retry_ms;
+        }
+    }
+
+    //提交超时的重新标记为脏, 返回超时的数量; 之后迟到的回复不再处理
+    pub fn expire(&mut self, now: i64) -> usize {
+        let keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.saving.is_some_and(|(_, deadline)| deadline <= now))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys.iter() {
+            self.unsave(key, now);
+        }
+        keys.len()
+    }
+
+    //卸载数据: 没有未提交的修改时立即移除并返回数据, 否则提交成功后移除
+    pub fn unload(&mut self, key: &str) -> Option<D> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.dirty || entry.saving.is_some() || matches!(entry.slot, Slot::Loading(_)) {
+            entry.unload = true;
+            return None;
+        }
+        match self.entries.remove(key)?.slot {
+            Slot::Ready(doc) => Some(doc),
+            Slot::Loading(_) => None,
+        }
+    }
+
+    //处理 db 的回复, 不是缓存发出的请求时原样返回
+    pub fn on_resp(&mut self, resp: DbResp, now: i64) -> Result<DataEvent<D, W>, DbResp> {
+        let Some(req) = self.requests.remove(&resp.id) else {
+            return Err(resp);
+        };
+        match req {
+            DataReq::Load(key) => {
+                let waiters = match self.entries.get_mut(&key).map(|e| &mut e.slot) {
+                    Some(Slot::Loading(waiters)) => std::mem::take(waiters),
+                    _ => Vec::new(),
+                };
+                if resp.ok {
+                    let value = resp.rows.into_iter().next().map(|row| row.value);
+                    return Ok(DataEvent::Loaded {
+                        key,
+                        value,
+                        waiters,
+                    });
+                }
+                self.entries.remove(&key);
+                Ok(DataEvent::LoadFailed {
+                    key,
+                    reason: resp.reason,
+                    waiters,
+                })
+            }
+            DataReq::Save(key) => {
+                let Some(entry) = self.entries.get_mut(&key) else {
+                    return Ok(DataEvent::Saved {
+                        key,
+                        released: None,
+                    });
+                };
+                entry.saving = None;
+                if !resp.ok {
+                    entry.dirty = true;
+                    entry.retry_at = now + self.conf.retry_ms;
+                    return Ok(DataEvent::SaveFailed {
+                        key,
+                        reason: resp.reason,
+                    });
+                }
+                let released = if entry.unload && !entry.dirty {
+                    self.unload(&key)
+                } else {
+                    None
+                };
+                Ok(DataEvent::Saved { key, released })
+            }
+        }
+    }
+
+    fn request(&mut self, req: DataReq, op: DbOp, key: &str, value: Vec<u8>) -> DbReq {
+        let id = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.requests.insert(id, req);
+        DbReq {
+            id,
+            host: 0,
+            op: op as u32,
+            table: self.conf.table.clone(),
+            key: key.to_owned(),
+            value,
+            limit: 0,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//网关
+//
+//  网关只接收客户端连接, 不运行脚本; 新连接按连接数最少分配一台后端逻辑服, 之后连接的消息原样转发过去
+//  网关和后端之间复用 rpc 连接: GateOpen 通知后端有新连接, 双向的消息和连接断开都用 SessionRelay 中转
+//  后端给连接分配本机 vfd, 脚本层和处理本机连接一样处理
+//  登录成功之前只转发 gate_login_protos 中的协议, 后端回复登录成功后放开
+use crate::config::Config;
+use crate::protos::{ProtoMessage, S2cLogin};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct GateConf {
+    pub backends: Vec<i32>,     //后端逻辑服的 host_id
+    pub login_protos: Vec<u32>, //登录成功之前允许转发的协议
+}
+
+impl GateConf {
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        let mut backends = Vec::new();
+        let list = conf.get_string("gate_backends").map_or("", String::as_str);
+        for s in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match s.parse::<i32>() {
+                Ok(host) => backends.push(host),
+                Err(_) => return Err(format!("[gate]: wrong_config=gate_backends = {list}").into()),
+            }
+        }
+        if backends.is_empty() {
+            return Err("[gate]: config=gate_backends is required".into());
+        }
+        let login_protos = conf
+            .get_string("gate_login_protos")
+            .map(|s| {
+                s.split(',')
+                    .filter_map(|id| id.trim().parse::<u32>().ok())
+                    .collect()
+            })
+            .unwrap_or_else(|| vec![S2cLogin::PROTO_ID]);
+        Ok(GateConf {
+            backends,
+            login_protos,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GateClient {
+    backend: i32,
+    authed: bool,
+}
+
+pub struct GateTable {
+    conf: GateConf,
+    clients: HashMap<u64, GateClient>, //[vfd] = 连接所在的后端
+    load: HashMap<i32, usize>,         //[host] = 连接数
+}
+
+impl GateTable {
+    pub fn new(conf: GateConf) -> Self {
+        let load = conf.backends.iter().map(|host| (*host, 0)).collect();
+        GateTable {
+            conf,
+            clients: HashMap::new(),
+            load,
+        }
+    }
+
+    //新连接分配连接数最少的后端, 相同时取配置中靠前的
+    pub fn connect(&mut self, vfd: u64) -> Option<i32> {
+        if let Some(client) = self.clients.get(&vfd) {
+            return Some(client.backend);
+        }
+        let backend = *self
+            .conf
+            .backends
+            .iter()
+            .min_by_key(|host| self.load.get(host).copied().unwrap_or(0))?;
+        *self.load.entry(backend).or_insert(0) += 1;
+        self.clients.insert(
+            vfd,
+            GateClient {
+                backend,
+                authed: false,
+            },
+        );
+        Some(backend)
+    }
+
+    //连接发来的协议可以转发时返回后端
+    pub fn allow(&self, vfd: u64, proto_id: u32) -> crate::Result<i32> {
+        let Some(client) = self.clients.get(&vfd) else {
+            return Err(format!("[gate]: unknown_vfd={vfd}").into());
+        };
+        if !client.authed && !self.conf.login_protos.contains(&proto_id) {
+            return Err(format!("[gate]: unauthed=true,vfd={vfd},proto_id={proto_id}").into());
+        }
+        Ok(client.backend)
+    }
+
+    //后端回复登录成功
+    pub fn authenticate(&mut self, vfd: u64) {
+        if let Some(client) = self.clients.get_mut(&vfd) {
+            client.authed = true;
+        }
+    }
+
+    pub fn is_authed(&self, vfd: u64) -> bool {
+        self.clients.get(&vfd).is_some_and(|c| c.authed)
+    }
+
+    pub fn backend_of(&self, vfd: u64) -> Option<i32> {
+        self.clients.get(&vfd).map(|c| c.backend)
+    }
+
+    //连接断开, 返回连接所在的后端
+    pub fn close(&mut self, vfd: u64) -> Option<i32> {
+        let client = self.clients.remove(&vfd)?;
+        if let Some(n) = self.load.get_mut(&client.backend) {
+            *n = n.saturating_sub(1);
+        }
+        Some(client.backend)
+    }
+
+    pub fn load_of(&self, host: i32) -> usize {
+        self.load.get(&host).copied().unwrap_or(0)
+    }
+}
@@ -16,9 +16,10 @@ pub struct Module {
 
 impl Module {
     pub fn new(module_name: String) -> Self {
-        let mut m = Module::default();
-        m.name = module_name;
-        m
+        Module {
+            name: module_name,
+            ..Default::default()
+        }
     }
 
     pub fn name(&self) -> &str {
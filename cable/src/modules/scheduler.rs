@@ -0,0 +1,166 @@
+//hub 的消息调度
+//
+//  每个连接一个队列, 同一个连接的消息保持先后顺序
+//  连接按队首消息的优先级放入对应优先级的轮转队列, 同一优先级的连接轮流处理, 每次一条
+//  不同优先级按权重轮流处理, 每一轮每个优先级最多处理 weight 条, 低优先级不会被饿死
+//  定时器不经过调度器, 由 hub 在每次循环开始时检查, hub 每次循环最多处理 budget 条消息
+use crate::config::Config;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+//优先级从高到低
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    Rpc,
+    Login,
+    Gameplay,
+}
+
+const CLASSES: [Class; 3] = [Class::Rpc, Class::Login, Class::Gameplay];
+
+impl Class {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedConf {
+    pub budget: usize,              //hub 每次循环最多处理的消息数
+    pub weights: [usize; 3],        //每一轮各优先级最多处理的消息数
+    pub queue_max: usize,           //每个连接排队的消息上限
+    pub rpc_queue_max: usize, //rpc 消息排队的总上限, 超过后 hub 暂停接收, 由流控把背压传给对端
+    pub login_protos: HashSet<u32>, //归为登录优先级的协议
+}
+
+impl Default for SchedConf {
+    fn default() -> Self {
+        SchedConf {
+            budget: 256,
+            weights: [4, 2, 1],
+            queue_max: 256,
+            rpc_queue_max: 4096,
+            login_protos: HashSet::new(),
+        }
+    }
+}
+
+impl SchedConf {
+    pub fn from_config(conf: &Config) -> Self {
+        let default = SchedConf::default();
+        let get = |k: &str, v: usize| conf.get_int(k).map_or(v, |n| n.max(1) as usize);
+        let login_protos = conf
+            .get_string("sched_login_protos")
+            .map(|s| {
+                s.split(',')
+                    .filter_map(|id| id.trim().parse::<u32>().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        SchedConf {
+            budget: get("sched_budget", default.budget),
+            weights: [
+                get("sched_weight_rpc", default.weights[0]),
+                get("sched_weight_login", default.weights[1]),
+                get("sched_weight_gameplay", default.weights[2]),
+            ],
+            queue_max: get("sched_queue_max", default.queue_max),
+            rpc_queue_max: get("sched_rpc_queue_max", default.rpc_queue_max),
+            login_protos,
+        }
+    }
+
+    //游戏连接上的协议按协议id区分登录和游戏优先级
+    pub fn classify(&self, proto_id: u32) -> Class {
+        if self.login_protos.contains(&proto_id) {
+            Class::Login
+        } else {
+            Class::Gameplay
+        }
+    }
+}
+
+pub struct Scheduler<K, T> {
+    conf: SchedConf,
+    queues: HashMap<K, VecDeque<(Class, T)>>,
+    rings: [VecDeque<K>; 3],
+    credits: [usize; 3],
+    len: usize,
+}
+
+impl<K: Copy + Eq + Hash, T> Scheduler<K, T> {
+    pub fn new(conf: SchedConf) -> Self {
+        let credits = conf.weights;
+        Scheduler {
+            conf,
+            queues: HashMap::new(),
+            rings: Default::default(),
+            credits,
+            len: 0,
+        }
+    }
+
+    pub fn conf(&self) -> &SchedConf {
+        &self.conf
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    //连接的队列已满时返回该消息, 由调用方决定如何处理
+    pub fn push(&mut self, key: K, class: Class, item: T) -> Result<(), T> {
+        if self.queues.get(&key).map_or(0, VecDeque::len) >= self.conf.queue_max {
+            return Err(item);
+        }
+        self.push_unbounded(key, class, item);
+        Ok(())
+    }
+
+    //不受队列上限限制, 用于 rpc 和连接断开这类不能丢弃的消息
+    pub fn push_unbounded(&mut self, key: K, class: Class, item: T) {
+        let queue = self.queues.entry(key).or_default();
+        if queue.is_empty() {
+            self.rings[class.index()].push_back(key);
+        }
+        queue.push_back((class, item));
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<(K, T)> {
+        if self.is_empty() {
+            return None;
+        }
+        //第一遍用本轮剩余的额度, 额度用完后开始新的一轮
+        for _ in 0..2 {
+            for class in CLASSES {
+                let i = class.index();
+                if self.credits[i] > 0 && !self.rings[i].is_empty() {
+                    self.credits[i] -= 1;
+                    return self.pop_class(i);
+                }
+            }
+            self.credits = self.conf.weights;
+        }
+        None
+    }
+
+    fn pop_class(&mut self, i: usize) -> Option<(K, T)> {
+        let key = self.rings[i].pop_front()?;
+        let queue = self.queues.get_mut(&key)?;
+        let (_, item) = queue.pop_front()?;
+        self.len -= 1;
+        //按新的队首消息的优先级, 放回对应轮转队列的末尾
+        match queue.front() {
+            Some((class, _)) => self.rings[class.index()].push_back(key),
+            None => {
+                self.queues.remove(&key);
+            }
+        }
+        Some((key, item))
+    }
+}
@@ -0,0 +1,173 @@
+//玩家会话迁移
+//
+//  会话可以迁移到本机的其他分片, 或者通过 rpc 迁移到其他机器, 客户端不需要重连
+//  源端先冻结会话, 之后收到的消息暂存, 不交给脚本层; 再把脚本层的玩家数据序列化后发给目标端
+//  目标端恢复会话后回复结果: 成功时源端把暂存的消息交给目标端, 连接的路由切换过去;
+//  失败或超时时源端解冻, 暂存的消息在本地按顺序处理, 会话回到迁移前的状态
+//  迁移到其他机器后连接仍然在本机, 双向的消息都通过 rpc 中转; 目标机器给会话分配一个本地 vfd
+//  已经迁移到其他机器的会话暂不支持再次迁移
+use std::collections::HashMap;
+
+//其他机器迁移过来的会话在本机使用的 vfd 从这里开始分配, 不会和本机的连接冲突
+pub const REMOTE_VFD_BASE: u64 = 1 << 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionTarget {
+    pub host: i32,
+    pub shard: u32,
+}
+
+//迁移中的会话
+#[derive(Debug)]
+pub struct Outgoing<T> {
+    pub id: u64,
+    pub shard: u32, //发起迁移的分片
+    pub target: SessionTarget,
+    pub pending: Vec<T>, //冻结后收到的消息
+    deadline: i64,
+}
+
+//本机所有分片共用一份
+pub struct SessionTable<T> {
+    next_id: u64,
+    next_alias: u64,
+    outgoing: HashMap<u64, Outgoing<T>>, //[vfd] = 迁移中的会话
+    remote: HashMap<u64, i32>,           //已迁移到其他机器的本机连接, [vfd] = host
+    aliases: HashMap<(i32, u64), (u64, u32)>, //其他机器迁移过来的会话, [(host, vfd)] = (本机 vfd, 分片)
+    origins: HashMap<u64, (i32, u64)>,        //[本机 vfd] = (host, vfd)
+}
+
+impl<T> Default for SessionTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SessionTable<T> {
+    pub fn new() -> Self {
+        SessionTable {
+            next_id: 1,
+            next_alias: REMOTE_VFD_BASE,
+            outgoing: HashMap::new(),
+            remote: HashMap::new(),
+            aliases: HashMap::new(),
+            origins: HashMap::new(),
+        }
+    }
+
+    //冻结会话, 返回本次迁移的 id; deadline 之前没有收到结果则回滚
+    pub fn freeze(
+        &mut self,
+        vfd: u64,
+        shard: u32,
+        target: SessionTarget,
+        deadline: i64,
+    ) -> crate::Result<u64> {
+        if self.outgoing.contains_key(&vfd) {
+            return Err(format!("[session]: migrating=true,vfd={vfd}").into());
+        }
+        if self.remote.contains_key(&vfd) || self.origins.contains_key(&vfd) {
+            return Err(format!("[session]: remote_session=true,vfd={vfd}").into());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.outgoing.insert(
+            vfd,
+            Outgoing {
+                id,
+                shard,
+                target,
+                pending: Vec::new(),
+                deadline,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn is_frozen(&self, vfd: u64) -> bool {
+        self.outgoing.contains_key(&vfd)
+    }
+
+    //发起迁移的分片
+    pub fn frozen_by(&self, vfd: u64) -> Option<u32> {
+        self.outgoing.get(&vfd).map(|o| o.shard)
+    }
+
+    //会话冻结时暂存消息, 返回 None; 否则原样返回
+    pub fn hold(&mut self, vfd: u64, item: T) -> Option<T> {
+        match self.outgoing.get_mut(&vfd) {
+            Some(outgoing) => {
+                outgoing.pending.push(item);
+                None
+            }
+            None => Some(item),
+        }
+    }
+
+    //收到迁移结果, id 不一致时是过期的结果, 返回 None
+    pub fn thaw(&mut self, vfd: u64, id: u64) -> Option<Outgoing<T>> {
+        match self.outgoing.get(&vfd) {
+            Some(outgoing) if outgoing.id == id => self.outgoing.remove(&vfd),
+            _ => None,
+        }
+    }
+
+    //shard 发起的迁移中已经超时的
+    pub fn expired(&mut self, shard: u32, now: i64) -> Vec<(u64, Outgoing<T>)> {
+        let vfds: Vec<u64> = self
+            .outgoing
+            .iter()
+            .filter(|(_, o)| o.shard == shard && o.deadline <= now)
+            .map(|(vfd, _)| *vfd)
+            .collect();
+        vfds.into_iter()
+            .filter_map(|vfd| self.outgoing.remove(&vfd).map(|o| (vfd, o)))
+            .collect()
+    }
+
+    //迁移到其他机器成功, 之后连接的消息转发到 host
+    pub fn bind_remote(&mut self, vfd: u64, host: i32) {
+        self.remote.insert(vfd, host);
+    }
+
+    pub fn remote_of(&self, vfd: u64) -> Option<i32> {
+        self.remote.get(&vfd).copied()
+    }
+
+    //给其他机器迁移过来的会话分配本机 vfd
+    pub fn alias(&mut self, host: i32, vfd: u64, shard: u32) -> u64 {
+        if let Some((alias, _)) = self.aliases.get(&(host, vfd)) {
+            return *alias;
+        }
+        let alias = self.next_alias;
+        self.next_alias += 1;
+        self.aliases.insert((host, vfd), (alias, shard));
+        self.origins.insert(alias, (host, vfd));
+        alias
+    }
+
+    //返回 (本机 vfd, 分片)
+    pub fn alias_of(&self, host: i32, vfd: u64) -> Option<(u64, u32)> {
+        self.aliases.get(&(host, vfd)).copied()
+    }
+
+    //返回 (来源 host, 来源 vfd)
+    pub fn origin_of(&self, alias: u64) -> Option<(i32, u64)> {
+        self.origins.get(&alias).copied()
+    }
+
+    pub fn unalias(&mut self, alias: u64) -> Option<(i32, u64)> {
+        let origin = self.origins.remove(&alias)?;
+        self.aliases.remove(&origin);
+        Some(origin)
+    }
+
+    //连接断开, 返回迁移中的会话和会话所在的其他机器
+    pub fn close(&mut self, vfd: u64) -> (Option<Outgoing<T>>, Option<i32>) {
+        (self.outgoing.remove(&vfd), self.remote.remove(&vfd))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outgoing.is_empty() && self.remote.is_empty() && self.origins.is_empty()
+    }
+}
@@ -0,0 +1,246 @@
+//游戏逻辑分片
+//
+//  开启多个分片时, 每个分片有自己的 lua 虚拟机, TcpState 和 TimerState, 各自在一个协程中运行
+//  路由协程接收所有游戏连接的消息, 按连接所属的分片转发; 新连接按策略分配分片
+//  连接可以从一个分片迁移到另一个分片, 迁移期间路由暂存新消息, 旧分片处理完已收到的消息后再转发, 保持消息顺序
+//  暂存超过 max_pending 条时放弃迁移, 断开连接
+//  rpc 固定由 0 号分片接收, 回复按 session 转给发出请求的分片
+use crate::config::Config;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+const SCENE_PREFIX: &str = "shard_scene.";
+
+//分配分片的依据
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShardKey {
+    Vfd(u64),   //新连接
+    Scene(u64), //进入场景
+}
+
+//分片策略, 路由协程和所有分片共用一个
+pub trait ShardPolicy: Send + Sync {
+    //返回 [0, shards) 中的一个分片
+    fn pick(&self, key: ShardKey, shards: usize) -> usize;
+}
+
+//按哈希分配
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashPolicy;
+
+impl ShardPolicy for HashPolicy {
+    fn pick(&self, key: ShardKey, shards: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % shards.max(1) as u64) as usize
+    }
+}
+
+//按场景分配: 新连接先进入大厅分片, 进入场景时迁移到场景所在的分片, 没有配置的场景按哈希分配
+#[derive(Debug, Clone, Default)]
+pub struct ScenePolicy {
+    pub lobby: usize,
+    pub scenes: HashMap<u64, usize>,
+}
+
+impl ShardPolicy for ScenePolicy {
+    fn pick(&self, key: ShardKey, shards: usize) -> usize {
+        let shard = match key {
+            ShardKey::Vfd(_) => self.lobby,
+            ShardKey::Scene(scene) => match self.scenes.get(&scene) {
+                Some(&shard) => shard,
+                None => HashPolicy.pick(key, shards),
+            },
+        };
+        shard.min(shards.max(1) - 1)
+    }
+}
+
+#[derive(Clone)]
+pub struct ShardConf {
+    pub shards: usize,
+    pub policy: Arc<dyn ShardPolicy>,
+    pub max_pending: usize, //迁移中每个连接最多暂存的消息数
+    pub max_backlog: usize, //分片之间的消息在目标分片通道满时, 每个目标分片最多暂存的消息数
+}
+
+impl Default for ShardConf {
+    fn default() -> Self {
+        ShardConf {
+            shards: 1,
+            policy: Arc::new(HashPolicy),
+            max_pending: 1000,
+            max_backlog: 10000,
+        }
+    }
+}
+
+impl ShardConf {
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        let shards = conf.get_int("logic_shards").unwrap_or(1).max(1) as usize;
+        let policy: Arc<dyn ShardPolicy> = match conf.get_string("shard_policy").map(String::as_str)
+        {
+            None | Some("hash") => Arc::new(HashPolicy),
+            Some("scene") => {
+                let mut scenes = HashMap::new();
+                for (scene, shard) in conf.get_prefixed(SCENE_PREFIX) {
+                    match (scene.parse::<u64>(), shard.trim().parse::<usize>()) {
+                        (Ok(scene), Ok(shard)) if shard < shards => {
+                            scenes.insert(scene, shard);
+                        }
+                        _ => {
+                            return Err(format!(
+                                "[shard]: wrong_config={SCENE_PREFIX}{scene} = {shard}"
+                            )
+                            .into())
+                        }
+                    }
+                }
+                let lobby = conf.get_int("shard_lobby").unwrap_or(0).max(0) as usize;
+                if lobby >= shards {
+                    return Err(format!("[shard]: wrong_config=shard_lobby = {lobby}").into());
+                }
+                Arc::new(ScenePolicy { lobby, scenes })
+            }
+            Some(other) => return Err(format!("[shard]: unknown_policy={other}").into()),
+        };
+        let max_pending = conf
+            .get_int("shard_migrate_pending_max")
+            .unwrap_or(1000)
+            .max(1) as usize;
+        let max_backlog = conf.get_int("shard_backlog_max").unwrap_or(10000).max(1) as usize;
+        Ok(ShardConf {
+            shards,
+            policy,
+            max_pending,
+            max_backlog,
+        })
+    }
+
+    //使用自定义的分片策略
+    pub fn with_policy(mut self, policy: Arc<dyn ShardPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        self.shards > 1
+    }
+
+    pub fn pick(&self, key: ShardKey) -> usize {
+        self.policy.pick(key, self.shards).min(self.shards - 1)
+    }
+}
+
+enum Route<T> {
+    Shard(usize),
+    //迁移中, 等待旧分片处理完已收到的消息, 新消息先暂存
+    Migrating { to: usize, pending: Vec<T> },
+    //暂存已满, 放弃迁移, 等待连接断开; 之后的消息都丢弃
+    Aborted,
+}
+
+//连接到分片的路由表, T 为转发的消息
+pub struct ShardRouter<T> {
+    conf: ShardConf,
+    routes: HashMap<u64, Route<T>>,
+}
+
+impl<T> ShardRouter<T> {
+    pub fn new(conf: ShardConf) -> Self {
+        ShardRouter {
+            conf,
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn conf(&self) -> &ShardConf {
+        &self.conf
+    }
+
+    //新连接按策略分配分片
+    pub fn connect(&mut self, vfd: u64) -> usize {
+        let shard = self.conf.pick(ShardKey::Vfd(vfd));
+        self.routes.insert(vfd, Route::Shard(shard));
+        shard
+    }
+
+    //连接当前所属的分片, 迁移中返回目标分片
+    pub fn shard_of(&self, vfd: u64) -> Option<usize> {
+        match self.routes.get(&vfd)? {
+            Route::Shard(shard) => Some(*shard),
+            Route::Migrating { to, .. } => Some(*to),
+            Route::Aborted => None,
+        }
+    }
+
+    //返回消息应该转发到的分片; 迁移中的连接暂存消息, 放弃迁移的连接丢弃消息, 都返回 None
+    //暂存已满时放弃迁移并返回错误, 调用方需要断开连接
+    pub fn route(&mut self, vfd: u64, item: T) -> crate::Result<Option<(usize, T)>> {
+        let max_pending = self.conf.max_pending;
+        let route = self
+            .routes
+            .entry(vfd)
+            .or_insert_with(|| Route::Shard(self.conf.pick(ShardKey::Vfd(vfd))));
+        match route {
+            Route::Shard(shard) => Ok(Some((*shard, item))),
+            Route::Migrating { pending, .. } if pending.len() < max_pending => {
+                pending.push(item);
+                Ok(None)
+            }
+            Route::Migrating { pending, .. } => {
+                let dropped = pending.len() + 1;
+                *route = Route::Aborted;
+                Err(format!("[shard]: migrate=abort,vfd={vfd},dropped={dropped}").into())
+            }
+            Route::Aborted => Ok(None),
+        }
+    }
+
+    //分片发起迁移, 连接确实属于 from 时返回 true, 之后需要给 from 发送 fence
+    pub fn begin_migrate(&mut self, vfd: u64, from: usize, to: usize) -> bool {
+        if to >= self.conf.shards || from == to {
+            return false;
+        }
+        let Some(route) = self.routes.get_mut(&vfd) else {
+            return false;
+        };
+        if !matches!(route, Route::Shard(shard) if *shard == from) {
+            return false;
+        }
+        *route = Route::Migrating {
+            to,
+            pending: Vec::new(),
+        };
+        true
+    }
+
+    //旧分片已处理完迁移前收到的消息, 返回目标分片和暂存的消息
+    pub fn end_migrate(&mut self, vfd: u64) -> Option<(usize, Vec<T>)> {
+        let route = self.routes.get_mut(&vfd)?;
+        let Route::Migrating { to, pending } = route else {
+            return None;
+        };
+        let (to, pending) = (*to, std::mem::take(pending));
+        *route = Route::Shard(to);
+        Some((to, pending))
+    }
+
+    //连接断开, 返回丢弃的暂存消息数
+    pub fn close(&mut self, vfd: u64) -> usize {
+        match self.routes.remove(&vfd) {
+            Some(Route::Migrating { pending, .. }) => pending.len(),
+            _ => 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
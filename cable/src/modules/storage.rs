@@ -0,0 +1,52 @@
+//db 服务的存储层
+//
+//  数据按 (表名, 键) 存放, 值为脚本层序列化后的字节串
+//  一批写入原子地提交, 全部成功或者全部失败
+//  存储的调用是阻塞的, 由 db_hub 放到阻塞线程上执行
+use crate::config::Config;
+use std::sync::Arc;
+
+pub mod memory;
+pub use memory::MemStorage;
+pub mod sled_storage;
+pub use sled_storage::SledStorage;
+
+//一次写入, value 为 None 时删除
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbWrite {
+    pub table: String,
+    pub key: String,
+    pub value: Option<Vec<u8>>,
+}
+
+pub trait Storage: Send + Sync {
+    fn load(&self, table: &str, key: &str) -> crate::Result<Option<Vec<u8>>>;
+
+    //按键的前缀查找, 结果按键排序, limit 为 0 时不限制条数
+    fn query(
+        &self,
+        table: &str,
+        prefix: &str,
+        limit: usize,
+    ) -> crate::Result<Vec<(String, Vec<u8>)>>;
+
+    //一批写入原子地提交
+    fn write(&self, batch: &[DbWrite]) -> crate::Result<()>;
+
+    //落盘
+    fn flush(&self) -> crate::Result<()>;
+}
+
+//按 db_engine 配置创建存储: sled 为嵌入式存储, 数据放在 db_path 目录; memory 只用于测试
+pub fn from_config(conf: &Config) -> crate::Result<Arc<dyn Storage>> {
+    match conf.get_string("db_engine").map(String::as_str) {
+        None | Some("sled") => {
+            let path = conf
+                .get_string("db_path")
+                .map_or("./data/db", String::as_str);
+            Ok(Arc::new(SledStorage::open(path)?))
+        }
+        Some("memory") => Ok(Arc::new(MemStorage::new())),
+        Some(other) => Err(format!("[storage]: unknown_engine={other}").into()),
+    }
+}
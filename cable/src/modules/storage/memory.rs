@@ -0,0 +1,55 @@
+use super::{DbWrite, Storage};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+//内存存储, 进程退出后数据丢失
+#[derive(Default)]
+pub struct MemStorage {
+    tables: Mutex<BTreeMap<(String, String), Vec<u8>>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn load(&self, table: &str, key: &str) -> crate::Result<Option<Vec<u8>>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables.get(&(table.to_owned(), key.to_owned())).cloned())
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        prefix: &str,
+        limit: usize,
+    ) -> crate::Result<Vec<(String, Vec<u8>)>> {
+        let tables = self.tables.lock().unwrap();
+        let rows = tables
+            .range((table.to_owned(), prefix.to_owned())..)
+            .take_while(|((t, k), _)| t == table && k.starts_with(prefix))
+            .map(|((_, k), v)| (k.clone(), v.clone()));
+        Ok(match limit {
+            0 => rows.collect(),
+            n => rows.take(n).collect(),
+        })
+    }
+
+    fn write(&self, batch: &[DbWrite]) -> crate::Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        for w in batch {
+            let key = (w.table.clone(), w.key.clone());
+            match &w.value {
+                Some(value) => tables.insert(key, value.clone()),
+                None => tables.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use super::{DbWrite, Storage};
+
+//sled 嵌入式存储, 所有表放在同一棵树中, 键为 表名 + '\0' + 键, 一批写入可以原子地提交
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    pub fn open(path: &str) -> crate::Result<Self> {
+        let db = sled::open(path).map_err(|err| format!("[sled]: open={path},err={err}"))?;
+        Ok(SledStorage { db })
+    }
+
+    //临时目录中的存储, 对象销毁时删除
+    pub fn temporary() -> crate::Result<Self> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(|err| format!("[sled]: temporary=true,err={err}"))?;
+        Ok(SledStorage { db })
+    }
+}
+
+fn full_key(table: &str, key: &str) -> Vec<u8> {
+    let mut k = Vec::with_capacity(table.len() + key.len() + 1);
+    k.extend_from_slice(table.as_bytes());
+    k.push(0);
+    k.extend_from_slice(key.as_bytes());
+    k
+}
+
+impl Storage for SledStorage {
+    fn load(&self, table: &str, key: &str) -> crate::Result<Option<Vec<u8>>> {
+        match self.db.get(full_key(table, key)) {
+            Ok(value) => Ok(value.map(|v| v.to_vec())),
+            Err(err) => Err(format!("[sled]: load={table}.{key},err={err}").into()),
+        }
+    }
+
+    fn query(
+        &self,
+        table: &str,
+        prefix: &str,
+        limit: usize,
+    ) -> crate::Result<Vec<(String, Vec<u8>)>> {
+        let skip = table.len() + 1;
+        let mut rows = Vec::new();
+        for item in self.db.scan_prefix(full_key(table, prefix)) {
+            let (k, v) = item.map_err(|err| format!("[sled]: query={table}.{prefix},err={err}"))?;
+            rows.push((String::from_utf8_lossy(&k[skip..]).into_owned(), v.to_vec()));
+            if rows.len() == limit {
+                break;
+            }
+        }
+        Ok(rows)
+    }
+
+    fn write(&self, batch: &[DbWrite]) -> crate::Result<()> {
+        let mut b = sled::Batch::default();
+        for w in batch {
+            let key = full_key(&w.table, &w.key);
+            match &w.value {
+                Some(value) => b.insert(key, value.as_slice()),
+                None => b.remove(key),
+            }
+        }
+        self.db
+            .apply_batch(b)
+            .map_err(|err| format!("[sled]: write={},err={err}", batch.len()).into())
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        match self.db.flush() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("[sled]: flush=failed,err={err}").into()),
+        }
+    }
+}
@@ -1,39 +1,62 @@
+pub mod compress;
+pub mod console;
+pub mod credit;
+pub mod crypto;
+pub mod frame;
 pub mod http;
+pub mod limit;
+pub mod rpc_auth;
 pub mod tcp;
+pub mod tls;
 pub mod udp;
 pub mod ws;
 
 use crate::error::Error;
 use crate::message::*;
+use crate::metrics;
 use tokio::sync::mpsc::error::TrySendError;
 
 // try_send 不会阻塞
 pub fn try_send(sender: &SMSender, vfd: u64, pto: ProtoType) -> crate::Result<()> {
-    inner_try_send(sender, MessageType::Tcp, vfd, pto)
+    inner_try_send(sender, MessageType::Tcp, vfd, Packet::Proto(pto))
 }
 
 pub fn try_send_rpc(sender: &SMSender, vfd: u64, pto: ProtoType) -> crate::Result<()> {
-    inner_try_send(sender, MessageType::Rpc, vfd, pto)
+    inner_try_send(sender, MessageType::Rpc, vfd, Packet::Proto(pto))
+}
+
+//发送已经编码好的协议帧, 用于转发和广播, 写协程不再重新编码
+pub fn try_send_frame(sender: &SMSender, vfd: u64, frame: Frame) -> crate::Result<()> {
+    inner_try_send(sender, MessageType::Tcp, vfd, Packet::Encoded(frame))
+}
+
+//原样转发收到的消息, 由调用方指定消息类型
+pub fn try_send_packet(
+    sender: &SMSender,
+    msg_type: MessageType,
+    vfd: u64,
+    packet: Packet,
+) -> crate::Result<()> {
+    inner_try_send(sender, msg_type, vfd, packet)
 }
 
 fn inner_try_send(
     sender: &SMSender,
     msg_type: MessageType,
     vfd: u64,
-    pto: ProtoType,
+    packet: Packet,
 ) -> crate::Result<()> {
-    let (proto_id, name) = pto.inner_info();
-    if let Err(err) = sender.try_send((msg_type, vfd, pto)) {
+    let proto_id = packet.proto_id();
+    if let Err(err) = sender.try_send((msg_type, vfd, packet)) {
         match err {
             TrySendError::Full(_err) => {
-                let res =
-                    format!("[try_send]: send=chan_full,vfd={vfd},proto_id={proto_id},name={name}");
+                metrics::send_failed("try_send", "full");
+                let res = format!("[try_send]: send=chan_full,vfd={vfd},proto_id={proto_id}");
                 return Err(Error::Message(res));
             }
             TrySendError::Closed(_err) => {
-                let res = format!(
-                    "[try_send]: send=chan_closed,vfd={vfd},proto_id={proto_id},name={name}"
-                );
+                metrics::send_failed("try_send", "closed");
+                let res = format!("[try_send]: send=chan_closed,vfd={vfd},proto_id={proto_id}");
                 return Err(Error::Message(res));
             }
         }
@@ -0,0 +1,238 @@
+//帧压缩, 每个连接在登录时协商压缩算法
+//
+//  客户端发送 S2cLogin 时, compress 字段带上本端支持的算法(按位标记)
+//  服务端收到后按本端配置的优先顺序选出一个, 回复 C2sLogin 时带上选定的算法
+//  双方只在协商成功后才发送压缩帧, 不认识 compress 字段的旧版本两端都按不压缩处理
+use super::frame::Frame;
+use super::tcp::PROTO_BODY_MAX_LEN;
+use crate::config::Config;
+use crate::message::Packet;
+use crate::protos::ProtoType;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+//帧头 flags 中的压缩标记, 同时作为登录协商时的算法标记位
+pub const FLAG_LZ4: u8 = 0x01;
+pub const FLAG_ZSTD: u8 = 0x02;
+pub const FLAG_COMPRESS_MASK: u8 = FLAG_LZ4 | FLAG_ZSTD;
+
+//协议体达到该长度才压缩
+const DEFAULT_THRESHOLD: usize = 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub fn flag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => FLAG_LZ4,
+            Codec::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    pub fn from_flag(flag: u8) -> Option<Codec> {
+        match flag {
+            0 => Some(Codec::None),
+            FLAG_LZ4 => Some(Codec::Lz4),
+            FLAG_ZSTD => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, body: &[u8]) -> crate::Result<Bytes> {
+        match self {
+            Codec::None => Ok(Bytes::copy_from_slice(body)),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(body).into()),
+            Codec::Zstd => Ok(zstd::bulk::compress(body, ZSTD_LEVEL)?.into()),
+        }
+    }
+
+    //解压前先检查声明的原始长度, 不会为恶意构造的帧分配超过上限的内存
+    pub fn decompress(self, body: &[u8]) -> crate::Result<Bytes> {
+        match self {
+            Codec::None => Ok(Bytes::copy_from_slice(body)),
+            Codec::Lz4 => {
+                if body.len() < 4 {
+                    return Err("[decompress]: lz4=truncated".into());
+                }
+                let size = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as usize;
+                check_size(size)?;
+                let buf = lz4_flex::block::decompress(&body[4..], size)
+                    .map_err(|err| format!("[decompress]: lz4={err}"))?;
+                if buf.len() != size {
+                    return Err(format!("[decompress]: lz4_size={},{}", buf.len(), size).into());
+                }
+                Ok(buf.into())
+            }
+            Codec::Zstd => {
+                let size = match zstd::zstd_safe::get_frame_content_size(body) {
+                    Ok(Some(size)) => size as usize,
+                    _ => return Err("[decompress]: zstd=unknown_content_size".into()),
+                };
+                check_size(size)?;
+                Ok(zstd::bulk::decompress(body, size)?.into())
+            }
+        }
+    }
+}
+
+fn check_size(size: usize) -> crate::Result<()> {
+    if size >= PROTO_BODY_MAX_LEN {
+        return Err(format!("[decompress]: exceed=PROTO_BODY_MAX_LEN,{}", size).into());
+    }
+    Ok(())
+}
+
+//本端的压缩配置, 对应配置项:
+//  compress = zstd,lz4         #支持的压缩算法, 按优先顺序排列, 不配置则不压缩
+//  compress_threshold = 1024   #协议体达到该长度才压缩
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressConf {
+    pub prefer: Vec<Codec>,
+    pub threshold: usize,
+}
+
+impl Default for CompressConf {
+    fn default() -> Self {
+        CompressConf {
+            prefer: vec![],
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+impl CompressConf {
+    pub fn new(prefer: Vec<Codec>, threshold: usize) -> Self {
+        CompressConf { prefer, threshold }
+    }
+
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        let mut prefer = vec![];
+        if let Some(names) = conf.get_string("compress") {
+            for name in names.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                let codec = match name {
+                    "lz4" => Codec::Lz4,
+                    "zstd" => Codec::Zstd,
+                    "none" => continue,
+                    _ => return Err(format!("[compress]: unknown_codec={name}").into()),
+                };
+                if !prefer.contains(&codec) {
+                    prefer.push(codec);
+                }
+            }
+        }
+        let threshold = match conf.get_int("compress_threshold") {
+            Some(n) if n >= 0 => n as usize,
+            Some(n) => return Err(format!("[compress]: compress_threshold={n}").into()),
+            None => DEFAULT_THRESHOLD,
+        };
+        Ok(CompressConf { prefer, threshold })
+    }
+
+    //本端支持的算法标记位
+    pub fn supported(&self) -> u8 {
+        self.prefer
+            .iter()
+            .fold(0, |bits, codec| bits | codec.flag())
+    }
+}
+
+//单个连接的压缩状态, 读写两端共享: 读端收到登录协议时完成协商, 写端按协商结果压缩
+#[derive(Debug, Clone, Default)]
+pub struct Compression {
+    conf: Arc<CompressConf>,
+    codec: Arc<AtomicU8>,
+}
+
+impl Compression {
+    pub fn new(conf: Arc<CompressConf>) -> Self {
+        Compression {
+            conf,
+            codec: Arc::new(AtomicU8::new(0)),
+        }
+    }
+
+    //当前协商好的算法
+    pub fn codec(&self) -> Codec {
+        Codec::from_flag(self.codec.load(Ordering::Acquire)).unwrap_or(Codec::None)
+    }
+
+    fn set_codec(&self, codec: Codec) {
+        self.codec.store(codec.flag(), Ordering::Release);
+    }
+
+    //服务端: 从对端支持的算法中, 按本端的优先顺序选出一个
+    pub fn negotiate(&self, offered: u32) -> Codec {
+        let codec = self
+            .conf
+            .prefer
+            .iter()
+            .copied()
+            .find(|codec| offered & codec.flag() as u32 != 0)
+            .unwrap_or(Codec::None);
+        self.set_codec(codec);
+        codec
+    }
+
+    //客户端: 接受服务端选定的算法, 本端不支持时不压缩
+    pub fn accept(&self, chosen: u32) -> Codec {
+        let codec = u8::try_from(chosen)
+            .ok()
+            .and_then(Codec::from_flag)
+            .filter(|codec| self.conf.prefer.contains(codec))
+            .unwrap_or(Codec::None);
+        self.set_codec(codec);
+        codec
+    }
+
+    //读端收到协议后调用, 登录协议触发协商
+    pub fn observe(&self, pto: &ProtoType) {
+        match pto {
+            ProtoType::S2cLogin(req) => {
+                self.negotiate(req.compress);
+            }
+            ProtoType::C2sLogin(resp) => {
+                self.accept(resp.compress);
+            }
+            _ => {}
+        }
+    }
+
+    //写端发送登录协议时填写协商字段
+    pub fn fill_login(&self, pto: &mut ProtoType) {
+        match pto {
+            ProtoType::S2cLogin(req) => req.compress = self.conf.supported() as u32,
+            ProtoType::C2sLogin(resp) => resp.compress = self.codec().flag() as u32,
+            _ => {}
+        }
+    }
+
+    //写端把消息编码为帧, 协商成功且协议体足够大时压缩
+    pub fn encode(&self, packet: Packet) -> crate::Result<Frame> {
+        let frame = match packet {
+            Packet::Proto(mut pto) => {
+                self.fill_login(&mut pto);
+                Frame::from_proto(pto)?
+            }
+            Packet::Encoded(frame) => frame,
+        };
+        let codec = self.codec();
+        //转发的帧可能已按其他连接协商的算法压缩过, 与本连接不一致时先解压
+        let frame = if frame.is_compressed() && frame.codec() != Some(codec) {
+            frame.decompress()?
+        } else {
+            frame
+        };
+        if codec == Codec::None || frame.is_compressed() || frame.body.len() < self.conf.threshold {
+            return Ok(frame);
+        }
+        frame.compress(codec)
+    }
+}
@@ -0,0 +1,248 @@
+//本机的 lua 调试控制台
+//
+//  监听 unix socket 或者本机地址, 每行一条命令, 在 game_hub 的脚本虚拟机中执行后返回结果的文本
+//  以 ':' 开头的是控制台自己的命令, 见 HELP; 每个会话保存自己的历史, !n 重新执行第 n 条
+//  执行的每条脚本都写入审计日志 console_audit.log, 不受日志等级影响
+use super::http::service::ask;
+use super::http::{ChanHttpProtoSenderOp, HttpProtoType};
+use crate::logger::{build_logger, LogLevel, Outter};
+use crate::{error, info};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+
+const LOG_NAME: &str = "console.log";
+const AUDIT_LOG_NAME: &str = "console_audit.log";
+//每个会话保存的历史条数
+const HISTORY_MAX: usize = 1000;
+
+const HELP: &str = "\
+<lua>        evaluate an expression or run a statement in the game hub's lua vm
+!<n>         run history entry n again
+:history     list this session's history
+:shard <n>   switch to logic shard n
+:help        print this message
+:quit        close the session";
+
+//会话 id, 只用于审计日志区分会话
+static SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleAddr {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl ConsoleAddr {
+    //以 '/' 开头为 unix socket 路径, 其他的只能是本机地址
+    pub fn parse(addr: &str) -> Result<Self, String> {
+        if addr.starts_with('/') {
+            return Ok(ConsoleAddr::Unix(PathBuf::from(addr)));
+        }
+        match addr.parse::<SocketAddr>() {
+            Ok(sa) if sa.ip().is_loopback() => Ok(ConsoleAddr::Tcp(sa)),
+            Ok(_) => Err(format!("{addr}: must be a loopback address")),
+            Err(err) => Err(format!("{addr}: {err}")),
+        }
+    }
+}
+
+//控制台使用的 game_hub, 下标即分片 id
+pub struct Console {
+    hubs: Vec<ChanHttpProtoSenderOp>,
+    readonly: bool,
+}
+
+impl Console {
+    pub fn new(hubs: Vec<ChanHttpProtoSenderOp>) -> Self {
+        Console {
+            hubs,
+            readonly: false,
+        }
+    }
+
+    //只读时拒绝给全局变量赋值
+    pub fn with_readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+}
+
+pub async fn start_service(addr: ConsoleAddr, console: Console) {
+    let mut log = build_logger(LOG_NAME);
+    let console = Arc::new(console);
+    //绑定失败时只记录日志, 不影响其他服务
+    let res = match &addr {
+        ConsoleAddr::Unix(path) => serve_unix(path, console, &mut log).await,
+        ConsoleAddr::Tcp(sa) => serve_tcp(*sa, console, &mut log).await,
+    };
+    if let Err(err) = res {
+        error!(log, "[console]: addr={:?},err={}", addr, err);
+    }
+}
+
+async fn serve_unix(
+    path: &PathBuf,
+    console: Arc<Console>,
+    log: &mut Outter,
+) -> std::io::Result<()> {
+    //上次运行留下的 socket 文件
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    //只有启动服务的用户可以连接
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!(log, "[console]: listen={}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve(stream, "unix".into(), console.clone()));
+    }
+}
+
+async fn serve_tcp(
+    addr: SocketAddr,
+    console: Arc<Console>,
+    log: &mut Outter,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(log, "[console]: listen={}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(serve(stream, peer.to_string(), console.clone()));
+    }
+}
+
+//一个控制台会话, 连接关闭或者 :quit 时结束
+pub async fn serve<S>(stream: S, peer: String, console: Arc<Console>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let mut session = Session {
+        id: SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        peer,
+        shard: 0,
+        history: Vec::new(),
+        console,
+        audit: build_logger(AUDIT_LOG_NAME).with_level(LogLevel::Debug),
+    };
+    info!(
+        session.audit,
+        "[console]: session={},peer={},open=true", session.id, session.peer
+    );
+    loop {
+        let prompt = format!("lua[{}]> ", session.shard);
+        if writer.write_all(prompt.as_bytes()).await.is_err() {
+            break;
+        }
+        let Ok(Some(line)) = lines.next_line().await else {
+            break;
+        };
+        let Some(out) = session.handle(line.trim()).await else {
+            break;
+        };
+        if !out.is_empty()
+            && writer
+                .write_all(format!("{out}\n").as_bytes())
+                .await
+                .is_err()
+        {
+            break;
+        }
+    }
+    info!(
+        session.audit,
+        "[console]: session={},peer={},close=true", session.id, session.peer
+    );
+}
+
+struct Session {
+    id: u64,
+    peer: String,
+    shard: usize,
+    history: Vec<String>,
+    console: Arc<Console>,
+    audit: Outter,
+}
+
+impl Session {
+    //返回输出的文本, 结束会话时返回 None
+    async fn handle(&mut self, line: &str) -> Option<String> {
+        let out = match line.split_once(' ').unwrap_or((line, "")) {
+            ("", _) => String::new(),
+            (":quit" | ":q", _) => return None,
+            (":help", _) => HELP.into(),
+            (":history", _) => self
+                .history
+                .iter()
+                .enumerate()
+                .map(|(i, code)| format!("{:>4}  {code}", i + 1))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            (":shard", n) => match n.trim().parse::<usize>() {
+                Ok(n) if n < self.console.hubs.len() => {
+                    self.shard = n;
+                    format!("shard = {n}")
+                }
+                _ => format!("error: shard must be 0..{}", self.console.hubs.len()),
+            },
+            _ if line.starts_with(':') => format!("error: unknown command {line}, try :help"),
+            _ => match line.strip_prefix('!') {
+                Some(n) => match n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.history.get(n.wrapping_sub(1)))
+                {
+                    Some(code) => {
+                        let code = code.clone();
+                        let out = self.eval(code.clone()).await;
+                        format!("{code}\n{out}")
+                    }
+                    None => format!("error: no history entry {n}"),
+                },
+                None => self.eval(line.to_owned()).await,
+            },
+        };
+        Some(out)
+    }
+
+    async fn eval(&mut self, code: String) -> String {
+        if self.history.len() >= HISTORY_MAX {
+            self.history.remove(0);
+        }
+        self.history.push(code.clone());
+        let readonly = self.console.readonly;
+        info!(
+            self.audit,
+            "[console]: session={},peer={},shard={},readonly={},code={:?}",
+            self.id,
+            self.peer,
+            self.shard,
+            readonly,
+            code
+        );
+        let Some(hub) = self.console.hubs.get(self.shard) else {
+            return "error: no game hub in this service".into();
+        };
+        let (ok, out) = match ask(hub, HttpProtoType::ReqLua(code, readonly)).await {
+            Ok(HttpProtoType::Resp(serde_json::Value::String(text))) => (true, text),
+            Ok(HttpProtoType::Error(err)) => (false, format!("error: {err}")),
+            Ok(resp) => (false, format!("error: unexpected reply {resp}")),
+            Err((_, err)) => (false, format!("error: {err}")),
+        };
+        info!(
+            self.audit,
+            "[console]: session={},ok={},out_len={}",
+            self.id,
+            ok,
+            out.len()
+        );
+        out
+    }
+}
@@ -0,0 +1,237 @@
+//rpc 连接基于额度(credit)的流量控制
+//
+//  rpc 连接上需要流控的是 rpc 客户端 -> rpc 服务端 方向的消息, 反方向只有认证和额度消息
+//  服务端的读端用 send 把消息交给 hub, hub 处理不过来时读端等待, 不再丢弃消息
+//  读端每交付一批消息, 就通过写端发送 RpcCredit 把额度还给客户端; 连接建立时先给出整个窗口的额度
+//  客户端的写端额度用完时不再从发件队列取消息, 直到收到服务端的 RpcCredit; 发件队列满时由 hub 暂存
+//  两个 hub 之间不会死锁: hub 发给连接只用 try_send, 从不等待连接, 读端等待 hub 时 hub 总能继续处理
+use crate::message::{SMSender, SystemMsg};
+use crate::metrics;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::Notify;
+
+//同一个连接的读端和写端共享的额度
+#[derive(Clone, Default)]
+pub struct Credit {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    active: bool, //收到第一次额度之前不限制, 兼容没有开启流控的对端
+    value: u64,
+    debt: u64, //额度不足时发出的消息, 从之后的额度中扣除
+}
+
+impl Credit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap()
+    }
+
+    pub fn grant(&self, n: u64) {
+        {
+            let mut state = self.state();
+            let pay = state.debt.min(n);
+            state.debt -= pay;
+            state.value += n - pay;
+            state.active = true;
+        }
+        self.inner.notify.notify_one();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state().active
+    }
+
+    pub fn available(&self) -> u64 {
+        self.state().value
+    }
+
+    //最多取出 n, 返回实际取出的数量
+    pub fn take(&self, n: u64) -> u64 {
+        let mut state = self.state();
+        let taken = state.value.min(n);
+        state.value -= taken;
+        taken
+    }
+
+    //记录已发出 n 条消息, 额度不足的部分记为欠额
+    pub fn spend(&self, n: u64) {
+        let mut state = self.state();
+        let pay = state.value.min(n);
+        state.value -= pay;
+        state.debt += n - pay;
+    }
+
+    //等待直到有额度, 不取出; 只能有一个等待者, 可以安全地用在 select! 中
+    pub async fn wait(&self) -> u64 {
+        loop {
+            let n = self.available();
+            if n > 0 {
+                return n;
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+}
+
+//服务端读端使用: 每交付 batch 条消息归还一次额度, 客户端在途的消息不超过 window 条
+pub struct CreditGrant {
+    credit: Credit,
+    batch: u64,
+    delivered: u64,
+}
+
+impl CreditGrant {
+    //返回的 Credit 交给写端, 写端把其中的额度发给客户端
+    pub fn new(window: u64) -> (CreditGrant, Credit) {
+        let credit = Credit::new();
+        credit.grant(window);
+        let grant = CreditGrant {
+            credit: credit.clone(),
+            batch: (window / 2).max(1),
+            delivered: 0,
+        };
+        (grant, credit)
+    }
+
+    pub fn delivered(&mut self) {
+        self.delivered += 1;
+        if self.delivered >= self.batch {
+            self.credit.grant(self.delivered);
+            self.delivered = 0;
+        }
+    }
+}
+
+//发件队列满时暂存的消息, 按连接保持顺序; 每个连接最多暂存 max 条, 超过时丢弃并计数
+pub struct Backlog {
+    site: &'static str, //指标中的 site 标签, 即使用暂存的 hub
+    max: usize,
+    queued: usize,
+    queues: HashMap<u64, VecDeque<SystemMsg>>,
+}
+
+impl Backlog {
+    pub fn new(site: &'static str) -> Self {
+        Backlog {
+            site,
+            max: usize::MAX,
+            queued: 0,
+            queues: HashMap::new(),
+        }
+    }
+
+    //对端长时间不给额度时, 暂存的消息不能无限增长
+    pub fn with_max(mut self, max: usize) -> Self {
+        self.max = max.max(1);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub fn len(&self, vfd: u64) -> usize {
+        self.queues.get(&vfd).map_or(0, VecDeque::len)
+    }
+
+    //连接还能暂存的消息数, 发件队列本身的空间不算在内
+    pub fn room(&self, vfd: u64) -> usize {
+        self.max - self.len(vfd)
+    }
+
+    //所有连接暂存的消息总数
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    //已有暂存的消息时排在后面, 否则直接发送; 暂存已满或者连接关闭时返回错误
+    pub fn send(&mut self, sender: &SMSender, vfd: u64, msg: SystemMsg) -> crate::Result<()> {
+        if let Some(queue) = self.queues.get_mut(&vfd) {
+            if queue.len() >= self.max {
+                metrics::backlog_dropped(self.site, 1);
+                return Err(format!("[backlog]: send=overflow,vfd={vfd},max={}", self.max).into());
+            }
+            queue.push_back(msg);
+            self.set_queued(self.queued + 1);
+            return Ok(());
+        }
+        match sender.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(msg)) => {
+                self.queues.entry(vfd).or_default().push_back(msg);
+                self.set_queued(self.queued + 1);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => {
+                Err(format!("[backlog]: send=chan_closed,vfd={vfd}").into())
+            }
+        }
+    }
+
+    //尽量把暂存的消息放入发件队列, 返回放入的数量; 连接关闭时丢弃暂存并返回错误
+    pub fn flush(&mut self, sender: &SMSender, vfd: u64) -> crate::Result<usize> {
+        let Some(queue) = self.queues.get_mut(&vfd) else {
+            return Ok(0);
+        };
+        let mut sent = 0;
+        let mut closed = false;
+        while let Some(msg) = queue.pop_front() {
+            match sender.try_send(msg) {
+                Ok(()) => sent += 1,
+                Err(TrySendError::Full(msg)) => {
+                    queue.push_front(msg);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        self.set_queued(self.queued - sent);
+        if closed {
+            //取出的那条消息已经随 TrySendError 丢弃
+            self.set_queued(self.queued - 1);
+            metrics::backlog_dropped(self.site, 1);
+            let dropped = self.remove(vfd) + 1;
+            return Err(format!("[backlog]: flush=chan_closed,vfd={vfd},dropped={dropped}").into());
+        }
+        if self.len(vfd) == 0 {
+            self.queues.remove(&vfd);
+        }
+        Ok(sent)
+    }
+
+    pub fn vfds(&self) -> Vec<u64> {
+        self.queues.keys().copied().collect()
+    }
+
+    //丢弃连接暂存的消息, 返回丢弃的数量
+    pub fn remove(&mut self, vfd: u64) -> usize {
+        let dropped = self.queues.remove(&vfd).map_or(0, |q| q.len());
+        if dropped > 0 {
+            metrics::backlog_dropped(self.site, dropped);
+            self.set_queued(self.queued - dropped);
+        }
+        dropped
+    }
+
+    fn set_queued(&mut self, queued: usize) {
+        self.queued = queued;
+        metrics::backlog_queued(self.site, queued);
+    }
+}
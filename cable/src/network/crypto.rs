@@ -0,0 +1,285 @@
+//tcp 游戏连接的应用层加密
+//
+//  连接建立后双方各自生成临时的 X25519 密钥, 互相发送 hello: magic(4) + 公钥(32)
+//  服务端另有长期的静态密钥, 客户端预先配置服务端的静态公钥(固定, 不在网络上传输)
+//  共享密钥由两次 DH 组成: 双方的临时密钥, 客户端的临时密钥与服务端的静态密钥
+//  没有服务端静态私钥的中间人算不出后者, 派生的密钥不一致, 第一条记录就无法解密
+//  由共享密钥经 HKDF-SHA256 分别派生两个方向的密钥, 之后每个帧加密为一条记录:
+//      len(u32, 小端) + ChaCha20-Poly1305(帧头 + 帧体) + tag(16)
+//  nonce 是每个方向各自递增的计数器, 不在网络上传输, 重放或乱序的记录无法通过校验
+use super::frame::Frame;
+use super::tcp::{PROTO_BODY_MAX_LEN, PROTO_HEADER_LEN};
+use crate::config::Config;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+const HELLO_MAGIC: &[u8; 4] = b"CBL2";
+const HELLO_LEN: usize = HELLO_MAGIC.len() + 32;
+const HKDF_INFO: &[u8] = b"cable2 tcp frame key v2";
+const KEY_LEN: usize = 32;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECORD_LEN_SIZE: usize = 4;
+const TAG_LEN: usize = 16;
+const RECORD_MAX_LEN: usize = PROTO_HEADER_LEN + PROTO_BODY_MAX_LEN + TAG_LEN;
+
+//本端在握手中的身份
+#[derive(Clone)]
+pub enum Identity {
+    Server(StaticSecret), //服务端的静态私钥
+    Client(PublicKey),    //客户端固定的服务端静态公钥
+}
+
+impl Identity {
+    //服务端从 encrypt_key_file 读取静态私钥, 客户端(机器人)使用 encrypt_server_key
+    pub fn from_config(conf: &Config) -> crate::Result<Identity> {
+        if let Some(key) = conf.get_string("encrypt_server_key") {
+            return Ok(Identity::Client(PublicKey::from(parse_key(key)?)));
+        }
+        let Some(path) = conf.get_string("encrypt_key_file") else {
+            return Err("[crypto]: encrypt_key_file or encrypt_server_key is required".into());
+        };
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("[crypto]: encrypt_key_file={path},err={err}"))?;
+        Ok(Identity::Server(StaticSecret::from(parse_key(&text)?)))
+    }
+
+    //服务端的静态公钥, 十六进制写到客户端的 encrypt_server_key
+    pub fn server_key(&self) -> PublicKey {
+        match self {
+            Identity::Server(secret) => PublicKey::from(secret),
+            Identity::Client(public) => *public,
+        }
+    }
+}
+
+//64 个十六进制字符的密钥, 可以用 openssl rand -hex 32 生成
+pub fn parse_key(text: &str) -> crate::Result<[u8; KEY_LEN]> {
+    let text = text.trim();
+    if text.len() != KEY_LEN * 2 || !text.is_ascii() {
+        return Err("[crypto]: key must be 64 hex characters".into());
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)
+            .map_err(|_| "[crypto]: key must be 64 hex characters")?;
+    }
+    Ok(key)
+}
+
+pub fn key_hex(key: &PublicKey) -> String {
+    key.as_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+//开始一次握手, 读写两端各持有一半:
+//写端发送本端的 hello, 读端读取对端的 hello 并派生密钥, 再把加密用的 Sealer 交给写端
+pub fn handshake(identity: &Identity) -> (ReadHandshake, WriteHandshake) {
+    //两次 DH 都要用到临时私钥, 所以使用 StaticSecret, 每个连接重新生成
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    let (sealer_tx, sealer_rx) = oneshot::channel();
+    (
+        ReadHandshake {
+            secret,
+            public,
+            identity: identity.clone(),
+            sealer_tx,
+        },
+        WriteHandshake { public, sealer_rx },
+    )
+}
+
+pub struct ReadHandshake {
+    secret: StaticSecret,
+    public: PublicKey,
+    identity: Identity,
+    sealer_tx: oneshot::Sender<Sealer>,
+}
+
+impl ReadHandshake {
+    //读取对端的 hello, 多读到的数据留在 buffer 中, 作为之后的记录继续解析
+    pub async fn run<R>(self, stream: &mut R, buffer: &mut BytesMut) -> crate::Result<Opener>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let read_hello = async {
+            while buffer.len() < HELLO_LEN {
+                if 0 == stream.read_buf(buffer).await? {
+                    return Err::<(), crate::Error>("[handshake]: connection reset by peer".into());
+                }
+            }
+            Ok(())
+        };
+        match timeout(HANDSHAKE_TIMEOUT, read_hello).await {
+            Ok(res) => res?,
+            Err(_) => return Err("[handshake]: timeout=true".into()),
+        }
+        let hello = buffer.split_to(HELLO_LEN);
+        if &hello[..HELLO_MAGIC.len()] != HELLO_MAGIC {
+            return Err("[handshake]: wrong_magic=true".into());
+        }
+        let mut peer = [0u8; 32];
+        peer.copy_from_slice(&hello[HELLO_MAGIC.len()..]);
+        let peer = PublicKey::from(peer);
+        //对端原样返回本端的公钥时, 两个方向的密钥会相同
+        if peer == self.public {
+            return Err("[handshake]: reflected_key=true".into());
+        }
+        let ephemeral = self.secret.diffie_hellman(&peer);
+        //服务端用静态私钥与客户端的临时公钥, 客户端用临时私钥与服务端的静态公钥
+        let server_key = self.identity.server_key();
+        let static_dh = match &self.identity {
+            Identity::Server(secret) => secret.diffie_hellman(&peer),
+            Identity::Client(server) => self.secret.diffie_hellman(server),
+        };
+        if !ephemeral.was_contributory() || !static_dh.was_contributory() {
+            return Err("[handshake]: low_order_key=true".into());
+        }
+        let shared = [&ephemeral, &static_dh];
+        let sealer = Sealer::new(derive_key(&shared, &server_key, &self.public, &peer));
+        let opener = Opener::new(derive_key(&shared, &server_key, &peer, &self.public));
+        if self.sealer_tx.send(sealer).is_err() {
+            return Err("[handshake]: writer=closed".into());
+        }
+        Ok(opener)
+    }
+}
+
+pub struct WriteHandshake {
+    public: PublicKey,
+    sealer_rx: oneshot::Receiver<Sealer>,
+}
+
+impl WriteHandshake {
+    //发送本端的 hello, 等待读端完成密钥派生
+    pub async fn run<W>(self, stream: &mut W) -> crate::Result<Sealer>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut hello = Vec::with_capacity(HELLO_LEN);
+        hello.extend_from_slice(HELLO_MAGIC);
+        hello.extend_from_slice(self.public.as_bytes());
+        stream.write_all(&hello).await?;
+        stream.flush().await?;
+        match self.sealer_rx.await {
+            Ok(sealer) => Ok(sealer),
+            Err(_) => Err("[handshake]: reader=failed".into()),
+        }
+    }
+}
+
+//方向由 from 和 to 的顺序决定, 双方对同一方向派生出相同的密钥
+fn derive_key(
+    shared: &[&SharedSecret; 2],
+    server_key: &PublicKey,
+    from: &PublicKey,
+    to: &PublicKey,
+) -> Key {
+    let mut ikm = [0u8; KEY_LEN * 2];
+    ikm[..KEY_LEN].copy_from_slice(shared[0].as_bytes());
+    ikm[KEY_LEN..].copy_from_slice(shared[1].as_bytes());
+    let hk = Hkdf::<Sha256>::new(None, &ikm);
+    let mut okm = [0u8; 32];
+    let info = [
+        HKDF_INFO,
+        server_key.as_bytes(),
+        from.as_bytes(),
+        to.as_bytes(),
+    ];
+    hk.expand_multi_info(&info, &mut okm)
+        .expect("32 bytes is a valid hkdf output length");
+    Key::from(okm)
+}
+
+//每个方向独立的 nonce 计数器
+struct NonceCounter(u64);
+
+impl NonceCounter {
+    fn next(&mut self) -> crate::Result<Nonce> {
+        if self.0 == u64::MAX {
+            return Err("[crypto]: nonce=exhausted".into());
+        }
+        let mut nonce = Nonce::default();
+        nonce[..8].copy_from_slice(&self.0.to_le_bytes());
+        self.0 += 1;
+        Ok(nonce)
+    }
+}
+
+//写端: 把帧加密为一条记录
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    nonce: NonceCounter,
+}
+
+impl Sealer {
+    pub fn new(key: Key) -> Self {
+        Sealer {
+            cipher: ChaCha20Poly1305::new(&key),
+            nonce: NonceCounter(0),
+        }
+    }
+
+    pub fn seal(&mut self, frame: &Frame) -> crate::Result<Bytes> {
+        let payload_len = PROTO_HEADER_LEN + frame.body.len() + TAG_LEN;
+        let mut buf = BytesMut::with_capacity(RECORD_LEN_SIZE + payload_len);
+        buf.put_u32_le(payload_len as u32);
+        let mut payload = buf.split_off(RECORD_LEN_SIZE);
+        payload.put_slice(&frame.header());
+        payload.put_slice(&frame.body);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&self.nonce.next()?, b"", &mut payload)
+            .map_err(|_| "[crypto]: encrypt=failed")?;
+        payload.put_slice(&tag);
+        buf.unsplit(payload);
+        Ok(buf.freeze())
+    }
+}
+
+//读端: 从读缓冲中切出一条记录并解密, 得到一个完整帧的数据
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    nonce: NonceCounter,
+}
+
+impl Opener {
+    pub fn new(key: Key) -> Self {
+        Opener {
+            cipher: ChaCha20Poly1305::new(&key),
+            nonce: NonceCounter(0),
+        }
+    }
+
+    pub fn open(&mut self, buf: &mut BytesMut) -> crate::Result<Option<BytesMut>> {
+        if buf.len() < RECORD_LEN_SIZE {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if !(PROTO_HEADER_LEN + TAG_LEN..=RECORD_MAX_LEN).contains(&len) {
+            return Err(format!("[crypto]: wrong_record_len={}", len).into());
+        }
+        if buf.len() < RECORD_LEN_SIZE + len {
+            buf.reserve(RECORD_LEN_SIZE + len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(RECORD_LEN_SIZE);
+        let mut payload = buf.split_to(len);
+        let tag = payload.split_off(len - TAG_LEN);
+        self.cipher
+            .decrypt_in_place_detached(
+                &self.nonce.next()?,
+                b"",
+                &mut payload,
+                Tag::from_slice(&tag),
+            )
+            .map_err(|_| "[crypto]: decrypt=failed")?;
+        Ok(Some(payload))
+    }
+}
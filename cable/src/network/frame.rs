@@ -0,0 +1,239 @@
+use super::compress::{Codec, FLAG_COMPRESS_MASK};
+use super::tcp::{PROTO_BODY_MAX_LEN, PROTO_HEADER_LEN};
+use crate::protos::{self, ProtoType};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::IoSlice;
+
+//帧头版本, 位于帧头的第 4 个字节
+//  v0: proto_id(u32) + body_len(u32), 小端, 即最初的帧头格式
+//  v1: proto_id(u24) + version(u8) + body_len(u24) + flags(u8), 小端
+//协议id和协议体长度都小于 2^24, 所以 v0 帧头的 version 和 flags 字节总是 0,
+//新旧两种帧头可以在同一个连接上混用, 只有 flags 不为 0 的帧才需要写成 v1
+pub const FRAME_VERSION_V0: u8 = 0;
+pub const FRAME_VERSION_V1: u8 = 1;
+
+//一个完整的协议帧, body 为编码后的协议体, flags 不为 0 时 body 是压缩后的数据
+//body 是 Bytes, clone 时只增加引用计数, 广播和转发时多个连接共享同一份编码结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub proto_id: u32,
+    pub flags: u8,
+    pub body: Bytes,
+}
+
+impl Frame {
+    pub fn new(proto_id: u32, body: Bytes) -> Frame {
+        Frame {
+            proto_id,
+            flags: 0,
+            body,
+        }
+    }
+
+    pub fn from_proto(pto: ProtoType) -> crate::Result<Frame> {
+        let (proto_id, _) = pto.inner_info();
+        let body = protos::encode(pto)?;
+        Ok(Frame::new(proto_id, Bytes::from(body)))
+    }
+
+    //压缩的帧先解压再解码
+    pub fn decode(&self) -> crate::Result<ProtoType> {
+        if self.is_compressed() {
+            return self.clone().decompress()?.decode();
+        }
+        Ok(protos::decode(self.proto_id, &self.body)?)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESS_MASK != 0
+    }
+
+    //帧使用的压缩算法, 标记无法识别时返回 None
+    pub fn codec(&self) -> Option<Codec> {
+        Codec::from_flag(self.flags & FLAG_COMPRESS_MASK)
+    }
+
+    //压缩后没有变小时保持原样
+    pub fn compress(self, codec: Codec) -> crate::Result<Frame> {
+        if codec == Codec::None || self.is_compressed() {
+            return Ok(self);
+        }
+        let body = codec.compress(&self.body)?;
+        if body.len() >= self.body.len() {
+            return Ok(self);
+        }
+        Ok(Frame {
+            proto_id: self.proto_id,
+            flags: self.flags | codec.flag(),
+            body,
+        })
+    }
+
+    pub fn decompress(self) -> crate::Result<Frame> {
+        if !self.is_compressed() {
+            return Ok(self);
+        }
+        let Some(codec) = self.codec() else {
+            return Err(format!("[decompress]: unknown_flags={}", self.flags).into());
+        };
+        Ok(Frame {
+            proto_id: self.proto_id,
+            flags: self.flags & !FLAG_COMPRESS_MASK,
+            body: codec.decompress(&self.body)?,
+        })
+    }
+
+    //消息头, flags 为 0 时写成 v0, 旧版本的对端可以直接读取
+    pub fn header(&self) -> [u8; PROTO_HEADER_LEN] {
+        debug_assert!(self.proto_id < 1 << 24);
+        let mut header = [0u8; PROTO_HEADER_LEN];
+        header[..4].copy_from_slice(&self.proto_id.to_le_bytes());
+        header[4..].copy_from_slice(&(self.body.len() as u32).to_le_bytes());
+        if self.flags != 0 {
+            header[3] = FRAME_VERSION_V1;
+            header[7] = self.flags;
+        }
+        header
+    }
+
+    //从读缓冲中切出一个完整的帧, 数据不足时返回 None 并为剩余的数据预留空间
+    //切出的 body 与读缓冲共享内存, 不会复制
+    pub fn parse(buf: &mut BytesMut) -> crate::Result<Option<Frame>> {
+        if buf.len() < PROTO_HEADER_LEN {
+            return Ok(None);
+        }
+        let version = buf[3];
+        let (proto_id, body_len, flags) = match version {
+            FRAME_VERSION_V0 => (
+                u32::from_le_bytes([buf[0], buf[1], buf[2], 0]),
+                u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize,
+                0,
+            ),
+            FRAME_VERSION_V1 => (
+                u32::from_le_bytes([buf[0], buf[1], buf[2], 0]),
+                u32::from_le_bytes([buf[4], buf[5], buf[6], 0]) as usize,
+                buf[7],
+            ),
+            _ => return Err(format!("[parse_fram]: unknown_version={}", version).into()),
+        };
+
+        //协议长度超出最大上限
+        if body_len >= PROTO_BODY_MAX_LEN {
+            return Err(format!("[parse_fram]: exceed=PROTO_BODY_MAX_LEN,{}", body_len).into());
+        }
+        let frame_len = PROTO_HEADER_LEN + body_len;
+        if buf.len() < frame_len {
+            buf.reserve(frame_len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(PROTO_HEADER_LEN);
+        let body = buf.split_to(body_len).freeze();
+        Ok(Some(Frame {
+            proto_id,
+            flags,
+            body,
+        }))
+    }
+
+    //buf 中必须正好是一个完整的帧, 用于 websocket 消息和解密后的记录
+    pub fn parse_exact(mut buf: BytesMut) -> crate::Result<Frame> {
+        match Frame::parse(&mut buf)? {
+            Some(frame) if buf.is_empty() => Ok(frame),
+            Some(frame) => Err(format!(
+                "[parse_exact]: body_len!=buff_body_len,{},{}",
+                frame.body.len(),
+                frame.body.len() + buf.len()
+            )
+            .into()),
+            None => Err("[parse_exact]: wrong header".into()),
+        }
+    }
+}
+
+//一次写入 socket 的多个帧, 实现了 Buf, 配合 write_all_buf 以 writev 的方式写出
+//所有帧的消息头写在同一块内存上, body 直接引用帧本身, 不会复制
+#[derive(Debug, Default)]
+pub struct FrameBatch {
+    headers: BytesMut,
+    chunks: VecDeque<Bytes>,
+    remaining: usize,
+    frames: usize,
+}
+
+impl FrameBatch {
+    pub fn new() -> FrameBatch {
+        FrameBatch::default()
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        self.headers.put_slice(&frame.header());
+        self.chunks.push_back(self.headers.split().freeze());
+        self.remaining += PROTO_HEADER_LEN + frame.body.len();
+        if !frame.body.is_empty() {
+            self.chunks.push_back(frame.body);
+        }
+        self.frames += 1;
+    }
+
+    //已经编码好的整段数据, 例如加密后的记录, 按一个帧计数
+    pub fn push_bytes(&mut self, chunk: Bytes) {
+        self.remaining += chunk.len();
+        if !chunk.is_empty() {
+            self.chunks.push_back(chunk);
+        }
+        self.frames += 1;
+    }
+
+    //当前批次中的帧数量
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+
+    //写完后重置, 消息头的内存在所有引用释放后可以被复用
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.remaining = 0;
+        self.frames = 0;
+    }
+}
+
+impl Buf for FrameBatch {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => chunk,
+            None => &[],
+        }
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.remaining, "advance out of range");
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let front = self.chunks.front_mut().unwrap();
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.chunks.pop_front();
+        }
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut n = 0;
+        for (slot, chunk) in dst.iter_mut().zip(self.chunks.iter()) {
+            *slot = IoSlice::new(chunk);
+            n += 1;
+        }
+        n
+    }
+}
@@ -3,36 +3,49 @@ use tokio::sync::{mpsc, oneshot};
 
 pub mod service;
 
-#[derive(Debug)]
+//管理接口和调试控制台发给 game_hub 的请求, 以及 hub 的回复
+#[derive(Debug, Clone)]
 pub enum HttpProtoType {
-    ReqServerAll,
-    ReqServerInfo(u32),
-    RespServerInfo(String),
+    ReqConns,
+    ReqKick(u64, String),
+    ReqPlayer(u64),
     ReqGM(String),
-    RespGM(String),
-    Unimplemented(String),
+    ReqLogLevel(i32),
+    ReqLua(String, bool), //控制台执行的脚本, 是否只读
+    Resp(serde_json::Value),
+    NotFound,
+    Error(String),
 }
 
 impl Display for HttpProtoType {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match self {
-            HttpProtoType::ReqServerAll => {
-                write!(f, "ReqServerAll")
+            HttpProtoType::ReqConns => {
+                write!(f, "ReqConns")
             }
-            HttpProtoType::ReqServerInfo(hostid) => {
-                write!(f, "ReqServerInfo({})", hostid)
+            HttpProtoType::ReqKick(vfd, reason) => {
+                write!(f, "ReqKick({},{})", vfd, reason)
             }
-            HttpProtoType::RespServerInfo(info) => {
-                write!(f, "RespServerInfo({})", info)
+            HttpProtoType::ReqPlayer(vfd) => {
+                write!(f, "ReqPlayer({})", vfd)
             }
             HttpProtoType::ReqGM(cmdstr) => {
                 write!(f, "ReqGM({})", cmdstr)
             }
-            HttpProtoType::RespGM(cmdstr) => {
-                write!(f, "RespGM({})", cmdstr)
+            HttpProtoType::ReqLogLevel(level) => {
+                write!(f, "ReqLogLevel({})", level)
             }
-            HttpProtoType::Unimplemented(info) => {
-                write!(f, "Unimplemented({})", info)
+            HttpProtoType::ReqLua(code, readonly) => {
+                write!(f, "ReqLua({},{})", code, readonly)
+            }
+            HttpProtoType::Resp(value) => {
+                write!(f, "Resp({})", value)
+            }
+            HttpProtoType::NotFound => {
+                write!(f, "NotFound")
+            }
+            HttpProtoType::Error(info) => {
+                write!(f, "Error({})", info)
             }
         }
     }
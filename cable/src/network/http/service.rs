@@ -1,115 +1,390 @@
+//http 管理端口
+//
+//  /metrics 不需要认证, 只输出运行指标
+//  /admin 下的接口需要请求头 Authorization: Bearer <http_token>, 回复都是 json
+//  连接, 玩家和 gm 命令由 game_hub 处理, 分片时发给所有分片; 日志等级和重新加载在这里直接完成
 use super::{ChanHttpProtoSenderOp, HttpProtoType};
-use std::future::Future;
-use std::net::SocketAddr;
-
-use crate::logger::build_logger;
+use crate::config::{reload_blocking, Reloader};
+use crate::logger::{self, build_logger, LogLevel};
+use crate::metrics;
 use crate::{error, info};
+use futures_util::future;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use tokio::sync::oneshot;
-use warp::Filter;
+use tokio::time::{self, Duration};
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::{Filter, Rejection, Reply};
 
 const LOG_NAME: &str = "http.log";
+//等待 hub 回复的时间, hub 繁忙时不让请求一直挂着
+const HUB_TIMEOUT: Duration = Duration::from_secs(5);
+//gm 命令的最大长度
+const GM_BODY_MAX: u64 = 64 * 1024;
+
+//管理接口使用的服务; 网关和 db 服务没有 game_hub, 只能修改日志等级和重新加载
+#[derive(Default)]
+pub struct Admin {
+    token: Option<String>,
+    hubs: Vec<ChanHttpProtoSenderOp>,
+    reloader: Option<Arc<Mutex<Reloader>>>,
+}
+
+impl Admin {
+    //没有令牌时拒绝所有的管理请求
+    pub fn new(token: Option<String>) -> Self {
+        Admin {
+            token,
+            ..Default::default()
+        }
+    }
 
-fn with_sender(
-    sender: ChanHttpProtoSenderOp,
-) -> impl Filter<Extract = (ChanHttpProtoSenderOp,), Error = Infallible> + Clone {
-    warp::any().map(move || sender.clone())
+    //每个 game_hub 一个, 下标即分片 id
+    pub fn with_hubs(mut self, hubs: Vec<ChanHttpProtoSenderOp>) -> Self {
+        self.hubs = hubs;
+        self
+    }
+
+    pub fn with_reloader(mut self, reloader: Arc<Mutex<Reloader>>) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    fn authorized(&self, header: Option<&str>) -> bool {
+        let (Some(token), Some(header)) = (self.token.as_deref(), header) else {
+            return false;
+        };
+        match header.strip_prefix("Bearer ") {
+            Some(given) => constant_time_eq(token.as_bytes(), given.trim().as_bytes()),
+            None => false,
+        }
+    }
+}
+
+//比较耗时与内容无关, 不能逐字节猜出令牌
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
 pub async fn start_service(
     addr: SocketAddr,
-    shutdown: impl Future,
-    chan_out: ChanHttpProtoSenderOp,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    admin: Admin,
 ) {
-    // get /req/server/all
-    let handler_req_server_all = warp::get()
-        .and(warp::path!("req" / "server" / "all"))
-        .map(|| "name: s1, host: s1.xxx.com:8081");
-
-    let chan_out_req_server = chan_out.clone();
-    // get /req/server/:u32
-    // builds on example2 but adds custom error handling
-    let handler_req_server = warp::get()
-        .and(warp::path!("req" / "server" / u32))
-        .and(with_sender(chan_out_req_server))
-        .then(req_server)
-        .map(|res| res);
-
-    // get /gm/:string
-    let chan_out_gm_add_item = chan_out.clone();
-    let handler_gm_add_item = warp::get()
-        .and(warp::path("gm"))
-        .and(warp::path::param())
-        .and(with_sender(chan_out_gm_add_item))
-        .then(gm)
-        .map(|res| res);
-
-    let routes = handler_req_server_all
-        .or(handler_req_server)
-        .or(handler_gm_add_item);
-
     let mut log = build_logger(LOG_NAME);
-    tokio::select! {
-        _ = warp::serve(routes).run(addr) => {
-            error!(log,"http.run closed.");
+    //绑定失败时只记录日志, 不影响其他服务
+    match warp::serve(routes(Arc::new(admin))).try_bind_with_graceful_shutdown(addr, shutdown) {
+        Ok((addr, server)) => {
+            info!(log, "http.run listen={}", addr);
+            server.await;
+            info!(log, "http.run shut down.");
         }
-        _ = shutdown => {
-            info!(log,"http.run shut down.");
+        Err(err) => {
+            error!(log, "http.run bind failed: addr={},err={}", addr, err);
         }
     }
 }
 
-async fn req_server(hostid: u32, chan_out: ChanHttpProtoSenderOp) -> String {
+pub fn routes(
+    admin: Arc<Admin>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+    // get /metrics, prometheus 文本格式
+    let handler_metrics = warp::get().and(warp::path!("metrics")).map(|| {
+        warp::reply::with_header(
+            metrics::gather(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+        .into_response()
+    });
+
+    // get /admin/conns
+    let handler_conns = warp::path!("conns")
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .then(conns);
+
+    // post /admin/kick/:vfd?reason=
+    let handler_kick = warp::path!("kick" / u64)
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_admin(admin.clone()))
+        .then(kick);
+
+    // get /admin/player/:vfd
+    let handler_player = warp::path!("player" / u64)
+        .and(warp::get())
+        .and(with_admin(admin.clone()))
+        .then(player);
+
+    // post /admin/gm?shard=, 请求体为命令
+    let handler_gm = warp::path!("gm")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::content_length_limit(GM_BODY_MAX))
+        .and(warp::body::bytes())
+        .and(with_admin(admin.clone()))
+        .then(gm);
+
+    // put /admin/log_level/:level
+    let handler_log_level = warp::path!("log_level" / i32)
+        .and(warp::put())
+        .and(with_admin(admin.clone()))
+        .then(log_level);
+
+    // post /admin/reload
+    let handler_reload = warp::path!("reload")
+        .and(warp::post())
+        .and(with_admin(admin.clone()))
+        .then(reload);
+
+    //先匹配路径再匹配方法, 未知的路径回复 404 而不是 405
+    let handler_admin = warp::path("admin").and(authorize(admin)).and(
+        handler_conns
+            .or(handler_kick)
+            .unify()
+            .or(handler_player)
+            .unify()
+            .or(handler_gm)
+            .unify()
+            .or(handler_log_level)
+            .unify()
+            .or(handler_reload)
+            .unify(),
+    );
+
+    handler_metrics
+        .or(handler_admin.map(|r: WithStatus<Json>| r.into_response()))
+        .unify()
+        .recover(rejection)
+        .unify()
+}
+
+fn with_admin(
+    admin: Arc<Admin>,
+) -> impl Filter<Extract = (Arc<Admin>,), Error = Infallible> + Clone {
+    warp::any().map(move || admin.clone())
+}
+
+fn authorize(admin: Arc<Admin>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let ok = admin.authorized(header.as_deref());
+            async move {
+                match ok {
+                    true => Ok(()),
+                    false => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn reply(status: StatusCode, body: Value) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+fn fail(status: StatusCode, err: &str) -> WithStatus<Json> {
+    reply(status, json!({ "ok": false, "error": err }))
+}
+
+async fn rejection(err: Rejection) -> Result<warp::reply::Response, Infallible> {
+    let (status, msg) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "unauthorized")
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found")
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload too large")
+    } else {
+        (StatusCode::BAD_REQUEST, "bad request")
+    };
+    Ok(fail(status, msg).into_response())
+}
+
+//发给 hub 并等待回复; hub 已经停止或者超时时返回对应的状态码; 调试控制台也使用
+pub(crate) async fn ask(
+    hub: &ChanHttpProtoSenderOp,
+    req: HttpProtoType,
+) -> Result<HttpProtoType, (StatusCode, String)> {
     let (optx, oprx) = oneshot::channel();
-    tokio::spawn(async move {
-        //这里不需要 try_send, 如果 channel 阻塞,就让它一直阻塞, 因为send出去的是 oneshot 的 chan
-        if let Err(err) = chan_out
-            .send((HttpProtoType::ReqServerInfo(hostid), optx))
-            .await
-        {
+    let res = time::timeout(HUB_TIMEOUT, async move {
+        hub.send((req, optx)).await.ok()?;
+        oprx.await.ok()
+    })
+    .await;
+    match res {
+        Ok(Some(resp)) => Ok(resp),
+        Ok(None) => Err((StatusCode::SERVICE_UNAVAILABLE, "hub closed".into())),
+        Err(_) => Err((StatusCode::GATEWAY_TIMEOUT, "hub timeout".into())),
+    }
+}
+
+//发给所有 hub, 回复按分片的顺序
+async fn ask_all(
+    admin: &Admin,
+    req: HttpProtoType,
+) -> Result<Vec<HttpProtoType>, (StatusCode, String)> {
+    if admin.hubs.is_empty() {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            "not supported by this service".into(),
+        ));
+    }
+    future::join_all(admin.hubs.iter().map(|hub| ask(hub, req.clone())))
+        .await
+        .into_iter()
+        .collect()
+}
+
+//连接只在一个分片上: 取第一个成功的回复, 都没有找到时为 404
+async fn ask_owner(admin: &Admin, req: HttpProtoType) -> WithStatus<Json> {
+    let resps = match ask_all(admin, req.clone()).await {
+        Ok(resps) => resps,
+        Err((status, err)) => return fail(status, &err),
+    };
+    let mut error = None;
+    for resp in resps {
+        match resp {
+            HttpProtoType::Resp(value) => return reply(StatusCode::OK, value),
+            HttpProtoType::Error(err) => error = Some(err),
+            _ => {}
+        }
+    }
+    match error {
+        Some(err) => {
             let mut log = build_logger(LOG_NAME);
-            error!(
-                log,
-                "req_server, chan full: hostid={},err={:?}", hostid, err
-            );
+            error!(log, "[admin]: req={},err={}", req, err);
+            fail(StatusCode::INTERNAL_SERVER_ERROR, &err)
         }
-    });
-    match oprx.await {
-        Ok(hpt) => {
-            format!("success.\n{:?}", hpt)
+        None => fail(StatusCode::NOT_FOUND, "vfd not found"),
+    }
+}
+
+async fn conns(admin: Arc<Admin>) -> WithStatus<Json> {
+    let resps = match ask_all(&admin, HttpProtoType::ReqConns).await {
+        Ok(resps) => resps,
+        Err((status, err)) => return fail(status, &err),
+    };
+    let mut conns = Vec::new();
+    for (shard, resp) in resps.into_iter().enumerate() {
+        match resp {
+            HttpProtoType::Resp(Value::Array(vfds)) => conns.extend(
+                vfds.into_iter()
+                    .map(|vfd| json!({ "vfd": vfd, "shard": shard })),
+            ),
+            resp => {
+                return fail(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("shard {shard}: {resp}"),
+                )
+            }
         }
-        Err(err) => {
-            let mut log = build_logger(LOG_NAME);
-            error!(log, "/req/server/{},err={:?}", hostid, err);
-            format!("failed,{}", err)
+    }
+    reply(
+        StatusCode::OK,
+        json!({ "count": conns.len(), "conns": conns }),
+    )
+}
+
+async fn kick(vfd: u64, query: HashMap<String, String>, admin: Arc<Admin>) -> WithStatus<Json> {
+    let reason = query
+        .get("reason")
+        .cloned()
+        .unwrap_or_else(|| "kicked by admin".into());
+    let mut log = build_logger(LOG_NAME);
+    info!(log, "[admin]: kick=true,vfd={},reason={}", vfd, reason);
+    ask_owner(&admin, HttpProtoType::ReqKick(vfd, reason)).await
+}
+
+async fn player(vfd: u64, admin: Arc<Admin>) -> WithStatus<Json> {
+    ask_owner(&admin, HttpProtoType::ReqPlayer(vfd)).await
+}
+
+async fn gm(
+    query: HashMap<String, String>,
+    body: bytes::Bytes,
+    admin: Arc<Admin>,
+) -> WithStatus<Json> {
+    let shard = match query.get("shard").map(|s| s.parse::<usize>()) {
+        None => 0,
+        Some(Ok(shard)) => shard,
+        Some(Err(err)) => return fail(StatusCode::BAD_REQUEST, &format!("shard: {err}")),
+    };
+    let Ok(cmd) = String::from_utf8(body.to_vec()) else {
+        return fail(StatusCode::BAD_REQUEST, "command is not utf-8");
+    };
+    let Some(hub) = admin.hubs.get(shard) else {
+        return fail(StatusCode::NOT_FOUND, &format!("shard {shard} not found"));
+    };
+    let mut log = build_logger(LOG_NAME);
+    info!(log, "[admin]: gm=true,shard={},cmd={}", shard, cmd);
+    match ask(hub, HttpProtoType::ReqGM(cmd)).await {
+        Ok(HttpProtoType::Resp(value)) => {
+            reply(StatusCode::OK, json!({ "ok": true, "result": value }))
         }
+        Ok(resp) => {
+            error!(log, "[admin]: gm=failed,shard={},resp={}", shard, resp);
+            fail(StatusCode::INTERNAL_SERVER_ERROR, &resp.to_string())
+        }
+        Err((status, err)) => fail(status, &err),
     }
 }
 
-async fn gm(cmdstr: String, chan_out: ChanHttpProtoSenderOp) -> String {
-    let (optx, oprx) = oneshot::channel();
-    let cmdstr2 = cmdstr.clone();
-    tokio::spawn(async move {
-        //这里不需要 try_send, 如果 channel 阻塞,就让它一直阻塞, 因为send出去的是 oneshot 的 chan
-        if let Err(err) = chan_out
-            .send((HttpProtoType::ReqGM(cmdstr.clone()), optx))
-            .await
-        {
-            let mut log = build_logger(LOG_NAME);
-            error!(
-                log,
-                "gm_add_item, chan full: cmdstr={},err={:?}", cmdstr, err
-            );
+//全局日志等级立即生效, 脚本层的日志等级由各个 hub 更新
+async fn log_level(level: i32, admin: Arc<Admin>) -> WithStatus<Json> {
+    if !(1..=4).contains(&level) {
+        return fail(StatusCode::BAD_REQUEST, "log_level: expected 1..4");
+    }
+    let old: i32 = logger::get_global_log_level().into();
+    logger::set_global_log_level(LogLevel::from(level));
+    let mut log = build_logger(LOG_NAME);
+    info!(log, "[admin]: log_level={},old={}", level, old);
+    if !admin.hubs.is_empty() {
+        if let Err((status, err)) = ask_all(&admin, HttpProtoType::ReqLogLevel(level)).await {
+            return fail(status, &err);
         }
-    });
-    match oprx.await {
-        Ok(hpt) => {
-            format!("success.\n{:?}", hpt)
+    }
+    reply(
+        StatusCode::OK,
+        json!({ "ok": true, "old": old, "new": level }),
+    )
+}
+
+//与 SIGHUP 相同, 结果直接返回给调用方
+async fn reload(admin: Arc<Admin>) -> WithStatus<Json> {
+    let Some(reloader) = admin.reloader.as_ref() else {
+        return fail(StatusCode::NOT_IMPLEMENTED, "reload is not enabled");
+    };
+    let res = reload_blocking(reloader.clone()).await;
+    let mut log = build_logger(LOG_NAME);
+    match res {
+        Ok(changes) => {
+            let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+            info!(log, "[admin]: reload=ok,changed={:?}", keys);
+            let changed: Vec<Value> = changes
+                .iter()
+                .map(|c| json!({ "key": c.key, "old": c.old, "new": c.new }))
+                .collect();
+            reply(StatusCode::OK, json!({ "ok": true, "changed": changed }))
         }
         Err(err) => {
-            let mut log = build_logger(LOG_NAME);
-            error!(log, "/gm/{},err={:?}", cmdstr2, err);
-            format!("failed,{}", err)
+            error!(log, "[admin]: reload=rejected,{}", err);
+            reply(
+                StatusCode::BAD_REQUEST,
+                json!({ "ok": false, "errors": err.errors }),
+            )
         }
     }
 }
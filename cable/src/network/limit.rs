@@ -0,0 +1,306 @@
+//游戏连接的限流和防刷
+//
+//  每个连接按 消息数/秒 和 字节数/秒 两个令牌桶限流, 个别协议可以单独配置消息数限流
+//  限流在解码之前按帧头检查, 超过限流的帧按配置处理: 丢弃, 延迟读取, 或者断开连接
+//  另外在 accept 时限制同一个 ip 的连接数
+//  限流配置可以重新加载, 连接在下一条消息时按新配置重建令牌桶
+use crate::config::{Config, ConfigWatch};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const PROTO_PREFIX: &str = "limit_proto.";
+
+//超过限流时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitAction {
+    Drop,       //丢弃该帧
+    Delay,      //暂停读取, 直到令牌足够, 背压由 tcp 传递给客户端
+    Disconnect, //通知客户端原因后断开连接
+}
+
+impl From<&str> for LimitAction {
+    fn from(value: &str) -> Self {
+        match value {
+            "delay" => Self::Delay,
+            "disconnect" => Self::Disconnect,
+            _ => Self::Drop,
+        }
+    }
+}
+
+//令牌桶的配置, rate 为每秒补充的令牌数, burst 为桶的容量; rate 为 0 表示不限制
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rate {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        //容量至少能放下一秒的令牌
+        Rate {
+            rate,
+            burst: burst.max(rate),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.rate > 0.0
+    }
+}
+
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.rate).min(self.rate.burst);
+        self.last = now;
+    }
+
+    //令牌足够时返回 0, 否则返回需要等待的时间, 不扣除令牌
+    //超过容量的请求按容量计算, 避免永远无法通过
+    pub fn wait(&mut self, n: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let n = n.min(self.rate.burst);
+        if self.tokens >= n {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((n - self.tokens) / self.rate.rate)
+        }
+    }
+
+    pub fn take(&mut self, n: f64) {
+        self.tokens -= n.min(self.rate.burst);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitConf {
+    pub msg: Rate,
+    pub bytes: Rate,
+    pub protos: HashMap<u32, Rate>,
+    pub action: LimitAction,
+    pub max_per_ip: usize,
+}
+
+impl Default for LimitConf {
+    fn default() -> Self {
+        LimitConf {
+            msg: Rate::default(),
+            bytes: Rate::default(),
+            protos: HashMap::new(),
+            action: LimitAction::Drop,
+            max_per_ip: 0,
+        }
+    }
+}
+
+impl LimitConf {
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        let rate = |rate: &str, burst: &str| {
+            let rate = conf.get_int(rate).unwrap_or(0).max(0) as f64;
+            Rate::new(rate, conf.get_int(burst).unwrap_or(0).max(0) as f64)
+        };
+        let mut protos = HashMap::new();
+        for (proto_id, v) in conf.get_prefixed(PROTO_PREFIX) {
+            let parsed = match (proto_id.parse::<u32>(), v.split_once(',')) {
+                (Ok(proto_id), Some((rate, burst))) => {
+                    match (rate.trim().parse::<f64>(), burst.trim().parse::<f64>()) {
+                        (Ok(rate), Ok(burst)) if rate >= 0.0 => Some((proto_id, rate, burst)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            let Some((proto_id, rate, burst)) = parsed else {
+                return Err(format!("[limit]: wrong_config={PROTO_PREFIX}{proto_id} = {v}").into());
+            };
+            protos.insert(proto_id, Rate::new(rate, burst));
+        }
+        let action = conf
+            .get_string("limit_action")
+            .map(|s| LimitAction::from(s.as_str()))
+            .unwrap_or(LimitAction::Drop);
+        Ok(LimitConf {
+            msg: rate("limit_msg_rate", "limit_msg_burst"),
+            bytes: rate("limit_byte_rate", "limit_byte_burst"),
+            protos,
+            action,
+            max_per_ip: conf.get_int("max_connection_per_ip").unwrap_or(0).max(0) as usize,
+        })
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.msg.is_limited()
+            || self.bytes.is_limited()
+            || self.protos.values().any(Rate::is_limited)
+    }
+}
+
+//超过限流的原因和需要等待的时间
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exceed {
+    pub wait: Duration,
+    pub reason: &'static str,
+}
+
+//每个连接一个
+pub struct RateLimiter {
+    conf: Arc<LimitConf>,
+    msg: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    protos: HashMap<u32, TokenBucket>,
+    live: Option<ConfigWatch>,
+}
+
+impl RateLimiter {
+    pub fn new(conf: Arc<LimitConf>) -> Self {
+        let mut limiter = RateLimiter {
+            conf: Arc::new(LimitConf::default()),
+            msg: None,
+            bytes: None,
+            protos: HashMap::new(),
+            live: None,
+        };
+        limiter.reset(conf, Instant::now());
+        limiter
+    }
+
+    //跟随重新加载的配置
+    pub fn with_watch(mut self, live: ConfigWatch) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    fn reset(&mut self, conf: Arc<LimitConf>, now: Instant) {
+        let bucket = |rate: Rate| rate.is_limited().then(|| TokenBucket::new(rate, now));
+        self.msg = bucket(conf.msg);
+        self.bytes = bucket(conf.bytes);
+        self.protos.clear();
+        self.conf = conf;
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        let Some(live) = self.live.as_mut() else {
+            return;
+        };
+        if !live.has_changed().unwrap_or(false) {
+            return;
+        }
+        let conf = live.borrow_and_update().clone();
+        //重新加载前已经检查过, 解析失败时保持原来的配置
+        if let Ok(conf) = LimitConf::from_config(&conf) {
+            self.reset(Arc::new(conf), now);
+        }
+    }
+
+    pub fn action(&self) -> LimitAction {
+        self.conf.action
+    }
+
+    pub fn check(&mut self, proto_id: u32, len: usize) -> Option<Exceed> {
+        self.check_at(proto_id, len, Instant::now())
+    }
+
+    //所有令牌桶都足够时才扣除令牌, 否则返回等待时间最长的一个
+    pub fn check_at(&mut self, proto_id: u32, len: usize, now: Instant) -> Option<Exceed> {
+        self.refresh(now);
+        if !self.protos.contains_key(&proto_id) {
+            if let Some(rate) = self.conf.protos.get(&proto_id).filter(|r| r.is_limited()) {
+                self.protos.insert(proto_id, TokenBucket::new(*rate, now));
+            }
+        }
+        let mut exceed: Option<Exceed> = None;
+        let mut check = |bucket: Option<&mut TokenBucket>, n: f64, reason: &'static str| {
+            let Some(bucket) = bucket else {
+                return;
+            };
+            let wait = bucket.wait(n, now);
+            if wait > exceed.as_ref().map_or(Duration::ZERO, |e| e.wait) {
+                exceed = Some(Exceed { wait, reason });
+            }
+        };
+        check(self.msg.as_mut(), 1.0, "msg_rate");
+        check(self.bytes.as_mut(), len as f64, "byte_rate");
+        check(self.protos.get_mut(&proto_id), 1.0, "proto_rate");
+        if exceed.is_some() {
+            return exceed;
+        }
+        for (bucket, n) in [
+            (self.msg.as_mut(), 1.0),
+            (self.bytes.as_mut(), len as f64),
+            (self.protos.get_mut(&proto_id), 1.0),
+        ] {
+            if let Some(bucket) = bucket {
+                bucket.take(n);
+            }
+        }
+        None
+    }
+}
+
+//同一个 ip 的连接计数, 连接结束时 IpPermit 被 drop, 计数减一
+#[derive(Clone, Default)]
+pub struct IpLimit {
+    max: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl IpLimit {
+    //max 为 0 表示不限制
+    pub fn new(max: usize) -> Self {
+        IpLimit {
+            max,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if self.max > 0 && *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+
+    pub fn count(&self, ip: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+pub struct IpPermit {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
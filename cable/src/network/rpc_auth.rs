@@ -0,0 +1,334 @@
+//rpc 连接的身份认证和调用权限
+//
+//  rpc 服务端在连接建立后发送 RpcChallenge: 本端 host_id + 随机 nonce
+//  rpc 客户端回复 RpcAuth: 本端 host_id, service_type, rpc 监听地址,
+//      以及用集群共享密钥 rpc_secret 对 nonce 和以上字段计算的 HMAC-SHA256
+//  服务端校验通过后记录该连接的身份, 之后收到的 RpcSend/RpcResp 中的 from_host/from_addr
+//  以认证的身份为准; 认证之前收到的其他协议一律拒绝
+//  调用权限由 rpc_acl.<函数名> = <服务类型列表> 配置, rpc_acl.* 为未单独配置的函数的默认规则
+//  RpcResp 同样按函数名检查, 只有允许该函数的服务才能回复; DbResp 只接受 _db 权限中的 db 服务
+use crate::config::Config;
+use crate::message::{ProtoType, ServiceType};
+use crate::protos::{RpcAuth, RpcChallenge};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 32;
+const MAC_DOMAIN: &[u8] = b"cable2 rpc auth";
+const ACL_PREFIX: &str = "rpc_acl.";
+const ACL_DEFAULT: &str = "*";
+//会话迁移和网关的消息在 acl 中使用的名字
+pub const SESSION_FUNC: &str = "_session";
+//db 服务的请求和回复在 acl 中使用的名字
+pub const DB_FUNC: &str = "_db";
+
+//认证后的对端身份
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcPeer {
+    pub host_id: i32,
+    pub service_type: ServiceType,
+    pub addr: String,
+}
+
+//本端的身份和集群共享密钥
+#[derive(Clone)]
+pub struct RpcIdentity {
+    secret: Vec<u8>,
+    pub host_id: i32,
+    pub service_type: ServiceType,
+    pub addr: String,
+}
+
+impl RpcIdentity {
+    pub fn new(secret: &[u8], host_id: i32, service_type: ServiceType, addr: &str) -> Self {
+        RpcIdentity {
+            secret: secret.to_vec(),
+            host_id,
+            service_type,
+            addr: addr.to_owned(),
+        }
+    }
+
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        let secret = match conf.get_string("rpc_secret") {
+            Some(s) if !s.is_empty() => s,
+            _ => return Err("[rpc_auth]: config=rpc_secret is required".into()),
+        };
+        let host_id = conf.get_int("host_id").unwrap();
+        let service_type = conf.get_string("service_type").unwrap();
+        let addr = conf.get_string("rpc_service_addr").unwrap();
+        Ok(Self::new(
+            secret.as_bytes(),
+            host_id,
+            ServiceType::from(service_type.as_str()),
+            addr,
+        ))
+    }
+
+    pub fn challenge(&self) -> RpcChallenge {
+        let mut nonce = vec![0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        RpcChallenge {
+            host_id: self.host_id,
+            nonce,
+        }
+    }
+
+    //客户端回复服务端的 challenge
+    pub fn respond(&self, challenge: &RpcChallenge) -> RpcAuth {
+        let service_type: String = self.service_type.into();
+        let mut auth = RpcAuth {
+            host_id: self.host_id,
+            service_type,
+            addr: self.addr.clone(),
+            mac: vec![],
+        };
+        auth.mac = self
+            .mac(&challenge.nonce, challenge.host_id, &auth)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        auth
+    }
+
+    //服务端校验客户端的回复, nonce 为本端发出的 challenge 中的 nonce
+    pub fn verify(&self, nonce: &[u8], auth: &RpcAuth) -> crate::Result<RpcPeer> {
+        //比较时间与 mac 内容无关
+        if self
+            .mac(nonce, self.host_id, auth)
+            .verify_slice(&auth.mac)
+            .is_err()
+        {
+            return Err(format!("[rpc_auth]: wrong_mac=true,host_id={}", auth.host_id).into());
+        }
+        let service_type = ServiceType::from(auth.service_type.as_str());
+        if service_type == ServiceType::UNKNOW {
+            return Err(format!(
+                "[rpc_auth]: unknow_service_type={},host_id={}",
+                auth.service_type, auth.host_id
+            )
+            .into());
+        }
+        Ok(RpcPeer {
+            host_id: auth.host_id,
+            service_type,
+            addr: auth.addr.clone(),
+        })
+    }
+
+    //每个字段带上长度, 避免字段拼接产生歧义
+    fn mac(&self, nonce: &[u8], server_host: i32, auth: &RpcAuth) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("hmac accepts keys of any length");
+        mac.update(MAC_DOMAIN);
+        for field in [
+            nonce,
+            &server_host.to_le_bytes(),
+            &auth.host_id.to_le_bytes(),
+            auth.service_type.as_bytes(),
+            auth.addr.as_bytes(),
+        ] {
+            mac.update(&(field.len() as u32).to_le_bytes());
+            mac.update(field);
+        }
+        mac
+    }
+}
+
+//按函数名配置的调用权限, 没有配置的函数使用默认规则, 都没有配置时拒绝
+#[derive(Debug, Default, Clone)]
+pub struct RpcAcl {
+    rules: HashMap<String, Vec<ServiceType>>,
+}
+
+impl RpcAcl {
+    pub fn new() -> Self {
+        RpcAcl::default()
+    }
+
+    //允许 service_types 调用 func, func 为 * 时是默认规则
+    pub fn allow(mut self, func: &str, service_types: &[ServiceType]) -> Self {
+        self.rules
+            .entry(func.to_owned())
+            .or_default()
+            .extend_from_slice(service_types);
+        self
+    }
+
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        let mut acl = RpcAcl::new();
+        for (func, v) in conf.get_prefixed(ACL_PREFIX) {
+            let mut service_types = vec![];
+            for s in v.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                let service_type = ServiceType::from(s);
+                if service_type == ServiceType::UNKNOW {
+                    return Err(format!("[rpc_acl]: func={func},unknow_service_type={s}").into());
+                }
+                service_types.push(service_type);
+            }
+            acl = acl.allow(func, &service_types);
+        }
+        Ok(acl)
+    }
+
+    pub fn check(&self, func: &str, service_type: ServiceType) -> bool {
+        match self.rules.get(func).or_else(|| self.rules.get(ACL_DEFAULT)) {
+            Some(service_types) => service_types.contains(&service_type),
+            None => false,
+        }
+    }
+}
+
+//rpc 服务端每个连接的认证状态
+pub struct RpcGuard {
+    identity: RpcIdentity,
+    acl: RpcAcl,
+    pending: HashMap<u64, Vec<u8>>,
+    peers: HashMap<u64, RpcPeer>,
+}
+
+impl RpcGuard {
+    pub fn new(identity: RpcIdentity, acl: RpcAcl) -> Self {
+        RpcGuard {
+            identity,
+            acl,
+            pending: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(conf: &Config) -> crate::Result<Self> {
+        Ok(Self::new(
+            RpcIdentity::from_config(conf)?,
+            RpcAcl::from_config(conf)?,
+        ))
+    }
+
+    //新连接, 返回需要发给对端的 challenge
+    pub fn connect(&mut self, vfd: u64) -> RpcChallenge {
+        let challenge = self.identity.challenge();
+        self.peers.remove(&vfd);
+        self.pending.insert(vfd, challenge.nonce.clone());
+        challenge
+    }
+
+    pub fn close(&mut self, vfd: u64) {
+        self.pending.remove(&vfd);
+        self.peers.remove(&vfd);
+    }
+
+    pub fn peer(&self, vfd: u64) -> Option<&RpcPeer> {
+        self.peers.get(&vfd)
+    }
+
+    //检查连接上收到的协议:
+    //  RpcAuth 完成认证, 返回 None;
+    //  RpcSend/RpcResp 替换为认证的身份后返回;
+    //  返回错误时调用方应该断开该连接
+    pub fn check(&mut self, vfd: u64, pto: ProtoType) -> crate::Result<Option<ProtoType>> {
+        if let ProtoType::RpcAuth(auth) = pto {
+            let Some(nonce) = self.pending.remove(&vfd) else {
+                return Err(format!("[rpc_guard]: unexpected_auth=true,vfd={vfd}").into());
+            };
+            let peer = self.identity.verify(&nonce, &auth)?;
+            self.peers.insert(vfd, peer);
+            return Ok(None);
+        }
+        let Some(peer) = self.peers.get(&vfd) else {
+            let (proto_id, _) = pto.inner_info();
+            return Err(
+                format!("[rpc_guard]: unauthenticated=true,vfd={vfd},proto_id={proto_id}").into(),
+            );
+        };
+        match pto {
+            ProtoType::RpcSend(mut p) => {
+                if !self.acl.check(&p.func, peer.service_type) {
+                    return Err(format!(
+                        "[rpc_guard]: denied=true,vfd={vfd},host_id={},func={}",
+                        peer.host_id, p.func
+                    )
+                    .into());
+                }
+                p.from_host = peer.host_id;
+                p.from_addr = peer.addr.clone();
+                Ok(Some(ProtoType::RpcSend(p)))
+            }
+            //回复同样按函数名检查权限, 只有允许提供该函数的服务才能回复
+            ProtoType::RpcResp(mut p) => {
+                if !self.acl.check(&p.func, peer.service_type) {
+                    return Err(format!(
+                        "[rpc_guard]: denied=true,vfd={vfd},host_id={},func={}",
+                        peer.host_id, p.func
+                    )
+                    .into());
+                }
+                p.from_host = peer.host_id;
+                p.from_addr = peer.addr.clone();
+                Ok(Some(ProtoType::RpcResp(p)))
+            }
+            //会话迁移和网关的消息按 SESSION_FUNC 检查权限, 来源以认证结果为准
+            ProtoType::SessionMigrate(_)
+            | ProtoType::SessionAck(_)
+            | ProtoType::SessionRelay(_)
+            | ProtoType::GateOpen(_)
+                if !self.acl.check(SESSION_FUNC, peer.service_type) =>
+            {
+                Err(format!(
+                    "[rpc_guard]: denied=true,vfd={vfd},host_id={},func={SESSION_FUNC}",
+                    peer.host_id
+                )
+                .into())
+            }
+            ProtoType::SessionMigrate(mut p) => {
+                p.from_host = peer.host_id;
+                Ok(Some(ProtoType::SessionMigrate(p)))
+            }
+            ProtoType::SessionAck(mut p) => {
+                p.host = peer.host_id;
+                Ok(Some(ProtoType::SessionAck(p)))
+            }
+            ProtoType::SessionRelay(mut p) => {
+                p.host = peer.host_id;
+                Ok(Some(ProtoType::SessionRelay(p)))
+            }
+            ProtoType::GateOpen(mut p) => {
+                p.host = peer.host_id;
+                Ok(Some(ProtoType::GateOpen(p)))
+            }
+            //db 请求按 DB_FUNC 检查权限, 回复的对象以认证结果为准
+            ProtoType::DbReq(mut p) => {
+                if !self.acl.check(DB_FUNC, peer.service_type) {
+                    return Err(format!(
+                        "[rpc_guard]: denied=true,vfd={vfd},host_id={},func={DB_FUNC}",
+                        peer.host_id
+                    )
+                    .into());
+                }
+                p.host = peer.host_id;
+                Ok(Some(ProtoType::DbReq(p)))
+            }
+            //db 回复只接受 db 服务, 且该服务要在 DB_FUNC 的权限中
+            ProtoType::DbResp(p) => {
+                if peer.service_type != ServiceType::DB
+                    || !self.acl.check(DB_FUNC, peer.service_type)
+                {
+                    return Err(format!(
+                        "[rpc_guard]: denied=true,vfd={vfd},host_id={},func={DB_FUNC}",
+                        peer.host_id
+                    )
+                    .into());
+                }
+                Ok(Some(ProtoType::DbResp(p)))
+            }
+            other => {
+                let (proto_id, _) = other.inner_info();
+                Err(format!("[rpc_guard]: unexpected_proto={proto_id},vfd={vfd}").into())
+            }
+        }
+    }
+}
@@ -3,7 +3,14 @@ pub mod read;
 pub mod service;
 pub mod write;
 
+use tokio::io::{AsyncRead, AsyncWrite};
+
+//连接的读写两端, 明文 tcp 和 tls 共用同一套读写逻辑
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
 //一个完整的自定义消息的头部,包括: 协议id(u32) + 协议包总长度(u32), 8个字节
+//压缩的帧使用 v1 帧头, 复用协议id和长度的最高字节作为版本和 flags, 见 frame.rs
 pub const PROTO_HEADER_LEN: usize = 8;
 //定义一个消息体长度上限为 10 mb, 包括消息头长度
 pub const PROTO_BODY_MAX_LEN: usize = 10 * 1024 * 1024 - PROTO_HEADER_LEN;
@@ -12,7 +12,8 @@ impl Service {
         );
         match TcpStream::connect(addr).await {
             Ok(stream) => {
-                self.handle_stream(stream, idenfity).await?;
+                let stream = self.tls_connect(addr, stream).await?;
+                self.handle_stream(stream, idenfity, None).await?;
                 Ok(())
             }
             Err(err) => {
@@ -1,30 +1,52 @@
-use super::{PROTO_BODY_MAX_LEN, PROTO_HEADER_LEN};
+use super::ReadStream;
 use crate::logger::{build_logger, Outter};
-use crate::message::{MessageType, SMSender, ServiceType, SystemMsg};
-use crate::{debug, error, info, protos};
+use crate::message::{MessageType, Packet, SMSender, ServiceType, SystemMsg};
+use crate::metrics;
+use crate::network::compress::Compression;
+use crate::network::credit::{Credit, CreditGrant};
+use crate::network::crypto::{Opener, ReadHandshake};
+use crate::network::frame::Frame;
+use crate::network::limit::{IpPermit, LimitAction, RateLimiter};
+use crate::protos::{C2sKick, ProtoMessage, ProtoType, S2cLogin};
+use crate::{debug, error, info};
 use bytes::BytesMut;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::io::AsyncReadExt;
 use tokio::sync::{
     broadcast,
     mpsc::{self, error::TrySendError},
-    Semaphore,
+    oneshot, Semaphore,
 };
+use tokio::time;
 
 const LOG_NAME: &str = "tcp_reader.log";
+//读缓冲的初始大小, 缓冲在连接的生命周期内复用
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+//连接关闭的原因, 用于统计
+const PEER_CLOSED: &str = "[read_frame]: connection reset by peer";
+const LIMIT_DISCONNECT: &str = "[ConnReader]: limit=disconnect";
 
 pub struct ConnReader {
     service_type: ServiceType,
     vfd: u64, //每个连接分配一个虚拟的唯一的fd
-    stream: BufReader<OwnedReadHalf>,
+    stream: ReadStream,
+    buffer: BytesMut,
+    compression: Compression,
+    handshake: Option<ReadHandshake>,
+    opener: Option<Opener>,
+    limiter: Option<RateLimiter>,
+    kick: Option<oneshot::Sender<ProtoType>>, //断开连接前通过写端通知客户端原因
+    _ip_permit: Option<IpPermit>,             // 对象销毁时归还 ip 的连接计数
+    credit_grant: Option<CreditGrant>,        // rpc 服务端: 交付消息后归还额度
+    credit_gate: Option<Credit>,              // rpc 客户端: 收到的额度交给写端
+    passthrough: bool,                        // 网关: 除登录协议外不解码, 原样转发
     limit_connections: Arc<Semaphore>,
     readnum: u64,
     log: Outter,
     proto_sender: SMSender,
     _shutdown_complete: mpsc::Sender<()>, // 对象销毁时自动销毁
     service_notify: Option<broadcast::Receiver<()>>,
-    _pairdrop_sender: mpsc::Sender<()>, // 对象销毁时自动销毁
+    pairdrop_sender: mpsc::Sender<()>, // 对象销毁时自动销毁; 写端先结束时读端随之结束
 }
 
 impl Drop for ConnReader {
@@ -34,123 +56,261 @@ impl Drop for ConnReader {
 }
 
 impl ConnReader {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         service_type: ServiceType,
         vfd: u64,
-        stream: OwnedReadHalf,
+        stream: ReadStream,
         limit_connections: Arc<Semaphore>,
         proto_sender: SMSender,
         _shutdown_complete: mpsc::Sender<()>,
         service_notify: broadcast::Receiver<()>,
         _pairdrop_sender: mpsc::Sender<()>,
     ) -> ConnReader {
-        let log: Outter = build_logger(LOG_NAME);
+        let log: Outter = build_logger(LOG_NAME).with_vfd(vfd);
         ConnReader {
             service_type,
             vfd,
-            stream: BufReader::new(stream),
+            stream,
+            buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            compression: Compression::default(),
+            handshake: None,
+            opener: None,
+            limiter: None,
+            kick: None,
+            _ip_permit: None,
+            credit_grant: None,
+            credit_gate: None,
+            passthrough: false,
             limit_connections,
             readnum: 0,
             log,
             proto_sender,
             _shutdown_complete,
             service_notify: Some(service_notify),
-            _pairdrop_sender,
+            pairdrop_sender: _pairdrop_sender,
         }
     }
 
+    //与写端共享的压缩状态, 读到登录协议时完成协商
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    //开启加密, 开始读之前先完成握手
+    pub fn with_handshake(mut self, handshake: ReadHandshake) -> Self {
+        self.handshake = Some(handshake);
+        self
+    }
+
+    //开启限流, kick 用于 disconnect 时把原因交给写端发出
+    pub fn with_limiter(mut self, limiter: RateLimiter, kick: oneshot::Sender<ProtoType>) -> Self {
+        self.limiter = Some(limiter);
+        self.kick = Some(kick);
+        self
+    }
+
+    //网关只转发编码好的帧; 登录协议仍然解码, 用于协商压缩
+    pub fn with_passthrough(mut self) -> Self {
+        self.passthrough = true;
+        self
+    }
+
+    pub fn with_ip_permit(mut self, permit: IpPermit) -> Self {
+        self._ip_permit = Some(permit);
+        self
+    }
+
+    //rpc 服务端开启流控, 消息不再丢弃, hub 处理不过来时等待
+    pub fn with_credit_grant(mut self, grant: CreditGrant) -> Self {
+        self.credit_grant = Some(grant);
+        self
+    }
+
+    //rpc 客户端读到的 RpcCredit 不交给 hub, 直接加到写端的额度上
+    pub fn with_credit_gate(mut self, credit: Credit) -> Self {
+        self.credit_gate = Some(credit);
+        self
+    }
+
     pub async fn run(&mut self) -> crate::Result<()> {
         let mut service_notify = self.service_notify.take().unwrap();
-        loop {
+        let writer = self.pairdrop_sender.clone();
+        if let Some(handshake) = self.handshake.take() {
+            match handshake.run(&mut self.stream, &mut self.buffer).await {
+                Ok(opener) => self.opener = Some(opener),
+                Err(err) => {
+                    metrics::conn_closed(self.service_type, "handshake");
+                    return Err(err);
+                }
+            }
+            debug!(self.log, "[ConnReader]: handshake=done,vfd={}", self.vfd);
+        }
+        let reason = loop {
             tokio::select! {
                 res = self.read_frame() => {
                     match res {
+                        Ok((_, _, Packet::Proto(ProtoType::RpcCredit(credit)))) if self.credit_gate.is_some() => {
+                            if let Some(gate) = self.credit_gate.as_ref() {
+                                gate.grant(credit.credits as u64);
+                            }
+                        },
                         Ok(pto) => {
                             // 注意, 如果这里使用 send 发送会产生阻塞,而对端的消息处理完毕后也可能会有消息返回也是通过 send.
                             // 如果这边的 send 出现阻塞, 对端返回的 send 也同样出现阻塞, 这时候会导致两端的协程产生 deadlock.
-                            // 解决的办法有 1) send 一个 oneshot 或者 2) 用 try_send 代替 send;
-                            // 用 1) 的弊端是必须在 send 的一端等待消息返回. 而用 try_send 的弊端则是发送失败时,只能选择丢弃消息
-                            // 这里选择 2), 因为这样做更符合背压原理, 对于游戏玩家的请求, 处理不过来就丢弃这也是合理的;
-                            // 但对于 rpc 的服务类型,如果确保不了发送消息的成功就会出现麻烦事.
-                            // :TODO: 对于 rpc 的发送, 后续是通过 spawn 一个协程来发送呢,还是有其他更好的办法.
-                            // 这里暂时的做法是把未发送成功的协议记录下来,通过日志的错误提示,再寻求扩大队列还是其他更好的办法.
-
-                            //self.proto_sender.send(pto).await?; // would block
-                            if let Err(err) = self.proto_sender.try_send(pto) {
+                            // 游戏连接用 try_send, 处理不过来就丢弃, 这符合背压原理.
+                            // rpc 连接开启流控后用 send 等待: hub 发给连接只用 try_send, 从不等待连接, 所以 hub 总能清空通道;
+                            // 对端在途的消息不超过额度窗口, 交付之后才归还额度, 所以等待不会让消息无限堆积. 见 network::credit
+                            if let Some(grant) = self.credit_grant.as_mut() {
+                                tokio::select! {
+                                    res = self.proto_sender.send(pto) => {
+                                        if res.is_err() {
+                                            error!(self.log,"[ConnReader]: proto_sender=close, vfd={}",self.vfd);
+                                            break "hub_closed";
+                                        }
+                                        grant.delivered();
+                                    },
+                                    _ = service_notify.recv() => {
+                                        info!(self.log,"[ConnReader]: notify_close=true,vfd={}",self.vfd);
+                                        break "shutdown";
+                                    },
+                                }
+                            } else if let Err(err) = self.proto_sender.try_send(pto) {
                                 match err {
                                     TrySendError::Full(err) => {
+                                        metrics::send_failed("conn_reader", "full");
                                         error!(self.log,"[ConnReader]: send=failed, msgtype={:?},vfd={}",err.0,err.1);
                                     },
                                     TrySendError::Closed(_err) =>{
+                                        metrics::send_failed("conn_reader", "closed");
                                         error!(self.log,"[ConnReader]: proto_sender=close, vfd={}",self.vfd);
-                                        break;
+                                        break "hub_closed";
                                     }
                                 }
                             }
                         },
                         Err(err) => {
                             info!(self.log,"[ConnReader]: closed=true,vfd={},err={}",self.vfd,err);
-                            break;
+                            break close_reason(&err);
                         }
                     }
                 }
                 _ = service_notify.recv() => {
                     info!(self.log,"[ConnReader]: notify_close=true,vfd={}",self.vfd);
-                    break;
+                    break "shutdown";
+                },
+                _ = writer.closed() => {
+                    info!(self.log,"[ConnReader]: writer=closed,vfd={}",self.vfd);
+                    break "writer_closed";
                 },
             };
-        }
+        };
+        metrics::conn_closed(self.service_type, reason);
         Ok(())
     }
 
+    //读一个完整的帧, 取消时已读到的数据保留在读缓冲中, 可以安全地用在 select! 中
+    //限流为 delay 时会在这里等待, 这时取消会丢弃正在等待的帧
     pub async fn read_frame(&mut self) -> crate::Result<SystemMsg> {
-        //读消息头
-        let mut header = vec![0; PROTO_HEADER_LEN];
-        let _rsize = self.stream.read_exact(&mut header).await?;
-        //println!("rsize1 = {}", _rsize);
-        let mut proto_id = 0u32;
-        proto_id |= header[0] as u32 & 0xff;
-        proto_id |= (header[1] as u32 & 0xff) << 8;
-        proto_id |= (header[2] as u32 & 0xff) << 16;
-        proto_id |= (header[3] as u32 & 0xff) << 24;
-
-        let mut body_len = 0u32;
-        body_len |= header[4] as u32 & 0xff;
-        body_len |= (header[5] as u32 & 0xff) << 8;
-        body_len |= (header[6] as u32 & 0xff) << 16;
-        body_len |= (header[7] as u32 & 0xff) << 24;
-
-        //协议长度超出最大上限
-        if body_len >= PROTO_BODY_MAX_LEN as u32 {
-            return Err(format!("[parse_fram]: exceed=PROTO_BODY_MAX_LEN,{}", body_len).into());
+        loop {
+            let frame = match self.opener.as_mut() {
+                Some(opener) => match opener.open(&mut self.buffer)? {
+                    Some(record) => Some(Frame::parse_exact(record)?),
+                    None => None,
+                },
+                None => Frame::parse(&mut self.buffer)?,
+            };
+            if let Some(frame) = frame {
+                metrics::frame_in(self.service_type, frame.proto_id, frame.body.len());
+                if !self.limit_frame(&frame).await? {
+                    continue;
+                }
+                return self.decode_frame(frame);
+            }
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                return Err(PEER_CLOSED.into());
+            }
         }
+    }
 
-        //println!("[read_frame]: body_len={},proto_id={}", body_len, proto_id);
-        //读消息体
-        let mut body = vec![0; body_len as usize];
-        let _rsize = self.stream.read_exact(&mut body).await?;
-        //println!("rsize2 = {}", _rsize);
-
-        //解码
-        match protos::decode(proto_id, &body) {
-            Ok(ptoobj) => {
-                self.readnum += 1;
+    //在解码之前检查限流, 返回 false 表示丢弃该帧, 返回错误表示断开连接
+    async fn limit_frame(&mut self, frame: &Frame) -> crate::Result<bool> {
+        let Some(limiter) = self.limiter.as_mut() else {
+            return Ok(true);
+        };
+        let len = frame.body.len();
+        let Some(exceed) = limiter.check(frame.proto_id, len) else {
+            return Ok(true);
+        };
+        match limiter.action() {
+            LimitAction::Drop => {
                 debug!(
                     self.log,
-                    "[read_frame]: proto_id={},buflen={},readnum={}",
-                    proto_id,
-                    body_len,
-                    self.readnum,
+                    "[ConnReader]: limit=drop,vfd={},proto_id={},reason={}",
+                    self.vfd,
+                    frame.proto_id,
+                    exceed.reason
                 );
-                let msg_type = match self.service_type {
-                    ServiceType::TCP => MessageType::Tcp,
-                    ServiceType::RPC => MessageType::Rpc,
-                    ServiceType::RPCCLIENT => MessageType::RpcClient,
-                    _ => MessageType::Dummy,
-                };
-                Ok((msg_type, self.vfd, ptoobj))
+                Ok(false)
+            }
+            LimitAction::Delay => {
+                //暂停读取, 直到所有令牌桶都足够
+                let mut wait = exceed.wait;
+                loop {
+                    time::sleep(wait).await;
+                    match limiter.check(frame.proto_id, len) {
+                        Some(exceed) => wait = exceed.wait,
+                        None => return Ok(true),
+                    }
+                }
             }
-            Err(err) => Err(err.into()),
+            LimitAction::Disconnect => {
+                let reason = format!("{},proto_id={}", exceed.reason, frame.proto_id);
+                if let Some(kick) = self.kick.take() {
+                    let _ = kick.send(ProtoType::C2sKick(C2sKick {
+                        reason: reason.clone(),
+                    }));
+                }
+                Err(format!("{LIMIT_DISCONNECT},reason={reason}").into())
+            }
+        }
+    }
+
+    fn decode_frame(&mut self, frame: Frame) -> crate::Result<SystemMsg> {
+        if self.passthrough && frame.proto_id != S2cLogin::PROTO_ID {
+            self.readnum += 1;
+            return Ok((self.msg_type(), self.vfd, Packet::Encoded(frame)));
+        }
+        //解码
+        let ptoobj = frame.decode()?;
+        self.compression.observe(&ptoobj);
+        self.readnum += 1;
+        debug!(
+            self.log,
+            "[read_frame]: proto_id={},buflen={},readnum={}",
+            frame.proto_id,
+            frame.body.len(),
+            self.readnum,
+        );
+        Ok((self.msg_type(), self.vfd, Packet::Proto(ptoobj)))
+    }
+
+    fn msg_type(&self) -> MessageType {
+        match self.service_type {
+            ServiceType::TCP => MessageType::Tcp,
+            ServiceType::RPC => MessageType::Rpc,
+            ServiceType::RPCCLIENT => MessageType::RpcClient,
+            _ => MessageType::Dummy,
         }
     }
 }
+
+fn close_reason(err: &crate::Error) -> &'static str {
+    match err {
+        crate::Error::Message(msg) if msg == PEER_CLOSED => "peer",
+        crate::Error::Message(msg) if msg.starts_with(LIMIT_DISCONNECT) => "limit",
+        crate::Error::IoError(_) => "io",
+        _ => "error",
+    }
+}
@@ -1,16 +1,25 @@
 use crate::logger::Outter;
+use crate::metrics;
 use crate::protos::{Dummy, ProtoType};
 use crate::{config::Config, error::Error};
 use crate::{error, info};
 use std::sync::Arc;
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::{broadcast, mpsc, Semaphore},
+    sync::{broadcast, mpsc, oneshot, Semaphore},
     time::{self, Duration},
 };
 
 use super::{read::ConnReader, write::ConnWriter};
-use crate::message::{MessageType, SMSender, SMSenderChan, ServiceType};
+use crate::message::{MessageType, Packet, SMSender, SMSenderChan, ServiceType};
+use crate::network::compress::{CompressConf, Compression};
+use crate::network::credit::{Credit, CreditGrant};
+use crate::network::crypto::{self, Identity};
+use crate::network::limit::{IpLimit, IpPermit, LimitConf, RateLimiter};
+use crate::network::tcp::{ReadStream, WriteStream};
+use crate::network::tls::{self, TlsAcceptor, TlsConnector};
+use tokio::io;
+use tokio_rustls::TlsStream;
 
 pub struct Service {
     pub service_type: ServiceType,
@@ -25,6 +34,45 @@ pub struct Service {
     pub shutdown_complete_receiver: mpsc::Receiver<()>,
     pub chan_sender: SMSenderChan, //vfd 暴露出来的私有 chan 传递给外面，外面有信息传入给对应的 vfd 时，通过这个 chan 传入
     pub msg_sender: SMSender,      //vfd 从网络读取消息时发送到外面处理
+    pub compress_conf: Arc<CompressConf>, //帧压缩配置, 每个连接在登录时协商
+    pub encrypt: Option<Identity>, //游戏连接在建立后先握手, 再加密所有帧; 握手中本端的身份
+    pub is_gate: bool,             //网关的游戏连接, 收到的帧不解码, 原样转发给后端
+    pub limit_conf: Arc<LimitConf>, //游戏连接的限流配置
+    pub ip_limit: IpLimit, //同一个 ip 的连接数限制, 与 limit_connections 一起在 accept 时检查
+    pub tls_acceptor: Option<TlsAcceptor>, //监听端的 tls, 在 run 时按配置创建
+    pub tls_connector: Option<TlsConnector>, //连接端的 tls, 在第一次连接时按配置创建
+}
+
+//tls 握手的超时时间, 握手在每个连接自己的任务中进行, 超时后关闭连接
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+//完成 tls 握手, 等待分配 vfd 的连接队列大小
+const TLS_HANDSHAKED_CHAN_SIZE: usize = 64;
+
+//一个已经建立的连接, 明文或者 tls
+pub enum ConnStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl ConnStream {
+    pub fn into_split(self) -> (ReadStream, WriteStream) {
+        match self {
+            ConnStream::Plain(stream) => {
+                let (r, w) = stream.into_split();
+                (Box::new(r), Box::new(w))
+            }
+            ConnStream::Tls(stream) => {
+                let (r, w) = io::split(stream);
+                (Box::new(r), Box::new(w))
+            }
+        }
+    }
+}
+
+impl From<TcpStream> for ConnStream {
+    fn from(stream: TcpStream) -> Self {
+        ConnStream::Plain(stream)
+    }
 }
 
 pub fn build(
@@ -42,7 +90,7 @@ impl Service {
     pub fn new(
         service_type: ServiceType,
         conf: Config,
-        log: Outter,
+        mut log: Outter,
         addr: String,
         msg_sender: SMSender,
         chan_sender: SMSenderChan,
@@ -51,6 +99,26 @@ impl Service {
         let (shutdown_complete_sender, shutdown_complete_receiver) = mpsc::channel(1);
 
         let max_connection = conf.get_int("max_connection").unwrap() as usize;
+        let compress_conf = CompressConf::from_config(&conf).unwrap();
+        //只对游戏连接生效, rpc 连接不加密
+        let encrypt = match service_type == ServiceType::TCP && conf.get_bool("is_encrypt") {
+            true => Some(Identity::from_config(&conf).unwrap()),
+            false => None,
+        };
+        //客户端需要配置服务端的静态公钥
+        if let Some(identity @ Identity::Server(_)) = encrypt.as_ref() {
+            let key = crypto::key_hex(&identity.server_key());
+            info!(log, "[tcp_service]: encrypt_server_key={}", key);
+        }
+        let is_gate = service_type == ServiceType::TCP
+            && conf.get_string("service_type").map(String::as_str) == Some("gate_service");
+        //限流只对游戏连接生效, rpc 连接来自集群内的服务器
+        let limit_conf = if service_type == ServiceType::TCP {
+            LimitConf::from_config(&conf).unwrap()
+        } else {
+            LimitConf::default()
+        };
+        let ip_limit = IpLimit::new(limit_conf.max_per_ip);
         Service {
             service_type,
             service_addr: addr,
@@ -64,6 +132,95 @@ impl Service {
             shutdown_complete_receiver,
             chan_sender,
             msg_sender,
+            compress_conf: Arc::new(compress_conf),
+            encrypt,
+            is_gate,
+            limit_conf: Arc::new(limit_conf),
+            ip_limit,
+            tls_acceptor: None,
+            tls_connector: None,
+        }
+    }
+
+    fn conf_path(&self, k: &str) -> crate::Result<&str> {
+        match self.conf.get_string(k) {
+            Some(v) => Ok(v.as_str()),
+            None => Err(format!("[tls]: config={k} is required").into()),
+        }
+    }
+
+    //游戏连接使用 tcp_tls(is_ssl 只用于 websocket); rpc 连接使用 rpc_tls, rpc_tls_mutual 时只接受持有集群 CA 签发证书的服务器
+    pub fn build_tls_acceptor(&self) -> crate::Result<Option<TlsAcceptor>> {
+        let acceptor = match self.service_type {
+            ServiceType::TCP if self.conf.get_bool("tcp_tls") => tls::build_acceptor(
+                self.conf_path("certificate_file")?,
+                self.conf_path("privatekey_file")?,
+                None,
+            )?,
+            ServiceType::RPC if self.conf.get_bool("rpc_tls") => {
+                let client_ca = if self.conf.get_bool("rpc_tls_mutual") {
+                    Some(self.conf_path("ca_file")?)
+                } else {
+                    None
+                };
+                tls::build_acceptor(
+                    self.conf_path("certificate_file")?,
+                    self.conf_path("privatekey_file")?,
+                    client_ca,
+                )?
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(acceptor))
+    }
+
+    //连接端用 ca_file 校验服务端证书; rpc 连接同时出示本端证书, 用于对端的双向 tls
+    pub fn build_tls_connector(&self) -> crate::Result<Option<TlsConnector>> {
+        let connector = match self.service_type {
+            ServiceType::TCP if self.conf.get_bool("tcp_tls") => {
+                tls::build_connector(self.conf_path("ca_file")?, None)?
+            }
+            ServiceType::RPCCLIENT if self.conf.get_bool("rpc_tls") => tls::build_connector(
+                self.conf_path("ca_file")?,
+                Some((
+                    self.conf_path("certificate_file")?,
+                    self.conf_path("privatekey_file")?,
+                )),
+            )?,
+            _ => return Ok(None),
+        };
+        Ok(Some(connector))
+    }
+
+    //握手在单独的任务中完成, 见 start_loop
+    pub async fn tls_accept(acceptor: TlsAcceptor, stream: TcpStream) -> crate::Result<ConnStream> {
+        match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => Ok(ConnStream::Tls(Box::new(stream.into()))),
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => Err("[tls_accept]: timeout=true".into()),
+        }
+    }
+
+    pub async fn tls_connect(
+        &mut self,
+        addr: &str,
+        stream: TcpStream,
+    ) -> crate::Result<ConnStream> {
+        if self.tls_connector.is_none() {
+            self.tls_connector = self.build_tls_connector()?;
+        }
+        let Some(connector) = self.tls_connector.as_ref() else {
+            return Ok(stream.into());
+        };
+        //游戏客户端可以用 tls_server_name 指定证书中的名字, 否则取连接地址中的主机
+        let name = match (self.service_type, self.conf.get_string("tls_server_name")) {
+            (ServiceType::TCP, Some(name)) => tls::server_name(name)?,
+            _ => tls::server_name(addr)?,
+        };
+        match time::timeout(TLS_HANDSHAKE_TIMEOUT, connector.connect(name, stream)).await {
+            Ok(Ok(stream)) => Ok(ConnStream::Tls(Box::new(stream.into()))),
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => Err("[tls_connect]: timeout=true".into()),
         }
     }
 
@@ -79,6 +236,7 @@ impl Service {
     }
 
     pub async fn run(mut self) -> crate::Result<()> {
+        self.tls_acceptor = self.build_tls_acceptor()?;
         self.init_listener().await?;
         self.start_loop().await?;
 
@@ -97,10 +255,49 @@ impl Service {
     }
 
     async fn start_loop(&mut self) -> crate::Result<()> {
+        //tls 握手可能要等待客户端很久, 每个连接在自己的任务中握手, 完成后再交回这里分配 vfd
+        let (handshaked_tx, mut handshaked_rx) = mpsc::channel(TLS_HANDSHAKED_CHAN_SIZE);
         loop {
-            let stream = self.accept().await?;
+            let (stream, ip_permit) = tokio::select! {
+                res = self.accept() => {
+                    let stream = res?;
+                    let ip_permit = match stream.peer_addr() {
+                        Ok(addr) => match self.ip_limit.acquire(addr.ip()) {
+                            Some(permit) => permit,
+                            None => {
+                                info!(self.log, "[start_loop]: ip_limit=refuse,addr={}", addr);
+                                continue;
+                            }
+                        },
+                        Err(err) => {
+                            error!(self.log, "[start_loop]: peer_addr=failed,err={}", err);
+                            continue;
+                        }
+                    };
+                    let Some(acceptor) = self.tls_acceptor.clone() else {
+                        let stream = ConnStream::from(stream);
+                        let vfd = self.inc_counter();
+                        self.handle_stream(stream, vfd, Some(ip_permit)).await?;
+                        continue;
+                    };
+                    let handshaked_tx = handshaked_tx.clone();
+                    let mut log = self.log.clone();
+                    tokio::spawn(async move {
+                        match Self::tls_accept(acceptor, stream).await {
+                            Ok(stream) => {
+                                let _ = handshaked_tx.send((stream, ip_permit)).await;
+                            }
+                            Err(err) => {
+                                error!(log, "[start_loop]: tls_accept=failed,err={}", err);
+                            }
+                        }
+                    });
+                    continue;
+                },
+                Some(handshaked) = handshaked_rx.recv() => handshaked,
+            };
             let vfd = self.inc_counter();
-            self.handle_stream(stream, vfd).await?;
+            self.handle_stream(stream, vfd, Some(ip_permit)).await?;
         }
     }
 
@@ -108,7 +305,10 @@ impl Service {
         let mut backoff = 1;
         loop {
             match self.listener.as_mut().unwrap().accept().await {
-                Ok((socket, _)) => return Ok(socket),
+                Ok((socket, _)) => {
+                    metrics::conn_accepted(self.service_type);
+                    return Ok(socket);
+                }
                 Err(err) => {
                     if backoff > 64 {
                         return Err(err.into());
@@ -123,13 +323,14 @@ impl Service {
 
     pub fn split_stream(
         &mut self,
-        stream: TcpStream,
+        stream: ConnStream,
         identify: u64,
     ) -> (ConnReader, ConnWriter, SMSender) {
         let vfd = identify;
         let (pairdrop_sender, pairdrop_receiver) = mpsc::channel(1);
         let (read_stream, write_stream) = stream.into_split();
-        let reader = ConnReader::new(
+        let compression = Compression::new(self.compress_conf.clone());
+        let mut reader = ConnReader::new(
             self.service_type,
             vfd,
             read_stream,
@@ -138,26 +339,79 @@ impl Service {
             self.shutdown_complete_sender.clone(),
             self.notify_client_shutdown.subscribe(),
             pairdrop_sender,
-        );
+        )
+        .with_compression(compression.clone());
 
         // 根据服务类型决定 channel 队列大小
         let conn_msg_chan_size = self.conf.get_int("conn_msg_chan_size").unwrap() as usize;
         let (conn_tx, conn_rx) = mpsc::channel(conn_msg_chan_size);
-        let writer = ConnWriter::new(
+        let mut writer = ConnWriter::new(
             self.service_type,
             vfd,
             write_stream,
             conn_rx,
             pairdrop_receiver,
-        );
+        )
+        .with_compression(compression);
+
+        //配置可以重新加载时, 游戏连接都带上限流, 之后开启的限流也能生效
+        let live = match self.service_type {
+            ServiceType::TCP => self.conf.watch(),
+            _ => None,
+        };
+        if self.limit_conf.is_limited() || live.is_some() {
+            let (kick_tx, kick_rx) = oneshot::channel();
+            let mut limiter = RateLimiter::new(self.limit_conf.clone());
+            if let Some(live) = live {
+                limiter = limiter.with_watch(live);
+            }
+            reader = reader.with_limiter(limiter, kick_tx);
+            writer = writer.with_kick(kick_rx);
+        }
+
+        //rpc 连接的流控: 服务端按窗口给出额度, 客户端收到额度后才开始受限
+        match self.service_type {
+            ServiceType::RPC => {
+                let window = self.conf.get_int("rpc_credit_window").unwrap_or(0);
+                if window > 0 {
+                    let (grant, credit) = CreditGrant::new(window as u64);
+                    reader = reader.with_credit_grant(grant);
+                    writer = writer.with_credit_grant(credit);
+                }
+            }
+            ServiceType::RPCCLIENT => {
+                let credit = Credit::new();
+                reader = reader.with_credit_gate(credit.clone());
+                writer = writer.with_credit_gate(credit);
+            }
+            _ => {}
+        }
+
+        if self.is_gate {
+            reader = reader.with_passthrough();
+        }
+
+        if let Some(identity) = self.encrypt.as_ref() {
+            let (read_handshake, write_handshake) = crypto::handshake(identity);
+            reader = reader.with_handshake(read_handshake);
+            writer = writer.with_handshake(write_handshake);
+        }
 
         (reader, writer, conn_tx)
     }
 
-    pub async fn handle_stream(&mut self, stream: TcpStream, identify: u64) -> crate::Result<()> {
+    pub async fn handle_stream(
+        &mut self,
+        stream: ConnStream,
+        identify: u64,
+        ip_permit: Option<IpPermit>,
+    ) -> crate::Result<()> {
         // 给下一个新连接分配一个自增的唯一id
         let vfd = identify;
-        let (reader, writer, conn_tx) = self.split_stream(stream, vfd);
+        let (mut reader, writer, conn_tx) = self.split_stream(stream, vfd);
+        if let Some(permit) = ip_permit {
+            reader = reader.with_ip_permit(permit);
+        }
         // 在 reader 被 drop 时归还计数
         self.limit_connections.acquire().await.unwrap().forget();
 
@@ -196,7 +450,11 @@ impl Service {
             }
             let dummy = Dummy::default();
             let _ = close_notify
-                .send((MessageType::SocketClosed, vfd, ProtoType::Dummy(dummy)))
+                .send((
+                    MessageType::SocketClosed,
+                    vfd,
+                    Packet::Proto(ProtoType::Dummy(dummy)),
+                ))
                 .await;
         });
         Ok(())
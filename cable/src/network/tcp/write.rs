@@ -1,17 +1,34 @@
+use super::WriteStream;
 use crate::logger::{build_logger, Outter};
-use crate::message::{MessageType, SMReceiver, ServiceType};
-use crate::{debug, error, info, protos};
+use crate::message::{MessageType, Packet, SMReceiver, ServiceType, SystemMsg};
+use crate::metrics;
+use crate::network::compress::Compression;
+use crate::network::credit::Credit;
+use crate::network::crypto::{Sealer, WriteHandshake};
+use crate::network::frame::{Frame, FrameBatch};
+use crate::protos::{C2sKick, ProtoMessage, ProtoType, RpcCredit};
+use crate::{debug, error, info};
+use std::future;
 use std::io;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::sync::mpsc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
 
 const LOG_NAME: &str = "tcp_writer.log";
+//每次唤醒最多合并写出的帧数量
+const WRITE_BATCH_MAX: usize = 256;
 
 pub struct ConnWriter {
     service_type: ServiceType,
     vfd: u64,
-    stream: BufWriter<OwnedWriteHalf>,
+    stream: WriteStream,
+    batch: FrameBatch,
+    compression: Compression,
+    handshake: Option<WriteHandshake>,
+    sealer: Option<Sealer>,
+    kick: Option<oneshot::Receiver<ProtoType>>,
+    credit_grant: Option<Credit>, // rpc 服务端: 读端归还的额度, 发给客户端
+    credit_gate: Option<Credit>,  // rpc 客户端: 额度用完时不再从发件队列取消息
+    closing: bool,                // 游戏连接: 写出 C2sKick 后关闭连接
     writenum: u64,
     log: Outter,
     msg_receiver: SMReceiver,
@@ -22,15 +39,23 @@ impl ConnWriter {
     pub fn new(
         service_type: ServiceType,
         vfd: u64,
-        stream: OwnedWriteHalf,
+        stream: WriteStream,
         msg_receiver: SMReceiver,
         pairdrop_receiver: mpsc::Receiver<()>,
     ) -> ConnWriter {
-        let log = build_logger(LOG_NAME);
+        let log = build_logger(LOG_NAME).with_vfd(vfd);
         ConnWriter {
             service_type,
             vfd,
-            stream: BufWriter::new(stream),
+            stream,
+            batch: FrameBatch::new(),
+            compression: Compression::default(),
+            handshake: None,
+            sealer: None,
+            kick: None,
+            credit_grant: None,
+            credit_gate: None,
+            closing: false,
             writenum: 0,
             log,
             msg_receiver,
@@ -38,59 +63,101 @@ impl ConnWriter {
         }
     }
 
+    //与读端共享的压缩状态, 协商成功后按协商的算法压缩
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    //开启加密, 握手完成前队列中的消息先不写出
+    pub fn with_handshake(mut self, handshake: WriteHandshake) -> Self {
+        self.handshake = Some(handshake);
+        self
+    }
+
+    //读端因限流断开连接时, 收到通知客户端的协议, 写出后结束
+    pub fn with_kick(mut self, kick: oneshot::Receiver<ProtoType>) -> Self {
+        self.kick = Some(kick);
+        self
+    }
+
+    pub fn with_credit_grant(mut self, credit: Credit) -> Self {
+        self.credit_grant = Some(credit);
+        self
+    }
+
+    pub fn with_credit_gate(mut self, credit: Credit) -> Self {
+        self.credit_gate = Some(credit);
+        self
+    }
+
     pub async fn run(&mut self) -> crate::Result<()> {
+        if let Some(handshake) = self.handshake.take() {
+            self.sealer = Some(handshake.run(&mut self.stream).await?);
+        }
         loop {
+            let quota = self.quota();
             tokio::select! {
-                res = self.msg_receiver.recv() => {
-                    if let Some((msg_type, from_vfd, pto)) = res {
-                        let mut proceed = false;
-                        if self.vfd != from_vfd {
-                            info!(
-                                self.log,
-                                "[ConnWriter]: wrong_vfd=true, vfd={}, from_vfd={}", self.vfd, from_vfd
-                            );
-                        }
-                        let (proto_id,_) = pto.inner_info();
-                        match self.service_type {
-                            ServiceType::TCP => {
-                                if msg_type != MessageType::Tcp {
-                                    info!(
-                                        self.log,
-                                        "[ConnWriter]: wrong_msgType=true, service_type={:?},msg_type={:?} from_vfd={}", self.service_type,msg_type,from_vfd
-                                    );
-                                } else {
-                                    proceed = true;
-                                }
-                            },
-                            ServiceType::RPC | ServiceType::RPCCLIENT => {
-                                if msg_type == MessageType::Rpc || msg_type == MessageType::RpcClient {
-                                    proceed = true;
-                                } else {
-                                    info!(
-                                        self.log,
-                                        "[ConnWriter]: wrong_msgType=true, service_type={:?},msg_type={:?} from_vfd={}", self.service_type,msg_type,from_vfd
-                                    );
-                                }
-                            },
-                            _ => {
-                                info!(
-                                    self.log,
-                                    "[ConnWriter]: unknow_serviceType=true, from_vfd={}", from_vfd
-                                );
-                            }
-                        }
-                        if proceed {
-                            let buf = protos::encode(pto)?;
-                            if let Err(err) = self.write_frame(proto_id, &buf).await {
-                                error!(
-                                    self.log,
-                                    "[ConnWriter]: closed=true,vfd={},proto_id={},err={}", self.vfd, proto_id, err
-                                );
-                                break;
-                            }
+                //读端先发出通知再结束, 通知需要先于 readhalf=drop 处理
+                biased;
+                res = recv_kick(&mut self.kick) => {
+                    self.kick = None;
+                    let Ok(pto) = res else {
+                        continue;
+                    };
+                    info!(self.log, "[ConnWriter]: kick=true,vfd={},pto={:?}", self.vfd, pto);
+                    self.push_packet(pto.into())?;
+                    if let Err(err) = self.write_batch().await {
+                        error!(self.log, "[ConnWriter]: kick=failed,vfd={},err={}", self.vfd, err);
+                    }
+                    let _ = self.stream.shutdown().await;
+                    break;
+                },
+                res = wait_credit(&self.credit_grant) => {
+                    let Some(credits) = self.credit_grant.as_ref().map(|c| c.take(res)) else {
+                        continue;
+                    };
+                    let pto = ProtoType::RpcCredit(RpcCredit { credits: credits as u32 });
+                    self.push_packet(pto.into())?;
+                    if let Err(err) = self.write_batch().await {
+                        error!(self.log, "[ConnWriter]: closed=true,vfd={},err={}", self.vfd, err);
+                        break;
+                    }
+                },
+                res = self.msg_receiver.recv(), if quota > 0 => {
+                    let Some(msg) = res else {
+                        info!(self.log, "[ConnWriter]: msg_receiver=close, vfd={}", self.vfd);
+                        break;
+                    };
+                    self.push_msg(msg)?;
+                    //把队列中已有的消息一起取出, 合并为一次写入, 不超过剩余的额度
+                    //按取出的消息计数, 被丢弃的消息也算在内, 一次唤醒最多处理 max 条
+                    let max = quota.min(WRITE_BATCH_MAX);
+                    let mut taken = 1;
+                    while taken < max {
+                        match self.msg_receiver.try_recv() {
+                            Ok(msg) => self.push_msg(msg)?,
+                            Err(_) => break,
                         }
+                        taken += 1;
+                    }
+                    if let Some(gate) = self.credit_gate.as_ref() {
+                        gate.spend(self.batch.frames() as u64);
+                    }
+                    if let Err(err) = self.write_batch().await {
+                        error!(
+                            self.log,
+                            "[ConnWriter]: closed=true,vfd={},err={}", self.vfd, err
+                        );
+                        break;
+                    }
+                    if self.closing {
+                        info!(self.log, "[ConnWriter]: kick=true,vfd={}", self.vfd);
+                        let _ = self.stream.shutdown().await;
+                        break;
                     }
                 },
+                _ = wait_credit(&self.credit_gate), if quota == 0 => {},
                 _ = self.pairdrop_receiver.recv() => {
                     info!(
                         self.log,
@@ -103,37 +170,108 @@ impl ConnWriter {
         Ok(())
     }
 
-    pub async fn write_frame(&mut self, proto_id: u32, buf: &[u8]) -> io::Result<()> {
-        self.writenum += 1;
+    //本次最多可以从发件队列取出的消息数, 没有开启流控或者对端还没有给出额度时不限制
+    fn quota(&self) -> usize {
+        match self.credit_gate.as_ref().filter(|c| c.is_active()) {
+            Some(gate) => gate.available().min(usize::MAX as u64) as usize,
+            None => usize::MAX,
+        }
+    }
+
+    //检查消息类型, 编码(按需压缩)后放入待写批次
+    fn push_msg(&mut self, msg: SystemMsg) -> crate::Result<()> {
+        let (msg_type, from_vfd, packet) = msg;
+        if self.vfd != from_vfd {
+            info!(
+                self.log,
+                "[ConnWriter]: wrong_vfd=true, vfd={}, from_vfd={}", self.vfd, from_vfd
+            );
+        }
+        let proceed = match self.service_type {
+            ServiceType::TCP => {
+                self.closing |= packet.proto_id() == C2sKick::PROTO_ID;
+                msg_type == MessageType::Tcp
+            }
+            ServiceType::RPC | ServiceType::RPCCLIENT => {
+                msg_type == MessageType::Rpc || msg_type == MessageType::RpcClient
+            }
+            _ => {
+                info!(
+                    self.log,
+                    "[ConnWriter]: unknow_serviceType=true, from_vfd={}", from_vfd
+                );
+                return Ok(());
+            }
+        };
+        if !proceed {
+            info!(
+                self.log,
+                "[ConnWriter]: wrong_msgType=true, service_type={:?},msg_type={:?} from_vfd={}",
+                self.service_type,
+                msg_type,
+                from_vfd
+            );
+            return Ok(());
+        }
+        self.push_packet(packet)
+    }
+
+    //编码或者压缩失败只丢弃这一条消息, 不影响连接上的其他消息
+    fn push_packet(&mut self, packet: Packet) -> crate::Result<()> {
+        let proto_id = packet.proto_id();
+        match self.compression.encode(packet) {
+            Ok(frame) => self.push_frame(frame),
+            Err(err) => {
+                error!(
+                    self.log,
+                    "[ConnWriter]: encode=failed,vfd={},proto_id={},err={}",
+                    self.vfd,
+                    proto_id,
+                    err
+                );
+                Ok(())
+            }
+        }
+    }
 
-        let buflen = buf.len() as u32;
-        // little-endian
-        let mut header = 0u64;
-        header |= proto_id as u64;
-        header |= ((buflen as u64) & 0xffffffff) << 32;
-
-        // let mut header2 = [0u8;PROTO_HEADER_LEN];
-        // header2[0] = (proto_id & 0xff) as u8;
-        // header2[1] = ((proto_id >> 8) & 0xff) as u8;
-        // header2[2] = ((proto_id >> 16) & 0xff) as u8;
-        // header2[3] = ((proto_id >> 24) & 0xff) as u8;
-
-        // header2[4] = (buflen & 0xff) as u8;
-        // header2[5] = ((buflen >> 8) & 0xff) as u8;
-        // header2[6] = ((buflen >> 16) & 0xff) as u8;
-        // header2[7] = ((buflen >> 24) & 0xff) as u8;
+    fn push_frame(&mut self, frame: Frame) -> crate::Result<()> {
+        self.writenum += 1;
+        metrics::frame_out(self.service_type, frame.proto_id, frame.body.len());
         debug!(
             self.log,
-            "[write_frame]: proto_id={},buflen={},writenum={}", proto_id, buflen, self.writenum,
+            "[write_frame]: proto_id={},flags={},buflen={},writenum={}",
+            frame.proto_id,
+            frame.flags,
+            frame.body.len(),
+            self.writenum,
         );
-        //self.stream.write(&header2).await?;
+        match self.sealer.as_mut() {
+            Some(sealer) => self.batch.push_bytes(sealer.seal(&frame)?),
+            None => self.batch.push(frame),
+        }
+        Ok(())
+    }
 
-        self.stream.write_u64_le(header).await?;
-        self.stream.write_all(&buf).await?;
+    //以 writev 的方式一次写出批次中的所有帧
+    async fn write_batch(&mut self) -> io::Result<()> {
+        let res = self.stream.write_all_buf(&mut self.batch).await;
+        self.batch.clear();
+        res
+    }
+}
+
+async fn recv_kick(
+    kick: &mut Option<oneshot::Receiver<ProtoType>>,
+) -> Result<ProtoType, oneshot::error::RecvError> {
+    match kick {
+        Some(kick) => kick.await,
+        None => future::pending().await,
+    }
+}
 
-        // Ensure the encoded frame is written to the socket. The calls above
-        // are to the buffered stream and writes. Calling `flush` writes the
-        // remaining contents of the buffer to the socket.
-        self.stream.flush().await
+async fn wait_credit(credit: &Option<Credit>) -> u64 {
+    match credit {
+        Some(credit) => credit.wait().await,
+        None => future::pending().await,
     }
 }
@@ -0,0 +1,105 @@
+//tcp 游戏连接和 rpc 连接的 tls
+//
+//websocket 使用 native_tls, 但 native_tls 不能要求和校验客户端证书,
+//rpc 的双向 tls 需要校验对端持有集群 CA 签发的证书, 所以这里使用 rustls
+//证书和私钥都是 PEM 格式, 与 websocket 使用同一份 certificate_file/privatekey_file
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn tls_error(file: &str, err: impl std::fmt::Display) -> crate::Error {
+    format!("[tls]: file={file},err={err}").into()
+}
+
+pub fn load_certs(cert_file: &str) -> crate::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .map_err(|err| tls_error(cert_file, err))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| tls_error(cert_file, err))?;
+    if certs.is_empty() {
+        return Err(tls_error(cert_file, "no certificate"));
+    }
+    Ok(certs)
+}
+
+pub fn load_key(key_file: &str) -> crate::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(key_file).map_err(|err| tls_error(key_file, err))
+}
+
+fn load_roots(ca_file: &str) -> crate::Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_file)? {
+        roots.add(cert).map_err(|err| tls_error(ca_file, err))?;
+    }
+    Ok(Arc::new(roots))
+}
+
+//服务端, 设置 client_ca_file 时要求对端出示由该 CA 签发的证书(双向 tls)
+pub fn build_acceptor(
+    cert_file: &str,
+    key_file: &str,
+    client_ca_file: Option<&str>,
+) -> crate::Result<TlsAcceptor> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| tls_error(cert_file, err))?;
+    let builder = match client_ca_file {
+        Some(ca_file) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(load_roots(ca_file)?, provider())
+                    .build()
+                    .map_err(|err| tls_error(ca_file, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert_file)?, load_key(key_file)?)
+        .map_err(|err| tls_error(cert_file, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+//客户端, 用 ca_file 校验服务端证书; 设置 identity 时向服务端出示本端的证书
+pub fn build_connector(
+    ca_file: &str,
+    identity: Option<(&str, &str)>,
+) -> crate::Result<TlsConnector> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| tls_error(ca_file, err))?
+        .with_root_certificates(load_roots(ca_file)?);
+    let config = match identity {
+        Some((cert_file, key_file)) => builder
+            .with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)
+            .map_err(|err| tls_error(cert_file, err))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+//校验服务端证书时使用的名字, 取连接地址中的主机部分, 可以是域名或者ip
+pub fn server_name(addr: &str) -> crate::Result<ServerName<'static>> {
+    //不带端口的 ipv6 地址本身包含 ':'
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok(ServerName::IpAddress(ip.into()));
+    }
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ServerName::IpAddress(ip.into()));
+    }
+    ServerName::try_from(host.to_owned())
+        .map_err(|err| format!("[tls]: addr={addr},err={err}").into())
+}
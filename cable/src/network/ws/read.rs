@@ -1,9 +1,12 @@
 use super::service::{ReadStreamMaybeTls, ReadStreamNoneTls, ReadStreamTls};
 use crate::error::Error;
 use crate::logger::{build_logger, Outter};
-use crate::message::{MessageType, SMSender, SystemMsg};
-use crate::network::tcp::{PROTO_BODY_MAX_LEN, PROTO_HEADER_LEN};
-use crate::{debug, error, info, protos};
+use crate::message::{MessageType, Packet, SMSender, ServiceType, SystemMsg};
+use crate::metrics;
+use crate::network::compress::Compression;
+use crate::network::frame::Frame;
+use crate::{debug, error, info};
+use bytes::BytesMut;
 use futures_util::StreamExt;
 use std::sync::Arc;
 use tokio::sync::{
@@ -20,13 +23,14 @@ pub struct ConnReader {
     stream_tls: Option<ReadStreamTls>,
     stream_ntls: Option<ReadStreamNoneTls>,
     stream_maybe_tls: Option<ReadStreamMaybeTls>,
+    compression: Compression,
     limit_connections: Arc<Semaphore>,
     readnum: u64,
     log: Outter,
     proto_sender: SMSender,
     _shutdown_complete: mpsc::Sender<()>, // 对象销毁时自动销毁
     service_notify: Option<broadcast::Receiver<()>>,
-    _pairdrop_sender: mpsc::Sender<()>, // 对象销毁时自动销毁
+    pairdrop_sender: mpsc::Sender<()>, // 对象销毁时自动销毁; 写端先结束时读端随之结束
 }
 
 impl Drop for ConnReader {
@@ -36,6 +40,7 @@ impl Drop for ConnReader {
 }
 
 impl ConnReader {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         vfd: u64,
         stream_tls: Option<ReadStreamTls>,
@@ -47,25 +52,33 @@ impl ConnReader {
         service_notify: broadcast::Receiver<()>,
         _pairdrop_sender: mpsc::Sender<()>,
     ) -> ConnReader {
-        let log: Outter = build_logger(LOG_NAME);
+        let log: Outter = build_logger(LOG_NAME).with_vfd(vfd);
         ConnReader {
             vfd,
             stream_tls,
             stream_ntls,
             stream_maybe_tls,
+            compression: Compression::default(),
             limit_connections,
             readnum: 0,
             log,
             proto_sender,
             _shutdown_complete,
             service_notify: Some(service_notify),
-            _pairdrop_sender,
+            pairdrop_sender: _pairdrop_sender,
         }
     }
 
+    //与写端共享的压缩状态, 读到登录协议时完成协商
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub async fn run(&mut self) -> crate::Result<()> {
         let mut service_notify = self.service_notify.take().unwrap();
-        loop {
+        let writer = self.pairdrop_sender.clone();
+        let reason = loop {
             tokio::select! {
                 res = self.read_frame() => {
                     match res {
@@ -85,11 +98,13 @@ impl ConnReader {
                                     if let Err(err) = self.proto_sender.try_send(pto) {
                                         match err {
                                             TrySendError::Full(err) => {
+                                                metrics::send_failed("conn_reader", "full");
                                                 error!(self.log,"[ConnReader]: send=failed, msgtype={:?},vfd={}",err.0,err.1);
                                             },
                                             TrySendError::Closed(_err) =>{
+                                                metrics::send_failed("conn_reader", "closed");
                                                 error!(self.log,"[ConnReader]: proto_sender=close, vfd={}",self.vfd);
-                                                break;
+                                                break "hub_closed";
                                             }
                                         }
                                     }
@@ -101,16 +116,21 @@ impl ConnReader {
                         },
                         Err(err) => {
                             error!(self.log,"[ConnReader]: vfd={},err={}",self.vfd,err);
-                            break;
+                            break "peer";
                         }
                     }
                 }
                 _ = service_notify.recv() => {
                     info!(self.log,"[ConnReader]: notify_close=true,vfd={}",self.vfd);
-                    break;
+                    break "shutdown";
+                },
+                _ = writer.closed() => {
+                    info!(self.log,"[ConnReader]: writer=closed,vfd={}",self.vfd);
+                    break "writer_closed";
                 },
             };
-        }
+        };
+        metrics::conn_closed(ServiceType::TCP, reason);
         Ok(())
     }
 
@@ -141,60 +161,29 @@ impl ConnReader {
                 return Ok(None);
             }
         };
-        if buff.len() < PROTO_HEADER_LEN {
-            return Err(Error::Message("wrong header".to_string()));
-        }
-        let buff_body_len = buff.len() - PROTO_HEADER_LEN;
-        if buff_body_len >= PROTO_BODY_MAX_LEN {
-            return Err(format!(
-                "[extract_msg]: buff_exceed=PROTO_BODY_MAX_LEN,{}",
-                buff_body_len
-            )
-            .into());
-        }
-        let (header, body) = buff.split_at(PROTO_HEADER_LEN);
-        //读消息头
-        let mut proto_id = 0u32;
-        proto_id |= header[0] as u32 & 0xff;
-        proto_id |= (header[1] as u32 & 0xff) << 8;
-        proto_id |= (header[2] as u32 & 0xff) << 16;
-        proto_id |= (header[3] as u32 & 0xff) << 24;
-
-        let mut body_len = 0u32;
-        body_len |= header[4] as u32 & 0xff;
-        body_len |= (header[5] as u32 & 0xff) << 8;
-        body_len |= (header[6] as u32 & 0xff) << 16;
-        body_len |= (header[7] as u32 & 0xff) << 24;
-
+        //一条 binary 消息必须正好是一个完整的帧
+        let frame = Frame::parse_exact(BytesMut::from(&buff[..]))?;
+        metrics::frame_in(ServiceType::TCP, frame.proto_id, frame.body.len());
         debug!(
             self.log,
-            "[extract_msg]: proto_id={proto_id},body_len={body_len},body={:?}", body
+            "[extract_msg]: proto_id={},flags={},body={:?}",
+            frame.proto_id,
+            frame.flags,
+            frame.body
         );
 
-        //协议长度超出最大上限
-        if body_len != buff_body_len as u32 {
-            return Err(format!(
-                "[extract_msg]: body_len!=buff_body_len,{body_len},{buff_body_len}",
-            )
-            .into());
-        }
-
-        //读消息体
         //解码
-        match protos::decode(proto_id, body) {
-            Ok(ptoobj) => {
-                self.readnum += 1;
-                debug!(
-                    self.log,
-                    "[extract_msg]: proto_id={},buflen={},readnum={}",
-                    proto_id,
-                    body_len,
-                    self.readnum,
-                );
-                Ok(Some((MessageType::Tcp, self.vfd, ptoobj)))
-            }
-            Err(err) => Err(err.into()),
-        }
+        let ptoobj = frame.decode()?;
+        self.compression.observe(&ptoobj);
+        self.readnum += 1;
+        debug!(
+            self.log,
+            "[extract_msg]: proto_id={},buflen={},readnum={}",
+            frame.proto_id,
+            frame.body.len(),
+            self.readnum,
+        );
+        Ok(Some((MessageType::Tcp, self.vfd, Packet::Proto(ptoobj))))
     }
 
     pub async fn read_frame(&mut self) -> crate::Result<Option<SystemMsg>> {
@@ -203,36 +192,30 @@ impl ConnReader {
             if let Some(res) = stream.next().await {
                 match res {
                     Ok(msg) => self.extract_msg(msg),
-                    Err(err) => {
-                        return Err(Error::Message(err.to_string()));
-                    }
+                    Err(err) => Err(Error::Message(err.to_string())),
                 }
             } else {
-                return Err(Error::Message("next() empty".to_string()));
+                Err(Error::Message("next() empty".to_string()))
             }
         } else if self.stream_ntls.is_some() {
             let stream = self.stream_ntls.as_mut().unwrap();
             if let Some(res) = stream.next().await {
                 match res {
                     Ok(msg) => self.extract_msg(msg),
-                    Err(err) => {
-                        return Err(Error::Message(err.to_string()));
-                    }
+                    Err(err) => Err(Error::Message(err.to_string())),
                 }
             } else {
-                return Err(Error::Message("next() empty".to_string()));
+                Err(Error::Message("next() empty".to_string()))
             }
         } else {
             let stream = self.stream_maybe_tls.as_mut().unwrap();
             if let Some(res) = stream.next().await {
                 match res {
                     Ok(msg) => self.extract_msg(msg),
-                    Err(err) => {
-                        return Err(Error::Message(err.to_string()));
-                    }
+                    Err(err) => Err(Error::Message(err.to_string())),
                 }
             } else {
-                return Err(Error::Message("next() empty".to_string()));
+                Err(Error::Message("next() empty".to_string()))
             }
         }
     }
@@ -1,6 +1,7 @@
 use super::{read::ConnReader, write::ConnWriter};
 use crate::error::Error;
-use crate::message::{MessageType, ProtoType};
+use crate::message::{MessageType, Packet, ProtoType};
+use crate::network::compress::Compression;
 use crate::network::tcp::service::Service;
 use crate::protos::Dummy;
 use crate::{error, info};
@@ -77,8 +78,7 @@ impl Service {
         &mut self,
         acceptor: Option<TlsAcceptor>,
     ) -> crate::Result<()> {
-        if acceptor.is_some() {
-            let a1 = acceptor.unwrap();
+        if let Some(a1) = acceptor {
             loop {
                 let stream = self.accept().await?;
                 let a2 = a1.clone();
@@ -110,8 +110,7 @@ impl Service {
         stream_tls: Option<TlsStream<TcpStream>>,
         stream_ntls: Option<TcpStream>,
     ) -> crate::Result<()> {
-        if stream_tls.is_some() {
-            let stream = stream_tls.unwrap();
+        if let Some(stream) = stream_tls {
             match accept_async(stream).await {
                 Ok(ws_stream) => {
                     self.handle_stream_tls(ws_stream).await?;
@@ -149,6 +148,7 @@ impl Service {
 
         let (pairdrop_sender, pairdrop_receiver) = mpsc::channel(1);
         let (write_stream, read_stream) = stream.split();
+        let compression = Compression::new(self.compress_conf.clone());
         let reader = ConnReader::new(
             vfd,
             Some(read_stream),
@@ -159,7 +159,8 @@ impl Service {
             self.shutdown_complete_sender.clone(),
             self.notify_client_shutdown.subscribe(),
             pairdrop_sender,
-        );
+        )
+        .with_compression(compression.clone());
 
         // 根据服务类型决定 channel 队列大小
         let conn_msg_chan_size = self.conf.get_int("conn_msg_chan_size").unwrap() as usize;
@@ -171,7 +172,8 @@ impl Service {
             None,
             conn_rx,
             pairdrop_receiver,
-        );
+        )
+        .with_compression(compression);
 
         // 在 reader 被 drop 时归还计数
         self.limit_connections.acquire().await.unwrap().forget();
@@ -194,6 +196,7 @@ impl Service {
 
         let (pairdrop_sender, pairdrop_receiver) = mpsc::channel(1);
         let (write_stream, read_stream) = stream.split();
+        let compression = Compression::new(self.compress_conf.clone());
         let reader = ConnReader::new(
             vfd,
             None,
@@ -204,7 +207,8 @@ impl Service {
             self.shutdown_complete_sender.clone(),
             self.notify_client_shutdown.subscribe(),
             pairdrop_sender,
-        );
+        )
+        .with_compression(compression.clone());
 
         // 根据服务类型决定 channel 队列大小
         let conn_msg_chan_size = self.conf.get_int("conn_msg_chan_size").unwrap() as usize;
@@ -216,7 +220,8 @@ impl Service {
             None,
             conn_rx,
             pairdrop_receiver,
-        );
+        )
+        .with_compression(compression);
 
         // 在 reader 被 drop 时归还计数
         self.limit_connections.acquire().await.unwrap().forget();
@@ -257,7 +262,11 @@ impl Service {
             }
             let dummy = Dummy::default();
             let _ = close_notify
-                .send((MessageType::SocketClosed, vfd, ProtoType::Dummy(dummy)))
+                .send((
+                    MessageType::SocketClosed,
+                    vfd,
+                    Packet::Proto(ProtoType::Dummy(dummy)),
+                ))
                 .await;
         });
         Ok(())
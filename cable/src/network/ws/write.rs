@@ -1,20 +1,28 @@
 use super::service::{WriteStreamMaybeTls, WriteStreamNoneTls, WriteStreamTls};
 use crate::error::Error;
 use crate::logger::{build_logger, Outter};
-use crate::message::SMReceiver;
+use crate::message::{SMReceiver, ServiceType, SystemMsg};
+use crate::metrics;
+use crate::network::compress::Compression;
+use crate::network::frame::Frame;
 use crate::network::tcp::PROTO_HEADER_LEN;
-use crate::{debug, error, info, protos};
+use crate::protos::{C2sKick, ProtoMessage};
+use crate::{debug, error, info};
 use futures_util::SinkExt;
 use tokio::sync::mpsc;
 use tokio_tungstenite::tungstenite::Message;
 
 const LOG_NAME: &str = "ws_writer.log";
+//每次唤醒最多合并写出的帧数量
+const WRITE_BATCH_MAX: usize = 256;
 
 pub struct ConnWriter {
     vfd: u64,
     stream_tls: Option<WriteStreamTls>,
     stream_ntls: Option<WriteStreamNoneTls>,
     stream_maybe_tls: Option<WriteStreamMaybeTls>,
+    compression: Compression,
+    closing: bool, // 写出 C2sKick 后关闭连接
     writenum: u64,
     log: Outter,
     msg_receiver: SMReceiver,
@@ -30,12 +38,14 @@ impl ConnWriter {
         msg_receiver: SMReceiver,
         pairdrop_receiver: mpsc::Receiver<()>,
     ) -> ConnWriter {
-        let log = build_logger(LOG_NAME);
+        let log = build_logger(LOG_NAME).with_vfd(vfd);
         ConnWriter {
             vfd,
             stream_tls,
             stream_ntls,
             stream_maybe_tls,
+            compression: Compression::default(),
+            closing: false,
             writenum: 0,
             log,
             msg_receiver,
@@ -43,26 +53,44 @@ impl ConnWriter {
         }
     }
 
+    //与读端共享的压缩状态, 协商成功后按协商的算法压缩
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub async fn run(&mut self) -> crate::Result<()> {
         loop {
             tokio::select! {
                 res = self.msg_receiver.recv() => {
-                    if let Some((msg_type, from_vfd, pto)) = res {
-                        if from_vfd >= 100  && self.vfd != from_vfd {
-                            info!(
-                                self.log,
-                                "[ConnWriter]: wrong=true, vfd={}, from_vfd={}", self.vfd, from_vfd
-                            );
-                        }
-                        let (proto_id,_) = pto.inner_info();
-                        let buf = protos::encode(pto)?;
-                        if let Err(err) = self.write_frame(proto_id, &buf).await {
-                            error!(
-                                self.log,
-                                "[ConnWriter]: closed=true,vfd={},proto_id={},err={}", self.vfd, proto_id, err
-                            );
-                            break;
+                    let Some(msg) = res else {
+                        info!(self.log, "[ConnWriter]: msg_receiver=close, vfd={}", self.vfd);
+                        break;
+                    };
+                    //队列中已有的消息依次 feed, 最后只 flush 一次
+                    let mut res = self.feed_msg(msg).await;
+                    let mut num = 1;
+                    while res.is_ok() && num < WRITE_BATCH_MAX {
+                        match self.msg_receiver.try_recv() {
+                            Ok(msg) => res = self.feed_msg(msg).await,
+                            Err(_) => break,
                         }
+                        num += 1;
+                    }
+                    if res.is_ok() {
+                        res = self.flush().await;
+                    }
+                    if let Err(err) = res {
+                        error!(
+                            self.log,
+                            "[ConnWriter]: closed=true,vfd={},err={}", self.vfd, err
+                        );
+                        break;
+                    }
+                    if self.closing {
+                        info!(self.log, "[ConnWriter]: kick=true,vfd={}", self.vfd);
+                        let _ = self.close().await;
+                        break;
                     }
                 },
                 _ = self.pairdrop_receiver.recv() => {
@@ -77,52 +105,83 @@ impl ConnWriter {
         Ok(())
     }
 
-    pub async fn write_frame(&mut self, proto_id: u32, buf: &[u8]) -> crate::Result<()> {
-        self.writenum += 1;
-
-        let buflen = buf.len() as u32;
-        // little-endian
-        // let mut header = 0u64;
-        // header |= proto_id as u64;
-        // header |= ((buflen as u64) & 0xffffffff) << 32;
-        let whole_len = PROTO_HEADER_LEN + buflen as usize;
-        let mut whole_buff = Vec::with_capacity(whole_len);
-        whole_buff.push((proto_id & 0xff) as u8);
-        whole_buff.push(((proto_id >> 8) & 0xff) as u8);
-        whole_buff.push(((proto_id >> 16) & 0xff) as u8);
-        whole_buff.push(((proto_id >> 24) & 0xff) as u8);
+    async fn feed_msg(&mut self, msg: SystemMsg) -> crate::Result<()> {
+        let (_msg_type, from_vfd, packet) = msg;
+        if from_vfd >= 100 && self.vfd != from_vfd {
+            info!(
+                self.log,
+                "[ConnWriter]: wrong=true, vfd={}, from_vfd={}", self.vfd, from_vfd
+            );
+        }
+        let proto_id = packet.proto_id();
+        self.closing |= proto_id == C2sKick::PROTO_ID;
+        //编码或者压缩失败只丢弃这一条消息
+        let frame = match self.compression.encode(packet) {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!(
+                    self.log,
+                    "[ConnWriter]: encode=failed,vfd={},proto_id={},err={}",
+                    self.vfd,
+                    proto_id,
+                    err
+                );
+                return Ok(());
+            }
+        };
+        self.write_frame(&frame).await
+    }
 
-        whole_buff.push((buflen & 0xff) as u8);
-        whole_buff.push(((buflen >> 8) & 0xff) as u8);
-        whole_buff.push(((buflen >> 16) & 0xff) as u8);
-        whole_buff.push(((buflen >> 24) & 0xff) as u8);
+    //websocket 的一条 binary 消息就是一个完整的帧
+    pub async fn write_frame(&mut self, frame: &Frame) -> crate::Result<()> {
+        self.writenum += 1;
+        metrics::frame_out(ServiceType::TCP, frame.proto_id, frame.body.len());
 
-        whole_buff.extend_from_slice(buf);
+        let mut whole_buff = Vec::with_capacity(PROTO_HEADER_LEN + frame.body.len());
+        whole_buff.extend_from_slice(&frame.header());
+        whole_buff.extend_from_slice(&frame.body);
         debug!(
             self.log,
-            "[write_frame]: proto_id={},buflen={},writenum={},body={:?}",
-            proto_id,
-            buflen,
+            "[write_frame]: proto_id={},flags={},buflen={},writenum={},body={:?}",
+            frame.proto_id,
+            frame.flags,
+            frame.body.len(),
             self.writenum,
-            buf
+            frame.body
         );
-        if self.stream_tls.is_some() {
-            let stream = self.stream_tls.as_mut().unwrap();
-            if let Err(err) = stream.send(Message::binary(whole_buff)).await {
-                return Err(Error::Message(err.to_string()));
-            }
-        } else if self.stream_maybe_tls.is_none() {
+        let res = if let Some(stream) = self.stream_tls.as_mut() {
+            stream.feed(Message::binary(whole_buff)).await
+        } else if let Some(stream) = self.stream_maybe_tls.as_mut() {
+            stream.feed(Message::binary(whole_buff)).await
+        } else {
             let stream = self.stream_ntls.as_mut().unwrap();
-            if let Err(err) = stream.feed(Message::binary(whole_buff)).await {
-                return Err(Error::Message(err.to_string()));
-            }
+            stream.feed(Message::binary(whole_buff)).await
+        };
+        res.map_err(|err| Error::Message(err.to_string()))
+    }
+
+    pub async fn flush(&mut self) -> crate::Result<()> {
+        let res = if let Some(stream) = self.stream_tls.as_mut() {
+            stream.flush().await
+        } else if let Some(stream) = self.stream_maybe_tls.as_mut() {
+            stream.flush().await
         } else {
-            let stream = self.stream_maybe_tls.as_mut().unwrap();
-            if let Err(err) = stream.feed(Message::binary(whole_buff)).await {
-                return Err(Error::Message(err.to_string()));
-            }
-        }
+            let stream = self.stream_ntls.as_mut().unwrap();
+            stream.flush().await
+        };
+        res.map_err(|err| Error::Message(err.to_string()))
+    }
 
-        Ok(())
+    //发出 websocket 的关闭消息
+    pub async fn close(&mut self) -> crate::Result<()> {
+        let res = if let Some(stream) = self.stream_tls.as_mut() {
+            stream.close().await
+        } else if let Some(stream) = self.stream_maybe_tls.as_mut() {
+            stream.close().await
+        } else {
+            let stream = self.stream_ntls.as_mut().unwrap();
+            stream.close().await
+        };
+        res.map_err(|err| Error::Message(err.to_string()))
     }
 }
@@ -151,11 +151,11 @@ fn to_string(_ctx: Context, value: Value, mut s: Vec<u8>) -> rlua::Result<Vec<u8
             }
             Err(err) => {
                 let err = format!("[to_string]:not a utf-8 string {}", err);
-                return Err(rlua::Error::RuntimeError(err));
+                Err(rlua::Error::RuntimeError(err))
             }
         }
     } else {
         let err = format!("[to_string]:unspport string '{:?}'", value);
-        return Err(rlua::Error::RuntimeError(err));
+        Err(rlua::Error::RuntimeError(err))
     }
 }
@@ -1,23 +1,105 @@
-use crate::config::Config;
-use crate::info;
+use crate::config::{reload_blocking, Config, Reloader};
 use crate::logger::build_logger;
 use crate::message::{SMSender, ServiceType};
+use crate::modules::data_cache::DataConf;
+use crate::modules::shard::{ShardConf, ShardPolicy};
+use crate::modules::storage;
 use crate::modules::Module;
-use crate::states::GameState;
+use crate::network::http::ChanHttpProtoReceiverOp;
+use crate::states::db_state::DbConf;
+use crate::states::{DbState, GameState, Handlers, SessionState, ShardState};
+use crate::{error, info};
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 
+//每个 game_hub 接收管理接口和控制台请求的通道长度, 这些请求很少
+const ADMIN_CHAN_SIZE: usize = 16;
+
+mod console_hub;
+mod db_hub;
 mod game_hub;
+mod gate_hub;
+mod http_hub;
 mod rpc_client_hub;
+mod shard_hub;
 mod tcp_hub;
 
 pub fn start(conf: Config) {
+    start_with_handlers(conf, Handlers::new());
+}
+
+//handlers 为按协议类型注册的 rust 处理函数, 未注册的协议交给脚本层处理
+pub fn start_with_handlers(conf: Config, handlers: Handlers) {
+    let shard_conf = ShardConf::from_config(&conf).unwrap();
+    start_with_shards(conf, handlers, shard_conf, None);
+}
+
+//配置可以在运行中重新加载: 收到 SIGHUP 时重新读取配置文件
+pub fn start_with_reloader(reloader: Reloader, handlers: Handlers) {
+    let conf = reloader.config();
+    let shard_conf = ShardConf::from_config(&conf).unwrap();
+    start_with_shards(
+        conf,
+        handlers,
+        shard_conf,
+        Some(Arc::new(Mutex::new(reloader))),
+    );
+}
+
+//使用自定义的分片策略, 分片数仍由 logic_shards 配置
+pub fn start_with_policy(conf: Config, handlers: Handlers, policy: Arc<dyn ShardPolicy>) {
+    let shard_conf = ShardConf::from_config(&conf).unwrap().with_policy(policy);
+    start_with_shards(conf, handlers, shard_conf, None);
+}
+
+fn start_with_shards(
+    conf: Config,
+    handlers: Handlers,
+    shard_conf: ShardConf,
+    reloader: Option<Arc<Mutex<Reloader>>>,
+) {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let _ = rt.block_on(async move {
-        run_game_server(conf).await;
+    rt.block_on(async move {
+        if let Some(reloader) = reloader.clone() {
+            reload_on_hangup(reloader);
+        }
+        run_game_server(conf, handlers, shard_conf, reloader).await;
     });
 }
 
-async fn run_game_server(conf: Config) {
+//重新加载的结果只记录日志, 被拒绝时当前配置不变
+fn reload_on_hangup(reloader: Arc<Mutex<Reloader>>) {
+    tokio::spawn(async move {
+        let mut log = build_logger("serivces.log");
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!(log, "[reload]: signal=failed,err={}", err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            let res = reload_blocking(reloader.clone()).await;
+            match res {
+                Ok(changes) => {
+                    let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+                    info!(log, "[reload]: ok,changed={:?}", keys);
+                }
+                Err(err) => {
+                    error!(log, "[reload]: rejected,{}", err);
+                }
+            }
+        }
+    });
+}
+
+async fn run_game_server(
+    conf: Config,
+    handlers: Handlers,
+    shard_conf: ShardConf,
+    reloader: Option<Arc<Mutex<Reloader>>>,
+) {
     let mut log = build_logger("serivces.log");
     info!(log, "[run_game_server]: service=start");
 
@@ -27,8 +109,37 @@ async fn run_game_server(conf: Config) {
     let service_type = conf.get_string("service_type").unwrap();
     let service_type: ServiceType = ServiceType::from(service_type.as_str());
     assert!(service_type != ServiceType::UNKNOW);
-    //tcp 玩家网络连接服务
-    let mut tm = new_tcp_module(service_type, conf.clone(), "tcp_module", "game_state.log");
+    //管理接口和控制台的请求由各个 game_hub 处理, 网关和 db 服务没有 game_hub
+    //两者都没有启动时发送端随之销毁, game_hub 不再等待
+    let hubs = match service_type {
+        ServiceType::GATE | ServiceType::DB => 0,
+        _ if shard_conf.is_sharded() => shard_conf.shards,
+        _ => 1,
+    };
+    let (senders, mut admins): (Vec<_>, Vec<_>) = (0..hubs)
+        .map(|_| {
+            let (sender, receiver) = mpsc::channel(ADMIN_CHAN_SIZE);
+            (sender, Some(receiver))
+        })
+        .unzip();
+    http_hub::start(&conf, senders.clone(), reloader);
+    console_hub::start(&conf, senders);
+    if service_type == ServiceType::GATE || service_type == ServiceType::DB {
+        match service_type {
+            ServiceType::GATE => run_gate(conf, all_srv_close_sender.clone()),
+            _ => run_db(conf, all_srv_close_sender.clone()),
+        }
+        drop(all_srv_close_sender);
+        let _ = all_srv_close_receiver.recv().await;
+        info!(log, "[run_game_server]: service=ended");
+        return;
+    }
+    //tcp 玩家网络连接服务, 开启分片时由 shard_hub 转发给各个分片, 自身不运行脚本
+    let mut tm = if shard_conf.is_sharded() {
+        new_router_module(&conf, "tcp_module")
+    } else {
+        new_tcp_module(service_type, conf.clone(), "tcp_module", "game_state.log")
+    };
     if service_type == ServiceType::TCP {
         let service_addr = conf.get_string("service_addr").unwrap();
         let is_ws = conf.get_bool("is_ws");
@@ -64,6 +175,93 @@ async fn run_game_server(conf: Config) {
     );
 
     //rpc 跨机发送服务
+    let rpc_sender = start_rpc_client(&conf, all_srv_close_sender.clone());
+    //会话迁移, 所有分片共用
+    let session = SessionState::new(&conf).with_rpc(rpc_sender.clone());
+
+    if shard_conf.is_sharded() {
+        start_shards(
+            service_type,
+            conf.clone(),
+            tm,
+            rpcm,
+            rpc_sender,
+            session,
+            handlers,
+            shard_conf,
+            admins,
+            all_srv_close_sender.clone(),
+        );
+    } else {
+        tm.get_game_state().set_rpc_sender(rpc_sender);
+        if let Some(data) = DataConf::from_config(&conf) {
+            tm.get_game_state().set_data(data);
+        }
+        tm.get_game_state().set_handlers(handlers);
+        tm.get_game_state().set_session(session);
+        game_hub::start(
+            conf.clone(),
+            tm,
+            Some(rpcm),
+            admins.pop().flatten(),
+            all_srv_close_sender.clone(),
+        );
+    }
+
+    //等待其他服务停止
+    drop(all_srv_close_sender);
+    let _ = all_srv_close_receiver.recv().await;
+    info!(log, "[run_game_server]: service=ended");
+}
+
+//网关: 接收客户端连接, 经 rpc 转发给后端逻辑服, 不运行脚本
+fn run_gate(conf: Config, all_srv_close_sender: mpsc::Sender<()>) {
+    let tm = new_router_module(&conf, "tcp_module");
+    let service_addr = conf.get_string("service_addr").unwrap();
+    tcp_hub::start(
+        ServiceType::TCP,
+        conf.clone(),
+        conf.get_bool("is_ws"),
+        service_addr.clone(),
+        "tcp_hub.log",
+        tm.spawn_smsender(),
+        tm.spawn_smsender_chan(),
+        all_srv_close_sender.clone(),
+    );
+    //后端发回的消息经 rpc 连接到达
+    let (rpcm, rpc_sender) = start_rpc(&conf, all_srv_close_sender.clone());
+    gate_hub::start(conf, tm, rpcm, rpc_sender, all_srv_close_sender);
+}
+
+//db 服务: 只接收其他服务器的 rpc 请求, 不运行脚本
+fn run_db(conf: Config, all_srv_close_sender: mpsc::Sender<()>) {
+    let storage = storage::from_config(&conf).unwrap();
+    let db = DbState::new(DbConf::from_config(&conf), storage);
+    let (rpcm, rpc_sender) = start_rpc(&conf, all_srv_close_sender.clone());
+    db_hub::start(conf, rpcm, rpc_sender, db, all_srv_close_sender);
+}
+
+//不运行脚本的服务使用的 rpc 收发服务, 返回接收 rpc 连接的模块和发送 rpc 的 sender
+fn start_rpc(conf: &Config, all_srv_close_sender: mpsc::Sender<()>) -> (Module, SMSender) {
+    let rpcm = new_router_module(conf, "rpc_module");
+    let rpc_service_addr = conf.get_string("rpc_service_addr").unwrap();
+    tcp_hub::start(
+        ServiceType::RPC,
+        conf.clone(),
+        false,
+        rpc_service_addr.clone(),
+        "rcp_hub.log",
+        rpcm.spawn_smsender(),
+        rpcm.spawn_smsender_chan(),
+        all_srv_close_sender.clone(),
+    );
+
+    let rpc_sender = start_rpc_client(conf, all_srv_close_sender);
+    (rpcm, rpc_sender)
+}
+
+//rpc 跨机发送服务, 返回发给其他机器的 sender; 通道满时 try_send 返回错误, 交给之后的消息按对端暂存
+pub fn start_rpc_client(conf: &Config, all_srv_close_sender: mpsc::Sender<()>) -> SMSender {
     let rpc_clientm = new_rpc_client_module(
         ServiceType::RPCCLIENT,
         conf.clone(),
@@ -71,15 +269,81 @@ async fn run_game_server(conf: Config) {
         "rpc_client_state.log",
     );
     let rpc_sender = rpc_clientm.spawn_smsender();
-    rpc_client_hub::start(conf.clone(), rpc_clientm, all_srv_close_sender.clone());
+    rpc_client_hub::start(conf.clone(), rpc_clientm, all_srv_close_sender);
+    rpc_sender
+}
 
-    tm.get_game_state().set_rpc_sender(rpc_sender);
-    game_hub::start(conf.clone(), tm, rpcm, all_srv_close_sender.clone());
+//每个分片一个 game_hub, 各自运行自己的脚本虚拟机; rpc 的请求可以从任意分片发出, 收到的 rpc 由 0 号分片处理, 回复转给发出请求的分片
+#[allow(clippy::too_many_arguments)]
+fn start_shards(
+    service_type: ServiceType,
+    conf: Config,
+    tm: Module,
+    rpcm: Module,
+    rpc_sender: SMSender,
+    session: SessionState,
+    handlers: Handlers,
+    shard_conf: ShardConf,
+    admins: Vec<Option<ChanHttpProtoReceiverOp>>,
+    all_srv_close_sender: mpsc::Sender<()>,
+) {
+    let mut shards: Vec<Module> = (0..shard_conf.shards)
+        .map(|id| {
+            let conf = conf.clone().with("shard_id", &id.to_string());
+            new_tcp_module(
+                service_type,
+                conf,
+                &format!("shard_module_{id}"),
+                &format!("game_state_{id}.log"),
+            )
+        })
+        .collect();
+    let senders: Vec<_> = shards.iter().map(Module::spawn_smsender).collect();
+    let handlers = Arc::new(handlers);
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    for (id, m) in shards.iter_mut().enumerate() {
+        let shard = ShardState::new(id, shard_conf.clone(), senders.clone(), control_tx.clone());
+        let gs = m.get_game_state();
+        gs.set_rpc_sender(rpc_sender.clone());
+        if let Some(data) = DataConf::from_config(&conf) {
+            gs.set_data(data);
+        }
+        gs.set_shared_handlers(handlers.clone());
+        gs.set_session(session.clone().with_shard(shard.clone()));
+        gs.set_shard(shard);
+    }
 
-    //等待其他服务停止
-    drop(all_srv_close_sender);
-    let _ = all_srv_close_receiver.recv().await;
-    info!(log, "[run_game_server]: service=ended");
+    let chans = shards
+        .iter()
+        .map(|m| (m.spawn_smsender(), m.spawn_smsender_chan()))
+        .collect();
+    shard_hub::start(
+        conf.clone(),
+        tm,
+        control_rx,
+        chans,
+        shard_conf,
+        all_srv_close_sender.clone(),
+    );
+    let mut rpcm = Some(rpcm);
+    for (m, admin) in shards.into_iter().zip(admins) {
+        game_hub::start(
+            conf.clone(),
+            m,
+            rpcm.take(),
+            admin,
+            all_srv_close_sender.clone(),
+        );
+    }
+}
+
+//只负责接收连接消息, 不带 GameState
+fn new_router_module(conf: &Config, module_name: &str) -> Module {
+    let sender_size = conf.get_int("tcp_msg_chan_size").unwrap() as usize;
+    let sender_chan_size = conf.get_int("conn_chan_size").unwrap() as usize;
+    Module::new(module_name.to_owned())
+        .with_sender(sender_size)
+        .with_sender_chan(sender_chan_size)
 }
 
 pub fn new_tcp_module(
@@ -0,0 +1,32 @@
+use crate::config::Config;
+use crate::logger::build_logger;
+use crate::network::console::{self, Console, ConsoleAddr};
+use crate::network::http::ChanHttpProtoSenderOp;
+use crate::{error, info};
+
+//lua 调试控制台, 配置了 console_addr 时启动; 随进程退出, 不参与服务的停止流程
+//hubs 为各个 game_hub 接收请求的通道, 网关和 db 服务没有 game_hub, 不启动
+pub fn start(conf: &Config, hubs: Vec<ChanHttpProtoSenderOp>) {
+    let Some(addr) = conf.get_string("console_addr") else {
+        return;
+    };
+    let mut log = build_logger("console_hub.log");
+    if hubs.is_empty() {
+        error!(log, "[console_hub]: no_game_hub=true,addr={}", addr);
+        return;
+    }
+    let addr = match ConsoleAddr::parse(addr) {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!(log, "[console_hub]: err={}", err);
+            return;
+        }
+    };
+    let readonly = conf.get_bool("console_readonly");
+    info!(
+        log,
+        "[console_hub]: service=start,addr={:?},readonly={}", addr, readonly
+    );
+    let console = Console::new(hubs).with_readonly(readonly);
+    tokio::spawn(console::start_service(addr, console));
+}
@@ -0,0 +1,170 @@
+use crate::config::Config;
+use crate::logger::{build_logger, Outter};
+use crate::message::{MessageType, ProtoType, SMSender, SystemMsg};
+use crate::metrics;
+use crate::modules::Module;
+use crate::network::{self, rpc_auth::RpcGuard};
+use crate::protos::DbReq;
+use crate::states::DbState;
+use crate::{debug, error, info};
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration};
+
+//db 服务: 接收其他服务器的 rpc 连接, 请求攒成一批后在阻塞线程上执行, 回复经 rpc 客户端发回请求方
+//一批执行完才取下一批, 请求按到达顺序执行
+pub fn start(
+    conf: Config,
+    mut rpcm: Module,
+    rpc_sender: SMSender,
+    db: DbState,
+    all_srv_close_sender: Sender<()>,
+) {
+    tokio::spawn(async move {
+        let mut log = build_logger("db_hub.log");
+        info!(log, "[db_hub]: service=start,conf={:?}", db.conf());
+
+        let mut rpc_smreceiver_chan = rpcm.take_smreceiver_chan().unwrap();
+        let mut rpc_smreceiver = rpcm.take_smreceiver().unwrap();
+        let mut rpc_guard = RpcGuard::from_config(&conf).unwrap();
+        let mut rpc_conns: HashMap<u64, SMSender> = HashMap::new();
+
+        let batch_size = db.conf().batch_size;
+        let interval = db.conf().write_behind_ms.max(100);
+        let mut flush_tick = time::interval(Duration::from_millis(interval));
+        let mut db = Some(db);
+        loop {
+            tokio::select! {
+                biased;
+                _ = flush_tick.tick() => {
+                    let mut state = db.take().unwrap();
+                    let (state, res) = tokio::task::spawn_blocking(move || {
+                        let res = state.flush();
+                        (state, res)
+                    })
+                    .await
+                    .unwrap();
+                    db = Some(state);
+                    match res {
+                        Ok(0) => {}
+                        Ok(n) => {
+                            debug!(log, "[db_hub]: flush={}", n);
+                        }
+                        Err(err) => {
+                            error!(log, "[db_hub]: flush=failed,err={}", err);
+                        }
+                    }
+                },
+                res = rpc_smreceiver_chan.recv() => {
+                    let Some((vfd, sender)) = res else {
+                        error!(log, "[db_hub]: rpc_smreceiver_chan=close");
+                        break;
+                    };
+                    let challenge = rpc_guard.connect(vfd);
+                    if let Err(err) = network::try_send_rpc(&sender, vfd, ProtoType::RpcChallenge(challenge)) {
+                        error!(log, "[db_hub]: rpc_challenge=failed,vfd={},err={}", vfd, err);
+                    }
+                    rpc_conns.insert(vfd, sender);
+                    info!(log, "[db_hub]: new rpc connection channel: vfd={}", vfd);
+                },
+                res = rpc_smreceiver.recv() => {
+                    let Some(msg) = res else {
+                        error!(log, "[db_hub]: rpc_smreceiver=close");
+                        break;
+                    };
+                    //把通道中已有的请求一起取出, 作为一批执行
+                    let mut reqs = Vec::new();
+                    accept(&mut rpc_guard, &mut rpc_conns, &mut log, msg, &mut reqs);
+                    while reqs.len() < batch_size {
+                        let Ok(msg) = rpc_smreceiver.try_recv() else {
+                            break;
+                        };
+                        accept(&mut rpc_guard, &mut rpc_conns, &mut log, msg, &mut reqs);
+                    }
+                    if reqs.is_empty() {
+                        continue;
+                    }
+                    let num = reqs.len();
+                    let _timer = metrics::hub_timer("db");
+                    let mut state = db.take().unwrap();
+                    let (state, resps) = tokio::task::spawn_blocking(move || {
+                        let resps = state.execute(reqs);
+                        (state, resps)
+                    })
+                    .await
+                    .unwrap();
+                    db = Some(state);
+                    debug!(log, "[db_hub]: batch={}", num);
+                    for (host, resp) in resps {
+                        let msg = (MessageType::Rpc, host as u64, ProtoType::DbResp(resp).into());
+                        if rpc_sender.send(msg).await.is_err() {
+                            error!(log, "[db_hub]: rpc_sender=close,host={}", host);
+                        }
+                    }
+                },
+            }
+            metrics::hub_queued("db", rpc_smreceiver.len());
+        }
+
+        //停止前提交所有延迟写入的数据
+        let mut state = db.take().unwrap();
+        match tokio::task::spawn_blocking(move || state.close())
+            .await
+            .unwrap()
+        {
+            Ok(n) => {
+                info!(log, "[db_hub]: close=ok,flush={}", n);
+            }
+            Err(err) => {
+                error!(log, "[db_hub]: close=failed,err={}", err);
+            }
+        }
+        drop(all_srv_close_sender);
+        info!(log, "[db_hub]: service=stop");
+    });
+}
+
+//通过认证和权限检查的请求放入 reqs
+fn accept(
+    rpc_guard: &mut RpcGuard,
+    rpc_conns: &mut HashMap<u64, SMSender>,
+    log: &mut Outter,
+    msg: SystemMsg,
+    reqs: &mut Vec<DbReq>,
+) {
+    let (msg_type, session, packet) = msg;
+    if msg_type == MessageType::SocketClosed {
+        rpc_guard.close(session);
+        rpc_conns.remove(&session);
+        info!(log, "[db_hub]: rpc connection close: vfd={}", session);
+        return;
+    }
+    match packet
+        .into_proto()
+        .and_then(|pto| rpc_guard.check(session, pto))
+    {
+        Ok(Some(ProtoType::DbReq(req))) => reqs.push(req),
+        Ok(Some(pto)) => {
+            error!(
+                log,
+                "[db_hub]: unsupport_proto={},session={}",
+                pto.inner_info().0,
+                session
+            );
+        }
+        Ok(None) => {
+            if let Some(peer) = rpc_guard.peer(session) {
+                info!(log, "[db_hub]: rpc_auth=ok,vfd={},peer={:?}", session, peer);
+            }
+        }
+        Err(err) => {
+            error!(
+                log,
+                "[db_hub]: rpc_guard=reject,session={},err={}", session, err
+            );
+            if rpc_guard.peer(session).is_none() {
+                rpc_conns.remove(&session);
+            }
+        }
+    }
+}
@@ -1,32 +1,112 @@
-use crate::config::Config;
-use crate::logger::build_logger;
-use crate::message::MessageType;
+use crate::config::{reload, Config, ConfigWatch};
+use crate::logger::{build_logger, Outter};
+use crate::message::{MessageType, Packet, ProtoType, SMReceiver, SystemMsg};
+use crate::metrics;
+use crate::modules::scheduler::{Class, SchedConf, Scheduler};
 use crate::modules::Module;
-use crate::{error, info};
+use crate::network::http::ChanHttpProtoReceiverOp;
+use crate::network::{self, rpc_auth::RpcGuard};
+use crate::states::GameState;
+use crate::{debug, error, info};
 
 use chrono::Local;
+use std::future;
+use std::sync::Arc;
 use tokio::{
-    sync::mpsc::Sender,
+    sync::mpsc::{Receiver, Sender},
     time::{self, Duration},
 };
 
-pub fn start(conf: Config, mut tm: Module, mut rpcm: Module, all_srv_close_sender: Sender<()>) {
+//tcp 和 rpc 的 vfd 各自分配, 调度时需要区分来源; 分片消息按发送的分片排队
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Lane {
+    Tcp,
+    Rpc,
+    Shard,
+}
+
+type HubScheduler = Scheduler<(Lane, u64), (MessageType, Packet)>;
+
+//停止时等待 db 回复期间, 重新提交失败数据的间隔, 毫秒
+const DATA_RETRY_MS: u64 = 100;
+
+//开启分片时每个分片运行一个, 只有 0 号分片带有 rpcm; admin 为 http 管理接口发来的请求
+pub fn start(
+    conf: Config,
+    mut tm: Module,
+    rpcm: Option<Module>,
+    mut admin: Option<ChanHttpProtoReceiverOp>,
+    all_srv_close_sender: Sender<()>,
+) {
     tokio::spawn(async move {
         let mut log = build_logger("game_hub.log");
-        info!(log, "[game_hub]: service=start");
+        info!(log, "[game_hub]: service=start,module={}", tm.name());
+        //分片时每个分片一个 hub, 指标按模块名区分
+        let hub = tm.name().to_owned();
+        let hub_timer = format!("{hub}_timer");
 
         let mut smreceiver_chan = tm.take_smreceiver_chan().unwrap();
         let mut smreceiver = tm.take_smreceiver().unwrap();
         let mut gs = tm.take_game_state().unwrap();
 
-        let mut rpc_smreceiver_chan = rpcm.take_smreceiver_chan().unwrap();
-        let mut rpc_smreceiver = rpcm.take_smreceiver().unwrap();
-        let mut rpc_gs = rpcm.take_game_state().unwrap();
+        let (mut rpc_smreceiver_chan, mut rpc_smreceiver, mut rpc_gs) = match rpcm {
+            Some(mut rpcm) => (
+                rpcm.take_smreceiver_chan(),
+                rpcm.take_smreceiver(),
+                rpcm.take_game_state(),
+            ),
+            None => (None, None, None),
+        };
+        //rpc 连接需要先完成认证, 才能调用脚本层的 rpc 函数
+        let mut rpc_guard = RpcGuard::from_config(&conf).unwrap();
+
+        //收到的消息先按连接排队, 再按优先级和连接轮流处理
+        let mut sched: HubScheduler = Scheduler::new(SchedConf::from_config(&conf));
+        let budget = sched.conf().budget;
+        //已排队未处理的 rpc 消息数, 达到上限时不再从通道接收, rpc 读端等待, 对端的额度随之用完
+        let rpc_queue_max = sched.conf().rpc_queue_max;
+        let mut rpc_queued = 0;
 
         let fps = conf.get_int("fps").unwrap_or(10); //fps 默认为 10 帧,即定时器每一tick的时间为 1000/10 毫秒
         let mut heart_beat = time::interval(Duration::from_millis(1000 / fps as u64));
+        //重新加载的配置, 与当前配置比较后通知脚本层, 帧率变化时重建定时器
+        let mut live = conf.watch();
+        let mut current = Arc::new(conf.clone());
         loop {
             tokio::select! {
+                //按顺序检查: 定时器, 新连接, rpc 消息, 游戏消息; 有排队的消息时不等待
+                biased;
+                _ = heart_beat.tick() => {
+                    let _timer = metrics::hub_timer(&hub_timer);
+                    let now_ms = Local::now().timestamp_millis();
+                    gs.update_timer(now_ms);
+                    gs.update_sessions(now_ms);
+                    gs.update_shard();
+                    gs.update_data(now_ms);
+                },
+                res = changed_opt(&mut live) => {
+                    let Some(new) = res else {
+                        live = None;
+                        continue;
+                    };
+                    let changes = reload::diff(&current, &new);
+                    if changes.iter().any(|c| c.key == "fps") {
+                        let fps = new.get_int("fps").unwrap_or(10).max(1);
+                        heart_beat = time::interval(Duration::from_millis(1000 / fps as u64));
+                    }
+                    let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+                    info!(log, "[game_hub]: config_changed={:?}", keys);
+                    gs.config_changed(&changes);
+                    current = new;
+                },
+                res = recv_opt(&mut admin) => {
+                    let Some((req, resp)) = res else {
+                        admin = None;
+                        continue;
+                    };
+                    debug!(log, "[game_hub]: admin={}", req);
+                    let _ = resp.send(gs.admin(req));
+                },
                 // for tcp connection
                 res = smreceiver_chan.recv() => {
                     if let Some((vfd,sender)) = res {
@@ -37,25 +117,14 @@ pub fn start(conf: Config, mut tm: Module, mut rpcm: Module, all_srv_close_sende
                         break;
                     }
                 },
-                res = smreceiver.recv() => {
-                    if let Some((msg_type, session, pto)) = res {
-                        if msg_type != MessageType::SocketClosed {
-                            if let Err(err) = gs.dispatch(msg_type, session, pto) {
-                                error!(log,"[game_hub]: dispatch=failed,msg_type={:?},session={},err={}",msg_type,session,err);
-                            }
-                        } else {
-                            rpc_gs.delete_vfd(session);
-                            info!(log,"[game_hub]: tcp connection close: vfd={}",session);
-                        }
-                    } else {
-                        error!(log,"[game_hub]: smreceiver=close");
-                        break;
-                    }
-                },
                 // for rpc connection
                 //对于 rpc, rpc_gs 仅维护连接的 sender; 其余消息路由到 gs 处理
-                res = rpc_smreceiver_chan.recv() => {
-                    if let Some((vfd,sender)) = res {
+                res = recv_opt(&mut rpc_smreceiver_chan) => {
+                    if let (Some((vfd,sender)), Some(rpc_gs)) = (res, rpc_gs.as_mut()) {
+                        let challenge = rpc_guard.connect(vfd);
+                        if let Err(err) = network::try_send_rpc(&sender, vfd, ProtoType::RpcChallenge(challenge)) {
+                            error!(log,"[game_hub]: rpc_challenge=failed,vfd={},err={}",vfd,err);
+                        }
                         rpc_gs.add_vfd(vfd,sender);
                         info!(log,"[game_hub]: new rpc connection channel: vfd={}",vfd);
                     } else {
@@ -63,28 +132,349 @@ pub fn start(conf: Config, mut tm: Module, mut rpcm: Module, all_srv_close_sende
                         break;
                     }
                 },
-                res = rpc_smreceiver.recv() => {
-                    if let Some((msg_type, session, pto)) = res {
-                        if msg_type != MessageType::SocketClosed {
-                            if let Err(err) = gs.rpc_dispatch(msg_type, session, pto) {
-                                error!(log,"[game_hub]: rpc_dispatch=failed,msg_type={:?},session={},err={}",msg_type,session,err);
-                            }
-                        } else {
-                            rpc_gs.delete_vfd(session);
-                            info!(log,"[game_hub]: rpc connection close: vfd={}",session);
-                        }
-                    } else {
+                res = recv_opt(&mut rpc_smreceiver), if rpc_queued < rpc_queue_max => {
+                    let (Some(msg), Some(receiver)) = (res, rpc_smreceiver.as_mut()) else {
                         error!(log,"[game_hub]: rpc_smreceiver=close");
                         break;
-                    }
+                    };
+                    enqueue(&mut sched, &mut log, Lane::Rpc, msg);
+                    let max = budget.min(rpc_queue_max - rpc_queued - 1);
+                    rpc_queued += 1 + drain(&mut sched, &mut log, Lane::Rpc, receiver, max);
                 },
-                _ = heart_beat.tick() => {
-                    let now_ms = Local::now().timestamp_millis();
-                    gs.update_timer(now_ms);
+                res = smreceiver.recv() => {
+                    let Some(msg) = res else {
+                        error!(log,"[game_hub]: smreceiver=close");
+                        break;
+                    };
+                    enqueue(&mut sched, &mut log, Lane::Tcp, msg);
+                    drain(&mut sched, &mut log, Lane::Tcp, &mut smreceiver, budget);
+                },
+                _ = future::ready(()), if !sched.is_empty() => {},
+            }
+
+            //每次循环最多处理 budget 条, 之后回到 select 检查定时器和新消息
+            let timer = (!sched.is_empty()).then(|| metrics::hub_timer(&hub));
+            for _ in 0..budget {
+                let Some(((lane, session), (msg_type, packet))) = sched.pop() else {
+                    break;
+                };
+                match (lane, rpc_gs.as_mut()) {
+                    (Lane::Rpc, Some(rpc_gs)) => {
+                        rpc_queued -= 1;
+                        handle_rpc(
+                            &mut gs,
+                            rpc_gs,
+                            &mut rpc_guard,
+                            &mut log,
+                            msg_type,
+                            session,
+                            packet,
+                        )
+                    }
+                    _ => handle_tcp(&mut gs, &mut log, msg_type, session, packet),
                 }
             }
+            drop(timer);
+            let queued =
+                sched.len() + smreceiver.len() + rpc_smreceiver.as_ref().map_or(0, |r| r.len());
+            metrics::hub_queued(&hub, queued);
+        }
+        //停止前提交所有玩家的脏数据, 等待 db 服务确认, 超时后记录没有保存的键
+        let flushed = gs.close_data();
+        let mut smreceiver = Some(smreceiver);
+        let unsaved = wait_data(
+            &mut gs,
+            rpc_gs.as_mut(),
+            &mut rpc_guard,
+            &mut log,
+            &mut smreceiver,
+            &mut rpc_smreceiver,
+        )
+        .await;
+        if !unsaved.is_empty() {
+            error!(log, "[game_hub]: data_unsaved={:?}", unsaved);
         }
         drop(all_srv_close_sender);
-        info!(log, "[game_hub]: service=stop");
+        info!(
+            log,
+            "[game_hub]: service=stop,data_flushed={},data_unsaved={}",
+            flushed,
+            unsaved.len()
+        );
     });
 }
+
+//等待提交的数据都收到 db 服务的回复, 最多等待 data_save_timeout_ms; 返回没有保存的键
+//  只处理 rpc 消息和分片消息, 其他分片发出的 db 请求由 0 号分片收到回复后转发过来
+async fn wait_data(
+    gs: &mut GameState,
+    mut rpc_gs: Option<&mut GameState>,
+    rpc_guard: &mut RpcGuard,
+    log: &mut Outter,
+    smreceiver: &mut Option<SMReceiver>,
+    rpc_smreceiver: &mut Option<SMReceiver>,
+) -> Vec<String> {
+    let Some(timeout) = gs.data_timeout() else {
+        return Vec::new();
+    };
+    let deadline = time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut retry = time::interval(Duration::from_millis(DATA_RETRY_MS));
+    loop {
+        let unsaved = gs.unsaved_data();
+        if unsaved.is_empty() || (smreceiver.is_none() && rpc_smreceiver.is_none()) {
+            return unsaved;
+        }
+        tokio::select! {
+            _ = &mut deadline => return unsaved,
+            //转发给其他分片的回复, 和提交失败后重新提交的数据
+            _ = retry.tick() => {
+                gs.update_shard();
+                gs.close_data();
+            },
+            res = recv_opt(rpc_smreceiver) => match (res, rpc_gs.as_deref_mut()) {
+                (Some((msg_type, session, packet)), Some(rpc_gs)) => {
+                    handle_rpc(gs, rpc_gs, rpc_guard, log, msg_type, session, packet)
+                }
+                _ => *rpc_smreceiver = None,
+            },
+            res = recv_opt(smreceiver) => match res {
+                Some((MessageType::Shard, session, packet)) => handle_shard(gs, log, session, packet),
+                Some(_) => {}
+                None => *smreceiver = None,
+            },
+        }
+    }
+}
+
+fn enqueue(sched: &mut HubScheduler, log: &mut Outter, lane: Lane, msg: SystemMsg) {
+    let (msg_type, session, packet) = msg;
+    let key = (lane, session);
+    //rpc 消息, 分片消息和连接断开不能丢弃; 游戏消息超过连接的排队上限时丢弃, 只影响该连接自己
+    if lane == Lane::Rpc {
+        sched.push_unbounded(key, Class::Rpc, (msg_type, packet));
+    } else if msg_type == MessageType::Shard {
+        //迁移和 fence 需要和该连接的游戏消息保持顺序, 其他分片的消息按发送的分片排队
+        let key = match packet {
+            Packet::Proto(ProtoType::ShardMsg(_)) => (Lane::Shard, session),
+            _ => key,
+        };
+        sched.push_unbounded(key, Class::Rpc, (msg_type, packet));
+    } else if msg_type == MessageType::SocketClosed {
+        sched.push_unbounded(key, Class::Gameplay, (msg_type, packet));
+    } else {
+        let class = sched.conf().classify(packet.proto_id());
+        if let Err((_, packet)) = sched.push(key, class, (msg_type, packet)) {
+            metrics::send_failed("hub_queue", "full");
+            error!(
+                log,
+                "[game_hub]: queue=full,vfd={},proto_id={}",
+                session,
+                packet.proto_id()
+            );
+        }
+    }
+}
+
+//把通道中已有的消息一起取出排队, 最多 max 条, 返回取出的数量
+fn drain(
+    sched: &mut HubScheduler,
+    log: &mut Outter,
+    lane: Lane,
+    receiver: &mut SMReceiver,
+    max: usize,
+) -> usize {
+    let mut num = 0;
+    while num < max {
+        match receiver.try_recv() {
+            Ok(msg) => enqueue(sched, log, lane, msg),
+            Err(_) => break,
+        }
+        num += 1;
+    }
+    debug!(log, "[game_hub]: lane={:?},queued={}", lane, sched.len());
+    num
+}
+
+fn handle_tcp(
+    gs: &mut GameState,
+    log: &mut Outter,
+    msg_type: MessageType,
+    session: u64,
+    packet: Packet,
+) {
+    match msg_type {
+        MessageType::SocketClosed => {
+            if let Some(shard) = gs.get_shard() {
+                shard.close(session);
+            }
+            gs.close_session(session);
+            gs.delete_vfd(session);
+            info!(log, "[game_hub]: tcp connection close: vfd={}", session);
+        }
+        MessageType::Shard => handle_shard(gs, log, session, packet),
+        _ => {
+            let Some((msg_type, packet)) = intercept_session(gs, log, msg_type, session, packet)
+            else {
+                return;
+            };
+            //已经迁移到其他分片的连接, 迟到的消息转发过去
+            if let Some(shard) = gs.get_shard() {
+                if let Some(to) = shard.moved_to(session) {
+                    if let Err(err) = shard.forward(to, msg_type, session, packet) {
+                        error!(log, "[game_hub]: shard_forward=failed,err={}", err);
+                    }
+                    return;
+                }
+            }
+            if let Err(err) = packet
+                .into_proto()
+                .and_then(|pto| gs.dispatch(msg_type, session, pto))
+            {
+                error!(
+                    log,
+                    "[game_hub]: dispatch=failed,msg_type={:?},session={},err={}",
+                    msg_type,
+                    session,
+                    err
+                );
+            }
+        }
+    }
+}
+
+//其他分片和路由发来的消息
+fn handle_shard(gs: &mut GameState, log: &mut Outter, session: u64, packet: Packet) {
+    let res = packet.into_proto().and_then(|pto| match pto {
+        ProtoType::ShardFence(fence) => match gs.get_shard() {
+            Some(shard) => {
+                debug!(log, "[game_hub]: shard_fence=true,vfd={}", fence.vfd);
+                shard.fence(fence.vfd)
+            }
+            None => Err("[game_hub]: not_shard=true".into()),
+        },
+        pto @ (ProtoType::SessionMigrate(_)
+        | ProtoType::SessionAck(_)
+        | ProtoType::SessionRelay(_)
+        | ProtoType::GateOpen(_)) => dispatch_session(gs, log, pto),
+        ProtoType::DbResp(resp) => gs.db_dispatch(resp),
+        pto @ ProtoType::RpcResp(_) => gs.rpc_dispatch(MessageType::Shard, session, pto),
+        pto => gs.shard_dispatch(session, pto),
+    });
+    if let Err(err) = res {
+        error!(
+            log,
+            "[game_hub]: shard_dispatch=failed,session={},err={}", session, err
+        );
+    }
+}
+
+//迁移中的会话暂存消息, 已经迁移到其他机器的会话把消息转发过去; 返回需要本地处理的消息
+fn intercept_session(
+    gs: &mut GameState,
+    log: &mut Outter,
+    msg_type: MessageType,
+    vfd: u64,
+    packet: Packet,
+) -> Option<(MessageType, Packet)> {
+    let Some(session) = gs.get_session() else {
+        return Some((msg_type, packet));
+    };
+    let (msg_type, packet) = session.hold(vfd, msg_type, packet)?;
+    let Some(host) = session.remote_of(vfd) else {
+        return Some((msg_type, packet));
+    };
+    if let Err(err) = session.relay(vfd, host, packet) {
+        error!(
+            log,
+            "[game_hub]: session_relay=failed,vfd={},err={}", vfd, err
+        );
+    }
+    None
+}
+
+//会话迁移和网关的消息, 其他机器转来的连接消息按本机连接处理
+fn dispatch_session(gs: &mut GameState, log: &mut Outter, pto: ProtoType) -> crate::Result<()> {
+    if let Some((msg_type, vfd, packet)) = gs.session_dispatch(pto)? {
+        handle_tcp(gs, log, msg_type, vfd, packet);
+    }
+    Ok(())
+}
+
+fn handle_rpc(
+    gs: &mut GameState,
+    rpc_gs: &mut GameState,
+    rpc_guard: &mut RpcGuard,
+    log: &mut Outter,
+    msg_type: MessageType,
+    session: u64,
+    packet: Packet,
+) {
+    if msg_type == MessageType::SocketClosed {
+        rpc_guard.close(session);
+        rpc_gs.delete_vfd(session);
+        info!(log, "[game_hub]: rpc connection close: vfd={}", session);
+        return;
+    }
+    match packet
+        .into_proto()
+        .and_then(|pto| rpc_guard.check(session, pto))
+    {
+        Ok(Some(pto)) => {
+            let res = match pto {
+                ProtoType::SessionMigrate(_)
+                | ProtoType::SessionAck(_)
+                | ProtoType::SessionRelay(_)
+                | ProtoType::GateOpen(_) => dispatch_session(gs, log, pto),
+                ProtoType::DbResp(resp) => gs.db_dispatch(resp),
+                pto => gs.rpc_dispatch(msg_type, session, pto),
+            };
+            if let Err(err) = res {
+                error!(
+                    log,
+                    "[game_hub]: rpc_dispatch=failed,msg_type={:?},session={},err={}",
+                    msg_type,
+                    session,
+                    err
+                );
+            }
+        }
+        Ok(None) => {
+            if let Some(peer) = rpc_guard.peer(session) {
+                info!(
+                    log,
+                    "[game_hub]: rpc_auth=ok,vfd={},peer={:?}", session, peer
+                );
+            }
+        }
+        Err(err) => {
+            error!(
+                log,
+                "[game_hub]: rpc_guard=reject,session={},err={}", session, err
+            );
+            //未通过认证的连接直接断开, 已认证连接上被拒绝的调用只丢弃
+            if rpc_guard.peer(session).is_none() {
+                rpc_gs.delete_vfd(session);
+            }
+        }
+    }
+}
+
+//没有对应模块时一直等待, 不会被选中
+//配置不支持重新加载时一直等待; 发送端关闭时返回 None
+async fn changed_opt(live: &mut Option<ConfigWatch>) -> Option<Arc<Config>> {
+    match live {
+        Some(live) => match live.changed().await {
+            Ok(()) => Some(live.borrow_and_update().clone()),
+            Err(_) => None,
+        },
+        None => future::pending().await,
+    }
+}
+
+async fn recv_opt<T>(receiver: &mut Option<Receiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => future::pending().await,
+    }
+}
@@ -0,0 +1,289 @@
+use crate::config::Config;
+use crate::logger::{build_logger, Outter};
+use crate::message::{MessageType, Packet, ProtoType, SMSender, SystemMsg};
+use crate::metrics;
+use crate::modules::gate::{GateConf, GateTable};
+use crate::modules::Module;
+use crate::network::credit::Backlog;
+use crate::network::frame::Frame;
+use crate::network::{self, rpc_auth::RpcGuard};
+use crate::protos::{C2sKick, C2sLogin, GateOpen, ProtoMessage, SessionRelay};
+use crate::{error, info};
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Duration};
+
+//暂存消息的重试间隔, 毫秒
+const BACKLOG_RETRY_MS: u64 = 10;
+
+//网关: 客户端连接的消息转发给后端逻辑服, 后端发来的消息转发给客户端, 自身不运行脚本
+//tm 接收客户端连接的消息, rpcm 接收后端的 rpc 连接, rpc_sender 经 rpc 客户端发给后端
+pub fn start(
+    conf: Config,
+    mut tm: Module,
+    mut rpcm: Module,
+    rpc_sender: SMSender,
+    all_srv_close_sender: Sender<()>,
+) {
+    tokio::spawn(async move {
+        let mut log = build_logger("gate_hub.log");
+        let gate_conf = GateConf::from_config(&conf).unwrap();
+        info!(
+            log,
+            "[gate_hub]: service=start,backends={:?}", gate_conf.backends
+        );
+
+        let host_id = conf.get_int("host_id").unwrap();
+        let mut smreceiver_chan = tm.take_smreceiver_chan().unwrap();
+        let mut smreceiver = tm.take_smreceiver().unwrap();
+        let mut rpc_smreceiver_chan = rpcm.take_smreceiver_chan().unwrap();
+        let mut rpc_smreceiver = rpcm.take_smreceiver().unwrap();
+        //后端的 rpc 连接同样需要先完成认证
+        let mut rpc_guard = RpcGuard::from_config(&conf).unwrap();
+        //发给后端的消息按后端暂存, rpc 客户端处理不过来时定时重试, 同一后端的消息保持顺序
+        let backlog_max = conf.get_int("rpc_backlog_max").unwrap_or(10000).max(1) as usize;
+        let mut backlog = Backlog::new("gate_hub").with_max(backlog_max);
+        let mut backlog_tick = time::interval(Duration::from_millis(BACKLOG_RETRY_MS));
+
+        let mut table = GateTable::new(gate_conf);
+        let mut clients: HashMap<u64, SMSender> = HashMap::new();
+        let mut rpc_conns: HashMap<u64, SMSender> = HashMap::new();
+        loop {
+            tokio::select! {
+                biased;
+                res = smreceiver_chan.recv() => {
+                    let Some((vfd, sender)) = res else {
+                        error!(log, "[gate_hub]: smreceiver_chan=close");
+                        break;
+                    };
+                    let Some(backend) = table.connect(vfd) else {
+                        error!(log, "[gate_hub]: no_backend=true,vfd={}", vfd);
+                        kick(&mut log, &sender, vfd, "no backend");
+                        continue;
+                    };
+                    let open = ProtoType::GateOpen(GateOpen { vfd, host: host_id });
+                    if let Err(err) = backlog.send(&rpc_sender, backend as u64, rpc_msg(backend, open)) {
+                        error!(log, "[gate_hub]: gate_open=failed,vfd={},backend={},err={}", vfd, backend, err);
+                        table.close(vfd);
+                        kick(&mut log, &sender, vfd, "backend unavailable");
+                        continue;
+                    }
+                    clients.insert(vfd, sender);
+                    info!(log, "[gate_hub]: new tcp connection: vfd={},backend={}", vfd, backend);
+                },
+                res = rpc_smreceiver_chan.recv() => {
+                    let Some((vfd, sender)) = res else {
+                        error!(log, "[gate_hub]: rpc_smreceiver_chan=close");
+                        break;
+                    };
+                    let challenge = rpc_guard.connect(vfd);
+                    if let Err(err) = network::try_send_rpc(&sender, vfd, ProtoType::RpcChallenge(challenge)) {
+                        error!(log, "[gate_hub]: rpc_challenge=failed,vfd={},err={}", vfd, err);
+                    }
+                    rpc_conns.insert(vfd, sender);
+                    info!(log, "[gate_hub]: new rpc connection channel: vfd={}", vfd);
+                },
+                res = rpc_smreceiver.recv() => {
+                    let Some((msg_type, vfd, packet)) = res else {
+                        error!(log, "[gate_hub]: rpc_smreceiver=close");
+                        break;
+                    };
+                    let _timer = metrics::hub_timer("gate");
+                    handle_rpc(&mut table, &mut clients, &mut rpc_conns, &mut rpc_guard, &mut log, msg_type, vfd, packet);
+                },
+                res = smreceiver.recv() => {
+                    let Some((msg_type, vfd, packet)) = res else {
+                        error!(log, "[gate_hub]: smreceiver=close");
+                        break;
+                    };
+                    let _timer = metrics::hub_timer("gate");
+                    handle_tcp(&mut table, &mut clients, &mut backlog, &rpc_sender, host_id, &mut log, msg_type, vfd, packet);
+                },
+                _ = backlog_tick.tick(), if !backlog.is_empty() => {
+                    for backend in backlog.vfds() {
+                        if let Err(err) = backlog.flush(&rpc_sender, backend) {
+                            error!(log, "[gate_hub]: backlog_flush={}", err);
+                        }
+                    }
+                },
+            }
+            metrics::hub_queued("gate", smreceiver.len() + rpc_smreceiver.len());
+        }
+        drop(all_srv_close_sender);
+        info!(log, "[gate_hub]: service=stop");
+    });
+}
+
+//客户端发来的消息, 登录成功之前只转发登录协议
+#[allow(clippy::too_many_arguments)]
+fn handle_tcp(
+    table: &mut GateTable,
+    clients: &mut HashMap<u64, SMSender>,
+    backlog: &mut Backlog,
+    rpc_sender: &SMSender,
+    host_id: i32,
+    log: &mut Outter,
+    msg_type: MessageType,
+    vfd: u64,
+    packet: Packet,
+) {
+    if msg_type == MessageType::SocketClosed {
+        clients.remove(&vfd);
+        if let Some(backend) = table.close(vfd) {
+            let relay = SessionRelay {
+                vfd,
+                host: host_id,
+                closed: true,
+                ..Default::default()
+            };
+            let msg = rpc_msg(backend, ProtoType::SessionRelay(relay));
+            if let Err(err) = backlog.send(rpc_sender, backend as u64, msg) {
+                error!(
+                    log,
+                    "[gate_hub]: relay_close=failed,vfd={},err={}", vfd, err
+                );
+            }
+        }
+        info!(log, "[gate_hub]: tcp connection close: vfd={}", vfd);
+        return;
+    }
+    let backend = match table.allow(vfd, packet.proto_id()) {
+        Ok(backend) => backend,
+        Err(err) => {
+            error!(log, "[gate_hub]: forward=failed,vfd={},err={}", vfd, err);
+            return;
+        }
+    };
+    let res = packet.into_frame().and_then(|frame| {
+        let relay = SessionRelay {
+            vfd,
+            host: host_id,
+            proto_id: frame.proto_id,
+            flags: frame.flags as u32,
+            body: frame.body.to_vec(),
+            closed: false,
+        };
+        let msg = rpc_msg(backend, ProtoType::SessionRelay(relay));
+        backlog.send(rpc_sender, backend as u64, msg)
+    });
+    //丢了中间的消息, 连接的状态就和后端不一致了, 断开连接
+    if let Err(err) = res {
+        error!(log, "[gate_hub]: forward=failed,vfd={},err={}", vfd, err);
+        if let Some(sender) = clients.get(&vfd) {
+            kick(log, sender, vfd, "forward failed");
+        }
+    }
+}
+
+fn rpc_msg(backend: i32, pto: ProtoType) -> SystemMsg {
+    (MessageType::Rpc, backend as u64, Packet::Proto(pto))
+}
+
+//通知客户端后断开连接, 写端写出 C2sKick 后关闭
+fn kick(log: &mut Outter, sender: &SMSender, vfd: u64, reason: &str) {
+    let kick = ProtoType::C2sKick(C2sKick {
+        reason: reason.to_owned(),
+    });
+    if let Err(err) = network::try_send(sender, vfd, kick) {
+        error!(log, "[gate_hub]: kick=failed,vfd={},err={}", vfd, err);
+    }
+}
+
+//后端发来的消息
+#[allow(clippy::too_many_arguments)]
+fn handle_rpc(
+    table: &mut GateTable,
+    clients: &mut HashMap<u64, SMSender>,
+    rpc_conns: &mut HashMap<u64, SMSender>,
+    rpc_guard: &mut RpcGuard,
+    log: &mut Outter,
+    msg_type: MessageType,
+    session: u64,
+    packet: Packet,
+) {
+    if msg_type == MessageType::SocketClosed {
+        rpc_guard.close(session);
+        rpc_conns.remove(&session);
+        info!(log, "[gate_hub]: rpc connection close: vfd={}", session);
+        return;
+    }
+    match packet
+        .into_proto()
+        .and_then(|pto| rpc_guard.check(session, pto))
+    {
+        Ok(Some(ProtoType::SessionRelay(relay))) => {
+            if let Err(err) = deliver(table, clients, relay) {
+                error!(
+                    log,
+                    "[gate_hub]: deliver=failed,session={},err={}", session, err
+                );
+            }
+        }
+        Ok(Some(pto)) => {
+            error!(
+                log,
+                "[gate_hub]: unsupport_proto={},session={}",
+                pto.inner_info().0,
+                session
+            );
+        }
+        Ok(None) => {
+            if let Some(peer) = rpc_guard.peer(session) {
+                info!(
+                    log,
+                    "[gate_hub]: rpc_auth=ok,vfd={},peer={:?}", session, peer
+                );
+            }
+        }
+        Err(err) => {
+            error!(
+                log,
+                "[gate_hub]: rpc_guard=reject,session={},err={}", session, err
+            );
+            if rpc_guard.peer(session).is_none() {
+                rpc_conns.remove(&session);
+            }
+        }
+    }
+}
+
+//只接受连接所在的后端发来的消息; 登录回复需要解码, 登录成功后放开其他协议, 压缩算法由网络层填写
+fn deliver(
+    table: &mut GateTable,
+    clients: &mut HashMap<u64, SMSender>,
+    relay: SessionRelay,
+) -> crate::Result<()> {
+    let vfd = relay.vfd;
+    if table.backend_of(vfd) != Some(relay.host) {
+        return Err(format!("[gate_hub]: wrong_backend={},vfd={vfd}", relay.host).into());
+    }
+    if relay.closed {
+        //后端释放了连接, 断开客户端
+        table.close(vfd);
+        if let Some(sender) = clients.remove(&vfd) {
+            let kick = ProtoType::C2sKick(C2sKick {
+                reason: "backend closed".to_owned(),
+            });
+            network::try_send(&sender, vfd, kick)?;
+        }
+        return Ok(());
+    }
+    let Some(sender) = clients.get(&vfd) else {
+        return Err(format!("[gate_hub]: nosender=true,vfd={vfd}").into());
+    };
+    let frame = Frame {
+        proto_id: relay.proto_id,
+        flags: relay.flags as u8,
+        body: relay.body.into(),
+    };
+    if frame.proto_id != C2sLogin::PROTO_ID {
+        return network::try_send_frame(sender, vfd, frame);
+    }
+    let pto = frame.decode()?;
+    if let ProtoType::C2sLogin(login) = &pto {
+        if login.ret == 0 {
+            table.authenticate(vfd);
+        }
+    }
+    network::try_send(sender, vfd, pto)
+}
@@ -0,0 +1,35 @@
+use crate::config::{Config, Reloader};
+use crate::logger::build_logger;
+use crate::network::http::service::{self as http_service, Admin};
+use crate::network::http::ChanHttpProtoSenderOp;
+use crate::{error, info};
+use std::future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+//http 管理端口, 配置了 http_addr 时启动; 随进程退出, 不参与服务的停止流程
+//hubs 为各个 game_hub 接收管理请求的通道, 网关和 db 服务没有 game_hub
+pub fn start(
+    conf: &Config,
+    hubs: Vec<ChanHttpProtoSenderOp>,
+    reloader: Option<Arc<Mutex<Reloader>>>,
+) {
+    let Some(addr) = conf.get_string("http_addr") else {
+        return;
+    };
+    let mut log = build_logger("http_hub.log");
+    let addr: SocketAddr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!(log, "[http_hub]: addr={},err={}", addr, err);
+            return;
+        }
+    };
+    let num = hubs.len();
+    let mut admin = Admin::new(conf.get_string("http_token").cloned()).with_hubs(hubs);
+    if let Some(reloader) = reloader {
+        admin = admin.with_reloader(reloader);
+    }
+    info!(log, "[http_hub]: service=start,addr={},hubs={}", addr, num);
+    tokio::spawn(http_service::start_service(addr, future::pending(), admin));
+}
@@ -2,8 +2,12 @@ use std::collections::HashMap;
 
 use crate::config::Config;
 use crate::logger::build_logger;
-use crate::message::{MessageType, ProtoType, ServiceType};
+use crate::logger::Outter;
+use crate::message::{MessageType, Packet, ProtoType, SMSender, ServiceType, SystemMsg};
+use crate::metrics;
 use crate::modules::Module;
+use crate::network::credit::Backlog;
+use crate::network::rpc_auth::RpcIdentity;
 use crate::network::tcp::service::{self as tcp_service};
 use crate::network::try_send_rpc;
 use crate::{error, info};
@@ -13,6 +17,12 @@ use tokio::{
     time::{self, Duration},
 };
 
+//暂存消息的重试间隔, 毫秒
+const BACKLOG_RETRY_MS: u64 = 10;
+const RPC_HOST_PREFIX: &str = "rpc_host.";
+//连接完成认证之前每个对端最多缓存的消息数
+const DELAY_MSG_MAX: usize = 500;
+
 pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
     tokio::spawn(async move {
         let mut log = build_logger("rpc_client_hub.log");
@@ -23,6 +33,13 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
         let mut gs = tm.take_game_state().unwrap();
         let _host_id = gs.get_host_id() as u64; //本服务的 hostid
         let mut heart_beat = time::interval(Duration::from_millis(1000));
+        //对端额度用完时发件队列会满, 这时消息先暂存在这里, 定时重试; 超过上限时丢弃
+        let backlog_max = conf.get_int("rpc_backlog_max").unwrap_or(10000).max(1) as usize;
+        let mut backlog = Backlog::new("rpc_client_hub").with_max(backlog_max);
+        let mut backlog_tick = time::interval(Duration::from_millis(BACKLOG_RETRY_MS));
+        //收到对端的 challenge 后用本端身份回复认证
+        let identity = RpcIdentity::from_config(&conf).unwrap();
+        let rpc_hosts = rpc_hosts(&conf);
 
         let mut rpc_client_srv = tcp_service::build(
             ServiceType::RPCCLIENT,
@@ -42,25 +59,15 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                     if let Some((vfd,sender)) = res {
                         gs.add_vfd(vfd,sender.clone());
                         info!(log,"[rpc_client_hub]: new rpc client connection channel: vfd={}",vfd);
-                        proceeding_connections.insert(vfd, 2); //连接已完成
-
-                        //清空 delay 的消息
-                        if let Some(delay_msg_v) = delay_msg.remove(&vfd) {
-                            info!(log,"[rpc_client_hub]: new rpc client connection delay messages: vfd={},num={}",vfd,delay_msg.len());
-                            for (_mtype, session, pto) in delay_msg_v {
-                                if let Err(err) = try_send_rpc(&sender, session, pto) {
-                                    error!(log,"[rpc_client_hub]: delay=true,try_send_rpc={}",err);
-                                }
-                            }
-                        }
+                        proceeding_connections.insert(vfd, 2); //连接已完成, 等待对端的 challenge
                     } else {
                         error!(log,"[rpc_client_hub]: smreceiver_chan=close");
                         break;
                     }
                 },
                 res = smreceiver.recv() => {
-                    if let Some((msg_type, session, pto)) = res {
-                        let (proto_id,_proto_name) = pto.inner_info();
+                    if let Some((msg_type, session, packet)) = res {
+                        let proto_id = packet.proto_id();
                         if _host_id == session {
                             //rpc服务消息不能发送给本服务
                             error!(log,"[rpc_client_hub]: route_self=true,proto_id={}",proto_id);
@@ -69,28 +76,48 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                             // rpc 的client连接只做发送, 对端回来的消息不做处理
                             match msg_type {
                                 MessageType::Rpc => {
-                                    if let Some(sender) = gs.get_sender(session) {
-                                        if let Err(err) = try_send_rpc(&sender, session, pto) {
+                                    //完成认证之前的消息先缓存, 认证之后再发送
+                                    let sender = match proceeding_connections.get(&session) {
+                                        Some(&3) => gs.get_sender(session),
+                                        _ => None,
+                                    };
+                                    if let Some(sender) = sender {
+                                        if let Err(err) = backlog.send(sender, session, (msg_type, session, packet)) {
                                             error!(log,"[rpc_client_hub]: try_send_rpc={}",err);
                                         }
                                     } else {
-                                        let val = proceeding_connections.get(&session);
-                                        if val.is_none() {
+                                        if let Some(&res) = proceeding_connections.get(&session) {
+                                            if res == 1 {
+                                                // connection is not finished
+                                                info!(log,"[rpc_client_hub]: connecting to {}",session);
+                                            } else if res == 2 {
+                                                // connection is finished, waiting for authentication
+                                                info!(log,"[rpc_client_hub]: authenticating to {}",session);
+                                            } else {
+                                                // authenticated, but something is wrong
+                                                error!(log,"[rpc_client_hub]: new_client_service={},something is wrong.",session);
+                                            }
+                                        } else {
                                             //没有对应的 rpc 客户端连接
                                             proceeding_connections.insert(session,1); //连接未完成
                                             let idenfity = session;
-                                            let addr = match &pto {
-                                                ProtoType::RpcSend(inner) => {
+                                            let addr = match &packet {
+                                                Packet::Proto(ProtoType::RpcSend(inner)) => {
                                                     &inner.to_addr
                                                 },
-                                                ProtoType::RpcResp(inner) => {
+                                                Packet::Proto(ProtoType::RpcResp(inner)) => {
                                                     &inner.to_addr
                                                 }
                                                 _ => {
                                                     ""
                                                 }
                                             };
-                                            if addr.len() != 0 {
+                                            //会话迁移和网关的消息不带地址, 按配置的 rpc_host 连接
+                                            let addr = match rpc_hosts.get(&session) {
+                                                Some(host_addr) if addr.is_empty() => host_addr.as_str(),
+                                                _ => addr,
+                                            };
+                                            if !addr.is_empty() {
                                                 if let Err(err) = rpc_client_srv.new_client_service(addr, idenfity).await {
                                                     error!(log,"[rpc_client_hub]: new_client_service={}",err);
                                                     proceeding_connections.remove(&idenfity); //清理标识
@@ -99,15 +126,6 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                                                 error!(log,"[rpc_client_hub]: wrong_addr={}",addr);
                                                 proceeding_connections.remove(&idenfity); //清理标识
                                             }
-                                        } else {
-                                            let res = *(val.unwrap());
-                                            if res == 1 {
-                                                // connection is not finished
-                                                info!(log,"[rpc_client_hub]: connecting to {}",session);
-                                            } else if res == 2 {
-                                                // connection is finished, but something is wrong
-                                                error!(log,"[rpc_client_hub]: new_client_service={},something is wrong.",session);
-                                            }
                                         }
                                         info!(log,"[rpc_client_hub]: rpc client connection start: vfd={}",session);
 
@@ -119,14 +137,47 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                                             delay_msg.insert(session, delay_msg_v);
                                             delay_msg.get_mut(&session).unwrap()
                                         };
-                                        if delay_msg_v.len() < 500 {
-                                            delay_msg_v.push((msg_type, session, pto));
+                                        //认证之前最多缓存 DELAY_MSG_MAX 条, 超过的丢弃并计数
+                                        if delay_msg_v.len() < DELAY_MSG_MAX {
+                                            delay_msg_v.push((msg_type, session, packet));
                                         } else {
-                                            info!(log,"[rpc_client_hub]: too many delay message,dumped. vfd={}",session);
+                                            metrics::backlog_dropped("rpc_client_hub", 1);
+                                            error!(log,"[rpc_client_hub]: delay=full,dropped=1,vfd={}",session);
                                         }
                                     }
                                 },
+                                MessageType::RpcClient => {
+                                    //对端回来的消息只处理认证的 challenge
+                                    match packet.into_proto() {
+                                        Ok(ProtoType::RpcChallenge(challenge)) => {
+                                            if challenge.host_id as u64 != session {
+                                                //连接到的不是期望的服务器
+                                                error!(log,"[rpc_client_hub]: wrong_host=true,vfd={},host_id={}",session,challenge.host_id);
+                                                gs.delete_vfd(session);
+                                            } else if let Some(sender) = gs.get_sender(session) {
+                                                let auth = identity.respond(&challenge);
+                                                if let Err(err) = try_send_rpc(sender, session, ProtoType::RpcAuth(auth)) {
+                                                    error!(log,"[rpc_client_hub]: rpc_auth=failed,vfd={},err={}",session,err);
+                                                }
+                                                proceeding_connections.insert(session, 3); //认证已发送
+                                                if let Some(delay_msg_v) = delay_msg.remove(&session) {
+                                                    flush_delay_msg(&mut log, &mut backlog, sender, session, delay_msg_v);
+                                                }
+                                            }
+                                        },
+                                        Ok(pto) => {
+                                            info!(log,"[rpc_client_hub]: ignore=true,vfd={},proto_id={}",session,pto.inner_info().0);
+                                        },
+                                        Err(err) => {
+                                            error!(log,"[rpc_client_hub]: decode=failed,vfd={},err={}",session,err);
+                                        },
+                                    }
+                                },
                                 MessageType::SocketClosed => {
+                                    let dropped = backlog.remove(session);
+                                    if dropped > 0 {
+                                        error!(log,"[rpc_client_hub]: backlog=dropped,vfd={},num={}",session,dropped);
+                                    }
                                     gs.delete_vfd(session);
                                     info!(log,"[rpc_client_hub]: rpc client connection close: vfd={}",session);
                                     proceeding_connections.remove(&session); //清理标识
@@ -141,6 +192,17 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                         break;
                     }
                 },
+                _ = backlog_tick.tick(), if !backlog.is_empty() => {
+                    for vfd in backlog.vfds() {
+                        let res = match gs.get_sender(vfd) {
+                            Some(sender) => backlog.flush(sender, vfd),
+                            None => Err(format!("[rpc_client_hub]: no_sender=true,vfd={vfd},dropped={}", backlog.remove(vfd)).into()),
+                        };
+                        if let Err(err) = res {
+                            error!(log,"[rpc_client_hub]: backlog_flush={}",err);
+                        }
+                    }
+                },
                 _ = heart_beat.tick() => {
                     //println!("rpc_client_hub service heart_beat");
                 }
@@ -150,3 +212,32 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
         info!(log, "[rpc_client_hub]: service=stop");
     });
 }
+
+//集群中其他服务器的 rpc 地址: rpc_host.<host_id> = <addr>
+fn rpc_hosts(conf: &Config) -> HashMap<u64, String> {
+    conf.get_prefixed(RPC_HOST_PREFIX)
+        .into_iter()
+        .filter_map(|(host, addr)| Some((host.parse().ok()?, addr.to_string())))
+        .collect()
+}
+
+//清空 delay 的消息
+fn flush_delay_msg(
+    log: &mut Outter,
+    backlog: &mut Backlog,
+    sender: &SMSender,
+    vfd: u64,
+    delay_msg_v: Vec<SystemMsg>,
+) {
+    info!(
+        log,
+        "[rpc_client_hub]: new rpc client connection delay messages: vfd={},num={}",
+        vfd,
+        delay_msg_v.len()
+    );
+    for msg in delay_msg_v {
+        if let Err(err) = backlog.send(sender, vfd, msg) {
+            error!(log, "[rpc_client_hub]: delay=true,try_send_rpc={}", err);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::Config;
+use crate::logger::{build_logger, Outter};
+use crate::message::{MessageType, Packet, SMSender, SMSenderChan, SystemMsg};
+use crate::metrics;
+use crate::modules::shard::{ShardConf, ShardRouter};
+use crate::modules::Module;
+use crate::network::{try_send, try_send_packet};
+use crate::protos::{C2sKick, Dummy, ProtoType, ShardFence};
+use crate::{error, info};
+use std::collections::HashMap;
+use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+
+//开启分片时代替 game_hub 接收游戏连接的消息, 按连接所属的分片转发
+//所有分片都登记所有连接的 sender, 任何分片都可以给任何连接发消息, 但只有所属的分片处理连接发来的消息
+//分片发来的迁移消息走单独的 control 通道, 路由发给分片的 fence 和暂存的消息等待通道有空间, 都不会丢失
+pub fn start(
+    _conf: Config,
+    mut tm: Module,
+    mut control: UnboundedReceiver<SystemMsg>,
+    shards: Vec<(SMSender, SMSenderChan)>,
+    shard_conf: ShardConf,
+    all_srv_close_sender: Sender<()>,
+) {
+    tokio::spawn(async move {
+        let mut log = build_logger("shard_hub.log");
+        info!(log, "[shard_hub]: service=start,shards={}", shards.len());
+
+        let mut smreceiver_chan = tm.take_smreceiver_chan().unwrap();
+        let mut smreceiver = tm.take_smreceiver().unwrap();
+        let mut router: ShardRouter<(MessageType, Packet)> = ShardRouter::new(shard_conf);
+        //放弃迁移时用来断开连接
+        let mut clients: HashMap<u64, SMSender> = HashMap::new();
+        loop {
+            tokio::select! {
+                biased;
+                res = control.recv() => {
+                    let Some((_, vfd, packet)) = res else {
+                        error!(log, "[shard_hub]: control=close");
+                        break;
+                    };
+                    let _timer = metrics::hub_timer("shard_router");
+                    handle_shard(&mut log, &mut router, &shards, vfd, packet).await;
+                },
+                res = smreceiver_chan.recv() => {
+                    let Some((vfd, sender)) = res else {
+                        error!(log, "[shard_hub]: smreceiver_chan=close");
+                        break;
+                    };
+                    let shard = router.connect(vfd);
+                    clients.insert(vfd, sender.clone());
+                    for (_, chan) in shards.iter() {
+                        if chan.send((vfd, sender.clone())).await.is_err() {
+                            error!(log, "[shard_hub]: shard_chan=close,vfd={}", vfd);
+                        }
+                    }
+                    info!(log, "[shard_hub]: new tcp connection: vfd={},shard={}", vfd, shard);
+                },
+                res = smreceiver.recv() => {
+                    let Some((msg_type, vfd, packet)) = res else {
+                        error!(log, "[shard_hub]: smreceiver=close");
+                        break;
+                    };
+                    let _timer = metrics::hub_timer("shard_router");
+                    match msg_type {
+                        MessageType::SocketClosed => {
+                            clients.remove(&vfd);
+                            let dropped = router.close(vfd);
+                            for (sender, _) in shards.iter() {
+                                let packet = Packet::Proto(ProtoType::Dummy(Dummy::default()));
+                                send(&mut log, sender, msg_type, vfd, packet);
+                            }
+                            info!(log, "[shard_hub]: tcp connection close: vfd={},dropped={}", vfd, dropped);
+                        },
+                        _ => match router.route(vfd, (msg_type, packet)) {
+                            Ok(Some((shard, (msg_type, packet)))) => {
+                                send(&mut log, &shards[shard].0, msg_type, vfd, packet);
+                            },
+                            Ok(None) => {},
+                            //旧分片迟迟处理不到 fence, 放弃迁移并断开连接, 断开后各分片清理
+                            Err(err) => {
+                                error!(log, "[shard_hub]: route=failed,err={}", err);
+                                if let Some(sender) = clients.get(&vfd) {
+                                    let kick = ProtoType::C2sKick(C2sKick { reason: "shard migrate aborted".to_owned() });
+                                    if let Err(err) = try_send(sender, vfd, kick) {
+                                        error!(log, "[shard_hub]: kick=failed,vfd={},err={}", vfd, err);
+                                    }
+                                }
+                            },
+                        },
+                    }
+                },
+            }
+            metrics::hub_queued("shard_router", smreceiver.len());
+        }
+        drop(all_srv_close_sender);
+        info!(log, "[shard_hub]: service=stop");
+    });
+}
+
+//迁移: 分片通知迁移后, 路由暂存新消息并给旧分片发 fence; 旧分片处理到 fence 时回复, 路由再把暂存的消息发给新分片
+async fn handle_shard(
+    log: &mut Outter,
+    router: &mut ShardRouter<(MessageType, Packet)>,
+    shards: &[(SMSender, SMSenderChan)],
+    vfd: u64,
+    packet: Packet,
+) {
+    match packet.into_proto() {
+        Ok(ProtoType::ShardMigrate(migrate)) => {
+            let (from, to) = (migrate.from_shard as usize, migrate.to_shard as usize);
+            if router.begin_migrate(vfd, from, to) {
+                let fence = ProtoType::ShardFence(ShardFence {
+                    vfd,
+                    shard: from as u32,
+                });
+                send_control(log, &shards[from].0, MessageType::Shard, vfd, fence.into()).await;
+                info!(
+                    log,
+                    "[shard_hub]: migrate=begin,vfd={},from={},to={}", vfd, from, to
+                );
+            } else {
+                error!(
+                    log,
+                    "[shard_hub]: migrate=refuse,vfd={},from={},to={},shard={:?}",
+                    vfd,
+                    from,
+                    to,
+                    router.shard_of(vfd)
+                );
+            }
+        }
+        Ok(ProtoType::ShardFence(_)) => {
+            if let Some((to, pending)) = router.end_migrate(vfd) {
+                info!(
+                    log,
+                    "[shard_hub]: migrate=end,vfd={},to={},pending={}",
+                    vfd,
+                    to,
+                    pending.len()
+                );
+                for (msg_type, packet) in pending {
+                    send_control(log, &shards[to].0, msg_type, vfd, packet).await;
+                }
+            }
+        }
+        Ok(pto) => {
+            error!(
+                log,
+                "[shard_hub]: unsupport_proto={},vfd={}",
+                pto.inner_info().0,
+                vfd
+            );
+        }
+        Err(err) => {
+            error!(log, "[shard_hub]: decode=failed,vfd={},err={}", vfd, err);
+        }
+    }
+}
+
+//分片不会等待路由, 这里等待分片的通道有空间不会死锁
+async fn send_control(
+    log: &mut Outter,
+    sender: &SMSender,
+    msg_type: MessageType,
+    vfd: u64,
+    packet: Packet,
+) {
+    if sender.send((msg_type, vfd, packet)).await.is_err() {
+        error!(log, "[shard_hub]: shard=closed,vfd={}", vfd);
+    }
+}
+
+fn send(log: &mut Outter, sender: &SMSender, msg_type: MessageType, vfd: u64, packet: Packet) {
+    if let Err(err) = try_send_packet(sender, msg_type, vfd, packet) {
+        error!(log, "[shard_hub]: {}", err);
+    }
+}
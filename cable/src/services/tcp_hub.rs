@@ -5,6 +5,7 @@ use crate::network::tcp::service as tcp_service;
 use crate::{error, info};
 use tokio::sync::mpsc;
 
+#[allow(clippy::too_many_arguments)]
 pub fn start(
     service_type: ServiceType,
     conf: Config,
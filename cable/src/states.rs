@@ -1,5 +1,5 @@
 pub mod game_state;
-pub use game_state::GameState;
+pub use game_state::{GameState, Handlers};
 
 pub mod tcp_state;
 pub use tcp_state::TcpState;
@@ -7,6 +7,15 @@ pub use tcp_state::TcpState;
 pub mod timer_state;
 pub use timer_state::TimerState;
 
+pub mod shard_state;
+pub use shard_state::ShardState;
+
+pub mod session_state;
+pub use session_state::SessionState;
+
+pub mod db_state;
+pub use db_state::DbState;
+
 use std::collections::HashMap;
 
 pub trait Communicate<T> {
@@ -0,0 +1,218 @@
+//db 服务的请求处理
+//
+//  请求按到达顺序逐条执行, 同一个玩家(同一个键)的写入严格按顺序生效
+//  写入先放进脏数据表, 读取时优先读脏数据, 所以总能读到之前的写入
+//  不开启延迟写入时, 每批请求执行完后把这一批的写入原子地提交, 提交成功后才回复
+//  开启延迟写入时, 写入立即回复, 脏数据按 db_write_behind_ms 定时提交, 或者达到 db_write_behind_max 条时提交;
+//  同一个键的多次写入只提交最后一次, 提交失败的保留到下次
+use crate::config::Config;
+use crate::modules::storage::{DbWrite, Storage};
+use crate::protos::{db_resp::Row, DbReq, DbResp};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbOp {
+    Load = 1,
+    Save = 2,
+    Delete = 3,
+    Query = 4,
+}
+
+impl TryFrom<u32> for DbOp {
+    type Error = crate::Error;
+
+    fn try_from(op: u32) -> crate::Result<Self> {
+        match op {
+            1 => Ok(DbOp::Load),
+            2 => Ok(DbOp::Save),
+            3 => Ok(DbOp::Delete),
+            4 => Ok(DbOp::Query),
+            _ => Err(format!("[db]: unknown_op={op}").into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbConf {
+    pub batch_size: usize,       //每批最多处理的请求数
+    pub write_behind_ms: u64,    //延迟写入的提交间隔, 0 表示不开启
+    pub write_behind_max: usize, //脏数据达到该条数时立即提交
+}
+
+impl Default for DbConf {
+    fn default() -> Self {
+        DbConf {
+            batch_size: 256,
+            write_behind_ms: 0,
+            write_behind_max: 10000,
+        }
+    }
+}
+
+impl DbConf {
+    pub fn from_config(conf: &Config) -> Self {
+        let default = DbConf::default();
+        DbConf {
+            batch_size: conf
+                .get_int("db_batch_size")
+                .map_or(default.batch_size, |n| n.max(1) as usize),
+            write_behind_ms: conf
+                .get_int("db_write_behind_ms")
+                .map_or(default.write_behind_ms, |n| n.max(0) as u64),
+            write_behind_max: conf
+                .get_int("db_write_behind_max")
+                .map_or(default.write_behind_max, |n| n.max(1) as usize),
+        }
+    }
+
+    pub fn is_write_behind(&self) -> bool {
+        self.write_behind_ms > 0
+    }
+}
+
+type DirtyKey = (String, String);
+
+pub struct DbState {
+    conf: DbConf,
+    storage: Arc<dyn Storage>,
+    dirty: BTreeMap<DirtyKey, Option<Vec<u8>>>, //[(表名, 键)] = 未提交的值, None 为删除
+}
+
+impl DbState {
+    pub fn new(conf: DbConf, storage: Arc<dyn Storage>) -> Self {
+        DbState {
+            conf,
+            storage,
+            dirty: BTreeMap::new(),
+        }
+    }
+
+    pub fn conf(&self) -> &DbConf {
+        &self.conf
+    }
+
+    pub fn dirty_len(&self) -> usize {
+        self.dirty.len()
+    }
+
+    //执行一批请求, 返回 (请求方, 回复), 与请求的顺序一致; 会阻塞, 需要在阻塞线程上调用
+    pub fn execute(&mut self, reqs: Vec<DbReq>) -> Vec<(i32, DbResp)> {
+        let mut resps = Vec::with_capacity(reqs.len());
+        let mut writes = Vec::new();
+        for req in reqs {
+            let (host, id) = (req.host, req.id);
+            let res = DbOp::try_from(req.op).and_then(|op| match op {
+                DbOp::Load => self.load(&req.table, &req.key),
+                DbOp::Query => self.query(&req.table, &req.key, req.limit as usize),
+                DbOp::Save | DbOp::Delete => {
+                    let value = (op == DbOp::Save).then_some(req.value);
+                    self.dirty.insert((req.table, req.key), value);
+                    writes.push(resps.len());
+                    Ok(Vec::new())
+                }
+            });
+            let resp = match res {
+                Ok(rows) => DbResp {
+                    id,
+                    ok: true,
+                    reason: String::new(),
+                    rows,
+                },
+                Err(err) => DbResp {
+                    id,
+                    ok: false,
+                    reason: err.to_string(),
+                    rows: Vec::new(),
+                },
+            };
+            resps.push((host, resp));
+        }
+
+        if self.conf.is_write_behind() {
+            if self.dirty.len() >= self.conf.write_behind_max {
+                let _ = self.flush();
+            }
+            return resps;
+        }
+        //不开启延迟写入时, 提交失败的写入全部回复失败, 不再保留
+        if let Err(err) = self.flush() {
+            self.dirty.clear();
+            for i in writes {
+                let resp = &mut resps[i].1;
+                resp.ok = false;
+                resp.reason = err.to_string();
+            }
+        }
+        resps
+    }
+
+    //把脏数据原子地提交, 返回提交的条数; 失败时脏数据保留
+    pub fn flush(&mut self) -> crate::Result<usize> {
+        if self.dirty.is_empty() {
+            return Ok(0);
+        }
+        let batch: Vec<DbWrite> = self
+            .dirty
+            .iter()
+            .map(|((table, key), value)| DbWrite {
+                table: table.clone(),
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        self.storage.write(&batch)?;
+        self.dirty.clear();
+        Ok(batch.len())
+    }
+
+    //服务停止时提交所有脏数据并落盘
+    pub fn close(&mut self) -> crate::Result<usize> {
+        let n = self.flush()?;
+        self.storage.flush()?;
+        Ok(n)
+    }
+
+    fn load(&self, table: &str, key: &str) -> crate::Result<Vec<Row>> {
+        let value = match self.dirty.get(&(table.to_owned(), key.to_owned())) {
+            Some(value) => value.clone(),
+            None => self.storage.load(table, key)?,
+        };
+        Ok(value
+            .map(|value| Row {
+                key: key.to_owned(),
+                value,
+            })
+            .into_iter()
+            .collect())
+    }
+
+    //存储中的结果和脏数据合并后再取前 limit 条
+    fn query(&self, table: &str, prefix: &str, limit: usize) -> crate::Result<Vec<Row>> {
+        let overlay: Vec<_> = self
+            .dirty
+            .range((table.to_owned(), prefix.to_owned())..)
+            .take_while(|((t, k), _)| t == table && k.starts_with(prefix))
+            .collect();
+        if overlay.is_empty() {
+            let rows = self.storage.query(table, prefix, limit)?;
+            return Ok(rows
+                .into_iter()
+                .map(|(key, value)| Row { key, value })
+                .collect());
+        }
+        let mut rows: BTreeMap<String, Vec<u8>> =
+            self.storage.query(table, prefix, 0)?.into_iter().collect();
+        for ((_, key), value) in overlay {
+            match value {
+                Some(value) => rows.insert(key.clone(), value.clone()),
+                None => rows.remove(key),
+            };
+        }
+        let rows = rows.into_iter().map(|(key, value)| Row { key, value });
+        Ok(match limit {
+            0 => rows.collect(),
+            n => rows.take(n).collect(),
+        })
+    }
+}
@@ -1,13 +1,27 @@
 use std::ffi::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use super::{Communicate, TcpState, TimerState};
-use crate::config::Config;
-use crate::logger::{build_logger, Outter};
+use super::{Communicate, SessionState, ShardState, TcpState, TimerState};
+use crate::config::{Change, Config};
+use crate::logger::{build_logger, new_trace_id, Outter, Trace};
 use crate::luautil;
-use crate::message::{MessageType, ProtoType, SMSender, ServiceType};
+use crate::message::{MessageType, Packet, ProtoType, SMSender, ServiceType, SystemMsg};
+use crate::metrics;
+use crate::modules::data_cache::{DataCache, DataConf, DataEvent};
+use crate::modules::session::Outgoing;
+use crate::network::http::HttpProtoType;
+use crate::states::session_state::Pending;
 use crate::{error, info};
 use crate::{network, protos::*};
-use rlua::{Function, Lua, Table};
+use rlua::{Function, Lua, RegistryKey, Table, Value};
+
+//脚本层的玩家数据缓存: 数据和等待加载的回调都放在 lua 的注册表中
+pub type LuaDataCache = DataCache<RegistryKey, RegistryKey>;
+
+//GameState 上注册的 rust 协议处理函数
+pub type Handlers = ProtoHandlers<GameState>;
 
 pub struct GameState {
     host_id: i32,
@@ -16,8 +30,19 @@ pub struct GameState {
     pub lua_state: Option<Lua>,
     tcp_state: Box<TcpState>,
     timer_state: Box<TimerState>,
+    shard: Option<ShardState>,
+    session: Option<SessionState>,
+    data: Option<Arc<Mutex<LuaDataCache>>>,
+    db_seq: Arc<AtomicU64>, //db 请求的 id, 高位为分片, db 的回复据此交给发出请求的分片
+    rpc_shard: Arc<AtomicU64>, //rpc 请求的 session 高位填入分片, 对端原样带回, 回复据此交给发出请求的分片
+    handlers: Arc<Handlers>,
 }
 
+//db 请求 id 中分片所在的位置
+const DB_SHARD_SHIFT: u32 = 48;
+//rpc 请求 session 中分片所在的位置, 脚本层的 session 需要小于 2^48
+pub(crate) const RPC_SHARD_SHIFT: u32 = 48;
+
 impl GameState {
     pub fn new(service_type: ServiceType, conf: Config, host_id: i32, log_name: &str) -> Self {
         let log = build_logger(log_name);
@@ -51,6 +76,12 @@ impl GameState {
             lua_state,
             tcp_state,
             timer_state,
+            shard: None,
+            session: None,
+            data: None,
+            db_seq: Arc::new(AtomicU64::new(1)),
+            rpc_shard: Arc::new(AtomicU64::new(0)),
+            handlers: Arc::new(Handlers::new()),
         }
     }
 
@@ -76,33 +107,319 @@ impl GameState {
         assert!(self.rpc.is_none());
         self.rpc = Some(rpc_sender.clone());
 
-        luautil::init_rpc_send(self, rpc_sender);
+        luautil::init_rpc_send(self, rpc_sender.clone(), self.rpc_shard.clone());
+        luautil::init_db(self, rpc_sender, self.db_seq.clone());
     }
 
     pub fn get_rpc_sender(&mut self) -> Option<&SMSender> {
         self.rpc.as_ref()
     }
 
+    pub fn set_handlers(&mut self, handlers: Handlers) {
+        self.handlers = Arc::new(handlers);
+    }
+
+    //多个分片共用同一组处理函数
+    pub fn set_shared_handlers(&mut self, handlers: Arc<Handlers>) {
+        self.handlers = handlers;
+    }
+
+    //作为逻辑分片运行, 注册脚本层的分片函数
+    pub fn set_shard(&mut self, shard: ShardState) {
+        assert!(self.shard.is_none());
+        luautil::init_shard(self, shard.clone());
+        self.db_seq.store(
+            ((shard.id() as u64) << DB_SHARD_SHIFT) + 1,
+            Ordering::Relaxed,
+        );
+        self.rpc_shard
+            .store((shard.id() as u64) << RPC_SHARD_SHIFT, Ordering::Relaxed);
+        self.shard = Some(shard);
+    }
+
+    pub fn get_shard(&self) -> Option<&ShardState> {
+        self.shard.as_ref()
+    }
+
+    //注册脚本层的会话迁移函数
+    pub fn set_session(&mut self, session: SessionState) {
+        assert!(self.session.is_none());
+        luautil::init_session(self, session.clone());
+        self.session = Some(session);
+    }
+
+    pub fn get_session(&self) -> Option<&SessionState> {
+        self.session.as_ref()
+    }
+
+    //开启玩家数据缓存, 需要先设置 rpc
+    pub fn set_data(&mut self, conf: DataConf) {
+        assert!(self.data.is_none());
+        let rpc = self.rpc.clone().unwrap();
+        let cache = Arc::new(Mutex::new(DataCache::new(conf, self.db_seq.clone())));
+        luautil::init_data(self, cache.clone(), rpc);
+        self.data = Some(cache);
+    }
+
+    pub fn get_data(&self) -> Option<&Arc<Mutex<LuaDataCache>>> {
+        self.data.as_ref()
+    }
+
+    //提交超时的重新标记为脏, 定时提交脏数据
+    pub fn update_data(&mut self, now: i64) {
+        let Some(cache) = self.data.clone() else {
+            return;
+        };
+        let keys = {
+            let mut cache = cache.lock().unwrap();
+            let expired = cache.expire(now);
+            if expired > 0 {
+                error!(self.log, "[update_data]: save_timeout={expired}");
+            }
+            cache.due(now, false)
+        };
+        self.save_data(&cache, keys, now);
+    }
+
+    //服务停止时提交所有脏数据, 返回提交的数量; 提交失败或者超时的重新标记为脏, 再次调用时重新提交
+    pub fn close_data(&mut self) -> usize {
+        let Some(cache) = self.data.clone() else {
+            return 0;
+        };
+        let now = chrono::Local::now().timestamp_millis();
+        let keys = cache.lock().unwrap().due(now, true);
+        self.save_data(&cache, keys, now)
+    }
+
+    //还没有保存到 db 服务的键, 停止时等待它们的回复
+    pub fn unsaved_data(&self) -> Vec<String> {
+        self.data
+            .as_ref()
+            .map_or_else(Vec::new, |cache| cache.lock().unwrap().unsaved())
+    }
+
+    //停止时最多等待 db 服务回复的时间, 没有开启玩家数据缓存时为 None
+    pub fn data_timeout(&self) -> Option<Duration> {
+        let cache = self.data.as_ref()?;
+        let ms = cache.lock().unwrap().conf().save_timeout_ms;
+        Some(Duration::from_millis(ms.max(0) as u64))
+    }
+
+    fn save_data(&mut self, cache: &Mutex<LuaDataCache>, keys: Vec<String>, now: i64) -> usize {
+        if keys.is_empty() {
+            return 0;
+        }
+        let (Some(lua_state), Some(rpc)) = (self.lua_state.as_ref(), self.rpc.as_ref()) else {
+            return 0;
+        };
+        let mut num = 0;
+        lua_state.context(|ctx| {
+            for key in keys {
+                match luautil::save_data(ctx, cache, rpc, &key, now) {
+                    Ok(true) => num += 1,
+                    Ok(false) => {}
+                    Err(err) => {
+                        error!(self.log, "[save_data]: key={key},err={err}");
+                    }
+                }
+            }
+        });
+        num
+    }
+
+    //重试暂存的 rpc 消息; 迁移超时的会话回滚, 并通知目标端放弃
+    pub fn update_sessions(&mut self, now: i64) {
+        let Some(session) = self.session.clone() else {
+            return;
+        };
+        if let Err(err) = session.flush() {
+            error!(self.log, "[update_sessions]: flush=failed,err={err}");
+        }
+        for (vfd, outgoing) in session.expired(now) {
+            if let Err(err) = session.abort(vfd, &outgoing, "timeout".to_string()) {
+                error!(
+                    self.log,
+                    "[update_sessions]: abort=failed,vfd={vfd},err={err}"
+                );
+            }
+            self.rollback(vfd, outgoing, "timeout".to_string());
+        }
+    }
+
+    //重试发给其他分片的暂存消息
+    pub fn update_shard(&mut self) {
+        let Some(shard) = self.shard.as_ref() else {
+            return;
+        };
+        if let Err(err) = shard.flush() {
+            error!(self.log, "[update_shard]: flush=failed,err={err}");
+        }
+    }
+
     pub fn update_timer(&mut self, now: i64) {
         if let Some(trigger) = self.timer_state.update(now) {
             self.lua_state.as_ref().unwrap().context(|ctx| {
                 let _timer_msg: Function = ctx.globals().get("_timer_msg").unwrap();
+                let _timer = metrics::lua_timer("_timer_msg");
                 let _ = _timer_msg.call::<Vec<u64>, ()>(trigger);
             });
         }
     }
 
+    //重新加载配置: 更新帧率和脚本层的日志等级, 再通知脚本层 _on_config_changed(diff)
+    //diff 以键为索引, 值为 {old = ..., new = ...}, 新增或者删除的键对应的一边为 nil
+    pub fn config_changed(&mut self, changes: &[Change]) {
+        let new_int = |key: &str| {
+            changes
+                .iter()
+                .find(|c| c.key == key)
+                .and_then(|c| c.new.as_deref()?.parse::<i32>().ok())
+        };
+        if let Some(fps) = new_int("fps") {
+            self.timer_state.set_fps(fps);
+        }
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return;
+        };
+        let res = lua_state.context(|ctx| {
+            let globals = ctx.globals();
+            if let Some(level) = new_int("log_level") {
+                let xlib: Table = globals.get("xlib")?;
+                xlib.set("log_level", level)?;
+            }
+            let Ok(callback) = globals.get::<_, Function>("_on_config_changed") else {
+                return Ok(());
+            };
+            let diff = ctx.create_table()?;
+            for change in changes {
+                let t = ctx.create_table()?;
+                t.set("old", change.old.clone())?;
+                t.set("new", change.new.clone())?;
+                diff.set(change.key.as_str(), t)?;
+            }
+            let _timer = metrics::lua_timer("_on_config_changed");
+            callback.call::<_, ()>(diff)
+        });
+        if let Err(err) = res {
+            error!(self.log, "[config_changed]: err={}", err);
+        }
+    }
+
+    //管理接口的请求; 脚本层的返回值转换为 json
+    pub fn admin(&mut self, req: HttpProtoType) -> HttpProtoType {
+        let res = match req {
+            HttpProtoType::ReqConns => Ok(Some(serde_json::json!(self.conns()))),
+            HttpProtoType::ReqKick(vfd, reason) => self.kick(vfd, reason),
+            HttpProtoType::ReqPlayer(vfd) => match self.get_sender(vfd) {
+                Some(_) => self
+                    .call_admin("_dump_player", vfd)
+                    .map(|v| (!v.is_null()).then_some(v)),
+                None => Ok(None),
+            },
+            HttpProtoType::ReqGM(cmd) => self.call_admin("_gm", cmd).map(Some),
+            HttpProtoType::ReqLogLevel(level) => self
+                .set_lua_log_level(level)
+                .map(|()| Some(serde_json::Value::Null))
+                .map_err(|err| err.to_string()),
+            HttpProtoType::ReqLua(code, readonly) => self
+                .console(&code, readonly)
+                .map(|text| Some(serde_json::Value::String(text))),
+            req => Err(format!("unexpected request: {req}")),
+        };
+        match res {
+            Ok(Some(value)) => HttpProtoType::Resp(value),
+            Ok(None) => HttpProtoType::NotFound,
+            Err(err) => {
+                error!(self.log, "[admin]: err={}", err);
+                HttpProtoType::Error(err)
+            }
+        }
+    }
+
+    //本 hub 上的所有连接, 按 vfd 排序
+    pub fn conns(&mut self) -> Vec<u64> {
+        let mut vfds: Vec<u64> = self.tcp_state.conn_map().keys().copied().collect();
+        vfds.sort_unstable();
+        vfds
+    }
+
+    //通知客户端后断开连接, 写端写出 C2sKick 后关闭; 连接不在本 hub 时返回 None
+    fn kick(&mut self, vfd: u64, reason: String) -> Result<Option<serde_json::Value>, String> {
+        let Some(sender) = self.get_sender(vfd) else {
+            return Ok(None);
+        };
+        network::try_send(sender, vfd, ProtoType::C2sKick(C2sKick { reason }))
+            .map_err(|err| err.to_string())?;
+        info!(self.log, "[admin]: kick=true,vfd={vfd}");
+        Ok(Some(serde_json::json!({ "ok": true, "vfd": vfd })))
+    }
+
+    fn call_admin<A: for<'lua> rlua::ToLuaMulti<'lua>>(
+        &self,
+        func: &str,
+        args: A,
+    ) -> Result<serde_json::Value, String> {
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Err("lua is not running".into());
+        };
+        lua_state.context(|ctx| {
+            let f: Function = ctx
+                .globals()
+                .get(func)
+                .map_err(|_| format!("{func}: not defined"))?;
+            let _timer = metrics::lua_timer(func);
+            f.call::<A, Value>(args)
+                .map(luautil::lua_to_json)
+                .map_err(|err| format!("{func}: {err}"))
+        })
+    }
+
+    //调试控制台的脚本, 结果为文本
+    fn console(&self, code: &str, readonly: bool) -> Result<String, String> {
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Err("lua is not running".into());
+        };
+        let _timer = metrics::lua_timer("_console");
+        luautil::console_eval(lua_state, code, readonly)
+    }
+
+    //脚本层自己按 xlib.log_level 过滤日志
+    fn set_lua_log_level(&self, level: i32) -> rlua::Result<()> {
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Ok(());
+        };
+        lua_state.context(|ctx| {
+            let xlib: Table = ctx.globals().get("xlib")?;
+            xlib.set("log_level", level)
+        })
+    }
+
     pub fn dispatch(
         &mut self,
         _msg_type: MessageType,
         vfd: u64,
         pto: ProtoType,
     ) -> crate::Result<()> {
+        //每条客户端消息一个新的 trace_id, 处理过程中的日志和发出的 rpc 都带上它
+        let _trace = Trace::new(new_trace_id())
+            .with_vfd(vfd)
+            .with_proto_id(pto.inner_info().0)
+            .enter();
+        //优先交给注册的 rust 处理函数, 未注册的协议再转换为 lua table 交给脚本层
+        let handlers = self.handlers.clone();
+        let pto = match handlers.dispatch(self, vfd, pto) {
+            Some(pto) => pto,
+            None => return Ok(()),
+        };
         let (proto_id, proto_name) = pto.inner_info();
-        self.lua_state.as_ref().unwrap().context(|ctx| {
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Err(format!("[dispatch]: unhandled=true,vfd={vfd},proto_id={proto_id}").into());
+        };
+        lua_state.context(|ctx| {
             let _tcp_msg: Function = ctx.globals().get("_tcp_msg").unwrap();
             match pto.encode_to_lua(ctx) {
                 Ok(t) => {
+                    let _timer = metrics::lua_timer("_tcp_msg");
                     let _ = _tcp_msg
                         .call::<(u64, u32, &str, Table), ()>((vfd, proto_id, proto_name, t));
                 }
@@ -117,15 +434,42 @@ impl GameState {
         Ok(())
     }
 
+    //rpc 请求交给脚本层 _rpc_msg; 回复按 session 中的分片交给发出请求的分片, 脚本层看到的是原来的 session
     pub fn rpc_dispatch(
         &mut self,
         _msg_type: MessageType,
         _vfd: u64,
         pto: ProtoType,
     ) -> crate::Result<()> {
+        let pto = match pto {
+            ProtoType::RpcResp(mut resp) => {
+                if let Some(shard) = self.shard.as_ref() {
+                    let to = (resp.session >> RPC_SHARD_SHIFT) as usize;
+                    if to != shard.id() {
+                        let pto = ProtoType::RpcResp(resp);
+                        return shard.forward(to, MessageType::Shard, 0, pto.into());
+                    }
+                }
+                resp.session &= (1 << RPC_SHARD_SHIFT) - 1;
+                ProtoType::RpcResp(resp)
+            }
+            pto => pto,
+        };
         let (proto_id, proto_name) = pto.inner_info();
+        //沿用对端的 trace_id, 旧版本的对端没有时重新生成
+        let trace_id = match &pto {
+            ProtoType::RpcSend(p) => p.trace_id.as_str(),
+            ProtoType::RpcResp(p) => p.trace_id.as_str(),
+            _ => "",
+        };
+        let trace_id = match trace_id.is_empty() {
+            true => new_trace_id(),
+            false => trace_id.to_owned(),
+        };
+        let _trace = Trace::new(trace_id).with_proto_id(proto_id).enter();
         self.lua_state.as_ref().unwrap().context(|ctx| {
             let _rpc_msg: Function = ctx.globals().get("_rpc_msg").unwrap();
+            let _timer = metrics::lua_timer("_rpc_msg");
             match pto {
                 ProtoType::RpcSend(p) => {
                     let _ = _rpc_msg.call::<(bool, i32, String, u64, String, String), ()>((
@@ -155,6 +499,410 @@ impl GameState {
         Ok(())
     }
 
+    //db 服务的回复交给脚本层 _db_resp(id, ok, reason, rows), 其他分片发出的请求转给该分片
+    pub fn db_dispatch(&mut self, resp: DbResp) -> crate::Result<()> {
+        if let Some(shard) = self.shard.as_ref() {
+            let to = (resp.id >> DB_SHARD_SHIFT) as usize;
+            if to != shard.id() {
+                return shard.forward(to, MessageType::Shard, 0, ProtoType::DbResp(resp).into());
+            }
+        }
+        let resp = match self.data.clone() {
+            Some(cache) if cache.lock().unwrap().owns(resp.id) => {
+                let now = chrono::Local::now().timestamp_millis();
+                let event = cache.lock().unwrap().on_resp(resp, now);
+                return match event {
+                    Ok(event) => self.data_event(&cache, event),
+                    Err(_) => Ok(()),
+                };
+            }
+            _ => resp,
+        };
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Err(format!("[db_dispatch]: unhandled=true,id={}", resp.id).into());
+        };
+        let id = resp.id;
+        let res = lua_state.context(|ctx| {
+            let rows = ctx.create_table()?;
+            for (i, row) in resp.rows.into_iter().enumerate() {
+                let t = ctx.create_table()?;
+                t.set("key", row.key)?;
+                t.set("value", ctx.create_string(&row.value)?)?;
+                rows.set(i + 1, t)?;
+            }
+            let _db_resp: Function = ctx.globals().get("_db_resp")?;
+            let _timer = metrics::lua_timer("_db_resp");
+            _db_resp.call::<(u64, bool, String, Table), ()>((id, resp.ok, resp.reason, rows))
+        });
+        res.map_err(|err| format!("[db_dispatch]: id={id},err={err}").into())
+    }
+
+    //缓存的加载结果交给等待的回调 cb(data, err)
+    fn data_event(
+        &mut self,
+        cache: &Mutex<LuaDataCache>,
+        event: DataEvent<RegistryKey, RegistryKey>,
+    ) -> crate::Result<()> {
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Ok(());
+        };
+        let res = lua_state.context(|ctx| match event {
+            DataEvent::Loaded {
+                key,
+                value,
+                waiters,
+            } => {
+                let doc = match value {
+                    Some(value) => ctx
+                        .load(&[b"return ", value.as_slice()].concat())
+                        .eval::<Table>(),
+                    None => ctx.create_table(),
+                };
+                let (doc, err) = match doc {
+                    Ok(doc) => {
+                        let doc_key = ctx.create_registry_value(doc.clone())?;
+                        cache.lock().unwrap().ready(&key, doc_key);
+                        (Value::Table(doc), Value::Nil)
+                    }
+                    Err(err) => {
+                        cache.lock().unwrap().cancel(&key);
+                        let err = format!("[data_load]: key={key},err={err}");
+                        (Value::Nil, Value::String(ctx.create_string(&err)?))
+                    }
+                };
+                luautil::call_waiters(ctx, waiters, doc, err)
+            }
+            DataEvent::LoadFailed {
+                key,
+                reason,
+                waiters,
+            } => {
+                let err = format!("[data_load]: key={key},err={reason}");
+                let err = Value::String(ctx.create_string(&err)?);
+                luautil::call_waiters(ctx, waiters, Value::Nil, err)
+            }
+            DataEvent::Saved { released, .. } => {
+                if let Some(doc_key) = released {
+                    ctx.remove_registry_value(doc_key)?;
+                }
+                Ok(())
+            }
+            DataEvent::SaveFailed { key, reason } => {
+                error!(self.log, "[data_event]: save=failed,key={key},err={reason}");
+                Ok(())
+            }
+        });
+        res.map_err(|err| format!("[data_event]: err={err}").into())
+    }
+
+    //其他分片发来的消息, 以及迁移进来的连接
+    pub fn shard_dispatch(&mut self, vfd: u64, pto: ProtoType) -> crate::Result<()> {
+        let (proto_id, proto_name) = pto.inner_info();
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Err(
+                format!("[shard_dispatch]: unhandled=true,vfd={vfd},proto_id={proto_id}").into(),
+            );
+        };
+        let res = lua_state.context(|ctx| match pto {
+            ProtoType::ShardMsg(p) => {
+                let _shard_msg: Function = ctx.globals().get("_shard_msg")?;
+                let _timer = metrics::lua_timer("_shard_msg");
+                _shard_msg.call::<(u32, String, String), ()>((p.from_shard, p.func, p.args))
+            }
+            ProtoType::ShardMigrate(p) => {
+                let _shard_migrate: Function = ctx.globals().get("_shard_migrate")?;
+                let _timer = metrics::lua_timer("_shard_migrate");
+                _shard_migrate.call::<(u64, u32, String), ()>((p.vfd, p.from_shard, p.data))
+            }
+            _ => Err(rlua::Error::RuntimeError(format!(
+                "unhandled proto: {proto_id},{proto_name}"
+            ))),
+        });
+        res.map_err(|err| {
+            format!("[shard_dispatch]: vfd={vfd},proto_id={proto_id},err={err}").into()
+        })
+    }
+
+    //会话迁移和网关的消息, 返回需要在本分片按连接消息处理的消息
+    pub fn session_dispatch(&mut self, pto: ProtoType) -> crate::Result<Option<SystemMsg>> {
+        let Some(session) = self.session.clone() else {
+            return Err("[session_dispatch]: no_session=true".into());
+        };
+        let shard = session.shard_id();
+        match pto {
+            ProtoType::SessionMigrate(m) if m.to_shard != shard => {
+                //其他机器发来的迁移先分配本机 vfd, 再交给目标分片
+                let vfd = if m.from_host == session.host_id() {
+                    m.vfd
+                } else {
+                    session.table().alias(m.from_host, m.vfd, m.to_shard)
+                };
+                let pto = ProtoType::SessionMigrate(m.clone());
+                if let Err(err) = session.forward(m.to_shard, MessageType::Shard, vfd, pto.into()) {
+                    //目标分片不存在, 直接拒绝
+                    if vfd != m.vfd {
+                        session.release(vfd);
+                    }
+                    session.ack(&m, false, err.to_string())?;
+                }
+            }
+            ProtoType::SessionMigrate(m) => self.session_resume(&session, m)?,
+            ProtoType::SessionAck(a) if a.abort => {
+                let (vfd, to) = if a.host == session.host_id() {
+                    (a.vfd, shard)
+                } else {
+                    let alias = session.table().alias_of(a.host, a.vfd);
+                    alias.ok_or_else(|| {
+                        format!(
+                            "[session_dispatch]: unknown_session=true,host={},vfd={}",
+                            a.host, a.vfd
+                        )
+                    })?
+                };
+                if to != shard {
+                    session.forward(
+                        to,
+                        MessageType::Shard,
+                        vfd,
+                        ProtoType::SessionAck(a).into(),
+                    )?;
+                    return Ok(None);
+                }
+                info!(
+                    self.log,
+                    "[session_dispatch]: abort=true,vfd={vfd},reason={}", a.reason
+                );
+                if session.release(vfd).is_some() {
+                    self.delete_vfd(vfd);
+                }
+                self.call_session("_session_abort", (vfd, a.reason))?;
+            }
+            ProtoType::SessionAck(a) => {
+                let vfd = a.vfd;
+                match session.table().frozen_by(vfd) {
+                    Some(from) if from != shard => {
+                        session.forward(
+                            from,
+                            MessageType::Shard,
+                            vfd,
+                            ProtoType::SessionAck(a).into(),
+                        )?;
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+                let Some(outgoing) = session.thaw(vfd, a.id) else {
+                    return Err(format!(
+                        "[session_dispatch]: stale_ack=true,vfd={vfd},id={}",
+                        a.id
+                    )
+                    .into());
+                };
+                if !a.ok {
+                    self.rollback(vfd, outgoing, a.reason);
+                    return Ok(None);
+                }
+                let res = match session.commit(vfd, outgoing) {
+                    Ok(()) => Ok(()),
+                    //暂存的消息交不出去, 通知目标端放弃, 会话留在本机
+                    Err((Some(outgoing), err)) => {
+                        let reason = err.to_string();
+                        if let Err(err) = session.abort(vfd, &outgoing, reason.clone()) {
+                            error!(
+                                self.log,
+                                "[session_dispatch]: abort=failed,vfd={vfd},err={err}"
+                            );
+                        }
+                        self.rollback(vfd, outgoing, reason);
+                        return Ok(None);
+                    }
+                    Err((None, err)) => Err(err),
+                };
+                info!(
+                    self.log,
+                    "[session_dispatch]: migrated=true,vfd={vfd},host={},shard={}", a.host, a.shard
+                );
+                self.call_session("_session_migrated", (vfd, true, String::new()))?;
+                res?;
+            }
+            ProtoType::GateOpen(g) => {
+                //网关上的新连接, 分配本机 vfd 后和本机的连接一样处理
+                let to = self
+                    .shard
+                    .as_ref()
+                    .map_or(0, |s| s.shard_of_vfd(g.vfd) as u32);
+                if to != shard {
+                    session.forward(
+                        to,
+                        MessageType::Shard,
+                        g.vfd,
+                        ProtoType::GateOpen(g).into(),
+                    )?;
+                    return Ok(None);
+                }
+                let (vfd, relay) = session.open(g.host, g.vfd, shard)?;
+                info!(
+                    self.log,
+                    "[session_dispatch]: gate_open=true,host={},gate_vfd={},vfd={vfd}",
+                    g.host,
+                    g.vfd
+                );
+                self.add_vfd(vfd, relay);
+            }
+            ProtoType::SessionRelay(r) => {
+                if session.remote_of(r.vfd) == Some(r.host) {
+                    //会话所在的机器发给连接的消息
+                    let Some(sender) = self.get_sender(r.vfd) else {
+                        return Err(
+                            format!("[session_dispatch]: nosender=true,vfd={}", r.vfd).into()
+                        );
+                    };
+                    session.deliver(sender, r)?;
+                    return Ok(None);
+                }
+                let alias = session.table().alias_of(r.host, r.vfd);
+                let Some((vfd, to)) = alias else {
+                    return Err(format!(
+                        "[session_dispatch]: unknown_session=true,host={},vfd={}",
+                        r.host, r.vfd
+                    )
+                    .into());
+                };
+                //连接所在的机器转来的消息, 和本机连接的消息一样处理
+                let (msg_type, packet) = if r.closed {
+                    (
+                        MessageType::SocketClosed,
+                        ProtoType::Dummy(Dummy::default()).into(),
+                    )
+                } else {
+                    let frame = network::frame::Frame {
+                        proto_id: r.proto_id,
+                        flags: r.flags as u8,
+                        body: r.body.into(),
+                    };
+                    (MessageType::Tcp, Packet::Encoded(frame))
+                };
+                if to != shard {
+                    session.forward(to, msg_type, vfd, packet)?;
+                    return Ok(None);
+                }
+                return Ok(Some((msg_type, vfd, packet)));
+            }
+            pto => {
+                let (proto_id, proto_name) = pto.inner_info();
+                return Err(format!(
+                    "[session_dispatch]: unhandled proto: {proto_id},{proto_name}"
+                )
+                .into());
+            }
+        }
+        Ok(None)
+    }
+
+    //连接断开: 迁移中的会话通知目标端放弃, 已迁移到其他机器的会话通知所在的机器
+    pub fn close_session(&mut self, vfd: u64) {
+        let Some(session) = self.session.clone() else {
+            return;
+        };
+        let (outgoing, remote) = session.table().close(vfd);
+        if let Some(outgoing) = outgoing {
+            if let Err(err) = session.abort(vfd, &outgoing, "closed".to_string()) {
+                error!(
+                    self.log,
+                    "[close_session]: abort=failed,vfd={vfd},err={err}"
+                );
+            }
+        }
+        if let Some(host) = remote {
+            if let Err(err) = session.relay_close(vfd, host) {
+                error!(
+                    self.log,
+                    "[close_session]: relay_close=failed,vfd={vfd},err={err}"
+                );
+            }
+        }
+        session.release(vfd);
+    }
+
+    //目标端恢复会话, 脚本层返回 false 或者出错时拒绝
+    fn session_resume(&mut self, session: &SessionState, m: SessionMigrate) -> crate::Result<()> {
+        let (vfd, relay) = match session.accept(&m) {
+            Ok(accepted) => accepted,
+            Err(err) => return session.ack(&m, false, err.to_string()),
+        };
+        let remote = relay.is_some();
+        if let Some(relay) = relay {
+            self.add_vfd(vfd, relay);
+        }
+        let res = match self.lua_state.as_ref() {
+            Some(lua_state) => lua_state.context(|ctx| {
+                let _session_resume: Function = ctx.globals().get("_session_resume")?;
+                let _timer = metrics::lua_timer("_session_resume");
+                _session_resume.call::<(u64, i32, u32, &str), bool>((
+                    vfd,
+                    m.from_host,
+                    m.from_shard,
+                    m.data.as_str(),
+                ))
+            }),
+            None => Ok(false),
+        };
+        let (ok, reason) = match res {
+            Ok(true) => (true, String::new()),
+            Ok(false) => (false, "refused".to_string()),
+            Err(err) => (false, err.to_string()),
+        };
+        info!(
+            self.log,
+            "[session_resume]: vfd={vfd},from_host={},from_shard={},ok={ok}",
+            m.from_host,
+            m.from_shard
+        );
+        if !ok && remote {
+            session.release(vfd);
+            self.delete_vfd(vfd);
+        }
+        session.ack(&m, ok, reason)
+    }
+
+    //迁移失败, 脚本层恢复会话, 冻结期间暂存的消息在本地按顺序处理
+    fn rollback(&mut self, vfd: u64, outgoing: Outgoing<Pending>, reason: String) {
+        info!(
+            self.log,
+            "[rollback]: vfd={vfd},id={},pending={},reason={reason}",
+            outgoing.id,
+            outgoing.pending.len()
+        );
+        if let Err(err) = self.call_session("_session_migrated", (vfd, false, reason)) {
+            error!(self.log, "{err}");
+        }
+        for (msg_type, packet) in outgoing.pending {
+            if let Err(err) = packet
+                .into_proto()
+                .and_then(|pto| self.dispatch(msg_type, vfd, pto))
+            {
+                error!(self.log, "[rollback]: dispatch=failed,vfd={vfd},err={err}");
+            }
+        }
+    }
+
+    fn call_session<A: for<'lua> rlua::ToLuaMulti<'lua>>(
+        &self,
+        func: &str,
+        args: A,
+    ) -> crate::Result<()> {
+        let Some(lua_state) = self.lua_state.as_ref() else {
+            return Ok(());
+        };
+        lua_state
+            .context(|ctx| {
+                let f: Function = ctx.globals().get(func)?;
+                let _timer = metrics::lua_timer(func);
+                f.call::<A, ()>(args)
+            })
+            .map_err(|err| format!("[call_session]: func={func},err={err}").into())
+    }
+
     pub fn robot_dispatch(
         &mut self,
         msg_type: MessageType,
@@ -172,7 +920,7 @@ impl GameState {
     fn _test_dispatch(
         &mut self,
         _msg_type: MessageType,
-        vfd: u64,
+        _vfd: u64,
         _pto: ProtoType,
     ) -> crate::Result<()> {
         // let mut c_inventory_req = CInventoryReq::default();
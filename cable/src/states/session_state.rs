@@ -0,0 +1,372 @@
+use super::ShardState;
+use crate::config::Config;
+use crate::message::{Frame, MessageType, Packet, SMSender, SystemMsg};
+use crate::modules::session::{Outgoing, SessionTable, SessionTarget};
+use crate::network::credit::Backlog;
+use crate::network::try_send_frame;
+use crate::protos::{ProtoType, SessionAck, SessionMigrate, SessionRelay};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub type Pending = (MessageType, Packet);
+
+//转发时暂存已满, 重试的间隔
+const RELAY_RETRY: Duration = Duration::from_millis(10);
+
+//会话迁移的状态, 本机所有分片共用同一张表, 每个分片各持有一份
+#[derive(Clone)]
+pub struct SessionState {
+    host_id: i32,
+    timeout_ms: i64,
+    relay_chan_size: usize,
+    shard: Option<ShardState>,
+    rpc: Option<SMSender>,
+    table: Arc<Mutex<SessionTable<Pending>>>,
+    backlog: Arc<Mutex<Backlog>>, //经 rpc 发给其他机器的消息, 发件队列满时按机器暂存, 保持顺序
+}
+
+impl SessionState {
+    pub fn new(conf: &Config) -> Self {
+        SessionState {
+            host_id: conf.get_int("host_id").unwrap_or(0),
+            timeout_ms: conf.get_int("session_migrate_timeout_ms").unwrap_or(5000) as i64,
+            relay_chan_size: conf.get_int("conn_msg_chan_size").unwrap_or(2000) as usize,
+            shard: None,
+            rpc: None,
+            table: Arc::new(Mutex::new(SessionTable::new())),
+            backlog: Arc::new(Mutex::new(Backlog::new("session").with_max(
+                conf.get_int("rpc_backlog_max").unwrap_or(10000).max(1) as usize,
+            ))),
+        }
+    }
+
+    //迁移到其他机器需要 rpc
+    pub fn with_rpc(mut self, rpc: SMSender) -> Self {
+        self.rpc = Some(rpc);
+        self
+    }
+
+    //开启分片时, 每个分片一份, 共用同一张表
+    pub fn with_shard(mut self, shard: ShardState) -> Self {
+        self.shard = Some(shard);
+        self
+    }
+
+    pub fn host_id(&self) -> i32 {
+        self.host_id
+    }
+
+    pub fn shard_id(&self) -> u32 {
+        self.shard.as_ref().map_or(0, |s| s.id() as u32)
+    }
+
+    pub fn table(&self) -> MutexGuard<'_, SessionTable<Pending>> {
+        self.table.lock().unwrap()
+    }
+
+    fn backlog(&self) -> MutexGuard<'_, Backlog> {
+        self.backlog.lock().unwrap()
+    }
+
+    //重试暂存的 rpc 消息, 每帧调用
+    pub fn flush(&self) -> crate::Result<()> {
+        let Some(rpc) = &self.rpc else {
+            return Ok(());
+        };
+        let mut backlog = self.backlog();
+        for host in backlog.vfds() {
+            backlog.flush(rpc, host)?;
+        }
+        Ok(())
+    }
+
+    //冻结会话并把数据发给目标端, 发送失败时立即解冻
+    pub fn migrate(
+        &self,
+        vfd: u64,
+        target: SessionTarget,
+        data: String,
+        now: i64,
+    ) -> crate::Result<u64> {
+        let shard = self.shard_id();
+        if target.host == self.host_id && target.shard == shard {
+            return Err(format!("[session]: migrate_self=true,vfd={vfd}").into());
+        }
+        let id = self
+            .table()
+            .freeze(vfd, shard, target, now + self.timeout_ms)?;
+        let migrate = SessionMigrate {
+            id,
+            vfd,
+            from_host: self.host_id,
+            from_shard: shard,
+            to_host: target.host,
+            to_shard: target.shard,
+            data,
+        };
+        if let Err(err) = self.send_to(target, vfd, ProtoType::SessionMigrate(migrate)) {
+            self.table().thaw(vfd, id);
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    //会话冻结时暂存消息, 返回 None
+    pub fn hold(&self, vfd: u64, msg_type: MessageType, packet: Packet) -> Option<Pending> {
+        self.table().hold(vfd, (msg_type, packet))
+    }
+
+    pub fn remote_of(&self, vfd: u64) -> Option<i32> {
+        self.table().remote_of(vfd)
+    }
+
+    pub fn thaw(&self, vfd: u64, id: u64) -> Option<Outgoing<Pending>> {
+        self.table().thaw(vfd, id)
+    }
+
+    //本分片发起的, 已经超时的迁移
+    pub fn expired(&self, now: i64) -> Vec<(u64, Outgoing<Pending>)> {
+        let shard = self.shard_id();
+        self.table().expired(shard, now)
+    }
+
+    //目标端已接管: 暂存的消息交给目标端, 之后的消息也转发过去
+    //暂存的消息交不出去时返回 outgoing, 由调用方通知目标端放弃并回滚; 返回 None 时目标端已经接管, 不能回滚
+    pub fn commit(
+        &self,
+        vfd: u64,
+        mut outgoing: Outgoing<Pending>,
+    ) -> Result<(), (Option<Outgoing<Pending>>, crate::Error)> {
+        let target = outgoing.target;
+        if target.host == self.host_id {
+            let shard = match self.local_shard() {
+                Ok(shard) => shard,
+                Err(err) => return Err((Some(outgoing), err)),
+            };
+            let to = target.shard as usize;
+            if let Err(err) = shard.handoff(vfd, to) {
+                return Err((Some(outgoing), err));
+            }
+            for (msg_type, packet) in outgoing.pending {
+                shard
+                    .forward(to, msg_type, vfd, packet)
+                    .map_err(|err| (None, err))?;
+            }
+            return Ok(());
+        }
+        let rpc = match self.rpc() {
+            Ok(rpc) => rpc,
+            Err(err) => return Err((Some(outgoing), err)),
+        };
+        //暂存的消息要么全部交给目标端, 要么都留在本机
+        let mut backlog = self.backlog();
+        let host = target.host as u64;
+        let num = outgoing.pending.len();
+        if backlog.room(host) < num {
+            let err = format!("[session]: commit=backlog_full,vfd={vfd},pending={num}").into();
+            return Err((Some(outgoing), err));
+        }
+        let mut pending = std::mem::take(&mut outgoing.pending).into_iter();
+        while let Some((msg_type, packet)) = pending.next() {
+            let frame = match packet.into_frame() {
+                Ok(frame) => frame,
+                Err(err) => {
+                    //编码失败的消息也交不出去, 和发送失败一样回滚
+                    outgoing.pending.extend(pending);
+                    return Err((Some(outgoing), err));
+                }
+            };
+            let relay = relay_of(vfd, self.host_id, frame.clone());
+            let msg = rpc_msg(target.host, ProtoType::SessionRelay(relay));
+            if let Err(err) = backlog.send(rpc, host, msg) {
+                outgoing.pending.push((msg_type, Packet::Encoded(frame)));
+                outgoing.pending.extend(pending);
+                return Err((Some(outgoing), err));
+            }
+        }
+        drop(backlog);
+        self.table().bind_remote(vfd, target.host);
+        Ok(())
+    }
+
+    //回复迁移结果给源端
+    pub fn ack(&self, migrate: &SessionMigrate, ok: bool, reason: String) -> crate::Result<()> {
+        let ack = SessionAck {
+            id: migrate.id,
+            vfd: migrate.vfd,
+            host: self.host_id,
+            shard: self.shard_id(),
+            ok,
+            abort: false,
+            reason,
+        };
+        let source = SessionTarget {
+            host: migrate.from_host,
+            shard: migrate.from_shard,
+        };
+        self.send_to(source, migrate.vfd, ProtoType::SessionAck(ack))
+    }
+
+    //源端放弃迁移, 通知目标端丢弃已经恢复的会话
+    pub fn abort(
+        &self,
+        vfd: u64,
+        outgoing: &Outgoing<Pending>,
+        reason: String,
+    ) -> crate::Result<()> {
+        let ack = SessionAck {
+            id: outgoing.id,
+            vfd,
+            host: self.host_id,
+            shard: outgoing.shard,
+            ok: false,
+            abort: true,
+            reason,
+        };
+        self.send_to(outgoing.target, vfd, ProtoType::SessionAck(ack))
+    }
+
+    //目标端接管会话, 返回会话在本机使用的 vfd; 来自其他机器时, 同时返回把消息转发回源机器的 sender
+    pub fn accept(&self, migrate: &SessionMigrate) -> crate::Result<(u64, Option<SMSender>)> {
+        if migrate.from_host == self.host_id {
+            return Ok((migrate.vfd, None));
+        }
+        let alias = self
+            .table()
+            .alias(migrate.from_host, migrate.vfd, migrate.to_shard);
+        let relay = self.spawn_relay(migrate.from_host, migrate.vfd)?;
+        Ok((alias, Some(relay)))
+    }
+
+    //网关上的新连接, 返回在本机使用的 vfd 和把消息转发回网关的 sender
+    pub fn open(&self, host: i32, vfd: u64, shard: u32) -> crate::Result<(u64, SMSender)> {
+        let relay = self.spawn_relay(host, vfd)?;
+        Ok((self.table().alias(host, vfd, shard), relay))
+    }
+
+    //脚本层发给会话的消息, 经 rpc 交给连接所在的机器; 会话释放时 sender 被丢弃, 协程结束
+    //  和其他会话消息走同一个暂存, 不会越过已经暂存的 SessionAck/SessionRelay
+    fn spawn_relay(&self, host: i32, vfd: u64) -> crate::Result<SMSender> {
+        let rpc = self.rpc()?.clone();
+        let backlog = self.backlog.clone();
+        let host_id = self.host_id;
+        let (tx, mut rx) = mpsc::channel::<SystemMsg>(self.relay_chan_size.max(1));
+        tokio::spawn(async move {
+            while let Some((_, _, packet)) = rx.recv().await {
+                let Ok(frame) = packet.into_frame() else {
+                    continue;
+                };
+                let msg = rpc_msg(host, ProtoType::SessionRelay(relay_of(vfd, host_id, frame)));
+                if relay_send(&backlog, &rpc, host as u64, msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tx)
+    }
+
+    //释放其他机器迁移过来的会话, 返回来源 (host, vfd)
+    pub fn release(&self, alias: u64) -> Option<(i32, u64)> {
+        self.table().unalias(alias)
+    }
+
+    //把连接的消息转发给会话所在的机器
+    pub fn relay(&self, vfd: u64, host: i32, packet: Packet) -> crate::Result<()> {
+        let relay = relay_of(vfd, self.host_id, packet.into_frame()?);
+        self.send_rpc(host, ProtoType::SessionRelay(relay))
+    }
+
+    //连接断开, 通知会话所在的机器
+    pub fn relay_close(&self, vfd: u64, host: i32) -> crate::Result<()> {
+        let relay = SessionRelay {
+            vfd,
+            host: self.host_id,
+            closed: true,
+            ..Default::default()
+        };
+        self.send_rpc(host, ProtoType::SessionRelay(relay))
+    }
+
+    //会话所在的机器发给连接的消息
+    pub fn deliver(&self, sender: &SMSender, relay: SessionRelay) -> crate::Result<()> {
+        let frame = Frame {
+            proto_id: relay.proto_id,
+            flags: relay.flags as u8,
+            body: relay.body.into(),
+        };
+        try_send_frame(sender, relay.vfd, frame)
+    }
+
+    //发给本机的其他分片, 或者其他机器
+    pub fn send_to(&self, target: SessionTarget, vfd: u64, pto: ProtoType) -> crate::Result<()> {
+        if target.host == self.host_id {
+            return self.forward(target.shard, MessageType::Shard, vfd, pto.into());
+        }
+        self.send_rpc(target.host, pto)
+    }
+
+    //经 rpc 发给其他机器, 和之前暂存的消息保持顺序; 暂存已满时返回错误
+    fn send_rpc(&self, host: i32, pto: ProtoType) -> crate::Result<()> {
+        let rpc = self.rpc()?;
+        self.backlog().send(rpc, host as u64, rpc_msg(host, pto))
+    }
+
+    //交给本机的其他分片
+    pub fn forward(
+        &self,
+        shard: u32,
+        msg_type: MessageType,
+        vfd: u64,
+        packet: Packet,
+    ) -> crate::Result<()> {
+        self.local_shard()?
+            .forward(shard as usize, msg_type, vfd, packet)
+    }
+
+    fn local_shard(&self) -> crate::Result<&ShardState> {
+        self.shard
+            .as_ref()
+            .ok_or_else(|| "[session]: not_shard=true".into())
+    }
+
+    fn rpc(&self) -> crate::Result<&SMSender> {
+        self.rpc
+            .as_ref()
+            .ok_or_else(|| "[session]: no_rpc=true".into())
+    }
+}
+
+//暂存已满时等待腾出空间再排入, 转发的消息不丢弃; 等待时由 sender 的接收端限流
+async fn relay_send(
+    backlog: &Mutex<Backlog>,
+    rpc: &SMSender,
+    host: u64,
+    msg: SystemMsg,
+) -> crate::Result<()> {
+    loop {
+        {
+            let mut backlog = backlog.lock().unwrap();
+            backlog.flush(rpc, host)?;
+            if backlog.room(host) > 0 {
+                return backlog.send(rpc, host, msg);
+            }
+        }
+        tokio::time::sleep(RELAY_RETRY).await;
+    }
+}
+
+fn rpc_msg(host: i32, pto: ProtoType) -> SystemMsg {
+    (MessageType::Rpc, host as u64, pto.into())
+}
+
+fn relay_of(vfd: u64, host: i32, frame: Frame) -> SessionRelay {
+    SessionRelay {
+        vfd,
+        host,
+        proto_id: frame.proto_id,
+        flags: frame.flags as u32,
+        body: frame.body.to_vec(),
+        closed: false,
+    }
+}
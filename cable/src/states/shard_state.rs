@@ -0,0 +1,201 @@
+use crate::message::{MessageType, Packet, SMSender, SystemMsg};
+use crate::modules::shard::{ShardConf, ShardKey};
+use crate::network::credit::Backlog;
+use crate::protos::{ProtoType, ShardFence, ShardMigrate, ShardMsg};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc::UnboundedSender;
+
+//分片发给路由的控制消息, 每次迁移只有两条, 不限长度, 不会因为连接的消息太多而丢失
+pub type ControlSender = UnboundedSender<SystemMsg>;
+
+//一个逻辑分片的状态, 脚本层的分片函数共用同一份
+#[derive(Clone)]
+pub struct ShardState {
+    id: usize,
+    conf: ShardConf,
+    senders: Vec<SMSender>,                  //所有分片的消息入口, 按分片编号
+    router: ControlSender,                   //路由协程的控制消息入口
+    moved: Arc<Mutex<HashMap<u64, usize>>>,  //已迁移出去, 还没有收到 fence 的连接, [vfd] = 目标分片
+    backlog: Arc<Mutex<Backlog>>, //发给其他分片的消息, 目标分片通道满时按目标分片暂存, 保持顺序
+    fences: Arc<Mutex<HashMap<u64, usize>>>, //转发的消息还在暂存中, 推迟通知路由的 fence, [vfd] = 目标分片
+}
+
+impl ShardState {
+    pub fn new(id: usize, conf: ShardConf, senders: Vec<SMSender>, router: ControlSender) -> Self {
+        assert!(id < senders.len() && senders.len() == conf.shards);
+        let backlog = Backlog::new("shard").with_max(conf.max_backlog);
+        ShardState {
+            id,
+            conf,
+            senders,
+            router,
+            moved: Arc::new(Mutex::new(HashMap::new())),
+            backlog: Arc::new(Mutex::new(backlog)),
+            fences: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn num(&self) -> usize {
+        self.senders.len()
+    }
+
+    //网关转来的新连接按新连接的策略分配
+    pub fn shard_of_vfd(&self, vfd: u64) -> usize {
+        self.conf.pick(ShardKey::Vfd(vfd))
+    }
+
+    pub fn shard_of_scene(&self, scene: u64) -> usize {
+        self.conf.pick(ShardKey::Scene(scene))
+    }
+
+    //连接已迁移到其他分片时, 返回目标分片, 迟到的消息需要转发过去
+    pub fn moved_to(&self, vfd: u64) -> Option<usize> {
+        self.moved.lock().unwrap().get(&vfd).copied()
+    }
+
+    fn sender(&self, shard: usize) -> crate::Result<&SMSender> {
+        self.senders
+            .get(shard)
+            .ok_or_else(|| format!("[shard]: wrong_shard={shard},num={}", self.num()).into())
+    }
+
+    fn backlog(&self) -> MutexGuard<'_, Backlog> {
+        self.backlog.lock().unwrap()
+    }
+
+    //排在发给同一分片的暂存消息后面; 暂存已满时返回错误, 由调用方决定重试还是放弃
+    fn push(
+        &self,
+        to: usize,
+        msg_type: MessageType,
+        vfd: u64,
+        packet: Packet,
+    ) -> crate::Result<()> {
+        let sender = self.sender(to)?;
+        self.backlog()
+            .send(sender, to as u64, (msg_type, vfd, packet))
+    }
+
+    //重试暂存的消息, 发给目标分片的暂存清空后再通知路由推迟的 fence
+    pub fn flush(&self) -> crate::Result<()> {
+        let mut res = Ok(());
+        let mut backlog = self.backlog();
+        for to in backlog.vfds() {
+            if let Err(err) = backlog.flush(self.sender(to as usize)?, to) {
+                res = Err(err);
+            }
+        }
+        let ready: Vec<u64> = {
+            let mut fences = self.fences.lock().unwrap();
+            let ready = fences
+                .iter()
+                .filter(|(_, &to)| backlog.len(to as u64) == 0)
+                .map(|(&vfd, _)| vfd)
+                .collect::<Vec<_>>();
+            for vfd in &ready {
+                fences.remove(vfd);
+            }
+            ready
+        };
+        drop(backlog);
+        for vfd in ready {
+            self.send_fence(vfd)?;
+        }
+        res
+    }
+
+    //发给其他分片的脚本层
+    pub fn send(&self, to: usize, func: String, args: String) -> crate::Result<()> {
+        let pto = ProtoType::ShardMsg(ShardMsg {
+            from_shard: self.id as u32,
+            to_shard: to as u32,
+            func,
+            args,
+        });
+        self.push(to, MessageType::Shard, self.id as u64, pto.into())
+    }
+
+    //把连接迁移到 to, data 交给目标分片的脚本层; 之后本分片不再处理该连接的消息
+    pub fn migrate(&self, vfd: u64, to: usize, data: String) -> crate::Result<()> {
+        if to == self.id {
+            return Err(format!("[shard]: migrate_self=true,vfd={vfd},shard={to}").into());
+        }
+        let migrate = ShardMigrate {
+            vfd,
+            from_shard: self.id as u32,
+            to_shard: to as u32,
+            data,
+        };
+        //先让目标分片接管, 再通知路由; 路由收到后给本分片发 fence
+        self.push(
+            to,
+            MessageType::Shard,
+            vfd,
+            ProtoType::ShardMigrate(migrate).into(),
+        )?;
+        self.handoff(vfd, to)
+    }
+
+    //目标分片已经接管连接, 之后收到的消息转发过去, 并通知路由切换
+    pub fn handoff(&self, vfd: u64, to: usize) -> crate::Result<()> {
+        if to == self.id || to >= self.num() {
+            return Err(format!("[shard]: wrong_handoff=true,vfd={vfd},shard={to}").into());
+        }
+        self.moved.lock().unwrap().insert(vfd, to);
+        let notify = ShardMigrate {
+            vfd,
+            from_shard: self.id as u32,
+            to_shard: to as u32,
+            data: String::new(),
+        };
+        self.control(vfd, ProtoType::ShardMigrate(notify))
+    }
+
+    //迁移前收到的消息已全部转发, 通知路由把暂存的消息发给目标分片
+    //  转发的消息还暂存在本分片时, 等 flush 把它们交给目标分片之后再通知, 路由的消息不会排到前面
+    pub fn fence(&self, vfd: u64) -> crate::Result<()> {
+        let to = self.moved.lock().unwrap().remove(&vfd);
+        if let Some(to) = to {
+            if self.backlog().len(to as u64) > 0 {
+                self.fences.lock().unwrap().insert(vfd, to);
+                return Ok(());
+            }
+        }
+        self.send_fence(vfd)
+    }
+
+    fn send_fence(&self, vfd: u64) -> crate::Result<()> {
+        let fence = ShardFence {
+            vfd,
+            shard: self.id as u32,
+        };
+        self.control(vfd, ProtoType::ShardFence(fence))
+    }
+
+    //路由已经退出时返回错误
+    fn control(&self, vfd: u64, pto: ProtoType) -> crate::Result<()> {
+        self.router
+            .send((MessageType::Shard, vfd, pto.into()))
+            .map_err(|_| format!("[shard]: router=closed,vfd={vfd}").into())
+    }
+
+    //转发迁移后迟到的消息, 和发给该分片的其他消息保持顺序
+    pub fn forward(
+        &self,
+        to: usize,
+        msg_type: MessageType,
+        vfd: u64,
+        packet: Packet,
+    ) -> crate::Result<()> {
+        self.push(to, msg_type, vfd, packet)
+    }
+
+    pub fn close(&self, vfd: u64) {
+        self.moved.lock().unwrap().remove(&vfd);
+    }
+}
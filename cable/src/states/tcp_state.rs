@@ -6,6 +6,12 @@ pub struct TcpState {
     conn_map: HashMap<u64, SMSender>, //存放所有完成连接后，暴露给 tcp 服务的网路连接的消息 chan，映射 [vfd] = sender
 }
 
+impl Default for TcpState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TcpState {
     pub fn new() -> Self {
         TcpState {
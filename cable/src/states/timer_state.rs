@@ -1,3 +1,4 @@
+use crate::metrics;
 use chrono::Local;
 
 //考虑到游戏业务中,对固定频率的定时器的使用,不同频率大小的定时器其实并不会很多,也不会无限大,而且一般都是短时的定时器比较多.
@@ -15,6 +16,12 @@ pub struct TimerState {
     once_orders: Vec<(i64, u64, i64)>, //只执行一次的 timer
 }
 
+impl Drop for TimerState {
+    fn drop(&mut self) {
+        metrics::timers_removed(self.len());
+    }
+}
+
 impl TimerState {
     pub fn new(fps: i32) -> Self {
         assert!(fps > 0);
@@ -26,6 +33,13 @@ impl TimerState {
         }
     }
 
+    //重新加载配置后修改帧率, 只影响之后添加的定时器
+    pub fn set_fps(&mut self, fps: i32) {
+        if fps > 0 {
+            self.fps = fps;
+        }
+    }
+
     // begin,freq 都是以毫秒为单位
     pub fn add_timer(&mut self, begin: i64, freq: i64) -> u64 {
         if freq < 0 || begin < 0 {
@@ -42,23 +56,24 @@ impl TimerState {
 
         let id = self.inc_id + 1;
         self.inc_id = id;
+        metrics::timers_added(1);
 
         let now_ms = Local::now().timestamp_millis();
-        let timeout = now_ms + (begin as i64);
+        let timeout = now_ms + begin;
         if freq == 0 {
             //这是一次性的定时器
             self.once_orders.push((timeout, id, freq));
             //保持有序
-            self.once_orders.sort_by(|a, b| a.0.cmp(&b.0));
+            self.once_orders.sort_by_key(|a| a.0);
             //println!("[add_timer]: once=true,{:?}", self.once_orders);
         } else {
             //这是以 freq 为频率执行的定时器
             self.orders.push((timeout, id, freq));
             //保持有序
-            self.orders.sort_by(|a, b| a.0.cmp(&b.0));
+            self.orders.sort_by_key(|a| a.0);
             //println!("[add_timer]: freq=true,{:?}", self.orders);
         }
-        return id;
+        id
     }
 
     pub fn remove_timer(&mut self, id: u64) {
@@ -68,6 +83,7 @@ impl TimerState {
             .position(|(_timeout, tid, _freq)| *tid == id)
         {
             self.orders.remove(pos);
+            metrics::timers_removed(1);
             //println!("[remove_timer]: freq=true,{:?}", self.orders);
         }
 
@@ -77,10 +93,19 @@ impl TimerState {
             .position(|(_timeout, tid, _freq)| *tid == id)
         {
             self.once_orders.remove(pos);
+            metrics::timers_removed(1);
             //println!("[remove_timer]: once=true,{:?}", self.once_orders);
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.orders.len() + self.once_orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn update(&mut self, now: i64) -> Option<Vec<u64>> {
         let num = self.orders.len();
         if num == 0 {
@@ -90,41 +115,43 @@ impl TimerState {
         let mut trigger_num = 0;
         for (timeout, _id, _freq) in &self.orders {
             if *timeout <= now {
-                trigger_num = trigger_num + 1;
+                trigger_num += 1;
             }
         }
 
         let mut trigger_num_once = 0;
         for (timeout, _id, _freq) in &self.once_orders {
             if *timeout <= now {
-                trigger_num_once = trigger_num_once + 1;
+                trigger_num_once += 1;
             }
         }
 
         if trigger_num == 0 && trigger_num_once == 0 {
-            return None;
+            None
         } else {
             //println!("[update]:{}", now);
             let mut trigger = Vec::with_capacity(trigger_num + trigger_num_once);
             if trigger_num > 0 {
                 for i in 0..trigger_num {
-                    let mut t = self.orders.get_mut(i).unwrap();
+                    let t = self.orders.get_mut(i).unwrap();
                     t.0 = now + t.2; //更新下一次触发时间
                     trigger.push(t.1);
                 }
                 //保持有序
-                self.orders.sort_by(|a, b| a.0.cmp(&b.0));
+                self.orders.sort_by_key(|a| a.0);
                 //println!("[update]: freq=true,{:?}", self.orders);
             }
             if trigger_num_once > 0 {
+                metrics::timers_removed(trigger_num_once);
                 for _i in 0..trigger_num_once {
                     let t = self.once_orders.remove(0); //从第一个移除,因为是一次性的
                     trigger.push(t.1);
                 }
                 //保持有序
-                self.once_orders.sort_by(|a, b| a.0.cmp(&b.0));
+                self.once_orders.sort_by_key(|a| a.0);
                 //println!("[update]: once=true,{:?}", self.once_orders);
             }
+            metrics::timers_fired(trigger.len());
             Some(trigger)
         }
     }
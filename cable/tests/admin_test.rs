@@ -0,0 +1,264 @@
+use cable::config::{ConfigLoader, Reloader, Schema};
+use cable::logger::{self, LogLevel};
+use cable::luautil;
+use cable::message::{Packet, ServiceType};
+use cable::network::http::service::{self as http_service, Admin};
+use cable::network::http::{ChanHttpProtoReceiverOp, ChanHttpProtoSenderOp, HttpProtoType};
+use cable::protos::ProtoType;
+use cable::states::GameState;
+use rlua::Lua;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+mod common;
+
+const TOKEN: &str = "t0ken";
+
+const MAIN_LUA: &str = r#"
+function _timer_msg() end
+function _dump_player(vfd)
+    if vfd == 8 then return nil end
+    return { vfd = vfd, name = "p" .. vfd, items = { 3, 2, 1 } }
+end
+function _gm(cmd)
+    if cmd == "boom" then error("bad command") end
+    return { cmd = cmd }
+end
+"#;
+
+fn write_conf(name: &str, extra: &str) -> String {
+    let content = format!(
+        "host_id = 1\nservice_type = game_service\nservice_addr = 0.0.0.0:8181\n\
+         rpc_service_addr = 0.0.0.0:8182\nrpc_secret = s\nlogic_path = {}\n\
+         http_addr = 127.0.0.1:8183\nhttp_token = {TOKEN}\n{extra}",
+        common::workdir().display()
+    );
+    let path = common::workdir().join(name);
+    std::fs::write(&path, content).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+async fn request(admin: Admin, method: &str, path: &str, body: &'static str) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method(method)
+        .path(path)
+        .header("authorization", format!("Bearer {TOKEN}"))
+        .body(body)
+        .reply(&http_service::routes(Arc::new(admin)))
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+//按请求回复固定内容的 hub, 同时返回收到的请求的记录
+fn fake_hub(
+    reply: impl Fn(&HttpProtoType) -> HttpProtoType + Send + 'static,
+) -> (ChanHttpProtoSenderOp, Arc<Mutex<Vec<String>>>) {
+    let (sender, mut receiver): (ChanHttpProtoSenderOp, ChanHttpProtoReceiverOp) = mpsc::channel(4);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    tokio::spawn(async move {
+        while let Some((req, resp)) = receiver.recv().await {
+            seen2.lock().unwrap().push(req.to_string());
+            let _ = resp.send(reply(&req));
+        }
+    });
+    (sender, seen)
+}
+
+#[tokio::test]
+async fn requires_token() {
+    let routes = http_service::routes(Arc::new(Admin::new(Some(TOKEN.into()))));
+    for auth in [None, Some("Bearer wrong"), Some(TOKEN)] {
+        let mut req = warp::test::request().path("/admin/conns");
+        if let Some(auth) = auth {
+            req = req.header("authorization", auth);
+        }
+        let resp = req.reply(&routes).await;
+        assert_eq!(resp.status(), 401, "{auth:?}");
+    }
+    //没有配置令牌时拒绝所有请求
+    let (status, _) = request(Admin::new(None), "GET", "/admin/conns", "").await;
+    assert_eq!(status, 401);
+    //指标不需要认证
+    let resp = warp::test::request().path("/metrics").reply(&routes).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn requests_go_to_hubs() {
+    let (hub0, seen0) = fake_hub(|req| match req {
+        HttpProtoType::ReqConns => HttpProtoType::Resp(json!([1, 2])),
+        HttpProtoType::ReqGM(cmd) => HttpProtoType::Resp(json!({ "cmd": cmd })),
+        _ => HttpProtoType::NotFound,
+    });
+    let (hub1, seen1) = fake_hub(|req| match req {
+        HttpProtoType::ReqConns => HttpProtoType::Resp(json!([5])),
+        HttpProtoType::ReqKick(5, _) => HttpProtoType::Resp(json!({ "ok": true, "vfd": 5 })),
+        HttpProtoType::ReqGM(_) => HttpProtoType::Error("_gm: not defined".into()),
+        _ => HttpProtoType::NotFound,
+    });
+    let admin = || Admin::new(Some(TOKEN.into())).with_hubs(vec![hub0.clone(), hub1.clone()]);
+
+    let (status, body) = request(admin(), "GET", "/admin/conns", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["count"], 3);
+    assert_eq!(body["conns"][2], json!({ "vfd": 5, "shard": 1 }));
+
+    //连接所在的分片回复
+    let (status, body) = request(admin(), "POST", "/admin/kick/5?reason=afk", "").await;
+    assert_eq!((status, body["vfd"].clone()), (200, json!(5)));
+    assert!(seen0.lock().unwrap().contains(&"ReqKick(5,afk)".to_owned()));
+    let (status, _) = request(admin(), "GET", "/admin/player/9", "").await;
+    assert_eq!(status, 404);
+
+    let (status, body) = request(admin(), "POST", "/admin/gm", "add_item 1001 2").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["result"]["cmd"], "add_item 1001 2");
+    let (status, body) = request(admin(), "POST", "/admin/gm?shard=1", "x").await;
+    assert_eq!(status, 500);
+    assert_eq!(body["ok"], false);
+    let (status, _) = request(admin(), "POST", "/admin/gm?shard=7", "x").await;
+    assert_eq!(status, 404);
+    assert_eq!(seen1.lock().unwrap().last().unwrap(), "ReqGM(x)");
+
+    //没有 game_hub 的服务
+    let (status, _) = request(Admin::new(Some(TOKEN.into())), "GET", "/admin/conns", "").await;
+    assert_eq!(status, 501);
+    let (status, _) = request(admin(), "GET", "/admin/unknown", "").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn log_level_and_reload() {
+    //只初始化一次, 之后不会再修改全局日志等级
+    common::workdir();
+    let (hub, seen) = fake_hub(|_| HttpProtoType::Resp(Value::Null));
+    let admin = || Admin::new(Some(TOKEN.into())).with_hubs(vec![hub.clone()]);
+    let (status, body) = request(admin(), "PUT", "/admin/log_level/2", "").await;
+    assert_eq!((status, body["new"].clone()), (200, json!(2)));
+    assert_eq!(logger::get_global_log_level(), LogLevel::Warning);
+    assert_eq!(seen.lock().unwrap().as_slice(), ["ReqLogLevel(2)"]);
+    let (status, _) = request(admin(), "PUT", "/admin/log_level/9", "").await;
+    assert_eq!(status, 400);
+
+    let path = write_conf("reload.conf", "");
+    let (reloader, _) = Reloader::new(ConfigLoader::new(&path, Schema::server())).unwrap();
+    let reloader = Arc::new(Mutex::new(reloader));
+    let admin = || Admin::new(Some(TOKEN.into())).with_reloader(reloader.clone());
+    write_conf("reload.conf", "limit_msg_rate = 5\n");
+    let (status, body) = request(admin(), "POST", "/admin/reload", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        body["changed"],
+        json!([{ "key": "limit_msg_rate", "old": null, "new": "5" }])
+    );
+    //不能重新加载的键, 密钥的值不输出
+    let text = std::fs::read_to_string(&path)
+        .unwrap()
+        .replace("rpc_secret = s", "rpc_secret = s2");
+    std::fs::write(&path, text).unwrap();
+    let (status, body) = request(admin(), "POST", "/admin/reload", "").await;
+    assert_eq!(status, 400);
+    assert_eq!(
+        body["errors"],
+        json!(["rpc_secret: ****** -> ****** requires a restart"])
+    );
+    let (status, _) = request(Admin::new(Some(TOKEN.into())), "POST", "/admin/reload", "").await;
+    assert_eq!(status, 501);
+}
+
+#[test]
+fn token_required_with_http_addr() {
+    let path = write_conf("no_token.conf", "");
+    let text = std::fs::read_to_string(&path)
+        .unwrap()
+        .replace(&format!("http_token = {TOKEN}\n"), "");
+    std::fs::write(&path, text).unwrap();
+    let err = ConfigLoader::new(&path, Schema::server())
+        .load()
+        .unwrap_err();
+    assert_eq!(
+        err.errors,
+        vec!["http_token: is required when http_addr is set"]
+    );
+}
+
+#[tokio::test]
+async fn game_state_admin() {
+    common::logic_dir(MAIN_LUA);
+    let path = write_conf("game.conf", "");
+    let (conf, _) = ConfigLoader::new(&path, Schema::server()).load().unwrap();
+    let mut gs = GameState::new(ServiceType::TCP, conf, 1, "admin_state.log");
+    let (sender, mut receiver) = mpsc::channel(4);
+    gs.add_vfd(7, sender.clone());
+    gs.add_vfd(3, sender);
+
+    let resp = |gs: &mut GameState, req| match gs.admin(req) {
+        HttpProtoType::Resp(value) => Ok(value),
+        other => Err(other.to_string()),
+    };
+    assert_eq!(resp(&mut gs, HttpProtoType::ReqConns), Ok(json!([3, 7])));
+    assert_eq!(
+        resp(&mut gs, HttpProtoType::ReqPlayer(7)),
+        Ok(json!({ "vfd": 7, "name": "p7", "items": [3, 2, 1] }))
+    );
+    //不在本 hub 上的连接, 以及脚本层找不到的玩家
+    assert_eq!(
+        resp(&mut gs, HttpProtoType::ReqPlayer(9)),
+        Err("NotFound".into())
+    );
+    gs.add_vfd(8, mpsc::channel(1).0);
+    assert_eq!(
+        resp(&mut gs, HttpProtoType::ReqPlayer(8)),
+        Err("NotFound".into())
+    );
+
+    assert_eq!(
+        resp(&mut gs, HttpProtoType::ReqGM("add_item 1".into())),
+        Ok(json!({ "cmd": "add_item 1" }))
+    );
+    let err = resp(&mut gs, HttpProtoType::ReqGM("boom".into())).unwrap_err();
+    assert!(err.contains("bad command"), "{err}");
+
+    assert!(resp(&mut gs, HttpProtoType::ReqKick(7, "afk".into())).is_ok());
+    let (_, vfd, packet) = receiver.recv().await.unwrap();
+    assert_eq!(vfd, 7);
+    match packet {
+        Packet::Proto(ProtoType::C2sKick(kick)) => assert_eq!(kick.reason, "afk"),
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(
+        resp(&mut gs, HttpProtoType::ReqKick(9, "afk".into())),
+        Err("NotFound".into())
+    );
+
+    assert!(resp(&mut gs, HttpProtoType::ReqLogLevel(1)).is_ok());
+    let level: i32 = gs
+        .lua_state
+        .as_ref()
+        .unwrap()
+        .context(|ctx| ctx.load("return xlib.log_level").eval().unwrap());
+    assert_eq!(level, 1);
+}
+
+#[test]
+fn lua_values_to_json() {
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        let eval = |code: &str| luautil::lua_to_json(ctx.load(code).eval().unwrap());
+        assert_eq!(eval("return nil"), Value::Null);
+        assert_eq!(eval("return {1, 'a', true}"), json!([1, "a", true]));
+        assert_eq!(eval("return {}"), json!({}));
+        assert_eq!(
+            eval("return {x = 1.5, [2] = 'b', sub = {n = 1}}"),
+            json!({ "x": 1.5, "2": "b", "sub": { "n": 1 } })
+        );
+        assert_eq!(eval("return {1, nil, 3}")["1"], json!(1));
+        assert_eq!(eval("return print"), json!("<function>"));
+        //循环引用在最大深度处截断
+        let t = eval("local t = {}; t.t = t; return t");
+        assert!(t.to_string().contains("{\"t\":null}"), "{t}");
+    });
+}
@@ -0,0 +1,113 @@
+use cable::cli::{self, Action, Cli};
+use cable::config::{ConfigLoader, Schema};
+use cable::luautil;
+use cable::message::ServiceType;
+use std::path::PathBuf;
+
+fn args(s: &str) -> Vec<String> {
+    s.split_whitespace().map(String::from).collect()
+}
+
+fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cable_cli_{}_{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn parse_options() {
+    let cli = Cli::parse(args(
+        "-c a.toml --set fps=20 --set=log_level=4 --check-config",
+    ))
+    .unwrap();
+    assert_eq!(cli.config.as_deref(), Some("a.toml"));
+    assert_eq!(cli.sets, vec!["fps=20", "log_level=4"]);
+    assert_eq!(cli.action, Action::CheckConfig);
+    assert_eq!(cli.config_path("/srv"), "a.toml");
+
+    let cli = Cli::parse(args("check-lua --exec --config=b.conf")).unwrap();
+    assert_eq!(cli.action, Action::CheckLua { exec: true });
+    let cli = Cli::parse(vec![]).unwrap();
+    assert_eq!(cli.action, Action::Run);
+    assert_eq!(cli.config_path("/srv"), "/srv/etc/sysconfig.conf");
+
+    for bad in [
+        "--set",
+        "--set =1",
+        "--exec",
+        "--version --help",
+        "--verbose",
+    ] {
+        assert!(Cli::parse(args(bad)).is_err(), "{bad}");
+    }
+    assert!(cli::version("server", "0.1.0").contains(cable::protos::version()));
+}
+
+#[test]
+fn effective_config_hides_secrets() {
+    let path = dir("conf").join("game.conf");
+    std::fs::write(
+        &path,
+        "host_id = 1\nservice_type = game_service\nservice_addr = 0.0.0.0:8181\n\
+         rpc_service_addr = 0.0.0.0:8182\nrpc_secret = s3cret\nlogic_path = ./logic\n",
+    )
+    .unwrap();
+    let cli = Cli::parse(vec![
+        "--config".into(),
+        path.to_str().unwrap().into(),
+        "--set".into(),
+        "fps=30".into(),
+    ])
+    .unwrap();
+    //--set 覆盖环境变量
+    let env = vec![("CABLE_FPS".to_owned(), "20".to_owned())];
+    let loader = cli.loader(Schema::server(), "/srv", env);
+    let (conf, sys) = loader.load().unwrap();
+    assert_eq!(sys.fps, 30);
+    let text = cli::effective(&conf, loader.schema());
+    assert!(text.contains("rpc_secret = ******\n"), "{text}");
+    assert!(!text.contains("s3cret"));
+    assert!(text.contains("workdir = /srv\n"));
+    assert!(text.contains("max_connection = 10000\n"));
+}
+
+#[test]
+fn check_lua_scripts() {
+    let logic = dir("lua");
+    std::fs::create_dir_all(logic.join("mod")).unwrap();
+    std::fs::write(logic.join("main.lua"), "function _timer_msg() end\n").unwrap();
+    std::fs::write(logic.join("mod/ok.lua"), "return {}\n").unwrap();
+    let conf_path = dir("lua_conf").join("game.conf");
+    std::fs::write(
+        &conf_path,
+        format!(
+            "host_id = 1\nservice_type = game_service\nservice_addr = 0.0.0.0:8181\n\
+             rpc_service_addr = 0.0.0.0:8182\nrpc_secret = s\nlogic_path = {}\n",
+            logic.display()
+        ),
+    )
+    .unwrap();
+    let (conf, _) = ConfigLoader::new(conf_path.to_str().unwrap(), Schema::server())
+        .load()
+        .unwrap();
+    assert!(luautil::check_scripts(ServiceType::TCP, &conf, true).is_empty());
+
+    //编译错误报告所有文件
+    std::fs::write(logic.join("mod/bad.lua"), "function (\n").unwrap();
+    std::fs::write(logic.join("mod/bad2.lua"), "local = 1\n").unwrap();
+    let errors = luautil::check_scripts(ServiceType::TCP, &conf, false);
+    assert_eq!(errors.len(), 2, "{errors:?}");
+    assert!(errors[0].contains("bad.lua"));
+    std::fs::remove_file(logic.join("mod/bad.lua")).unwrap();
+    std::fs::remove_file(logic.join("mod/bad2.lua")).unwrap();
+
+    //入口脚本执行出错只在 exec 时发现
+    std::fs::write(logic.join("main.lua"), "error('boom')\n").unwrap();
+    assert!(luautil::check_scripts(ServiceType::TCP, &conf, false).is_empty());
+    let errors = luautil::check_scripts(ServiceType::TCP, &conf, true);
+    assert!(errors[0].contains("boom"), "{errors:?}");
+
+    std::fs::remove_file(logic.join("main.lua")).unwrap();
+    let errors = luautil::check_scripts(ServiceType::TCP, &conf, false);
+    assert!(errors[0].ends_with("main.lua: not found"), "{errors:?}");
+}
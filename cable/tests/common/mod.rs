@@ -0,0 +1,29 @@
+//集成测试共用的准备: 每个测试进程一个临时目录, 日志和脚本都放在其中
+//  路径都显式传入, 不修改当前目录; 各个测试文件只用到其中一部分
+#![allow(dead_code)]
+
+use cable::logger::{self, LogLevel};
+use std::path::PathBuf;
+use std::sync::Once;
+
+//临时目录, 第一次调用时创建并初始化日志, 日志写在 <dir>/log 下
+pub fn workdir() -> PathBuf {
+    static INIT: Once = Once::new();
+    let dir = std::env::temp_dir().join(format!("cable_test_{}", std::process::id()));
+    INIT.call_once(|| {
+        std::fs::create_dir_all(&dir).unwrap();
+        logger::set_log_root(&dir);
+        logger::init(LogLevel::from(4), 1000);
+    });
+    dir
+}
+
+//写入入口脚本, 返回的目录作为 logic_path
+//同一个进程中的测试并行执行, 先写临时文件再改名, 其他测试不会读到写了一半的脚本
+pub fn logic_dir(main_lua: &str) -> PathBuf {
+    let dir = workdir();
+    let tmp = dir.join(format!("main.lua.{:?}", std::thread::current().id()));
+    std::fs::write(&tmp, main_lua).unwrap();
+    std::fs::rename(&tmp, dir.join("main.lua")).unwrap();
+    dir
+}
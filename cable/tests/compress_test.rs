@@ -0,0 +1,267 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use cable::message::{Frame, MessageType, Packet, ServiceType};
+use cable::network::compress::{Codec, CompressConf, Compression, FLAG_LZ4, FLAG_ZSTD};
+use cable::network::frame::{FRAME_VERSION_V0, FRAME_VERSION_V1};
+use cable::network::tcp::write::ConnWriter;
+use cable::protos::{C2sInventoryReq, C2sLogin, Item, ProtoType, S2cLogin};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+mod common;
+
+fn inventory(num: u64) -> ProtoType {
+    let items = (0..num).map(|i| Item { uid: i, id: 1001 }).collect();
+    ProtoType::C2sInventoryReq(C2sInventoryReq { tag: 1, items })
+}
+
+fn assert_inventory(pto: ProtoType, num: u64) {
+    match (pto, inventory(num)) {
+        (ProtoType::C2sInventoryReq(a), ProtoType::C2sInventoryReq(b)) => assert_eq!(a, b),
+        (other, _) => panic!("unexpected proto: {:?}", other),
+    }
+}
+
+fn login_req() -> ProtoType {
+    ProtoType::S2cLogin(S2cLogin {
+        account: "robot_1".to_string(),
+        passwd: "123456".to_string(),
+        version: "e46404f2".to_string(),
+        compress: 0,
+    })
+}
+
+fn login_resp() -> ProtoType {
+    ProtoType::C2sLogin(C2sLogin {
+        ret: 0,
+        magic: 7,
+        compress: 0,
+    })
+}
+
+fn peer(prefer: Vec<Codec>) -> Compression {
+    Compression::new(Arc::new(CompressConf::new(prefer, 64)))
+}
+
+//写端编码后经过网络, 读端切出帧并解码
+fn transfer(from: &Compression, to: &Compression, pto: ProtoType) -> (Frame, ProtoType) {
+    let frame = from.encode(Packet::Proto(pto)).unwrap();
+    let mut buf = BytesMut::new();
+    buf.put_slice(&frame.header());
+    buf.put_slice(&frame.body);
+    let wire = Frame::parse(&mut buf).unwrap().unwrap();
+    assert!(buf.is_empty());
+    let pto = wire.decode().unwrap();
+    to.observe(&pto);
+    (wire, pto)
+}
+
+//旧版本的写端: proto_id(u32) + body_len(u32) + body
+fn legacy_encode(pto: ProtoType) -> Vec<u8> {
+    let (proto_id, _) = pto.inner_info();
+    let body = cable::protos::encode(pto).unwrap();
+    let mut buf = vec![];
+    buf.extend_from_slice(&proto_id.to_le_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&body);
+    buf
+}
+
+#[test]
+fn uncompressed_frame_is_legacy_format() {
+    let frame = Frame::from_proto(inventory(100)).unwrap();
+    let mut wire = frame.header().to_vec();
+    wire.extend_from_slice(&frame.body);
+    assert_eq!(wire, legacy_encode(inventory(100)));
+    assert_eq!(frame.header()[3], FRAME_VERSION_V0);
+
+    let mut buf = BytesMut::from(&legacy_encode(inventory(100))[..]);
+    let parsed = Frame::parse(&mut buf).unwrap().unwrap();
+    assert_eq!(parsed, frame);
+}
+
+#[test]
+fn compressed_frame_roundtrip() {
+    for codec in [Codec::Lz4, Codec::Zstd] {
+        let frame = Frame::from_proto(inventory(500)).unwrap();
+        let compressed = frame.clone().compress(codec).unwrap();
+        assert!(compressed.is_compressed());
+        assert_eq!(compressed.codec(), Some(codec));
+        assert!(compressed.body.len() < frame.body.len());
+
+        let header = compressed.header();
+        assert_eq!(header[3], FRAME_VERSION_V1);
+        assert_eq!(header[7], codec.flag());
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(&header);
+        buf.put_slice(&compressed.body);
+        let parsed = Frame::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(parsed, compressed);
+        assert_eq!(parsed.decompress().unwrap(), frame);
+        assert_inventory(compressed.decode().unwrap(), 500);
+    }
+}
+
+#[test]
+fn small_or_incompressible_bodies_stay_plain() {
+    let small = Frame::from_proto(inventory(1)).unwrap();
+    assert!(!small.clone().compress(Codec::Zstd).unwrap().is_compressed());
+
+    let noise: Vec<u8> = (0..256u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let frame = Frame::new(100, Bytes::from(noise));
+    assert!(!frame.compress(Codec::Lz4).unwrap().is_compressed());
+}
+
+#[test]
+fn reject_bad_frames() {
+    //未知的帧头版本
+    let mut buf = BytesMut::from(&[100u8, 0, 0, 9, 0, 0, 0, 0][..]);
+    assert!(Frame::parse(&mut buf).is_err());
+
+    //声明的解压长度超过上限
+    let mut body = vec![];
+    body.extend_from_slice(&u32::MAX.to_le_bytes());
+    body.extend_from_slice(&[0u8; 16]);
+    let frame = Frame {
+        proto_id: 100,
+        flags: FLAG_LZ4,
+        body: Bytes::from(body),
+    };
+    assert!(frame.decompress().is_err());
+
+    let frame = Frame {
+        proto_id: 100,
+        flags: FLAG_ZSTD,
+        body: Bytes::from_static(b"not zstd"),
+    };
+    assert!(frame.decode().is_err());
+}
+
+#[test]
+fn new_client_new_server() {
+    let client = peer(vec![Codec::Lz4, Codec::Zstd]);
+    let server = peer(vec![Codec::Zstd, Codec::Lz4]);
+
+    let (_, req) = transfer(&client, &server, login_req());
+    match req {
+        ProtoType::S2cLogin(req) => assert_eq!(req.compress, (FLAG_LZ4 | FLAG_ZSTD) as u32),
+        other => panic!("unexpected proto: {:?}", other),
+    }
+    //服务端的优先顺序决定结果
+    assert_eq!(server.codec(), Codec::Zstd);
+
+    let (_, resp) = transfer(&server, &client, login_resp());
+    match resp {
+        ProtoType::C2sLogin(resp) => assert_eq!(resp.compress, FLAG_ZSTD as u32),
+        other => panic!("unexpected proto: {:?}", other),
+    }
+    assert_eq!(client.codec(), Codec::Zstd);
+
+    let (wire, pto) = transfer(&server, &client, inventory(500));
+    assert_eq!(wire.codec(), Some(Codec::Zstd));
+    assert_inventory(pto, 500);
+    let (wire, pto) = transfer(&client, &server, inventory(500));
+    assert_eq!(wire.codec(), Some(Codec::Zstd));
+    assert_inventory(pto, 500);
+
+    //小于阈值的协议不压缩
+    let (wire, _) = transfer(&server, &client, inventory(1));
+    assert!(!wire.is_compressed());
+}
+
+#[test]
+fn old_client_new_server() {
+    let server = peer(vec![Codec::Zstd, Codec::Lz4]);
+
+    //旧客户端的登录协议没有 compress 字段
+    let mut buf = BytesMut::from(&legacy_encode(login_req())[..]);
+    let pto = Frame::parse(&mut buf).unwrap().unwrap().decode().unwrap();
+    server.observe(&pto);
+    assert_eq!(server.codec(), Codec::None);
+
+    //回给旧客户端的帧都是旧格式
+    let frame = server.encode(Packet::Proto(inventory(500))).unwrap();
+    assert!(!frame.is_compressed());
+    let mut wire = frame.header().to_vec();
+    wire.extend_from_slice(&frame.body);
+    assert_eq!(wire, legacy_encode(inventory(500)));
+}
+
+#[test]
+fn new_client_old_server() {
+    let client = peer(vec![Codec::Lz4]);
+    let _ = client.encode(Packet::Proto(login_req())).unwrap();
+
+    //旧服务端回复的登录协议没有 compress 字段, 客户端不压缩
+    let mut buf = BytesMut::from(&legacy_encode(login_resp())[..]);
+    let pto = Frame::parse(&mut buf).unwrap().unwrap().decode().unwrap();
+    client.observe(&pto);
+    assert_eq!(client.codec(), Codec::None);
+    let frame = client.encode(Packet::Proto(inventory(500))).unwrap();
+    assert!(!frame.is_compressed());
+}
+
+#[test]
+fn server_without_compression() {
+    let client = peer(vec![Codec::Lz4, Codec::Zstd]);
+    let server = peer(vec![]);
+    transfer(&client, &server, login_req());
+    transfer(&server, &client, login_resp());
+    assert_eq!(server.codec(), Codec::None);
+    assert_eq!(client.codec(), Codec::None);
+}
+
+#[test]
+fn forward_frame_between_codecs() {
+    //从 lz4 连接收到的帧转发给不压缩的连接时先解压
+    let lz4_frame = Frame::from_proto(inventory(500))
+        .unwrap()
+        .compress(Codec::Lz4)
+        .unwrap();
+    let plain = peer(vec![]);
+    let frame = plain.encode(Packet::Encoded(lz4_frame.clone())).unwrap();
+    assert!(!frame.is_compressed());
+    assert_inventory(frame.decode().unwrap(), 500);
+
+    let zstd = peer(vec![Codec::Zstd]);
+    zstd.negotiate(FLAG_ZSTD as u32);
+    let frame = zstd.encode(Packet::Encoded(lz4_frame)).unwrap();
+    assert_eq!(frame.codec(), Some(Codec::Zstd));
+    assert_inventory(frame.decode().unwrap(), 500);
+}
+
+//转发的帧解压失败时只丢弃这一条, 连接上之后的消息照常写出
+#[tokio::test]
+async fn bad_frame_does_not_close_writer() {
+    common::workdir();
+
+    let (client, server) = tokio::io::duplex(4096);
+    let (msg_tx, msg_rx) = mpsc::channel(8);
+    let (_pair_tx, pair_rx) = mpsc::channel(1);
+    let mut writer = ConnWriter::new(ServiceType::TCP, 100, Box::new(server), msg_rx, pair_rx);
+    let task = tokio::spawn(async move { writer.run().await });
+
+    let mut bad = Frame::new(102, Bytes::from_static(b"not lz4"));
+    bad.flags = FLAG_LZ4;
+    msg_tx
+        .send((MessageType::Tcp, 100, Packet::Encoded(bad)))
+        .await
+        .unwrap();
+    msg_tx
+        .send((MessageType::Tcp, 100, Packet::Proto(inventory(3))))
+        .await
+        .unwrap();
+    drop(msg_tx);
+    assert!(task.await.unwrap().is_ok());
+
+    let mut client = client;
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    let mut buf = BytesMut::from(&buf[..]);
+    let frame = Frame::parse(&mut buf).unwrap().unwrap();
+    assert_inventory(frame.decode().unwrap(), 3);
+    assert!(buf.is_empty());
+}
@@ -0,0 +1,205 @@
+use cable::config::{Config, ConfigLoader, Schema};
+use cable::logger::LogLevel;
+use cable::message::ServiceType;
+
+const GAME_CONF: &str = "
+host_id = 1
+service_type = game_service
+service_addr = 0.0.0.0:8181
+rpc_service_addr = 0.0.0.0:8182
+rpc_secret = c2VjcmV0IGtleQ==
+logic_path = ./logic
+";
+
+fn write(name: &str, content: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("cable_config_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, content).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn legacy_format() {
+    let conf = Config::parse(&format!("{GAME_CONF}cert = \"cert.pem\" #证书\n\n")).unwrap();
+    //值中的 '=' 保留, 双引号去掉
+    assert_eq!(conf.get_string("rpc_secret").unwrap(), "c2VjcmV0IGtleQ==");
+    assert_eq!(conf.get_string("cert").unwrap(), "cert.pem");
+    assert_eq!(conf.get_int("host_id"), Some(1));
+    assert_eq!(conf.get_int("service_type"), None);
+
+    let err = Config::parse("a = 1\njust text\n = 2\na = 3\n").unwrap_err();
+    assert_eq!(err.errors.len(), 3);
+    assert!(err.errors[0].starts_with("line 2:"));
+    assert!(err.errors[2].contains("a is duplicated"));
+}
+
+#[test]
+fn legacy_comments() {
+    let text = "#注释\n  # 缩进的注释\nkey = a#b\nurl = \"http://x/#top\" #注释\nv = 1 #注释\nw = 2\t#注释\n";
+    let conf = Config::parse(text).unwrap();
+    //'#' 在空白之后才是注释, 值中和双引号内的保留
+    assert_eq!(conf.get_string("key").unwrap(), "a#b");
+    assert_eq!(conf.get_string("url").unwrap(), "http://x/#top");
+    assert_eq!(conf.get_int("v"), Some(1));
+    assert_eq!(conf.get_int("w"), Some(2));
+}
+
+#[test]
+fn toml_format() {
+    let text = r#"
+host_id = 1
+compress = ["zstd", "lz4"]
+is_ws = false
+
+[rpc_acl]
+_db = "game_service"
+
+[rpc_host]
+5 = "10.0.0.5:8182"
+"#;
+    let conf = Config::parse_toml(text).unwrap();
+    assert_eq!(conf.get_int("host_id"), Some(1));
+    assert_eq!(conf.get_string("compress").unwrap(), "zstd,lz4");
+    assert_eq!(conf.get_prefixed("rpc_acl."), vec![("_db", "game_service")]);
+    assert_eq!(conf.get_string("rpc_host.5").unwrap(), "10.0.0.5:8182");
+    assert!(Config::parse_toml("a = [[1]]").is_err());
+    assert!(Config::parse_toml("a = ").is_err());
+
+    //按扩展名选择格式
+    let toml = "host_id = 1\nservice_type = \"db_service\"\nrpc_service_addr = \"0.0.0.0:8182\"\n\
+                rpc_secret = \"s\"\n";
+    let path = write("db.toml", toml);
+    let (conf, sys) = ConfigLoader::new(&path, Schema::server()).load().unwrap();
+    assert_eq!(sys.service_type, ServiceType::DB);
+    assert_eq!(conf.get_int("log_chan_size"), Some(2000));
+}
+
+#[test]
+fn shipped_config_is_valid() {
+    let path = format!("{}/etc/sysconfig.conf", env!("CARGO_MANIFEST_DIR"));
+    //示例配置不带密钥, 部署时必须自己配置
+    let err = ConfigLoader::new(&path, Schema::server())
+        .with_override("workdir", "/tmp")
+        .load()
+        .unwrap_err();
+    assert_eq!(err.errors, ["rpc_secret: is required by every server"]);
+
+    let env = vec![("CABLE_RPC_SECRET".to_owned(), "s3cret".to_owned())];
+    let (conf, sys) = ConfigLoader::new(&path, Schema::server())
+        .with_env(env)
+        .with_override("workdir", "/tmp")
+        .load()
+        .unwrap();
+    assert_eq!(sys.service_type, ServiceType::TCP);
+    //没有认证的控制台和管理接口默认不启动
+    assert_eq!(conf.get_string("console_addr"), None);
+    assert_eq!(conf.get_string("http_addr"), None);
+}
+
+#[test]
+fn reject_placeholder_secrets() {
+    let text =
+        format!("{GAME_CONF}http_addr = 127.0.0.1:8183\nhttp_token = change_me_admin_token\n")
+            .replace("c2VjcmV0IGtleQ==", "change_me_cluster_secret");
+    let path = write("placeholder.conf", &text);
+    let err = ConfigLoader::new(&path, Schema::server())
+        .load()
+        .unwrap_err();
+    assert_eq!(
+        err.errors,
+        [
+            "http_token: replace the change_me placeholder",
+            "rpc_secret: replace the change_me placeholder"
+        ]
+    );
+}
+
+#[test]
+fn defaults_and_overrides() {
+    let path = write(
+        "game.conf",
+        &format!("{GAME_CONF}log_level = 1\nfps = 20\n"),
+    );
+    let env = vec![
+        ("CABLE_LOG_LEVEL".to_owned(), "2".to_owned()),
+        ("CABLE_FPS".to_owned(), "30".to_owned()),
+        ("CABLE_RPC_ACL___DB".to_owned(), "game_service".to_owned()),
+        ("HOME".to_owned(), "/root".to_owned()),
+    ];
+    let args = ["--set", "fps=40", "--set=is_ws=true"].map(String::from);
+    let (conf, sys) = ConfigLoader::new(&path, Schema::server())
+        .with_env(env)
+        .with_args(args)
+        .load()
+        .unwrap();
+    //命令行 > 环境变量 > 配置文件
+    assert_eq!(sys.log_level, LogLevel::Warning);
+    assert_eq!(sys.fps, 40);
+    assert!(sys.is_ws);
+    assert_eq!(conf.get_string("rpc_acl._db").unwrap(), "game_service");
+    //没有配置的使用默认值
+    assert_eq!(conf.get_int("max_connection"), Some(10000));
+    assert_eq!(sys.log_chan_size, 2000);
+}
+
+#[test]
+fn unknown_env_is_warning() {
+    let path = write("env.conf", GAME_CONF);
+    let env = vec![
+        ("CABLE_FPS".to_owned(), "30".to_owned()),
+        ("CABLE_HOME".to_owned(), "/opt/cable".to_owned()),
+    ];
+    let loader = ConfigLoader::new(&path, Schema::server()).with_env(env);
+    let (conf, sys) = loader.load().unwrap();
+    assert_eq!(sys.fps, 30);
+    assert_eq!(conf.get_string("home"), None);
+    assert_eq!(loader.warnings(), ["CABLE_HOME: unknown key home, ignored"]);
+
+    //配置文件和命令行中未知的键仍是错误
+    let err = ConfigLoader::new(&path, Schema::server())
+        .with_args(["--set", "home=/opt/cable"].map(String::from))
+        .load()
+        .unwrap_err();
+    assert_eq!(err.errors, ["home: unknown key"]);
+}
+
+#[test]
+fn reports_every_error() {
+    let text = "
+service_type = game_service
+service_addr = 8181
+log_level = 9
+is_ws = yes
+max_conection = 100
+compress = lz4,gzip
+is_encrypt = true
+";
+    let path = write("broken.conf", text);
+    let err = ConfigLoader::new(&path, Schema::server())
+        .with_args(["--verbose".to_owned()])
+        .load()
+        .unwrap_err();
+    let expected = [
+        "argument [--verbose]",
+        "host_id: is required",
+        "service_addr = 8181: expected host:port",
+        "log_level = 9: out of range 1..=4",
+        "is_ws = yes: expected true or false",
+        "max_conection: unknown key",
+        "compress = lz4,gzip: gzip is not one of",
+        "rpc_service_addr: is required",
+        "rpc_secret: is required",
+        "logic_path: is required",
+        "encrypt_key_file: is required when is_encrypt = true",
+    ];
+    for e in expected {
+        assert!(err.errors.iter().any(|s| s.starts_with(e)), "{e} in {err}");
+    }
+    assert_eq!(err.errors.len(), expected.len(), "{err}");
+
+    let err = ConfigLoader::new("/no/such/file.conf", Schema::server())
+        .load()
+        .unwrap_err();
+    assert!(err.errors[0].starts_with("/no/such/file.conf"));
+}
@@ -0,0 +1,149 @@
+use cable::config::{ConfigLoader, Schema};
+use cable::luautil;
+use cable::network::console::{self, Console, ConsoleAddr};
+use cable::network::http::{ChanHttpProtoReceiverOp, ChanHttpProtoSenderOp, HttpProtoType};
+use rlua::Lua;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+mod common;
+
+#[test]
+fn console_addr() {
+    assert_eq!(
+        ConsoleAddr::parse("/tmp/cable.sock"),
+        Ok(ConsoleAddr::Unix(PathBuf::from("/tmp/cable.sock")))
+    );
+    let sa: SocketAddr = "127.0.0.1:8184".parse().unwrap();
+    assert_eq!(
+        ConsoleAddr::parse("127.0.0.1:8184"),
+        Ok(ConsoleAddr::Tcp(sa))
+    );
+    assert!(ConsoleAddr::parse("[::1]:8184").is_ok());
+    assert!(ConsoleAddr::parse("0.0.0.0:8184").is_err());
+    assert!(ConsoleAddr::parse("console.sock").is_err());
+
+    let dir = std::env::temp_dir().join(format!("cable_console_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("console.conf");
+    std::fs::write(
+        &path,
+        "host_id = 1\nservice_type = game_service\nservice_addr = 0.0.0.0:8181\n\
+         rpc_service_addr = 0.0.0.0:8182\nrpc_secret = s\nlogic_path = ./logic\n\
+         console_addr = 10.0.0.1:8184\n",
+    )
+    .unwrap();
+    let err = ConfigLoader::new(path.to_str().unwrap(), Schema::server())
+        .load()
+        .unwrap_err();
+    assert_eq!(
+        err.errors,
+        vec!["console_addr: 10.0.0.1:8184: must be a loopback address"]
+    );
+}
+
+#[test]
+fn eval_in_vm() {
+    let lua = Lua::new();
+    let eval = |code: &str, readonly| luautil::console_eval(&lua, code, readonly);
+    assert_eq!(eval("1 + 1", false), Ok("2".into()));
+    assert_eq!(eval("x = 5", false), Ok("".into()));
+    assert_eq!(eval("x, 'a', nil", false), Ok("5\t\"a\"\tnil".into()));
+    assert_eq!(eval("{x}", false), Ok("{[1]=5,}".into()));
+    assert!(eval("{print}", false).unwrap().starts_with("table: "));
+    assert!(eval("error('boom')", false).unwrap_err().contains("boom"));
+
+    //只读时可以读取和调用, 不能给全局变量赋值
+    assert_eq!(eval("x * 2", true), Ok("10".into()));
+    for code in ["x = 6", "_G.x = 6", "function f() end"] {
+        let err = eval(code, true).unwrap_err();
+        assert!(err.contains("console is read-only"), "{code}: {err}");
+    }
+    assert_eq!(eval("getmetatable(_G)", true), Ok("false".into()));
+    assert_eq!(eval("local y = 1; return y + x", true), Ok("6".into()));
+    assert_eq!(eval("x", false), Ok("5".into()));
+
+    //死循环被中断, 之后虚拟机还可以继续使用
+    let err = eval("while true do end", false).unwrap_err();
+    assert!(err.contains("interrupted"), "{err}");
+    assert_eq!(eval("x", false), Ok("5".into()));
+}
+
+//在单独的线程中用真实的虚拟机回复控制台的请求
+fn vm_hub() -> (ChanHttpProtoSenderOp, Arc<Mutex<Vec<String>>>) {
+    let (sender, mut receiver): (ChanHttpProtoSenderOp, ChanHttpProtoReceiverOp) = mpsc::channel(4);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    thread::spawn(move || {
+        let lua = Lua::new();
+        while let Some((req, resp)) = receiver.blocking_recv() {
+            seen2.lock().unwrap().push(req.to_string());
+            let reply = match req {
+                HttpProtoType::ReqLua(code, readonly) => {
+                    match luautil::console_eval(&lua, &code, readonly) {
+                        Ok(text) => HttpProtoType::Resp(serde_json::Value::String(text)),
+                        Err(err) => HttpProtoType::Error(err),
+                    }
+                }
+                _ => HttpProtoType::NotFound,
+            };
+            let _ = resp.send(reply);
+        }
+    });
+    (sender, seen)
+}
+
+async fn session(console: Console, input: &str) -> String {
+    //审计日志写到临时目录中
+    common::workdir();
+    let (client, server) = tokio::io::duplex(4096);
+    let task = tokio::spawn(console::serve(server, "test".into(), Arc::new(console)));
+    let (mut reader, mut writer) = tokio::io::split(client);
+    writer.write_all(input.as_bytes()).await.unwrap();
+    writer.shutdown().await.unwrap();
+    let mut out = String::new();
+    reader.read_to_string(&mut out).await.unwrap();
+    task.await.unwrap();
+    out
+}
+
+#[tokio::test]
+async fn session_history_and_shards() {
+    let (hub0, seen0) = vm_hub();
+    let (hub1, _) = vm_hub();
+    let console = Console::new(vec![hub0, hub1]);
+    let out = session(
+        console,
+        "n = 41\nn + 1\n\n:history\n!2\n!9\n:shard 1\nn\n:shard 2\n:nope\n:quit\nn\n",
+    )
+    .await;
+    let expected = "lua[0]> lua[0]> 42\nlua[0]> lua[0]>    1  n = 41\n   2  n + 1\n\
+                    lua[0]> n + 1\n42\nlua[0]> error: no history entry 9\n\
+                    lua[0]> shard = 1\nlua[1]> nil\nlua[1]> error: shard must be 0..2\n\
+                    lua[1]> error: unknown command :nope, try :help\nlua[1]> ";
+    assert_eq!(out, expected);
+    assert_eq!(
+        seen0.lock().unwrap().as_slice(),
+        [
+            "ReqLua(n = 41,false)",
+            "ReqLua(n + 1,false)",
+            "ReqLua(n + 1,false)"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn readonly_session() {
+    let (hub, seen) = vm_hub();
+    let console = Console::new(vec![hub]).with_readonly(true);
+    let out = session(console, "n = 1\nmath.max(1, 2)\n").await;
+    let lines: Vec<&str> = out.split("lua[0]> ").filter(|l| !l.is_empty()).collect();
+    assert_eq!(lines.len(), 2, "{out}");
+    assert!(lines[0].starts_with("error: ") && lines[0].contains("read-only"));
+    assert_eq!(lines[1], "2\n");
+    assert_eq!(seen.lock().unwrap()[0], "ReqLua(n = 1,true)");
+}
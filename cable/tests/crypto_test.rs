@@ -0,0 +1,159 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use cable::config::Config;
+use cable::message::Frame;
+use cable::network::crypto::{self, Identity, Opener, Sealer};
+use cable::protos::{Item, ProtoType};
+use tokio::io::{duplex, split, AsyncWriteExt, DuplexStream};
+
+const SERVER_KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+const OTHER_KEY: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+fn server(key: &str) -> Identity {
+    Identity::Server(crypto::parse_key(key).unwrap().into())
+}
+
+//客户端固定 key 对应的服务端公钥
+fn client(key: &str) -> Identity {
+    Identity::Client(server(key).server_key())
+}
+
+async fn peer(stream: DuplexStream, identity: Identity) -> cable::Result<(Sealer, Opener)> {
+    let (mut r, mut w) = split(stream);
+    let (read_handshake, write_handshake) = crypto::handshake(&identity);
+    let mut buffer = BytesMut::new();
+    let (sealer, opener) = tokio::join!(
+        write_handshake.run(&mut w),
+        read_handshake.run(&mut r, &mut buffer)
+    );
+    assert!(buffer.is_empty());
+    Ok((sealer?, opener?))
+}
+
+//在内存管道的两端完成握手, 返回两端的 (Sealer, Opener), a 为服务端
+async fn connect(a: DuplexStream, b: DuplexStream) -> ((Sealer, Opener), (Sealer, Opener)) {
+    let (a, b) = tokio::join!(peer(a, server(SERVER_KEY)), peer(b, client(SERVER_KEY)));
+    (a.unwrap(), b.unwrap())
+}
+
+fn item_frame(id: u32) -> Frame {
+    Frame::from_proto(ProtoType::Item(Item { uid: 1, id })).unwrap()
+}
+
+fn open_frame(opener: &mut Opener, record: &Bytes) -> cable::Result<Option<Frame>> {
+    let mut buf = BytesMut::from(&record[..]);
+    match opener.open(&mut buf)? {
+        Some(plain) => Ok(Some(Frame::parse_exact(plain)?)),
+        None => Ok(None),
+    }
+}
+
+#[tokio::test]
+async fn handshake_and_both_directions() {
+    let (a, b) = duplex(1024);
+    let ((mut a_sealer, mut a_opener), (mut b_sealer, mut b_opener)) = connect(a, b).await;
+
+    for id in 0..3 {
+        let record = a_sealer.seal(&item_frame(id)).unwrap();
+        //密文中不包含明文帧
+        let frame = item_frame(id);
+        assert!(!record
+            .windows(frame.body.len())
+            .any(|w| w == &frame.body[..]));
+        assert_eq!(open_frame(&mut b_opener, &record).unwrap(), Some(frame));
+    }
+    let record = b_sealer.seal(&item_frame(9)).unwrap();
+    assert_eq!(
+        open_frame(&mut a_opener, &record).unwrap(),
+        Some(item_frame(9))
+    );
+}
+
+#[tokio::test]
+async fn partial_record_waits_for_more_data() {
+    let (a, b) = duplex(1024);
+    let ((mut sealer, _), (_, mut opener)) = connect(a, b).await;
+    let record = sealer.seal(&item_frame(1)).unwrap();
+
+    let mut buf = BytesMut::new();
+    for (i, b) in record.iter().enumerate() {
+        buf.put_u8(*b);
+        let res = opener.open(&mut buf).unwrap();
+        assert_eq!(res.is_some(), i + 1 == record.len());
+    }
+}
+
+#[tokio::test]
+async fn reject_replay_reorder_and_tamper() {
+    let (a, b) = duplex(1024);
+    let ((mut sealer, _), (_, mut opener)) = connect(a, b).await;
+
+    //重放
+    let r1 = sealer.seal(&item_frame(1)).unwrap();
+    assert!(open_frame(&mut opener, &r1).unwrap().is_some());
+    assert!(open_frame(&mut opener, &r1).is_err());
+
+    //乱序
+    let (a, b) = duplex(1024);
+    let ((mut sealer, _), (_, mut opener)) = connect(a, b).await;
+    let _r1 = sealer.seal(&item_frame(1)).unwrap();
+    let r2 = sealer.seal(&item_frame(2)).unwrap();
+    assert!(open_frame(&mut opener, &r2).is_err());
+
+    //篡改
+    let (a, b) = duplex(1024);
+    let ((mut sealer, _), (_, mut opener)) = connect(a, b).await;
+    let mut record = sealer.seal(&item_frame(1)).unwrap().to_vec();
+    let last = record.len() - 1;
+    record[last] ^= 0x01;
+    assert!(open_frame(&mut opener, &Bytes::from(record)).is_err());
+}
+
+#[tokio::test]
+async fn reject_plaintext_peer() {
+    //未开启加密的对端直接发送明文帧
+    let (a, mut b) = duplex(1024);
+    let frame = item_frame(1);
+    b.write_all(&frame.header()).await.unwrap();
+    b.write_all(&frame.body).await.unwrap();
+    b.write_all(&[0u8; 64]).await.unwrap();
+
+    let (mut r, _w) = split(a);
+    let (read_handshake, _write_handshake) = crypto::handshake(&server(SERVER_KEY));
+    let mut buffer = BytesMut::new();
+    assert!(read_handshake.run(&mut r, &mut buffer).await.is_err());
+}
+
+//中间人分别与两端握手, 但没有服务端的静态私钥, 与客户端派生的密钥不一致
+#[tokio::test]
+async fn reject_server_without_pinned_key() {
+    let (a, b) = duplex(1024);
+    let (mitm, client) = tokio::join!(peer(a, server(OTHER_KEY)), peer(b, client(SERVER_KEY)));
+    let ((mut mitm_sealer, mut mitm_opener), (mut client_sealer, mut client_opener)) =
+        (mitm.unwrap(), client.unwrap());
+    let record = client_sealer.seal(&item_frame(1)).unwrap();
+    assert!(open_frame(&mut mitm_opener, &record).is_err());
+    let record = mitm_sealer.seal(&item_frame(2)).unwrap();
+    assert!(open_frame(&mut client_opener, &record).is_err());
+}
+
+#[test]
+fn identity_from_config() {
+    assert!(crypto::parse_key("0a").is_err());
+    assert!(crypto::parse_key(&"zz".repeat(32)).is_err());
+
+    let dir = std::env::temp_dir().join(format!("cable_crypto_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("encrypt.key");
+    std::fs::write(&path, format!("{SERVER_KEY}\n")).unwrap();
+    let conf = Config::default().with("encrypt_key_file", path.to_str().unwrap());
+    let identity = Identity::from_config(&conf).unwrap();
+    assert!(matches!(identity, Identity::Server(_)));
+    let public = crypto::key_hex(&identity.server_key());
+
+    //客户端配置服务端启动日志中打印的公钥
+    let conf = Config::default().with("encrypt_server_key", &public);
+    let pinned = Identity::from_config(&conf).unwrap();
+    assert!(matches!(pinned, Identity::Client(_)));
+    assert_eq!(pinned.server_key(), identity.server_key());
+    assert!(Identity::from_config(&Config::default()).is_err());
+}
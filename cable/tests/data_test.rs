@@ -0,0 +1,279 @@
+use cable::config::Config;
+use cable::message::{MessageType, ServiceType, SystemMsg};
+use cable::modules::data_cache::{DataCache, DataConf, DataEvent, Load};
+use cable::modules::storage::{MemStorage, Storage};
+use cable::protos::{db_resp::Row, DbReq, DbResp, ProtoType};
+use cable::states::db_state::{DbConf, DbOp};
+use cable::states::{DbState, GameState};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Receiver};
+
+mod common;
+
+const MAIN_LUA: &str = r#"
+events = {}
+function _timer_msg() end
+function on_load(data, err)
+    if err then
+        events[#events + 1] = "err"
+        return
+    end
+    events[#events + 1] = "hp=" .. tostring(data.hp)
+    player = data
+end
+"#;
+
+const DB_HOST: i32 = 5;
+
+fn conf() -> DataConf {
+    DataConf {
+        db_host: DB_HOST,
+        table: "player".to_string(),
+        flush_ms: 1000,
+        retry_ms: 500,
+        save_timeout_ms: 3000,
+    }
+}
+
+fn resp(req: &DbReq, ok: bool, value: Option<&str>) -> DbResp {
+    DbResp {
+        id: req.id,
+        ok,
+        reason: if ok { String::new() } else { "down".into() },
+        rows: value
+            .map(|v| Row {
+                key: req.key.clone(),
+                value: v.as_bytes().to_vec(),
+            })
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn request(load: Load<&'static str>) -> DbReq {
+    match load {
+        Load::Request(req) => req,
+        _ => panic!("expected request"),
+    }
+}
+
+#[test]
+fn cache_loads_once() {
+    let mut cache: DataCache<String, &str> = DataCache::new(conf(), Arc::new(AtomicU64::new(1)));
+    let req = request(cache.load("1001", "cb1"));
+    assert_eq!((req.op, req.table.as_str()), (DbOp::Load as u32, "player"));
+    assert!(matches!(cache.load("1001", "cb2"), Load::Waiting));
+    assert!(cache.owns(req.id));
+    assert!(cache.mark_dirty("1001").is_err());
+
+    match cache.on_resp(resp(&req, true, None), 0) {
+        Ok(DataEvent::Loaded {
+            key,
+            value,
+            waiters,
+        }) => assert_eq!(
+            (key.as_str(), value, waiters),
+            ("1001", None, vec!["cb1", "cb2"])
+        ),
+        _ => panic!("expected loaded"),
+    }
+    cache.ready("1001", "{}".to_string());
+    assert!(matches!(cache.load("1001", "cb3"), Load::Ready("cb3")));
+
+    //加载失败时移除, 下次重新加载
+    let req = request(cache.load("1002", "cb"));
+    assert!(matches!(
+        cache.on_resp(resp(&req, false, None), 0),
+        Ok(DataEvent::LoadFailed { .. })
+    ));
+    assert!(matches!(cache.load("1002", "cb"), Load::Request(_)));
+    assert_eq!(cache.cancel("1002"), vec!["cb"]);
+    assert_eq!(cache.len(), 1);
+
+    //不是缓存发出的请求原样返回
+    assert!(cache.on_resp(DbResp::default(), 0).is_err());
+}
+
+#[test]
+fn cache_flushes_and_retries() {
+    let mut cache: DataCache<String, &str> = DataCache::new(conf(), Arc::new(AtomicU64::new(1)));
+    let req = request(cache.load("1001", "cb"));
+    cache.on_resp(resp(&req, true, None), 0).ok().unwrap();
+    cache.ready("1001", "{}".to_string());
+
+    cache.mark_dirty("1001").unwrap();
+    assert_eq!(cache.due(0, false), vec!["1001"]);
+    let save = cache.save("1001", b"{hp=1}".to_vec(), 0).unwrap();
+    assert_eq!(
+        (save.op, save.value.as_slice()),
+        (DbOp::Save as u32, &b"{hp=1}"[..])
+    );
+    assert!(cache.is_saving("1001") && !cache.is_dirty("1001"));
+    //提交期间的修改等回复后再提交
+    cache.mark_dirty("1001").unwrap();
+    assert!(cache.save("1001", Vec::new(), 0).is_none());
+
+    //提交失败, 重试时间之前不提交
+    assert!(matches!(
+        cache.on_resp(resp(&save, false, None), 900),
+        Ok(DataEvent::SaveFailed { .. })
+    ));
+    assert!(cache.due(1000, false).is_empty());
+    assert!(cache.due(1500, false).is_empty());
+    assert_eq!(cache.due(2000, false), vec!["1001"]);
+
+    //提交超时
+    let save = cache.save("1001", Vec::new(), 2000).unwrap();
+    assert_eq!(cache.expire(4999), 0);
+    assert_eq!(cache.expire(5000), 1);
+    assert!(cache.is_dirty("1001") && !cache.is_saving("1001"));
+    assert!(cache.on_resp(resp(&save, true, None), 5000).is_err());
+
+    //有未提交的修改时, 卸载在提交成功后完成
+    assert!(cache.unload("1001").is_none());
+    assert_eq!(cache.due(6000, true), vec!["1001"]);
+    let save = cache.save("1001", Vec::new(), 6000).unwrap();
+    match cache.on_resp(resp(&save, true, None), 6000) {
+        Ok(DataEvent::Saved { released, .. }) => assert_eq!(released.as_deref(), Some("{}")),
+        _ => panic!("expected saved"),
+    }
+    assert!(cache.is_empty());
+}
+
+fn game_state(name: &str) -> (GameState, Receiver<SystemMsg>) {
+    let dir = common::logic_dir(MAIN_LUA);
+    let path = dir.join(format!("{name}.conf"));
+    let content = format!(
+        "host_id = 2\nlog_level = 4\nlogic_path = {}\ndata_db_host = {DB_HOST}\n",
+        dir.display()
+    );
+    std::fs::write(&path, content).unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+
+    let (tx, rx) = mpsc::channel(16);
+    let mut gs = GameState::new(ServiceType::TCP, conf.clone(), 2, &format!("{name}.log"));
+    gs.set_rpc_sender(tx);
+    gs.set_data(DataConf::from_config(&conf).unwrap());
+    (gs, rx)
+}
+
+fn lua(gs: &GameState, code: &str) {
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    lua_state.context(|ctx| ctx.load(code).exec().unwrap());
+}
+
+fn events(gs: &GameState) -> Vec<String> {
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    lua_state.context(|ctx| {
+        ctx.load("__events = events; events = {}").exec().unwrap();
+        ctx.globals().get("__events").unwrap()
+    })
+}
+
+//把发给 db 服务的请求交给 db 执行, 回复交回逻辑层
+fn serve(gs: &mut GameState, rpc: &mut Receiver<SystemMsg>, db: &mut DbState) {
+    let mut reqs = Vec::new();
+    while let Ok((msg_type, host, packet)) = rpc.try_recv() {
+        assert_eq!((msg_type, host), (MessageType::Rpc, DB_HOST as u64));
+        match packet.into_proto().unwrap() {
+            ProtoType::DbReq(r) => reqs.push(DbReq { host: 2, ..r }),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+    for (_, resp) in db.execute(reqs) {
+        gs.db_dispatch(resp).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn lua_loads_and_flushes_player() {
+    let storage = Arc::new(MemStorage::new());
+    let mut db = DbState::new(DbConf::default(), storage.clone());
+    let (mut gs, mut rpc) = game_state("data_a");
+
+    //新玩家没有数据, 加载到空表
+    lua(
+        &gs,
+        "xlib.data_load('1001', on_load); xlib.data_load('1001', on_load)",
+    );
+    serve(&mut gs, &mut rpc, &mut db);
+    assert_eq!(events(&gs), vec!["hp=nil", "hp=nil"]);
+
+    lua(&gs, "player.hp = 10; xlib.data_mark_dirty('1001')");
+    gs.update_data(0);
+    serve(&mut gs, &mut rpc, &mut db);
+    assert!(storage.load("player", "1001").unwrap().is_some());
+
+    //立即提交, 然后卸载
+    lua(&gs, "player.hp = 7; xlib.data_mark_dirty('1001')");
+    lua(
+        &gs,
+        "assert(xlib.data_flush('1001')); assert(not xlib.data_unload('1001'))",
+    );
+    serve(&mut gs, &mut rpc, &mut db);
+    assert!(gs.get_data().unwrap().lock().unwrap().is_empty());
+
+    //其他逻辑服重新加载到提交的数据
+    let (mut gs, mut rpc) = game_state("data_b");
+    lua(&gs, "xlib.data_load('1001', on_load)");
+    serve(&mut gs, &mut rpc, &mut db);
+    assert_eq!(events(&gs), vec!["hp=7"]);
+
+    //停止时提交所有脏数据
+    lua(&gs, "player.hp = 3; xlib.data_mark_dirty('1001')");
+    assert_eq!(gs.close_data(), 1);
+    let (_, _, packet) = rpc.try_recv().unwrap();
+    assert!(matches!(
+        packet.into_proto().unwrap(),
+        ProtoType::DbReq(DbReq { op: 2, .. })
+    ));
+}
+
+//停止时提交所有脏数据, 提交失败的重新提交, 直到 db 服务确认保存
+#[tokio::test]
+async fn close_data_until_saved() {
+    let storage = Arc::new(MemStorage::new());
+    let mut db = DbState::new(DbConf::default(), storage.clone());
+    let (mut gs, mut rpc) = game_state("data_close");
+    assert_eq!(
+        gs.data_timeout(),
+        Some(std::time::Duration::from_millis(5000))
+    );
+    lua(
+        &gs,
+        "xlib.data_load('1001', on_load); xlib.data_load('1002', on_load)",
+    );
+    serve(&mut gs, &mut rpc, &mut db);
+    assert!(gs.unsaved_data().is_empty());
+
+    lua(
+        &gs,
+        "player.hp = 3; xlib.data_mark_dirty('1001'); xlib.data_mark_dirty('1002')",
+    );
+    assert_eq!(gs.close_data(), 2);
+    assert_eq!(gs.unsaved_data(), ["1001", "1002"]);
+
+    //1001 的提交失败, 仍然没有保存
+    while let Ok((_, _, packet)) = rpc.try_recv() {
+        let ProtoType::DbReq(req) = packet.into_proto().unwrap() else {
+            panic!("unexpected proto");
+        };
+        let resp = if req.key == "1001" {
+            resp(&req, false, None)
+        } else {
+            db.execute(vec![DbReq { host: 2, ..req }]).remove(0).1
+        };
+        gs.db_dispatch(resp).unwrap();
+    }
+    assert_eq!(gs.unsaved_data(), ["1001"]);
+    assert!(storage.load("player", "1001").unwrap().is_none());
+    let saved = storage.load("player", "1002").unwrap().unwrap();
+    assert!(String::from_utf8(saved).unwrap().contains("hp"));
+
+    //再次提交后保存成功
+    assert_eq!(gs.close_data(), 1);
+    serve(&mut gs, &mut rpc, &mut db);
+    assert!(gs.unsaved_data().is_empty());
+    assert!(storage.load("player", "1001").unwrap().is_some());
+}
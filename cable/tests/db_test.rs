@@ -0,0 +1,283 @@
+use cable::config::Config;
+use cable::message::{MessageType, ServiceType};
+use cable::modules::storage::{DbWrite, MemStorage, SledStorage, Storage};
+use cable::network::rpc_auth::{RpcAcl, RpcGuard, RpcIdentity, DB_FUNC};
+use cable::protos::{DbReq, DbResp, ProtoType};
+use cable::states::db_state::{DbConf, DbOp};
+use cable::states::{DbState, GameState};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+mod common;
+
+const MAIN_LUA: &str = r#"
+events = {}
+function _timer_msg() end
+function _db_resp(id, ok, reason, rows)
+    local t = {id, tostring(ok), reason}
+    for _, row in ipairs(rows) do t[#t + 1] = row.key .. "=" .. row.value end
+    events[#events + 1] = table.concat(t, ",")
+end
+"#;
+
+fn write(table: &str, key: &str, value: Option<&str>) -> DbWrite {
+    DbWrite {
+        table: table.to_string(),
+        key: key.to_string(),
+        value: value.map(|v| v.as_bytes().to_vec()),
+    }
+}
+
+fn req(id: u64, op: DbOp, key: &str, value: &str) -> DbReq {
+    DbReq {
+        id,
+        host: 2,
+        op: op as u32,
+        table: "player".to_string(),
+        key: key.to_string(),
+        value: value.as_bytes().to_vec(),
+        limit: 0,
+    }
+}
+
+fn rows(resp: &DbResp) -> Vec<(String, String)> {
+    resp.rows
+        .iter()
+        .map(|r| (r.key.clone(), String::from_utf8(r.value.clone()).unwrap()))
+        .collect()
+}
+
+fn check_storage(storage: &dyn Storage) {
+    storage
+        .write(&[
+            write("player", "1001", Some("a")),
+            write("player", "1002", Some("b")),
+            write("player", "2001", Some("c")),
+            write("guild", "1001", Some("g")),
+        ])
+        .unwrap();
+    assert_eq!(storage.load("player", "1001").unwrap(), Some(b"a".to_vec()));
+    assert_eq!(storage.load("guild", "1001").unwrap(), Some(b"g".to_vec()));
+    assert_eq!(storage.load("player", "3001").unwrap(), None);
+
+    let keys: Vec<String> = storage
+        .query("player", "100", 0)
+        .unwrap()
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    assert_eq!(keys, vec!["1001", "1002"]);
+    assert_eq!(storage.query("player", "", 2).unwrap().len(), 2);
+    assert_eq!(storage.query("player", "", 0).unwrap().len(), 3);
+
+    storage
+        .write(&[
+            write("player", "1001", None),
+            write("player", "1002", Some("d")),
+        ])
+        .unwrap();
+    assert_eq!(storage.load("player", "1001").unwrap(), None);
+    assert_eq!(storage.load("player", "1002").unwrap(), Some(b"d".to_vec()));
+    storage.flush().unwrap();
+}
+
+#[test]
+fn storage_backends() {
+    check_storage(&MemStorage::new());
+    check_storage(&SledStorage::temporary().unwrap());
+}
+
+#[test]
+fn batch_keeps_request_order() {
+    let storage = Arc::new(MemStorage::new());
+    let mut db = DbState::new(DbConf::default(), storage.clone());
+    let resps = db.execute(vec![
+        req(1, DbOp::Save, "1001", "v1"),
+        req(2, DbOp::Load, "1001", ""),
+        req(3, DbOp::Save, "1001", "v2"),
+        req(4, DbOp::Save, "1002", "x"),
+        req(5, DbOp::Delete, "1002", ""),
+        req(6, DbOp::Query, "100", ""),
+        DbReq {
+            op: 9,
+            ..req(7, DbOp::Load, "1001", "")
+        },
+    ]);
+    let ids: Vec<u64> = resps.iter().map(|(_, r)| r.id).collect();
+    assert_eq!(ids, vec![1, 2, 3, 4, 5, 6, 7]);
+    assert!(resps.iter().all(|(host, _)| *host == 2));
+    assert_eq!(rows(&resps[1].1), vec![("1001".into(), "v1".into())]);
+    assert_eq!(rows(&resps[5].1), vec![("1001".into(), "v2".into())]);
+    assert!(!resps[6].1.ok);
+
+    //不开启延迟写入时, 回复前已经提交
+    assert_eq!(db.dirty_len(), 0);
+    assert_eq!(
+        storage.load("player", "1001").unwrap(),
+        Some(b"v2".to_vec())
+    );
+    assert_eq!(storage.load("player", "1002").unwrap(), None);
+}
+
+#[test]
+fn write_behind_flushes_later() {
+    let storage = Arc::new(MemStorage::new());
+    storage
+        .write(&[write("player", "1003", Some("old"))])
+        .unwrap();
+    let conf = DbConf {
+        write_behind_ms: 100,
+        write_behind_max: 3,
+        ..Default::default()
+    };
+    let mut db = DbState::new(conf, storage.clone());
+    let resps = db.execute(vec![
+        req(1, DbOp::Save, "1001", "v1"),
+        req(2, DbOp::Delete, "1003", ""),
+    ]);
+    assert!(resps.iter().all(|(_, r)| r.ok));
+    assert_eq!(storage.load("player", "1001").unwrap(), None);
+
+    //未提交的写入也能读到
+    let resps = db.execute(vec![
+        req(3, DbOp::Load, "1001", ""),
+        req(4, DbOp::Query, "100", ""),
+    ]);
+    assert_eq!(rows(&resps[0].1), vec![("1001".into(), "v1".into())]);
+    assert_eq!(rows(&resps[1].1), vec![("1001".into(), "v1".into())]);
+
+    assert_eq!(db.flush().unwrap(), 2);
+    assert_eq!(
+        storage.load("player", "1001").unwrap(),
+        Some(b"v1".to_vec())
+    );
+    assert_eq!(storage.load("player", "1003").unwrap(), None);
+
+    //脏数据达到上限时立即提交
+    db.execute(vec![
+        req(5, DbOp::Save, "1", ""),
+        req(6, DbOp::Save, "2", ""),
+        req(7, DbOp::Save, "3", ""),
+    ]);
+    assert_eq!(db.dirty_len(), 0);
+    assert_eq!(db.close().unwrap(), 0);
+}
+
+struct Broken;
+
+impl Storage for Broken {
+    fn load(&self, _: &str, _: &str) -> cable::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn query(&self, _: &str, _: &str, _: usize) -> cable::Result<Vec<(String, Vec<u8>)>> {
+        Ok(Vec::new())
+    }
+
+    fn write(&self, _: &[DbWrite]) -> cable::Result<()> {
+        Err("disk full".into())
+    }
+
+    fn flush(&self) -> cable::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_commit_fails_writes() {
+    let mut db = DbState::new(DbConf::default(), Arc::new(Broken));
+    let resps = db.execute(vec![
+        req(1, DbOp::Save, "1001", "v1"),
+        req(2, DbOp::Load, "1001", ""),
+    ]);
+    assert!(!resps[0].1.ok);
+    assert!(resps[0].1.reason.contains("disk full"));
+    assert!(resps[1].1.ok);
+    assert_eq!(db.dirty_len(), 0);
+
+    //延迟写入时保留到下次提交
+    let conf = DbConf {
+        write_behind_ms: 100,
+        ..Default::default()
+    };
+    let mut db = DbState::new(conf, Arc::new(Broken));
+    db.execute(vec![req(1, DbOp::Save, "1001", "v1")]);
+    assert!(db.flush().is_err());
+    assert_eq!(db.dirty_len(), 1);
+}
+
+#[test]
+fn guard_checks_db_acl() {
+    const SECRET: &[u8] = b"cluster secret";
+    let acl = RpcAcl::new().allow(DB_FUNC, &[ServiceType::TCP]);
+    let mut guard = RpcGuard::new(
+        RpcIdentity::new(SECRET, 5, ServiceType::DB, "10.0.0.5:8182"),
+        acl,
+    );
+    let peers = [(1, 2, ServiceType::TCP), (2, 3, ServiceType::TCPROBOT)];
+    for (vfd, host, service_type) in peers {
+        let identity = RpcIdentity::new(SECRET, host, service_type, "10.0.0.2:8182");
+        let auth = identity.respond(&guard.connect(vfd));
+        assert!(guard
+            .check(vfd, ProtoType::RpcAuth(auth))
+            .unwrap()
+            .is_none());
+    }
+
+    //回复的对象以认证结果为准
+    let r = DbReq {
+        host: 99,
+        ..req(1, DbOp::Load, "1001", "")
+    };
+    match guard.check(1, ProtoType::DbReq(r.clone())).unwrap() {
+        Some(ProtoType::DbReq(r)) => assert_eq!(r.host, 2),
+        other => panic!("unexpected: {other:?}"),
+    }
+    assert!(guard.check(2, ProtoType::DbReq(r)).is_err());
+}
+
+#[tokio::test]
+async fn lua_calls_db_service() {
+    let dir = common::logic_dir(MAIN_LUA);
+    let path = dir.join("game.conf");
+    let content = format!(
+        "host_id = 2\nlog_level = 4\nlogic_path = {}\n",
+        dir.display()
+    );
+    std::fs::write(&path, content).unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+
+    let (tx, mut rpc) = mpsc::channel(16);
+    let mut gs = GameState::new(ServiceType::TCP, conf, 2, "game_state_db.log");
+    gs.set_rpc_sender(tx);
+
+    let lua = |gs: &GameState, code: &str| {
+        let lua_state = gs.lua_state.as_ref().unwrap();
+        lua_state.context(|ctx| ctx.load(code).eval::<u64>().unwrap())
+    };
+    let id = lua(&gs, "return xlib.db_save(5, 'player', '1001', 'hp=10')");
+    let id2 = lua(&gs, "return xlib.db_query(5, 'player', '100')");
+    assert_eq!(id2, id + 1);
+
+    //请求经 rpc 发给 db 服务, 在 db 服务上执行
+    let mut reqs = Vec::new();
+    for _ in 0..2 {
+        let (msg_type, host, packet) = rpc.try_recv().unwrap();
+        assert_eq!((msg_type, host), (MessageType::Rpc, 5));
+        match packet.into_proto().unwrap() {
+            ProtoType::DbReq(r) => reqs.push(DbReq { host: 2, ..r }),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+    let mut db = DbState::new(DbConf::default(), Arc::new(MemStorage::new()));
+    for (_, resp) in db.execute(reqs) {
+        gs.db_dispatch(resp).unwrap();
+    }
+
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    let events: Vec<String> = lua_state.context(|ctx| ctx.globals().get("events").unwrap());
+    assert_eq!(
+        events,
+        vec![format!("{id},true,"), format!("{id2},true,,1001=hp=10")]
+    );
+}
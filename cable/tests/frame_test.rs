@@ -0,0 +1,97 @@
+use bytes::{Buf, BufMut, BytesMut};
+use cable::message::{Frame, Packet};
+use cable::network::frame::FrameBatch;
+use cable::network::tcp::{PROTO_BODY_MAX_LEN, PROTO_HEADER_LEN};
+use cable::protos::{Item, ProtoType, S2cLogin};
+use tokio::io::AsyncWriteExt;
+
+fn login_frame() -> Frame {
+    let pto = ProtoType::S2cLogin(S2cLogin {
+        account: "robot_1".to_string(),
+        passwd: "123456".to_string(),
+        version: "e46404f2".to_string(),
+        compress: 0,
+    });
+    Frame::from_proto(pto).unwrap()
+}
+
+fn encode(frame: &Frame) -> Vec<u8> {
+    let mut buf = frame.header().to_vec();
+    buf.extend_from_slice(&frame.body);
+    buf
+}
+
+#[test]
+fn parse_partial_frames() {
+    let frame = login_frame();
+    let item = Frame::from_proto(ProtoType::Item(Item { uid: 1, id: 2 })).unwrap();
+    let mut wire = encode(&frame);
+    wire.extend(encode(&item));
+
+    //逐字节喂给读缓冲, 只有数据完整时才切出帧
+    let mut buf = BytesMut::new();
+    let mut frames = vec![];
+    for b in wire {
+        buf.put_u8(b);
+        while let Some(f) = Frame::parse(&mut buf).unwrap() {
+            frames.push(f);
+        }
+    }
+    assert!(buf.is_empty());
+    assert_eq!(frames, vec![frame, item]);
+
+    match frames[1].decode().unwrap() {
+        ProtoType::Item(item) => assert_eq!((item.uid, item.id), (1, 2)),
+        other => panic!("unexpected proto: {:?}", other),
+    }
+}
+
+#[test]
+fn parse_rejects_oversize_body() {
+    let mut buf = BytesMut::new();
+    buf.put_u32_le(100);
+    buf.put_u32_le(PROTO_BODY_MAX_LEN as u32);
+    assert!(Frame::parse(&mut buf).is_err());
+}
+
+#[test]
+fn packet_conversion() {
+    let frame = login_frame();
+    let packet = Packet::from(frame.clone());
+    assert_eq!(packet.proto_id(), frame.proto_id);
+    let pto = packet.into_proto().unwrap();
+    assert_eq!(Packet::from(pto).into_frame().unwrap(), frame);
+}
+
+#[tokio::test]
+async fn batch_vectored_write() {
+    let frames = [
+        login_frame(),
+        Frame::from_proto(ProtoType::Item(Item::default())).unwrap(),
+        login_frame(),
+    ];
+    let mut batch = FrameBatch::new();
+    for frame in frames.iter().cloned() {
+        batch.push(frame);
+    }
+    assert_eq!(batch.frames(), 3);
+    let expected: Vec<u8> = frames.iter().flat_map(encode).collect();
+    assert_eq!(batch.remaining(), expected.len());
+
+    let mut out: Vec<u8> = vec![];
+    out.write_all_buf(&mut batch).await.unwrap();
+    assert_eq!(out, expected);
+    assert!(batch.is_empty());
+
+    //跨越多个块的 advance
+    batch.clear();
+    for frame in frames.iter().cloned() {
+        batch.push(frame);
+    }
+    batch.advance(PROTO_HEADER_LEN + 3);
+    assert_eq!(batch.remaining(), expected.len() - PROTO_HEADER_LEN - 3);
+    assert_eq!(
+        batch.copy_to_bytes(batch.remaining()),
+        expected[PROTO_HEADER_LEN + 3..]
+    );
+}
@@ -0,0 +1,154 @@
+use cable::config::Config;
+use cable::message::{MessageType, Packet, ServiceType};
+use cable::modules::gate::{GateConf, GateTable};
+use cable::modules::session::REMOTE_VFD_BASE;
+use cable::network::frame::Frame;
+use cable::protos::{GateOpen, ProtoMessage, ProtoType, S2cLogin, SessionRelay};
+use cable::states::{GameState, SessionState};
+use tokio::sync::mpsc;
+
+mod common;
+
+const MAIN_LUA: &str = r#"
+events = {}
+function _timer_msg() end
+function _tcp_msg(vfd, proto_id, proto_name, t)
+    events[#events + 1] = table.concat({vfd, proto_name, t.account}, ",")
+end
+"#;
+
+const GATE: i32 = 9;
+
+fn config(name: &str, extra: &str) -> Config {
+    let dir = common::logic_dir(MAIN_LUA);
+    let path = dir.join(format!("{name}.conf"));
+    let content = format!(
+        "host_id = 2\nlog_level = 4\nlogic_path = {}\n{extra}",
+        dir.display()
+    );
+    std::fs::write(&path, content).unwrap();
+    Config::new(path.to_str().unwrap())
+}
+
+fn login(account: &str) -> Frame {
+    Frame::from_proto(ProtoType::S2cLogin(S2cLogin {
+        account: account.to_string(),
+        ..Default::default()
+    }))
+    .unwrap()
+}
+
+fn relay(vfd: u64, frame: Frame) -> ProtoType {
+    ProtoType::SessionRelay(SessionRelay {
+        vfd,
+        host: GATE,
+        proto_id: frame.proto_id,
+        flags: frame.flags as u32,
+        body: frame.body.to_vec(),
+        closed: false,
+    })
+}
+
+#[test]
+fn gate_conf_requires_backends() {
+    assert!(GateConf::from_config(&config("no_backend", "")).is_err());
+    assert!(GateConf::from_config(&config("bad_backend", "gate_backends = 2,x\n")).is_err());
+
+    let conf = GateConf::from_config(&config("backends", "gate_backends = 2, 3\n")).unwrap();
+    assert_eq!(conf.backends, vec![2, 3]);
+    assert_eq!(conf.login_protos, vec![S2cLogin::PROTO_ID]);
+}
+
+#[test]
+fn gate_table_picks_least_loaded() {
+    let mut table = GateTable::new(GateConf {
+        backends: vec![2, 3],
+        login_protos: vec![S2cLogin::PROTO_ID],
+    });
+    assert_eq!(table.connect(1), Some(2));
+    assert_eq!(table.connect(2), Some(3));
+    assert_eq!(table.connect(3), Some(2));
+    //重复的连接不重新分配
+    assert_eq!(table.connect(1), Some(2));
+    assert_eq!((table.load_of(2), table.load_of(3)), (2, 1));
+
+    assert_eq!(table.close(1), Some(2));
+    assert_eq!(table.close(1), None);
+    assert_eq!(table.connect(4), Some(2));
+    assert_eq!((table.load_of(2), table.load_of(3)), (2, 1));
+}
+
+#[test]
+fn gate_table_forwards_login_first() {
+    let mut table = GateTable::new(GateConf {
+        backends: vec![2],
+        login_protos: vec![S2cLogin::PROTO_ID],
+    });
+    assert!(table.allow(1, S2cLogin::PROTO_ID).is_err());
+    table.connect(1);
+    assert_eq!(table.allow(1, S2cLogin::PROTO_ID).unwrap(), 2);
+    assert!(table.allow(1, 110).is_err());
+
+    table.authenticate(1);
+    assert!(table.is_authed(1));
+    assert_eq!(table.allow(1, 110).unwrap(), 2);
+    assert_eq!(table.backend_of(1), Some(2));
+}
+
+#[tokio::test]
+async fn backend_serves_gate_connection() {
+    let (tx, mut rpc) = mpsc::channel(16);
+    let conf = config("backend", "");
+    let mut gs = GameState::new(ServiceType::TCP, conf.clone(), 2, "game_state_gate.log");
+    gs.set_session(SessionState::new(&conf).with_rpc(tx));
+
+    //网关的新连接在后端分配本机 vfd, 脚本层按本机连接处理
+    let open = ProtoType::GateOpen(GateOpen { vfd: 7, host: GATE });
+    assert!(gs.session_dispatch(open).unwrap().is_none());
+    let alias = REMOTE_VFD_BASE;
+    assert!(gs.get_sender(alias).is_some());
+
+    let (msg_type, vfd, packet) = gs
+        .session_dispatch(relay(7, login("bob")))
+        .unwrap()
+        .unwrap();
+    assert_eq!((msg_type, vfd), (MessageType::Tcp, alias));
+    assert!(matches!(packet, Packet::Encoded(_)));
+    gs.dispatch(msg_type, vfd, packet.into_proto().unwrap())
+        .unwrap();
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    let events: Vec<String> = lua_state.context(|ctx| {
+        ctx.load("__events = events; events = {}").exec().unwrap();
+        ctx.globals().get("__events").unwrap()
+    });
+    assert_eq!(events, vec![format!("{alias},S2cLogin,bob")]);
+
+    //脚本层发给连接的消息经 rpc 回到网关
+    lua_state
+        .context(|ctx| {
+            ctx.load(&format!(
+                "xlib.tcp_send({alias}, 102, 'C2sLogin', {{ret=0, magic=7}})"
+            ))
+            .exec()
+        })
+        .unwrap();
+    let (_, _, packet) = rpc.recv().await.unwrap();
+    match packet.into_proto().unwrap() {
+        ProtoType::SessionRelay(r) => assert_eq!((r.vfd, r.host, r.proto_id), (7, 2, 102)),
+        other => panic!("unexpected: {other:?}"),
+    }
+
+    //网关的连接断开, 后端释放
+    let closed = ProtoType::SessionRelay(SessionRelay {
+        vfd: 7,
+        host: GATE,
+        closed: true,
+        ..Default::default()
+    });
+    let (msg_type, vfd, _) = gs.session_dispatch(closed).unwrap().unwrap();
+    assert_eq!((msg_type, vfd), (MessageType::SocketClosed, alias));
+    gs.close_session(alias);
+    gs.delete_vfd(alias);
+    assert!(gs.get_session().unwrap().table().is_empty());
+    assert!(rpc.try_recv().is_err());
+}
@@ -0,0 +1,145 @@
+use cable::config::Config;
+use cable::network::limit::{IpLimit, LimitAction, LimitConf, Rate, RateLimiter, TokenBucket};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn limiter(conf: LimitConf) -> RateLimiter {
+    RateLimiter::new(Arc::new(conf))
+}
+
+fn config(content: &str) -> Config {
+    let path = std::env::temp_dir().join(format!(
+        "cable_limit_{}_{}.conf",
+        std::process::id(),
+        content.len()
+    ));
+    std::fs::write(&path, content).unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+    conf
+}
+
+#[test]
+fn token_bucket_refill() {
+    let t0 = Instant::now();
+    let mut bucket = TokenBucket::new(Rate::new(10.0, 20.0), t0);
+    for _ in 0..20 {
+        assert_eq!(bucket.wait(1.0, t0), Duration::ZERO);
+        bucket.take(1.0);
+    }
+    assert_eq!(bucket.wait(1.0, t0), Duration::from_millis(100));
+    //100ms 补充一个令牌
+    assert_eq!(
+        bucket.wait(1.0, t0 + Duration::from_millis(100)),
+        Duration::ZERO
+    );
+    //补充不超过容量
+    bucket.take(1.0);
+    let later = t0 + Duration::from_secs(60);
+    assert_eq!(bucket.wait(20.0, later), Duration::ZERO);
+    //超过容量的请求按容量计算
+    assert_eq!(bucket.wait(21.0, later), Duration::ZERO);
+}
+
+#[test]
+fn msg_and_byte_rate() {
+    let t0 = Instant::now();
+    let mut l = limiter(LimitConf {
+        msg: Rate::new(2.0, 2.0),
+        bytes: Rate::new(100.0, 100.0),
+        ..Default::default()
+    });
+    assert!(l.check_at(100, 10, t0).is_none());
+    assert!(l.check_at(100, 10, t0).is_none());
+    let exceed = l.check_at(100, 10, t0).unwrap();
+    assert_eq!(exceed.reason, "msg_rate");
+    assert_eq!(exceed.wait, Duration::from_millis(500));
+
+    //超过限流时不扣除令牌, 等待后可以通过
+    let t1 = t0 + Duration::from_secs(1);
+    assert!(l.check_at(100, 80, t1).is_none());
+    let exceed = l.check_at(100, 80, t1).unwrap();
+    assert_eq!(exceed.reason, "byte_rate");
+    //消息数的令牌没有被字节数的失败扣除
+    assert!(l.check_at(100, 20, t1).is_none());
+
+    //超过容量的帧按容量计算
+    let t2 = t1 + Duration::from_secs(10);
+    assert!(l.check_at(100, 100_000, t2).is_none());
+}
+
+#[test]
+fn proto_override() {
+    let t0 = Instant::now();
+    let mut conf = LimitConf::default();
+    conf.protos.insert(108, Rate::new(1.0, 1.0));
+    let mut l = limiter(conf);
+    assert!(l.check_at(108, 10, t0).is_none());
+    assert_eq!(l.check_at(108, 10, t0).unwrap().reason, "proto_rate");
+    //其他协议不受影响
+    for _ in 0..1000 {
+        assert!(l.check_at(100, 10, t0).is_none());
+    }
+    assert!(l.check_at(108, 10, t0 + Duration::from_secs(1)).is_none());
+}
+
+#[test]
+fn unlimited_by_default() {
+    let conf = LimitConf::default();
+    assert!(!conf.is_limited());
+    let mut l = limiter(conf);
+    let t0 = Instant::now();
+    for _ in 0..10_000 {
+        assert!(l.check_at(100, 1 << 20, t0).is_none());
+    }
+}
+
+#[test]
+fn limit_from_config() {
+    let conf = config(
+        "limit_msg_rate = 10\n\
+         limit_msg_burst = 30\n\
+         limit_byte_rate = 1000\n\
+         limit_proto.108 = 2, 5\n\
+         limit_action = disconnect\n\
+         max_connection_per_ip = 4\n",
+    );
+    let limit = LimitConf::from_config(&conf).unwrap();
+    assert!(limit.is_limited());
+    assert_eq!(limit.msg, Rate::new(10.0, 30.0));
+    //容量至少为一秒的令牌
+    assert_eq!(limit.bytes, Rate::new(1000.0, 1000.0));
+    assert_eq!(limit.protos.get(&108), Some(&Rate::new(2.0, 5.0)));
+    assert_eq!(limit.action, LimitAction::Disconnect);
+    assert_eq!(limit.max_per_ip, 4);
+
+    let limit = LimitConf::from_config(&conf.clone().with("limit_action", "delay")).unwrap();
+    assert_eq!(limit.action, LimitAction::Delay);
+
+    assert!(LimitConf::from_config(&conf.clone().with("limit_proto.abc", "1,2")).is_err());
+    assert!(LimitConf::from_config(&conf.with("limit_proto.100", "1")).is_err());
+}
+
+#[test]
+fn ip_limit() {
+    let a: IpAddr = "10.0.0.1".parse().unwrap();
+    let b: IpAddr = "10.0.0.2".parse().unwrap();
+    let limit = IpLimit::new(2);
+    let p1 = limit.acquire(a).unwrap();
+    let _p2 = limit.acquire(a).unwrap();
+    assert!(limit.acquire(a).is_none());
+    let _p3 = limit.acquire(b).unwrap();
+    assert_eq!(limit.count(a), 2);
+
+    //连接结束后归还
+    drop(p1);
+    assert_eq!(limit.count(a), 1);
+    assert!(limit.acquire(a).is_some());
+
+    let unlimited = IpLimit::new(0);
+    let permits: Vec<_> = (0..100).map(|_| unlimited.acquire(a).unwrap()).collect();
+    assert_eq!(unlimited.count(a), 100);
+    drop(permits);
+    assert_eq!(unlimited.count(a), 0);
+}
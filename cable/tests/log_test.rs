@@ -0,0 +1,343 @@
+use cable::config::{ConfigLoader, Schema};
+use cable::logger::{self, Inner, LogFormat, LogOverflow, LogReceiver, Outter, Trace};
+use cable::message::{MessageType, Packet, ServiceType, SystemMsg};
+use cable::protos::{C2sLogin, ProtoMessage, ProtoType, RpcSend};
+use cable::states::GameState;
+use chrono::{Duration, Local};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+
+mod common;
+
+//日志格式和 host_id 是全局的, 修改它们的测试串行执行
+static GLOBALS: Mutex<()> = Mutex::new(());
+
+const MAIN_LUA: &str = r#"
+seen = {}
+function _timer_msg() end
+function _tcp_msg(vfd, proto_id, proto_name, t)
+    seen[#seen + 1] = xlib.trace_id()
+    xlib.log("trace_lua.log", "error", "login", { magic = t.magic, vfd = 0 })
+    xlib.rpc_send(true, 1, "", 2, "", 9, "on_login", { vfd = vfd })
+end
+function _rpc_msg(is_send, from_host, from_addr, session, func, args)
+    seen[#seen + 1] = xlib.trace_id()
+    xlib.rpc_send(false, 1, "", from_host, "", session, func, {})
+end
+function bad_fields()
+    local ok, err = pcall(xlib.log, "trace_lua.log", "error", "x", { 1, 2 })
+    return ok, tostring(err)
+end
+"#;
+
+fn dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cable_log_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn outter(log_name: &str) -> (Outter, LogReceiver) {
+    let (sender, receiver) = logger::channel(16);
+    let log = Outter::new(log_name).with_sinker(sender);
+    (log, receiver)
+}
+
+fn json_line(receiver: &LogReceiver) -> Value {
+    let (_, line) = receiver.try_recv().unwrap();
+    assert!(line.starts_with("{\"ts\":\""), "{line}");
+    serde_json::from_str(&line).unwrap()
+}
+
+#[test]
+fn text_and_json_lines() {
+    let _globals = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+    logger::set_host_id(3);
+    let (mut log, receiver) = outter("fmt.log");
+    let fields = vec![("k".to_owned(), json!("v")), ("n".to_owned(), json!(2))];
+
+    //文本格式不变, 额外字段追加在后面
+    logger::set_log_format(LogFormat::Text);
+    log.log("info", "[fmt]: a=1");
+    log.log_fields("warn", "[fmt]: b=2", &fields);
+    let (path, line) = receiver.try_recv().unwrap();
+    assert_eq!(path, "log/fmt.log");
+    assert!(
+        line.starts_with('[') && line.ends_with("][info][fmt]: a=1"),
+        "{line}"
+    );
+    let (_, line) = receiver.try_recv().unwrap();
+    assert!(line.ends_with("][warn][fmt]: b=2,k=v,n=2"), "{line}");
+
+    logger::set_log_format(LogFormat::Json);
+    log.log_fields("info", "plain \"quoted\"", &fields);
+    let line = json_line(&receiver);
+    assert_eq!(line["level"], "info");
+    assert_eq!(line["logger"], "fmt.log");
+    assert_eq!(line["host_id"], 3);
+    assert_eq!(line["msg"], "plain \"quoted\"");
+    assert_eq!(
+        (line["k"].clone(), line["n"].clone()),
+        (json!("v"), json!(2))
+    );
+    assert!(line.get("trace_id").is_none() && line.get("vfd").is_none());
+
+    //处理消息期间带上上下文, 结束后恢复
+    {
+        let _trace = Trace::new("t-1".into())
+            .with_vfd(7)
+            .with_proto_id(102)
+            .enter();
+        {
+            let _inner = Trace::new("t-2".into()).enter();
+            log.log("debug", "inner");
+            assert_eq!(json_line(&receiver)["trace_id"], "t-2");
+        }
+        log.log("debug", "outer");
+        let line = json_line(&receiver);
+        assert_eq!(
+            (
+                line["trace_id"].clone(),
+                line["vfd"].clone(),
+                line["proto_id"].clone()
+            ),
+            (json!("t-1"), json!(7), json!(102))
+        );
+        //额外字段可以覆盖上下文, 但不能覆盖时间和等级
+        let fields = vec![
+            ("vfd".to_owned(), json!(8)),
+            ("ts".to_owned(), json!(0)),
+            ("level".to_owned(), json!("x")),
+        ];
+        log.log_fields("info", "override", &fields);
+        let line = json_line(&receiver);
+        assert_eq!(
+            (line["vfd"].clone(), line["level"].clone()),
+            (json!(8), json!("info"))
+        );
+        assert!(line["ts"].is_string());
+    }
+    assert!(logger::current_trace().is_none());
+
+    //连接的日志总是带上自己的 vfd
+    let (log, receiver) = outter("conn.log");
+    let mut log = log.with_vfd(11);
+    log.log("info", "conn");
+    assert_eq!(json_line(&receiver)["vfd"], 11);
+    logger::set_log_format(LogFormat::Text);
+}
+
+#[test]
+fn trace_ids() {
+    let _globals = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+    logger::set_host_id(10);
+    let a = logger::new_trace_id();
+    let b = logger::new_trace_id();
+    assert_ne!(a, b);
+    assert!(a.starts_with("a-"), "{a}");
+    assert_eq!(LogFormat::from("json"), LogFormat::Json);
+    assert_eq!(LogFormat::from("text"), LogFormat::Text);
+}
+
+#[test]
+fn json_files_roll_by_date() {
+    let path = dir().join("roll/json.log");
+    let path_str = path.to_str().unwrap();
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let today = Local::now().format("%Y-%m-%dT%H:%M:%S%.6f%:z");
+    std::fs::write(&path, format!("{{\"ts\":\"{today}\",\"msg\":\"a\"}}\n")).unwrap();
+    let mut inner = Inner::new(path_str, "json.log");
+    inner.write("{\"ts\":\"x\",\"msg\":\"b\"}").unwrap();
+    //写在缓冲区中, flush 之后才在文件中
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    inner.flush().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+    //最后一行是前一天写入的, 先滚动文件
+    let yesterday = (Local::now() - Duration::days(1)).format("%Y-%m-%dT%H:%M:%S%.6f%:z");
+    std::fs::write(&path, format!("{{\"ts\":\"{yesterday}\",\"msg\":\"a\"}}\n")).unwrap();
+    let mut inner = Inner::new(path_str, "json.log");
+    inner.write("{\"ts\":\"x\",\"msg\":\"c\"}").unwrap();
+    inner.flush().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    let rolled = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+    assert_eq!(rolled, 2);
+}
+
+#[test]
+fn overflow_policies() {
+    let _globals = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+    logger::set_log_format(LogFormat::Text);
+    let lines = |receiver: &LogReceiver| {
+        let mut lines = Vec::new();
+        while let Ok((_, line)) = receiver.try_recv() {
+            lines.push(line.rsplit(']').next().unwrap().to_owned());
+        }
+        lines
+    };
+
+    //队列满时直接丢弃
+    logger::set_log_overflow(LogOverflow::Drop);
+    let (sender, receiver) = logger::channel(2);
+    let mut log = Outter::new("overflow.log").with_sinker(sender);
+    let before = logger::dropped();
+    for i in 0..4 {
+        log.log("error", &i.to_string());
+    }
+    assert_eq!(logger::dropped() - before, 2);
+    assert_eq!(lines(&receiver), ["0", "1"]);
+
+    //超过 3/4 时丢弃 debug, 其他日志仍然可以写入
+    logger::set_log_overflow(LogOverflow::DropDebug);
+    let (sender, receiver) = logger::channel(4);
+    let mut log = Outter::new("overflow.log").with_sinker(sender);
+    let before = logger::dropped();
+    for i in 0..4 {
+        log.log("debug", &i.to_string());
+    }
+    log.log("info", "info");
+    assert_eq!(logger::dropped() - before, 1);
+    //队列满时其他日志也丢弃, 不会等待
+    log.log("error", "error");
+    assert_eq!(logger::dropped() - before, 2);
+    assert_eq!(lines(&receiver), ["0", "1", "2", "info"]);
+    assert!(cable::metrics::gather().contains("cable_log_dropped_total{level=\"debug\"}"));
+
+    //等待写线程取出后再写入
+    logger::set_log_overflow(LogOverflow::Block);
+    let (sender, receiver) = logger::channel(1);
+    let mut log = Outter::new("overflow.log").with_sinker(sender);
+    log.log("debug", "a");
+    let blocked = std::thread::spawn(move || log.log("debug", "b"));
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert!(!blocked.is_finished());
+    //取出一行后被阻塞的 b 才能写入, 逐行取出避免一次取到两行
+    let timeout = std::time::Duration::from_secs(5);
+    let (_, line) = receiver.recv_timeout(timeout).unwrap();
+    assert!(line.ends_with("]a"));
+    blocked.join().unwrap();
+    let (_, line) = receiver.recv_timeout(timeout).unwrap();
+    assert!(line.ends_with("]b"));
+    logger::set_log_overflow(LogOverflow::DropDebug);
+    assert_eq!(LogOverflow::from("block"), LogOverflow::Block);
+}
+
+fn wait_line(path: &PathBuf, pred: impl Fn(&Value) -> bool) -> Value {
+    let start = Instant::now();
+    while start.elapsed() < std::time::Duration::from_secs(5) {
+        if let Ok(text) = std::fs::read_to_string(path) {
+            let found = text
+                .lines()
+                .filter_map(|l| serde_json::from_str::<Value>(l).ok())
+                .find(|v| pred(v));
+            if let Some(v) = found {
+                return v;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("no matching line in {}", path.display());
+}
+
+fn seen(gs: &GameState) -> Vec<String> {
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    lua_state.context(|ctx| ctx.load("return seen").eval().unwrap())
+}
+
+fn rpc(receiver: &mut tokio::sync::mpsc::Receiver<SystemMsg>) -> (bool, String) {
+    let (msg_type, _, packet) = receiver.try_recv().unwrap();
+    assert_eq!(msg_type, MessageType::Rpc);
+    match packet {
+        Packet::Proto(ProtoType::RpcSend(p)) => (true, p.trace_id),
+        Packet::Proto(ProtoType::RpcResp(p)) => (false, p.trace_id),
+        other => panic!("{other:?}"),
+    }
+}
+
+#[test]
+fn trace_follows_message_to_rpc() {
+    let _globals = GLOBALS.lock().unwrap_or_else(|err| err.into_inner());
+    let dir = common::logic_dir(MAIN_LUA);
+    let path = dir.join("game.conf");
+    std::fs::write(
+        &path,
+        format!(
+            "host_id = 1\nservice_type = game_service\nservice_addr = 0.0.0.0:8181\n\
+             rpc_service_addr = 0.0.0.0:8182\nrpc_secret = s\nlogic_path = {}\n\
+             log_level = 4\nlog_format = json\n",
+            dir.display()
+        ),
+    )
+    .unwrap();
+    let (conf, sys) = ConfigLoader::new(path.to_str().unwrap(), Schema::server())
+        .load()
+        .unwrap();
+    assert_eq!(sys.log_format, LogFormat::Json);
+    logger::set_log_format(sys.log_format);
+    logger::set_host_id(sys.host_id);
+
+    let mut gs = GameState::new(ServiceType::TCP, conf, 1, "trace_state.log");
+    let (rpc_sender, mut rpc_receiver) = tokio::sync::mpsc::channel(4);
+    gs.set_rpc_sender(rpc_sender);
+
+    //客户端消息: 新的 trace_id 交给脚本层, 日志和发出的 rpc 都带上它
+    let login = ProtoType::C2sLogin(C2sLogin {
+        magic: 42,
+        ..Default::default()
+    });
+    gs.dispatch(MessageType::Tcp, 5, login).unwrap();
+    let trace_id = seen(&gs)[0].clone();
+    assert!(trace_id.starts_with("1-"), "{trace_id}");
+    assert_eq!(rpc(&mut rpc_receiver), (true, trace_id.clone()));
+    let line = wait_line(&dir.join("log/trace_lua.log"), |v| v["msg"] == "login");
+    assert_eq!(line["trace_id"], trace_id.as_str());
+    assert_eq!(line["proto_id"], C2sLogin::PROTO_ID);
+    assert_eq!(
+        (line["magic"].clone(), line["vfd"].clone()),
+        (json!(42), json!(0))
+    );
+    assert_eq!(line["logger"], "trace_lua.log");
+
+    //对端的 rpc: 沿用收到的 trace_id, 回复也带上它
+    let send = ProtoType::RpcSend(RpcSend {
+        from_host: 2,
+        session: 9,
+        func: "on_login".into(),
+        args: "{}".into(),
+        trace_id: "2-abc-1".into(),
+        ..Default::default()
+    });
+    gs.rpc_dispatch(MessageType::Rpc, 2, send).unwrap();
+    assert_eq!(seen(&gs)[1], "2-abc-1");
+    assert_eq!(rpc(&mut rpc_receiver), (false, "2-abc-1".into()));
+    //旧版本的对端没有 trace_id
+    gs.rpc_dispatch(MessageType::Rpc, 2, ProtoType::RpcSend(RpcSend::default()))
+        .unwrap();
+    let (_, fresh) = rpc(&mut rpc_receiver);
+    assert!(fresh.starts_with("1-") && fresh != trace_id, "{fresh}");
+    assert!(logger::current_trace().is_none());
+
+    let (ok, err): (bool, String) = gs
+        .lua_state
+        .as_ref()
+        .unwrap()
+        .context(|ctx| ctx.load("return bad_fields()").eval().unwrap());
+    assert!(!ok && err.contains("fields must be a table"), "{err}");
+
+    //退出前队列中的日志都写到文件, 不需要等待定时 flush
+    lua(
+        &gs,
+        "for i = 1, 500 do xlib.log('flush.log', 'error', 'n=' .. i) end",
+    );
+    logger::shutdown();
+    let text = std::fs::read_to_string(dir.join("log/flush.log")).unwrap();
+    assert_eq!(text.lines().count(), 500);
+    assert!(text.lines().last().unwrap().contains("n=500"));
+    logger::set_log_format(LogFormat::Text);
+}
+
+fn lua(gs: &GameState, code: &str) {
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    lua_state.context(|ctx| ctx.load(code).exec().unwrap());
+}
@@ -0,0 +1,95 @@
+use cable::message::ServiceType;
+use cable::metrics;
+use cable::network::{self, http::service as http_service};
+use cable::protos::{Dummy, ProtoType};
+use cable::states::TimerState;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+//取一个样本的值, series 为指标名加上按字母排序的标签
+fn value(text: &str, series: &str) -> f64 {
+    text.lines()
+        .find_map(|line| line.strip_prefix(series)?.trim().parse().ok())
+        .unwrap_or(0.0)
+}
+
+#[tokio::test]
+async fn metrics_route() {
+    metrics::conn_accepted(ServiceType::TCP);
+    metrics::frame_in(ServiceType::TCP, 9101, 10);
+    metrics::frame_in(ServiceType::TCP, 9101, 20);
+    metrics::frame_out(ServiceType::RPC, 9102, 7);
+    metrics::conn_closed(ServiceType::TCP, "peer");
+    drop(metrics::lua_timer("_metrics_test"));
+
+    //不需要认证
+    let admin = http_service::Admin::new(None);
+    let resp = warp::test::request()
+        .path("/metrics")
+        .reply(&http_service::routes(Arc::new(admin)))
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let text = String::from_utf8(resp.body().to_vec()).unwrap();
+    let frames = r#"cable_frames_total{dir="in",proto_id="9101",service="tcp"}"#;
+    assert_eq!(value(&text, frames), 2.0, "{text}");
+    let bytes = r#"cable_frame_bytes_total{dir="in",proto_id="9101",service="tcp"}"#;
+    assert_eq!(value(&text, bytes), 30.0);
+    let out = r#"cable_frames_total{dir="out",proto_id="9102",service="rpc"}"#;
+    assert_eq!(value(&text, out), 1.0);
+    assert!(value(&text, r#"cable_conn_accepted_total{service="tcp"}"#) >= 1.0);
+    assert!(
+        value(
+            &text,
+            r#"cable_conn_closed_total{reason="peer",service="tcp"}"#
+        ) >= 1.0
+    );
+    let lua = r#"cable_lua_callback_seconds_count{callback="_metrics_test"}"#;
+    assert_eq!(value(&text, lua), 1.0);
+    assert!(text.contains("# TYPE cable_log_backlog gauge"));
+}
+
+#[test]
+fn try_send_failures() {
+    let series =
+        |reason: &str| format!(r#"cable_send_failed_total{{reason="{reason}",site="try_send"}}"#);
+    let full = value(&metrics::gather(), &series("full"));
+    let closed = value(&metrics::gather(), &series("closed"));
+
+    let (sender, receiver) = mpsc::channel(1);
+    let pto = || ProtoType::Dummy(Dummy::default());
+    network::try_send(&sender, 1, pto()).unwrap();
+    assert!(network::try_send(&sender, 1, pto()).is_err());
+    drop(receiver);
+    assert!(network::try_send(&sender, 1, pto()).is_err());
+
+    let text = metrics::gather();
+    assert_eq!(value(&text, &series("full")), full + 1.0);
+    assert_eq!(value(&text, &series("closed")), closed + 1.0);
+}
+
+#[test]
+fn timer_counts() {
+    let timers = || value(&metrics::gather(), "cable_timers");
+    let before = timers();
+    let mut state = TimerState::new(10);
+    let once = state.add_timer(0, 0);
+    state.add_timer(0, 100);
+    state.add_timer(0, 200);
+    assert_eq!(timers(), before + 3.0);
+    state.remove_timer(once);
+    assert_eq!(timers(), before + 2.0);
+
+    let fired = value(&metrics::gather(), "cable_timers_fired_total");
+    let now = chrono::Local::now().timestamp_millis() + 1;
+    assert_eq!(state.update(now).unwrap().len(), 2);
+    assert_eq!(
+        value(&metrics::gather(), "cable_timers_fired_total"),
+        fired + 2.0
+    );
+    drop(state);
+    assert_eq!(timers(), before);
+}
@@ -0,0 +1,163 @@
+use cable::config::{reload, reload_blocking, Config, ConfigLoader, Reloader, Schema};
+use cable::logger::{self, LogLevel};
+use cable::message::ServiceType;
+use cable::network::limit::{LimitConf, RateLimiter};
+use cable::states::GameState;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+mod common;
+
+const MAIN_LUA: &str = r#"
+changed = {}
+function _timer_msg() end
+function _on_config_changed(diff)
+    for k, v in pairs(diff) do
+        changed[#changed + 1] = k .. ":" .. tostring(v.old) .. "->" .. tostring(v.new)
+    end
+end
+"#;
+
+fn write_conf(name: &str, extra: &str) -> String {
+    let content = format!(
+        "host_id = 1\nservice_type = game_service\nservice_addr = 0.0.0.0:8181\n\
+         rpc_service_addr = 0.0.0.0:8182\nrpc_secret = s\nlogic_path = {}\n{extra}",
+        common::workdir().display()
+    );
+    let path = common::workdir().join(name);
+    std::fs::write(&path, content).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn reload_applies_hot_keys() {
+    let path = write_conf("hot.conf", "log_level = 4\n");
+    let (mut reloader, _) =
+        Reloader::new(ConfigLoader::new(&path, Schema::server()).with_override("workdir", "/tmp"))
+            .unwrap();
+    let conf = reloader.config();
+    let mut live = conf.watch().unwrap();
+    assert!(reloader.reload().unwrap().is_empty());
+    assert!(!live.has_changed().unwrap());
+
+    write_conf("hot.conf", "log_level = 2\nlimit_msg_rate = 5\n");
+    let changes = reloader.reload().unwrap();
+    let keys: Vec<&str> = changes.iter().map(|c| c.key.as_str()).collect();
+    assert_eq!(keys, vec!["limit_msg_rate", "log_level"]);
+    assert_eq!(changes[0].old, None);
+    assert_eq!(changes[1].new.as_deref(), Some("2"));
+    assert_eq!(logger::get_global_log_level(), LogLevel::Warning);
+    assert!(live.has_changed().unwrap());
+    assert_eq!(live.borrow_and_update().get_int("limit_msg_rate"), Some(5));
+    //workdir 等启动时的覆盖保留
+    assert_eq!(reloader.config().get_string("workdir").unwrap(), "/tmp");
+}
+
+#[test]
+fn reload_rejects_cold_keys() {
+    let path = write_conf("cold.conf", "fps = 10\n");
+    let (mut reloader, _) = Reloader::new(ConfigLoader::new(&path, Schema::server())).unwrap();
+
+    let conf = write_conf("cold.conf", "fps = 20\nmax_connection = 5\n");
+    let text = std::fs::read_to_string(&conf)
+        .unwrap()
+        .replace("0.0.0.0:8181", "0.0.0.0:9000");
+    std::fs::write(&conf, text).unwrap();
+    let err = reloader.reload().unwrap_err();
+    assert_eq!(
+        err.errors,
+        vec![
+            "max_connection: 10000 -> 5 requires a restart",
+            "service_addr: 0.0.0.0:8181 -> 0.0.0.0:9000 requires a restart",
+        ]
+    );
+    //拒绝时当前配置不变
+    assert_eq!(reloader.config().get_int("fps"), Some(10));
+
+    write_conf("cold.conf", "fps = 0\n");
+    let err = reloader.reload().unwrap_err();
+    assert!(err.errors[0].starts_with("fps = 0"), "{err}");
+    write_conf("cold.conf", "limit_proto.108 = x\n");
+    let err = reloader.reload().unwrap_err();
+    assert!(err.errors[0].contains("limit_proto.108"), "{err}");
+}
+
+#[test]
+fn diff_lists_added_and_removed() {
+    let old = Config::parse("a = 1\nb = 2\n").unwrap();
+    let new = Config::parse("b = 3\nc = 4\n").unwrap();
+    let changes: Vec<(String, Option<String>, Option<String>)> = reload::diff(&old, &new)
+        .into_iter()
+        .map(|c| (c.key, c.old, c.new))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            ("a".into(), Some("1".into()), None),
+            ("b".into(), Some("2".into()), Some("3".into())),
+            ("c".into(), None, Some("4".into())),
+        ]
+    );
+}
+
+#[test]
+fn limiter_follows_reload() {
+    let path = write_conf("limit.conf", "");
+    let (mut reloader, _) = Reloader::new(ConfigLoader::new(&path, Schema::server())).unwrap();
+    let conf = reloader.config();
+    let limit = Arc::new(LimitConf::from_config(&conf).unwrap());
+    let mut limiter = RateLimiter::new(limit).with_watch(conf.watch().unwrap());
+    let now = Instant::now();
+    for _ in 0..10 {
+        assert!(limiter.check_at(100, 10, now).is_none());
+    }
+
+    write_conf("limit.conf", "limit_msg_rate = 1\nlimit_msg_burst = 2\n");
+    reloader.reload().unwrap();
+    assert!(limiter.check_at(100, 10, now).is_none());
+    assert!(limiter.check_at(100, 10, now).is_none());
+    assert!(limiter.check_at(100, 10, now).is_some());
+}
+
+#[tokio::test]
+async fn reload_off_the_runtime() {
+    let path = write_conf("blocking.conf", "fps = 10\n");
+    let (reloader, _) = Reloader::new(ConfigLoader::new(&path, Schema::server())).unwrap();
+    let reloader = Arc::new(Mutex::new(reloader));
+
+    write_conf("blocking.conf", "fps = 20\n");
+    let changes = reload_blocking(reloader.clone()).await.unwrap();
+    assert_eq!(changes[0].key, "fps");
+    //返回后锁已经释放
+    assert_eq!(
+        reloader.try_lock().unwrap().config().get_int("fps"),
+        Some(20)
+    );
+
+    write_conf("blocking.conf", "fps = 0\n");
+    let err = reload_blocking(reloader.clone()).await.unwrap_err();
+    assert!(err.errors[0].starts_with("fps = 0"), "{err}");
+}
+
+#[tokio::test]
+async fn lua_is_notified() {
+    common::logic_dir(MAIN_LUA);
+    let path = write_conf("lua.conf", "");
+    let (mut reloader, _) = Reloader::new(ConfigLoader::new(&path, Schema::server())).unwrap();
+    let conf = reloader.config();
+    let mut gs = GameState::new(ServiceType::TCP, conf.clone(), 1, "reload_state.log");
+
+    write_conf("lua.conf", "log_level = 1\nfps = 20\n");
+    let changes = reloader.reload().unwrap();
+    gs.config_changed(&changes);
+
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    let (mut changed, level): (Vec<String>, i32) = lua_state.context(|ctx| {
+        let changed = ctx.globals().get("changed").unwrap();
+        let level = ctx.load("return xlib.log_level").eval().unwrap();
+        (changed, level)
+    });
+    changed.sort();
+    assert_eq!(changed, vec!["fps:10->20", "log_level:3->1"]);
+    assert_eq!(level, 1);
+}
@@ -0,0 +1,251 @@
+use cable::config::Config;
+use cable::message::ServiceType;
+use cable::network::rpc_auth::{RpcAcl, RpcGuard, RpcIdentity, RpcPeer, DB_FUNC};
+use cable::protos::{DbResp, Item, ProtoType, RpcResp, RpcSend};
+
+const SECRET: &[u8] = b"cluster secret";
+
+fn server() -> RpcIdentity {
+    RpcIdentity::new(SECRET, 1, ServiceType::TCP, "10.0.0.1:8182")
+}
+
+fn client() -> RpcIdentity {
+    RpcIdentity::new(SECRET, 2, ServiceType::DB, "10.0.0.2:8182")
+}
+
+fn guard() -> RpcGuard {
+    let acl = RpcAcl::new()
+        .allow("save_player", &[ServiceType::TCP, ServiceType::DB])
+        .allow("kick_all", &[ServiceType::TCP]);
+    RpcGuard::new(server(), acl)
+}
+
+//对端自己填写的 from_host/from_addr
+fn rpc_send(func: &str) -> ProtoType {
+    ProtoType::RpcSend(RpcSend {
+        from_host: 99,
+        from_addr: "6.6.6.6:1".to_string(),
+        to_host: 1,
+        to_addr: "10.0.0.1:8182".to_string(),
+        session: 7,
+        func: func.to_string(),
+        args: String::new(),
+        trace_id: String::new(),
+    })
+}
+
+fn rpc_resp(func: &str) -> ProtoType {
+    ProtoType::RpcResp(RpcResp {
+        from_host: 99,
+        from_addr: "6.6.6.6:1".to_string(),
+        session: 7,
+        func: func.to_string(),
+        ..Default::default()
+    })
+}
+
+//完成 vfd 上的认证
+fn login(guard: &mut RpcGuard, vfd: u64, identity: &RpcIdentity) {
+    let challenge = guard.connect(vfd);
+    let auth = identity.respond(&challenge);
+    assert!(guard
+        .check(vfd, ProtoType::RpcAuth(auth))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn challenge_response() {
+    let server = server();
+    let challenge = server.challenge();
+    assert_eq!(challenge.host_id, 1);
+    assert_ne!(challenge.nonce, server.challenge().nonce);
+
+    let auth = client().respond(&challenge);
+    let peer = server.verify(&challenge.nonce, &auth).unwrap();
+    assert_eq!(
+        peer,
+        RpcPeer {
+            host_id: 2,
+            service_type: ServiceType::DB,
+            addr: "10.0.0.2:8182".to_string(),
+        }
+    );
+}
+
+#[test]
+fn reject_wrong_secret_and_tamper() {
+    let server = server();
+    let challenge = server.challenge();
+
+    let other = RpcIdentity::new(b"other secret", 2, ServiceType::DB, "10.0.0.2:8182");
+    assert!(server
+        .verify(&challenge.nonce, &other.respond(&challenge))
+        .is_err());
+
+    //认证的字段都在 mac 中, 修改任意一个都不能通过
+    let auth = client().respond(&challenge);
+    let mut forged = auth.clone();
+    forged.host_id = 3;
+    assert!(server.verify(&challenge.nonce, &forged).is_err());
+    let mut forged = auth.clone();
+    forged.service_type = "game_service".to_string();
+    assert!(server.verify(&challenge.nonce, &forged).is_err());
+    let mut forged = auth.clone();
+    forged.addr = "6.6.6.6:1".to_string();
+    assert!(server.verify(&challenge.nonce, &forged).is_err());
+
+    //旧的回复不能用于新的 challenge
+    assert!(server.verify(&server.challenge().nonce, &auth).is_err());
+
+    //发给其他服务器的回复不能转用到本服务器
+    let host3 = RpcIdentity::new(SECRET, 3, ServiceType::TCP, "10.0.0.3:8182");
+    let mut challenge3 = host3.challenge();
+    challenge3.nonce = challenge.nonce.clone();
+    assert!(server
+        .verify(&challenge.nonce, &client().respond(&challenge3))
+        .is_err());
+}
+
+#[test]
+fn guard_rejects_unauthenticated() {
+    let mut guard = guard();
+    //没有 challenge 的连接
+    assert!(guard.check(1, rpc_send("save_player")).is_err());
+
+    //已发出 challenge, 但还没有认证
+    let challenge = guard.connect(2);
+    assert!(guard.check(2, rpc_send("save_player")).is_err());
+
+    //认证失败后, challenge 不能再次使用
+    let mut auth = client().respond(&challenge);
+    auth.mac[0] ^= 1;
+    assert!(guard.check(2, ProtoType::RpcAuth(auth)).is_err());
+    let auth = client().respond(&challenge);
+    assert!(guard.check(2, ProtoType::RpcAuth(auth)).is_err());
+    assert!(guard.peer(2).is_none());
+}
+
+#[test]
+fn guard_uses_verified_identity() {
+    let mut guard = guard();
+    login(&mut guard, 5, &client());
+    assert_eq!(guard.peer(5).unwrap().host_id, 2);
+
+    match guard.check(5, rpc_send("save_player")).unwrap() {
+        Some(ProtoType::RpcSend(p)) => {
+            assert_eq!(p.from_host, 2);
+            assert_eq!(p.from_addr, "10.0.0.2:8182");
+            assert_eq!(p.session, 7);
+        }
+        other => panic!("unexpected: {:?}", other),
+    }
+    let resp = rpc_resp("save_player");
+    match guard.check(5, resp).unwrap() {
+        Some(ProtoType::RpcResp(p)) => assert_eq!(p.from_host, 2),
+        other => panic!("unexpected: {:?}", other),
+    }
+
+    //rpc 连接上只接受 rpc 协议
+    assert!(guard
+        .check(5, ProtoType::Item(Item { uid: 1, id: 1 }))
+        .is_err());
+
+    //连接断开后身份失效
+    guard.close(5);
+    assert!(guard.check(5, rpc_send("save_player")).is_err());
+}
+
+#[test]
+fn guard_applies_acl() {
+    let mut guard = guard();
+    login(&mut guard, 1, &client());
+    login(
+        &mut guard,
+        2,
+        &RpcIdentity::new(SECRET, 3, ServiceType::TCP, "10.0.0.3:8182"),
+    );
+
+    assert!(guard.check(1, rpc_send("save_player")).is_ok());
+    assert!(guard.check(1, rpc_send("kick_all")).is_err());
+    assert!(guard.check(1, rpc_send("unknown_func")).is_err());
+    //被拒绝的调用不影响已认证的身份
+    assert!(guard.peer(1).is_some());
+    assert!(guard.check(2, rpc_send("kick_all")).is_ok());
+}
+
+#[test]
+fn guard_checks_responses() {
+    let acl = RpcAcl::new()
+        .allow("save_player", &[ServiceType::DB])
+        .allow(DB_FUNC, &[ServiceType::TCP, ServiceType::DB]);
+    let mut guard = RpcGuard::new(server(), acl);
+    login(&mut guard, 1, &client());
+    let game = RpcIdentity::new(SECRET, 3, ServiceType::TCP, "10.0.0.3:8182");
+    login(&mut guard, 2, &game);
+
+    //回复按函数名检查权限
+    assert!(guard.check(1, rpc_resp("save_player")).is_ok());
+    assert!(guard.check(2, rpc_resp("save_player")).is_err());
+    assert!(guard.check(1, rpc_resp("unknown_func")).is_err());
+
+    //db 回复只接受 db 服务, 即使其他服务在 DB_FUNC 的权限中
+    let db_resp = || {
+        ProtoType::DbResp(DbResp {
+            id: 1,
+            ok: true,
+            ..Default::default()
+        })
+    };
+    assert!(guard.check(1, db_resp()).is_ok());
+    assert!(guard.check(2, db_resp()).is_err());
+
+    //db 服务不在 DB_FUNC 的权限中时同样拒绝
+    let acl = RpcAcl::new().allow(DB_FUNC, &[ServiceType::TCP]);
+    let mut guard = RpcGuard::new(server(), acl);
+    login(&mut guard, 1, &client());
+    assert!(guard.check(1, db_resp()).is_err());
+    assert!(guard.peer(1).is_some());
+}
+
+#[test]
+fn acl_from_config() {
+    let path = std::env::temp_dir().join(format!("cable_rpc_acl_{}.conf", std::process::id()));
+    std::fs::write(
+        &path,
+        "rpc_secret = s3cret\n\
+         rpc_acl.save_player = game_service, db_service\n\
+         rpc_acl.* = game_service #默认规则\n",
+    )
+    .unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    let acl = RpcAcl::from_config(&conf).unwrap();
+    assert!(acl.check("save_player", ServiceType::DB));
+    assert!(acl.check("save_player", ServiceType::TCP));
+    assert!(acl.check("anything", ServiceType::TCP));
+    assert!(!acl.check("anything", ServiceType::DB));
+
+    assert!(!RpcAcl::new().allow("f", &[]).check("f", ServiceType::TCP));
+    let bad = conf.with("rpc_acl.f", "game_service,nobody");
+    assert!(RpcAcl::from_config(&bad).is_err());
+}
+
+#[test]
+fn identity_requires_secret() {
+    let path = std::env::temp_dir().join(format!("cable_rpc_id_{}.conf", std::process::id()));
+    std::fs::write(
+        &path,
+        "host_id = 1\nservice_type = game_service\nrpc_service_addr = 0.0.0.0:8182\n",
+    )
+    .unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(RpcIdentity::from_config(&conf).is_err());
+    let conf = conf.with("rpc_secret", "s3cret");
+    let identity = RpcIdentity::from_config(&conf).unwrap();
+    assert_eq!(identity.host_id, 1);
+    assert_eq!(identity.service_type, ServiceType::TCP);
+}
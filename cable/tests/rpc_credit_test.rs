@@ -0,0 +1,272 @@
+use cable::config::Config;
+use cable::message::{MessageType, Packet, ServiceType, SystemMsg};
+use cable::network;
+use cable::network::credit::{Backlog, Credit, CreditGrant};
+use cable::network::tcp::{read::ConnReader, write::ConnWriter};
+use cable::protos::{ProtoType, RpcAuth, RpcChallenge, RpcSend};
+use cable::services;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+
+mod common;
+
+const VFD: u64 = 100;
+
+fn rpc_send(session: u64) -> SystemMsg {
+    let pto = ProtoType::RpcSend(RpcSend {
+        session,
+        func: "func_rpc_test".to_string(),
+        args: "x".repeat(64),
+        ..Default::default()
+    });
+    (MessageType::Rpc, VFD, Packet::Proto(pto))
+}
+
+fn session_of(msg: SystemMsg) -> u64 {
+    match msg.2.into_proto() {
+        Ok(ProtoType::RpcSend(inner)) => inner.session,
+        other => panic!("unexpected message: {:?}", other.map(|p| p.inner_info().0)),
+    }
+}
+
+#[test]
+fn credit_take_and_wait() {
+    let credit = Credit::new();
+    assert!(!credit.is_active());
+    assert_eq!(credit.take(10), 0);
+
+    credit.grant(5);
+    assert!(credit.is_active());
+    assert_eq!(credit.take(3), 3);
+    //不足时取出剩余的全部
+    assert_eq!(credit.take(3), 2);
+    assert_eq!(credit.available(), 0);
+
+    //收到额度之前发出的消息从之后的额度中扣除
+    let gate = Credit::new();
+    gate.spend(3);
+    gate.grant(2);
+    assert!(gate.is_active());
+    assert_eq!(gate.available(), 0);
+    gate.grant(4);
+    assert_eq!(gate.available(), 3);
+    gate.spend(1);
+    assert_eq!(gate.available(), 2);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let waiter = credit.clone();
+        let task = tokio::spawn(async move { waiter.wait().await });
+        tokio::task::yield_now().await;
+        credit.grant(7);
+        assert_eq!(task.await.unwrap(), 7);
+        //等待不会取出额度
+        assert_eq!(credit.available(), 7);
+    });
+}
+
+#[test]
+fn grant_by_batch() {
+    let (mut grant, credit) = CreditGrant::new(4);
+    //连接建立时先给出整个窗口
+    assert_eq!(credit.take(u64::MAX), 4);
+    grant.delivered();
+    assert_eq!(credit.available(), 0);
+    grant.delivered();
+    assert_eq!(credit.available(), 2);
+    for _ in 0..3 {
+        grant.delivered();
+    }
+    assert_eq!(credit.available(), 4);
+}
+
+#[test]
+fn backlog_keeps_order() {
+    let (tx, mut rx) = mpsc::channel(2);
+    let mut backlog = Backlog::new("rpc_credit_test");
+    for i in 0..5 {
+        backlog.send(&tx, VFD, rpc_send(i)).unwrap();
+    }
+    assert_eq!(backlog.len(VFD), 3);
+    assert_eq!(backlog.flush(&tx, VFD).unwrap(), 0);
+
+    let mut got = vec![session_of(rx.try_recv().unwrap())];
+    //有暂存的消息时, 新消息排在后面
+    backlog.send(&tx, VFD, rpc_send(5)).unwrap();
+    assert_eq!(backlog.len(VFD), 4);
+    while !backlog.is_empty() {
+        backlog.flush(&tx, VFD).unwrap();
+        while let Ok(msg) = rx.try_recv() {
+            got.push(session_of(msg));
+        }
+    }
+    assert_eq!(got, (0..6).collect::<Vec<_>>());
+
+    //连接关闭时返回错误并清理
+    backlog.send(&tx, VFD, rpc_send(6)).unwrap();
+    backlog.send(&tx, VFD, rpc_send(7)).unwrap();
+    backlog.send(&tx, VFD, rpc_send(8)).unwrap();
+    drop(rx);
+    assert!(backlog.flush(&tx, VFD).is_err());
+    assert!(backlog.is_empty());
+    assert!(backlog.send(&tx, VFD, rpc_send(9)).is_err());
+}
+
+#[test]
+fn backlog_drops_over_max() {
+    let (tx, mut rx) = mpsc::channel(1);
+    let mut backlog = Backlog::new("backlog_over_max").with_max(2);
+    backlog.send(&tx, VFD, rpc_send(0)).unwrap();
+    backlog.send(&tx, VFD, rpc_send(1)).unwrap();
+    backlog.send(&tx, VFD, rpc_send(2)).unwrap();
+    //暂存已满, 新消息丢弃并计数, 其他连接不受影响
+    assert!(backlog.send(&tx, VFD, rpc_send(3)).is_err());
+    assert_eq!(backlog.len(VFD), 2);
+    assert_eq!(backlog.queued(), 2);
+    let metrics = cable::metrics::gather();
+    assert!(metrics.contains("cable_backlog{site=\"backlog_over_max\"} 2"));
+    assert!(metrics.contains("cable_backlog_dropped_total{site=\"backlog_over_max\"} 1"));
+
+    let mut got = vec![session_of(rx.try_recv().unwrap())];
+    while !backlog.is_empty() {
+        backlog.flush(&tx, VFD).unwrap();
+        got.push(session_of(rx.try_recv().unwrap()));
+    }
+    assert_eq!(got, [0, 1, 2]);
+    assert_eq!(backlog.queued(), 0);
+    assert!(cable::metrics::gather().contains("cable_backlog{site=\"backlog_over_max\"} 0"));
+}
+
+//脚本持续以远超服务端处理能力的速度经 rpc_client_hub 发送, 服务端的 hub 通道很小且处理很慢, 所有消息按顺序送达
+#[tokio::test]
+async fn no_loss_under_overload() {
+    const TOTAL: u64 = 20_000;
+    const WINDOW: u64 = 64;
+    const HOST: u64 = 2;
+
+    //日志写到临时目录
+    common::workdir();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    //rpc 发送服务, 发件通道只有 16 个位置, 暂存放得下所有消息
+    let conf = Config::new(&format!(
+        "{}/etc/sysconfig.conf",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .with("host_id", "1")
+    .with("rpc_secret", "s3cret")
+    .with("tcp_msg_chan_size", "16")
+    .with("rpc_backlog_max", &TOTAL.to_string())
+    .with(&format!("rpc_host.{HOST}"), &addr);
+    let (close_tx, _close_rx) = mpsc::channel(1);
+    let rpc = services::start_rpc_client(&conf, close_tx);
+
+    //和脚本的 xlib.rpc_send 一样: 发件通道满时返回错误, 由发送方稍后重试
+    let producer = tokio::spawn(async move {
+        let mut retries = 0;
+        for i in 0..TOTAL {
+            loop {
+                let pto = ProtoType::RpcSend(RpcSend {
+                    to_host: HOST as i32,
+                    session: i,
+                    func: "func_rpc_test".to_string(),
+                    args: "x".repeat(64),
+                    ..Default::default()
+                });
+                match network::try_send_rpc(&rpc, HOST, pto) {
+                    Ok(()) => break,
+                    Err(_) => {
+                        retries += 1;
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                }
+            }
+        }
+        (retries, rpc)
+    });
+
+    //rpc 服务端, hub 通道只有 4 个位置
+    //第一条消息发出后 rpc_client_hub 才连接服务端
+    let (server, _) = tokio::time::timeout(Duration::from_secs(10), listener.accept())
+        .await
+        .expect("rpc client not connected")
+        .unwrap();
+    let (srv_read, srv_write) = server.into_split();
+    let limit = Arc::new(Semaphore::new(16));
+    let (shutdown_tx, _shutdown_rx) = mpsc::channel(1);
+    let (notify_tx, _) = broadcast::channel(1);
+    let (hub_tx, mut hub_rx) = mpsc::channel(4);
+    let (srv_out_tx, srv_out_rx) = mpsc::channel(16);
+    let (pair_tx, pair_rx) = mpsc::channel(1);
+    let (grant, credit) = CreditGrant::new(WINDOW);
+    let mut reader = ConnReader::new(
+        ServiceType::RPC,
+        VFD,
+        Box::new(srv_read),
+        limit,
+        hub_tx,
+        shutdown_tx,
+        notify_tx.subscribe(),
+        pair_tx,
+    )
+    .with_credit_grant(grant);
+    let mut writer = ConnWriter::new(
+        ServiceType::RPC,
+        VFD,
+        Box::new(srv_write),
+        srv_out_rx,
+        pair_rx,
+    )
+    .with_credit_grant(credit);
+    tokio::spawn(async move { reader.run().await });
+    tokio::spawn(async move { writer.run().await });
+    let challenge = ProtoType::RpcChallenge(RpcChallenge {
+        host_id: HOST as i32,
+        nonce: vec![7; 32],
+    });
+    srv_out_tx
+        .send((MessageType::Rpc, VFD, Packet::Proto(challenge)))
+        .await
+        .unwrap();
+
+    //处理很慢的 hub, 连接上的第一条消息是认证
+    let mut got = Vec::with_capacity(TOTAL as usize);
+    let mut authed = false;
+    while (got.len() as u64) < TOTAL {
+        let msg = tokio::time::timeout(Duration::from_secs(10), hub_rx.recv())
+            .await
+            .expect("rpc messages stalled")
+            .unwrap();
+        if !authed {
+            assert!(matches!(
+                msg.2.into_proto(),
+                Ok(ProtoType::RpcAuth(RpcAuth { host_id: 1, .. }))
+            ));
+            authed = true;
+            continue;
+        }
+        got.push(session_of(msg));
+        if got.len() % 200 == 0 {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+    }
+    let (retries, _rpc) = producer.await.unwrap();
+
+    assert_eq!(got, (0..TOTAL).collect::<Vec<_>>());
+    //确实发生了过载, 发送方因为通道满而重试, rpc_client_hub 没有丢弃消息
+    assert!(retries > 0, "retries={retries}");
+    assert!(
+        !cable::metrics::gather().contains("cable_backlog_dropped_total{site=\"rpc_client_hub\"}")
+    );
+    //服务端通道里没有多余的消息
+    assert!(hub_rx.try_recv().is_err());
+
+    drop(srv_out_tx);
+}
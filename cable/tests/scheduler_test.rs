@@ -0,0 +1,118 @@
+use cable::config::Config;
+use cable::modules::scheduler::{Class, SchedConf, Scheduler};
+
+fn sched(weights: [usize; 3], queue_max: usize) -> Scheduler<u64, u32> {
+    Scheduler::new(SchedConf {
+        weights,
+        queue_max,
+        ..Default::default()
+    })
+}
+
+fn drain(s: &mut Scheduler<u64, u32>) -> Vec<(u64, u32)> {
+    std::iter::from_fn(|| s.pop()).collect()
+}
+
+#[test]
+fn round_robin_between_connections() {
+    let mut s = sched([1, 1, 1], 1000);
+    //连接 1 一次发来大量消息, 连接 2 和 3 各一条
+    for i in 0..100 {
+        s.push(1, Class::Gameplay, i).unwrap();
+    }
+    s.push(2, Class::Gameplay, 0).unwrap();
+    s.push(3, Class::Gameplay, 0).unwrap();
+    assert_eq!(s.len(), 102);
+
+    let order = drain(&mut s);
+    assert_eq!(&order[..4], &[(1, 0), (2, 0), (3, 0), (1, 1)]);
+    //同一个连接的消息保持顺序
+    let vfd1: Vec<u32> = order
+        .iter()
+        .filter(|(k, _)| *k == 1)
+        .map(|(_, v)| *v)
+        .collect();
+    assert_eq!(vfd1, (0..100).collect::<Vec<_>>());
+    assert!(s.is_empty());
+}
+
+#[test]
+fn weighted_priority_classes() {
+    let mut s = sched([3, 2, 1], 1000);
+    for i in 0..10 {
+        s.push(100 + i, Class::Gameplay, 0).unwrap();
+        s.push(200 + i, Class::Login, 0).unwrap();
+        s.push(300 + i, Class::Rpc, 0).unwrap();
+    }
+    let classes: Vec<u64> = drain(&mut s).iter().map(|(k, _)| k / 100).collect();
+    //每一轮: rpc 3 条, 登录 2 条, 游戏 1 条
+    assert_eq!(&classes[..12], &[3, 3, 3, 2, 2, 1, 3, 3, 3, 2, 2, 1]);
+    //高优先级处理完后, 低优先级使用全部额度
+    assert_eq!(&classes[25..], &[1, 1, 1, 1, 1]);
+}
+
+#[test]
+fn gameplay_not_starved() {
+    let mut s = sched([4, 2, 1], 1000);
+    s.push(1, Class::Gameplay, 0).unwrap();
+    for i in 0..100 {
+        s.push_unbounded(2, Class::Rpc, i);
+    }
+    let order = drain(&mut s);
+    let pos = order.iter().position(|(k, _)| *k == 1).unwrap();
+    assert!(pos <= 4);
+}
+
+#[test]
+fn connection_order_across_classes() {
+    let mut s = sched([1, 1, 1], 1000);
+    //同一个连接先发游戏消息再发登录消息, 登录消息不能越过前面的游戏消息
+    s.push(1, Class::Gameplay, 1).unwrap();
+    s.push(1, Class::Login, 2).unwrap();
+    s.push(2, Class::Login, 1).unwrap();
+    let order = drain(&mut s);
+    assert_eq!(order, vec![(2, 1), (1, 1), (1, 2)]);
+}
+
+#[test]
+fn queue_limit_per_connection() {
+    let mut s = sched([1, 1, 1], 2);
+    s.push(1, Class::Gameplay, 1).unwrap();
+    s.push(1, Class::Gameplay, 2).unwrap();
+    assert_eq!(s.push(1, Class::Gameplay, 3), Err(3));
+    //只影响该连接自己
+    s.push(2, Class::Gameplay, 1).unwrap();
+    //连接断开等消息不受上限限制
+    s.push_unbounded(1, Class::Gameplay, 4);
+    assert_eq!(s.len(), 4);
+
+    assert_eq!(s.pop(), Some((1, 1)));
+    assert_eq!(s.pop(), Some((2, 1)));
+    assert_eq!(s.push(1, Class::Gameplay, 5), Err(5));
+    assert_eq!(s.pop(), Some((1, 2)));
+    assert!(s.push(1, Class::Gameplay, 5).is_ok());
+}
+
+#[test]
+fn sched_from_config() {
+    let path = std::env::temp_dir().join(format!("cable_sched_{}.conf", std::process::id()));
+    std::fs::write(
+        &path,
+        "sched_budget = 64\n\
+         sched_weight_rpc = 8\n\
+         sched_weight_gameplay = 0\n\
+         sched_login_protos = 109, 100\n",
+    )
+    .unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    let sc = SchedConf::from_config(&conf);
+    assert_eq!(sc.budget, 64);
+    //权重至少为 1, 未配置的使用默认值
+    assert_eq!(sc.weights, [8, 2, 1]);
+    assert_eq!(sc.queue_max, SchedConf::default().queue_max);
+    assert_eq!(sc.classify(109), Class::Login);
+    assert_eq!(sc.classify(100), Class::Login);
+    assert_eq!(sc.classify(101), Class::Gameplay);
+}
@@ -0,0 +1,466 @@
+use cable::config::Config;
+use cable::message::{MessageType, Packet, ServiceType, SystemMsg};
+use cable::modules::session::{SessionTable, SessionTarget, REMOTE_VFD_BASE};
+use cable::modules::shard::ShardConf;
+use cable::network::rpc_auth::{RpcAcl, RpcGuard, RpcIdentity, SESSION_FUNC};
+use cable::protos::{ProtoType, S2cLogin, SessionAck, SessionRelay};
+use cable::states::{GameState, SessionState, ShardState};
+use tokio::sync::mpsc::{self, Receiver};
+
+mod common;
+
+const MAIN_LUA: &str = r#"
+events = {}
+local function push(...)
+    local t = {}
+    for i, v in ipairs({...}) do t[i] = tostring(v) end
+    events[#events + 1] = table.concat(t, ",")
+end
+function _timer_msg() end
+function _tcp_msg(vfd, proto_id, proto_name, t) push("tcp", vfd, proto_name, t.account) end
+function _session_resume(vfd, from_host, from_shard, data)
+    local player = load("return " .. data)()
+    push("resume", vfd, from_host, from_shard, player.name, player.hp)
+    return player.hp > 0
+end
+function _session_migrated(vfd, ok, reason) push("migrated", vfd, ok, reason) end
+function _session_abort(vfd, reason) push("abort", vfd, reason) end
+"#;
+
+const VFD: u64 = 100;
+
+fn config(host_id: i32) -> Config {
+    config_with(host_id, "")
+}
+
+fn config_with(host_id: i32, extra: &str) -> Config {
+    let dir = common::logic_dir(MAIN_LUA);
+    let path = dir.join(format!("host_{host_id}_{}.conf", extra.len()));
+    let content = format!(
+        "host_id = {host_id}\nlog_level = 4\nlogic_path = {}\nsession_migrate_timeout_ms = 1000\n{extra}",
+        dir.display()
+    );
+    std::fs::write(&path, content).unwrap();
+    Config::new(path.to_str().unwrap())
+}
+
+fn game_state(host_id: i32, shard: usize) -> GameState {
+    let log = format!("game_state_{host_id}_{shard}.log");
+    GameState::new(ServiceType::TCP, config(host_id), host_id, &log)
+}
+
+//跨机的两个服务器, rpc 发出的消息留在通道里由测试转交
+struct Host {
+    gs: GameState,
+    rpc: Receiver<SystemMsg>,
+}
+
+fn host(host_id: i32) -> Host {
+    let (tx, rpc) = mpsc::channel(16);
+    let mut gs = game_state(host_id, 0);
+    gs.set_session(SessionState::new(&config(host_id)).with_rpc(tx));
+    Host { gs, rpc }
+}
+
+fn lua(gs: &GameState, code: &str) -> Result<(), String> {
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    lua_state.context(|ctx| ctx.load(code).exec().map_err(|err| err.to_string()))
+}
+
+//取出并清空脚本层记录的事件
+fn events(gs: &GameState) -> Vec<String> {
+    lua(gs, "__events = events; events = {}").unwrap();
+    let lua_state = gs.lua_state.as_ref().unwrap();
+    lua_state.context(|ctx| ctx.globals().get("__events").unwrap())
+}
+
+fn login(account: &str) -> Packet {
+    ProtoType::S2cLogin(S2cLogin {
+        account: account.to_string(),
+        ..Default::default()
+    })
+    .into()
+}
+
+fn rpc_out(rx: &mut Receiver<SystemMsg>, to_host: u64) -> ProtoType {
+    let (msg_type, session, packet) = rx.try_recv().unwrap();
+    assert_eq!((msg_type, session), (MessageType::Rpc, to_host));
+    packet.into_proto().unwrap()
+}
+
+fn hold(gs: &GameState, vfd: u64, account: &str) {
+    let session = gs.get_session().unwrap();
+    assert!(session
+        .hold(vfd, MessageType::Tcp, login(account))
+        .is_none());
+}
+
+#[test]
+fn table_freeze_and_alias() {
+    let target = SessionTarget { host: 2, shard: 0 };
+    let mut table: SessionTable<u32> = SessionTable::new();
+    assert_eq!(table.hold(VFD, 1), Some(1));
+
+    let id = table.freeze(VFD, 0, target, 1000).unwrap();
+    assert!(table.freeze(VFD, 0, target, 1000).is_err());
+    assert_eq!(table.frozen_by(VFD), Some(0));
+    assert_eq!(table.hold(VFD, 1), None);
+    assert_eq!(table.hold(VFD, 2), None);
+    //过期的结果不影响当前的迁移
+    assert!(table.thaw(VFD, id + 1).is_none());
+    let outgoing = table.thaw(VFD, id).unwrap();
+    assert_eq!(outgoing.pending, vec![1, 2]);
+    assert_eq!(outgoing.target, target);
+    assert!(!table.is_frozen(VFD));
+
+    //只有超时的迁移, 且只返回发起迁移的分片的
+    table.freeze(VFD, 0, target, 1000).unwrap();
+    table.freeze(VFD + 1, 1, target, 1000).unwrap();
+    table.freeze(VFD + 2, 0, target, 2000).unwrap();
+    let expired = table.expired(0, 1500);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].0, VFD);
+    assert_eq!(table.close(VFD + 1).0.unwrap().shard, 1);
+    table.close(VFD + 2);
+
+    //迁移到其他机器后不能再次迁移
+    table.bind_remote(VFD, 2);
+    assert_eq!(table.remote_of(VFD), Some(2));
+    assert!(table.freeze(VFD, 0, target, 1000).is_err());
+    assert!(matches!(table.close(VFD), (None, Some(2))));
+
+    //其他机器迁移过来的会话使用本机分配的 vfd
+    let alias = table.alias(2, VFD, 1);
+    assert!(alias >= REMOTE_VFD_BASE);
+    assert_eq!(table.alias(2, VFD, 1), alias);
+    assert_ne!(table.alias(3, VFD, 0), alias);
+    assert_eq!(table.alias_of(2, VFD), Some((alias, 1)));
+    assert_eq!(table.origin_of(alias), Some((2, VFD)));
+    assert!(table.freeze(alias, 1, target, 1000).is_err());
+    assert_eq!(table.unalias(alias), Some((2, VFD)));
+    assert_eq!(table.alias_of(2, VFD), None);
+    table.unalias(table.alias_of(3, VFD).unwrap().0);
+    assert!(table.is_empty());
+}
+
+#[tokio::test]
+async fn migrate_between_hosts() {
+    let (mut a, mut b) = (host(1), host(2));
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    a.gs.add_vfd(VFD, client_tx);
+
+    lua(
+        &a.gs,
+        "xlib.session_migrate(100, 2, 0, {name='bob', hp=10})",
+    )
+    .unwrap();
+    //冻结期间不能再次迁移, 收到的消息暂存
+    assert!(lua(&a.gs, "xlib.session_migrate(100, 2, 0, {})").is_err());
+    hold(&a.gs, VFD, "m1");
+    hold(&a.gs, VFD, "m2");
+
+    let migrate = rpc_out(&mut a.rpc, 2);
+    assert!(b.gs.session_dispatch(migrate).unwrap().is_none());
+    let alias = REMOTE_VFD_BASE;
+    assert_eq!(events(&b.gs), vec![format!("resume,{alias},1,0,bob,10")]);
+
+    let ack = rpc_out(&mut b.rpc, 1);
+    assert!(matches!(
+        ack,
+        ProtoType::SessionAck(SessionAck { ok: true, .. })
+    ));
+    assert!(a.gs.session_dispatch(ack).unwrap().is_none());
+    assert_eq!(events(&a.gs), vec!["migrated,100,true,"]);
+    assert_eq!(a.gs.get_session().unwrap().remote_of(VFD), Some(2));
+
+    //暂存的消息按顺序转给目标机器, 目标机器按本机连接处理
+    for account in ["m1", "m2"] {
+        let relay = rpc_out(&mut a.rpc, 2);
+        let (msg_type, vfd, packet) = b.gs.session_dispatch(relay).unwrap().unwrap();
+        assert_eq!((msg_type, vfd), (MessageType::Tcp, alias));
+        b.gs.dispatch(msg_type, vfd, packet.into_proto().unwrap())
+            .unwrap();
+        assert_eq!(
+            events(&b.gs),
+            vec![format!("tcp,{alias},S2cLogin,{account}")]
+        );
+    }
+
+    //目标机器发给会话的消息, 经源机器交给连接
+    lua(
+        &b.gs,
+        &format!("xlib.tcp_send({alias}, 102, 'C2sLogin', {{ret=0, magic=7}})"),
+    )
+    .unwrap();
+    let (msg_type, _, packet) = b.rpc.recv().await.unwrap();
+    assert_eq!(msg_type, MessageType::Rpc);
+    let relay = packet.into_proto().unwrap();
+    assert!(matches!(
+        relay,
+        ProtoType::SessionRelay(SessionRelay {
+            vfd: VFD,
+            host: 2,
+            ..
+        })
+    ));
+    assert!(a.gs.session_dispatch(relay).unwrap().is_none());
+    let (_, vfd, packet) = client_rx.try_recv().unwrap();
+    assert!(matches!(packet, Packet::Encoded(_)));
+    match packet.into_proto().unwrap() {
+        ProtoType::C2sLogin(p) => assert_eq!((vfd, p.magic), (VFD, 7)),
+        other => panic!("unexpected: {other:?}"),
+    }
+
+    //连接断开, 目标机器释放会话
+    a.gs.close_session(VFD);
+    let relay = rpc_out(&mut a.rpc, 2);
+    let (msg_type, vfd, _) = b.gs.session_dispatch(relay).unwrap().unwrap();
+    assert_eq!((msg_type, vfd), (MessageType::SocketClosed, alias));
+    b.gs.close_session(alias);
+    b.gs.delete_vfd(alias);
+    assert!(a.gs.get_session().unwrap().table().is_empty());
+    assert!(b.gs.get_session().unwrap().table().is_empty());
+}
+
+#[tokio::test]
+async fn rollback_when_refused() {
+    let (mut a, mut b) = (host(1), host(2));
+    lua(&a.gs, "xlib.session_migrate(100, 2, 0, {name='bob', hp=0})").unwrap();
+    hold(&a.gs, VFD, "m1");
+
+    b.gs.session_dispatch(rpc_out(&mut a.rpc, 2)).unwrap();
+    let alias = REMOTE_VFD_BASE;
+    assert_eq!(events(&b.gs), vec![format!("resume,{alias},1,0,bob,0")]);
+    assert!(b.gs.get_sender(alias).is_none());
+    assert!(b.gs.get_session().unwrap().table().is_empty());
+
+    //目标端拒绝, 源端恢复会话并处理暂存的消息
+    a.gs.session_dispatch(rpc_out(&mut b.rpc, 1)).unwrap();
+    assert_eq!(
+        events(&a.gs),
+        vec!["migrated,100,false,refused", "tcp,100,S2cLogin,m1"]
+    );
+    assert!(a.gs.get_session().unwrap().table().is_empty());
+    //之后可以再次迁移
+    lua(&a.gs, "xlib.session_migrate(100, 2, 0, {name='bob', hp=1})").unwrap();
+}
+
+//rpc 发件队列和暂存都放不下暂存的消息时, 不交出会话
+#[tokio::test]
+async fn rollback_when_backlog_full() {
+    let (tx, mut rpc) = mpsc::channel(1);
+    let conf = config_with(1, "rpc_backlog_max = 1\n");
+    let mut a = game_state(1, 0);
+    a.set_session(SessionState::new(&conf).with_rpc(tx));
+    let mut b = host(2);
+    lua(&a, "xlib.session_migrate(100, 2, 0, {name='bob', hp=10})").unwrap();
+    for account in ["m1", "m2", "m3"] {
+        hold(&a, VFD, account);
+    }
+
+    b.gs.session_dispatch(rpc_out(&mut rpc, 2)).unwrap();
+    let alias = REMOTE_VFD_BASE;
+    assert_eq!(events(&b.gs), vec![format!("resume,{alias},1,0,bob,10")]);
+    a.session_dispatch(rpc_out(&mut b.rpc, 1)).unwrap();
+    let a_events = events(&a);
+    assert!(a_events[0].starts_with("migrated,100,false,"));
+    assert!(a_events[0].contains("commit=backlog_full"));
+    assert_eq!(
+        a_events[1..],
+        [
+            "tcp,100,S2cLogin,m1",
+            "tcp,100,S2cLogin,m2",
+            "tcp,100,S2cLogin,m3"
+        ]
+    );
+    assert_eq!(a.get_session().unwrap().remote_of(VFD), None);
+
+    //目标端收到放弃的通知, 释放已经恢复的会话
+    let abort = rpc_out(&mut rpc, 2);
+    assert!(matches!(
+        abort,
+        ProtoType::SessionAck(SessionAck { abort: true, .. })
+    ));
+    b.gs.session_dispatch(abort).unwrap();
+    assert!(events(&b.gs)[0].starts_with(&format!("abort,{alias},")));
+    assert!(b.gs.get_session().unwrap().table().is_empty());
+}
+
+//脚本层转发的消息排在已经暂存的会话消息后面
+#[tokio::test]
+async fn relay_waits_for_backlog() {
+    let (tx, mut rpc) = mpsc::channel(1);
+    let conf = config_with(1, "rpc_backlog_max = 1\n");
+    let session = SessionState::new(&conf).with_rpc(tx);
+    //第一条占满发件队列, 第二条进入暂存, 暂存也满了
+    session.relay(1, 2, login("m1")).unwrap();
+    session.relay(2, 2, login("m2")).unwrap();
+    assert!(session.relay(9, 2, login("m9")).is_err());
+
+    let (_, relay) = session.open(2, 3, 0).unwrap();
+    relay
+        .send((MessageType::Tcp, 0, login("m3")))
+        .await
+        .unwrap();
+    relay
+        .send((MessageType::Tcp, 0, login("m4")))
+        .await
+        .unwrap();
+
+    //暂存由 update_sessions 每帧 flush
+    let mut vfds = vec![];
+    while vfds.len() < 4 {
+        session.flush().unwrap();
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(1), rpc.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((msg.0, msg.1), (MessageType::Rpc, 2));
+        match msg.2.into_proto().unwrap() {
+            ProtoType::SessionRelay(r) => vfds.push(r.vfd),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+    assert_eq!(vfds, vec![1, 2, 3, 3]);
+}
+
+#[tokio::test]
+async fn rollback_on_timeout() {
+    let (mut a, mut b) = (host(1), host(2));
+    lua(
+        &a.gs,
+        "xlib.session_migrate(100, 2, 0, {name='bob', hp=10})",
+    )
+    .unwrap();
+    let migrate = rpc_out(&mut a.rpc, 2);
+    hold(&a.gs, VFD, "m1");
+
+    let now = chrono::Local::now().timestamp_millis();
+    a.gs.update_sessions(now);
+    assert!(events(&a.gs).is_empty());
+    a.gs.update_sessions(now + 2000);
+    assert_eq!(
+        events(&a.gs),
+        vec!["migrated,100,false,timeout", "tcp,100,S2cLogin,m1"]
+    );
+
+    //迟到的迁移仍然被目标端恢复, 随后收到放弃的通知
+    let abort = rpc_out(&mut a.rpc, 2);
+    assert!(matches!(
+        abort,
+        ProtoType::SessionAck(SessionAck { abort: true, .. })
+    ));
+    b.gs.session_dispatch(migrate).unwrap();
+    b.gs.session_dispatch(abort).unwrap();
+    let alias = REMOTE_VFD_BASE;
+    assert_eq!(
+        events(&b.gs),
+        vec![
+            format!("resume,{alias},1,0,bob,10"),
+            format!("abort,{alias},timeout")
+        ]
+    );
+    assert!(b.gs.get_session().unwrap().table().is_empty());
+
+    //目标端的结果已经过期
+    assert!(a.gs.session_dispatch(rpc_out(&mut b.rpc, 1)).is_err());
+}
+
+#[tokio::test]
+async fn migrate_between_shards() {
+    let conf = ShardConf {
+        shards: 2,
+        ..Default::default()
+    };
+    let (senders, mut inboxes): (Vec<_>, Vec<_>) = (0..2).map(|_| mpsc::channel(16)).unzip();
+    let (router_tx, mut router) = mpsc::unbounded_channel();
+    let session = SessionState::new(&config(1));
+    let mut shards: Vec<GameState> = (0..2)
+        .map(|id| {
+            let shard = ShardState::new(id, conf.clone(), senders.clone(), router_tx.clone());
+            let mut gs = game_state(1, id);
+            gs.set_session(session.clone().with_shard(shard.clone()));
+            gs.set_shard(shard);
+            gs
+        })
+        .collect();
+
+    lua(
+        &shards[0],
+        "xlib.session_migrate(100, 1, 1, {name='bob', hp=10})",
+    )
+    .unwrap();
+    hold(&shards[0], VFD, "m1");
+    let (msg_type, vfd, packet) = inboxes[1].try_recv().unwrap();
+    assert_eq!((msg_type, vfd), (MessageType::Shard, VFD));
+    shards[1]
+        .session_dispatch(packet.into_proto().unwrap())
+        .unwrap();
+    assert_eq!(events(&shards[1]), vec!["resume,100,1,0,bob,10"]);
+
+    let (msg_type, _, packet) = inboxes[0].try_recv().unwrap();
+    assert_eq!(msg_type, MessageType::Shard);
+    shards[0]
+        .session_dispatch(packet.into_proto().unwrap())
+        .unwrap();
+    assert_eq!(events(&shards[0]), vec!["migrated,100,true,"]);
+
+    //路由切换到目标分片, 暂存的消息交给目标分片
+    let (_, vfd, packet) = router.try_recv().unwrap();
+    match packet.into_proto().unwrap() {
+        ProtoType::ShardMigrate(m) => assert_eq!((vfd, m.from_shard, m.to_shard), (VFD, 0, 1)),
+        other => panic!("unexpected: {other:?}"),
+    }
+    let (msg_type, vfd, _) = inboxes[1].try_recv().unwrap();
+    assert_eq!((msg_type, vfd), (MessageType::Tcp, VFD));
+    assert_eq!(shards[0].get_shard().unwrap().moved_to(VFD), Some(1));
+    assert!(shards[0].get_session().unwrap().table().is_empty());
+
+    //没有 rpc 时不能迁移到其他机器, 也不能迁移到不存在的分片
+    assert!(lua(&shards[1], "xlib.session_migrate(100, 2, 0, {})").is_err());
+    assert!(lua(&shards[1], "xlib.session_migrate(100, 1, 5, {})").is_err());
+    assert!(lua(&shards[1], "xlib.session_migrate(100, 1, 1, {})").is_err());
+    assert!(shards[1].get_session().unwrap().table().is_empty());
+}
+
+#[test]
+fn guard_checks_session_acl() {
+    const SECRET: &[u8] = b"cluster secret";
+    let acl = RpcAcl::new().allow(SESSION_FUNC, &[ServiceType::TCP]);
+    let mut guard = RpcGuard::new(
+        RpcIdentity::new(SECRET, 1, ServiceType::TCP, "10.0.0.1:8182"),
+        acl,
+    );
+    let login = |guard: &mut RpcGuard, vfd: u64, identity: RpcIdentity| {
+        let auth = identity.respond(&guard.connect(vfd));
+        assert!(guard
+            .check(vfd, ProtoType::RpcAuth(auth))
+            .unwrap()
+            .is_none());
+    };
+    login(
+        &mut guard,
+        1,
+        RpcIdentity::new(SECRET, 2, ServiceType::TCP, "10.0.0.2:8182"),
+    );
+    login(
+        &mut guard,
+        2,
+        RpcIdentity::new(SECRET, 3, ServiceType::DB, "10.0.0.3:8182"),
+    );
+
+    //来源以认证结果为准
+    let relay = SessionRelay {
+        vfd: VFD,
+        host: 99,
+        ..Default::default()
+    };
+    match guard
+        .check(1, ProtoType::SessionRelay(relay.clone()))
+        .unwrap()
+    {
+        Some(ProtoType::SessionRelay(r)) => assert_eq!(r.host, 2),
+        other => panic!("unexpected: {other:?}"),
+    }
+    assert!(guard.check(2, ProtoType::SessionRelay(relay)).is_err());
+}
@@ -0,0 +1,373 @@
+use cable::config::Config;
+use cable::message::{MessageType, Packet, ServiceType, SystemMsg};
+use cable::modules::shard::{
+    HashPolicy, ScenePolicy, ShardConf, ShardKey, ShardPolicy, ShardRouter,
+};
+use cable::protos::{Item, ProtoType, RpcResp};
+use cable::states::{GameState, ShardState};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Receiver, UnboundedReceiver};
+
+mod common;
+
+//每个分片调用 xlib.rpc_send 发出请求, 收到的回复记录在 resps 中
+const MAIN_LUA: &str = r#"
+resps = {}
+function _timer_msg() end
+function _rpc_msg(is_send, from_host, from_addr, session, func, args)
+    resps[#resps + 1] = func .. "," .. session
+end
+"#;
+
+fn config(content: &str) -> Config {
+    let path = std::env::temp_dir().join(format!(
+        "cable_shard_{}_{}.conf",
+        std::process::id(),
+        content.len()
+    ));
+    std::fs::write(&path, content).unwrap();
+    let conf = Config::new(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+    conf
+}
+
+fn shard_conf(shards: usize) -> ShardConf {
+    ShardConf {
+        shards,
+        ..Default::default()
+    }
+}
+
+fn proto(msg: SystemMsg) -> (MessageType, u64, ProtoType) {
+    (msg.0, msg.1, msg.2.into_proto().unwrap())
+}
+
+#[test]
+fn hash_policy_spreads_connections() {
+    let conf = shard_conf(4);
+    let mut counts = [0; 4];
+    for vfd in 100..10_100 {
+        let shard = conf.pick(ShardKey::Vfd(vfd));
+        counts[shard] += 1;
+        //同一个 key 总是分配到同一个分片
+        assert_eq!(shard, HashPolicy.pick(ShardKey::Vfd(vfd), 4));
+    }
+    for n in counts {
+        assert!(n > 2000, "counts={counts:?}");
+    }
+}
+
+#[test]
+fn scene_policy() {
+    let policy = ScenePolicy {
+        lobby: 1,
+        scenes: HashMap::from([(1001, 2), (1002, 9)]),
+    };
+    assert_eq!(policy.pick(ShardKey::Vfd(100), 4), 1);
+    assert_eq!(policy.pick(ShardKey::Scene(1001), 4), 2);
+    //超出分片数的配置取最后一个分片
+    assert_eq!(policy.pick(ShardKey::Scene(1002), 4), 3);
+    let other = policy.pick(ShardKey::Scene(2000), 4);
+    assert_eq!(other, HashPolicy.pick(ShardKey::Scene(2000), 4));
+}
+
+#[test]
+fn custom_policy() {
+    struct Fixed;
+    impl ShardPolicy for Fixed {
+        fn pick(&self, _key: ShardKey, _shards: usize) -> usize {
+            7
+        }
+    }
+    let conf = shard_conf(3).with_policy(Arc::new(Fixed));
+    //策略返回的分片超出范围时取最后一个
+    assert_eq!(conf.pick(ShardKey::Vfd(1)), 2);
+    let mut router: ShardRouter<u32> = ShardRouter::new(conf);
+    assert_eq!(router.connect(100), 2);
+}
+
+#[test]
+fn shard_from_config() {
+    let conf = ShardConf::from_config(&config("")).unwrap();
+    assert_eq!(conf.shards, 1);
+    assert!(!conf.is_sharded());
+    assert_eq!(conf.max_pending, 1000);
+    assert_eq!(conf.max_backlog, 10000);
+    let conf = ShardConf::from_config(&config(
+        "shard_migrate_pending_max = 5\nshard_backlog_max = 6\n",
+    ))
+    .unwrap();
+    assert_eq!(conf.max_pending, 5);
+    assert_eq!(conf.max_backlog, 6);
+
+    let conf = config(
+        "logic_shards = 4\n\
+         shard_policy = scene\n\
+         shard_lobby = 3\n\
+         shard_scene.1001 = 2\n",
+    );
+    let shards = ShardConf::from_config(&conf).unwrap();
+    assert!(shards.is_sharded());
+    assert_eq!(shards.pick(ShardKey::Vfd(100)), 3);
+    assert_eq!(shards.pick(ShardKey::Scene(1001)), 2);
+
+    assert!(ShardConf::from_config(&conf.clone().with("shard_scene.1002", "4")).is_err());
+    assert!(ShardConf::from_config(&conf.clone().with("shard_lobby", "4")).is_err());
+    assert!(ShardConf::from_config(&conf.with("shard_policy", "random")).is_err());
+}
+
+#[test]
+fn router_migrate_keeps_order() {
+    let mut router: ShardRouter<u32> = ShardRouter::new(shard_conf(2));
+    let from = router.connect(100);
+    let to = 1 - from;
+    assert_eq!(router.route(100, 1).unwrap(), Some((from, 1)));
+
+    //只有连接当前所属的分片可以发起迁移
+    assert!(!router.begin_migrate(100, to, from));
+    assert!(!router.begin_migrate(100, from, from));
+    assert!(!router.begin_migrate(100, from, 2));
+    assert!(router.begin_migrate(100, from, to));
+    assert_eq!(router.shard_of(100), Some(to));
+
+    //迁移中的消息暂存, fence 之后一起交给新分片
+    assert_eq!(router.route(100, 2).unwrap(), None);
+    assert_eq!(router.route(100, 3).unwrap(), None);
+    assert_eq!(router.end_migrate(100), Some((to, vec![2, 3])));
+    assert_eq!(router.route(100, 4).unwrap(), Some((to, 4)));
+    assert_eq!(router.end_migrate(100), None);
+
+    //迁移中断开连接, 暂存的消息丢弃
+    assert!(router.begin_migrate(100, to, from));
+    assert_eq!(router.route(100, 5).unwrap(), None);
+    assert_eq!(router.close(100), 1);
+    assert!(router.is_empty());
+    assert_eq!(router.end_migrate(100), None);
+}
+
+#[test]
+fn router_aborts_when_pending_full() {
+    let conf = ShardConf {
+        max_pending: 2,
+        ..shard_conf(2)
+    };
+    let mut router: ShardRouter<u32> = ShardRouter::new(conf);
+    let from = router.connect(100);
+    assert!(router.begin_migrate(100, from, 1 - from));
+    assert_eq!(router.route(100, 1).unwrap(), None);
+    assert_eq!(router.route(100, 2).unwrap(), None);
+    //暂存已满, 放弃迁移, 之后的消息和迟到的 fence 都不再处理
+    assert!(router.route(100, 3).is_err());
+    assert_eq!(router.route(100, 4).unwrap(), None);
+    assert_eq!(router.shard_of(100), None);
+    assert_eq!(router.end_migrate(100), None);
+    assert_eq!(router.close(100), 0);
+    assert!(router.is_empty());
+}
+
+struct Shards {
+    states: Vec<ShardState>,
+    inboxes: Vec<Receiver<SystemMsg>>,
+    router: UnboundedReceiver<SystemMsg>,
+}
+
+fn shards(n: usize) -> Shards {
+    let (senders, inboxes): (Vec<_>, Vec<_>) = (0..n).map(|_| mpsc::channel(16)).unzip();
+    let (router_tx, router) = mpsc::unbounded_channel();
+    let states = (0..n)
+        .map(|id| ShardState::new(id, shard_conf(n), senders.clone(), router_tx.clone()))
+        .collect();
+    Shards {
+        states,
+        inboxes,
+        router,
+    }
+}
+
+#[test]
+fn shard_send_and_migrate() {
+    let mut s = shards(3);
+    assert_eq!(s.states[1].id(), 1);
+    assert_eq!(s.states[1].num(), 3);
+
+    s.states[0]
+        .send(2, "on_hello".to_string(), "{1,2}".to_string())
+        .unwrap();
+    match proto(s.inboxes[2].try_recv().unwrap()) {
+        (MessageType::Shard, 0, ProtoType::ShardMsg(m)) => {
+            assert_eq!((m.from_shard, m.to_shard), (0, 2));
+            assert_eq!((m.func.as_str(), m.args.as_str()), ("on_hello", "{1,2}"));
+        }
+        other => panic!("unexpected: {other:?}"),
+    }
+    assert!(s.states[0].send(3, String::new(), String::new()).is_err());
+
+    //迁移: 目标分片收到数据, 路由收到通知, 旧分片记录迁移去向
+    s.states[0].migrate(100, 1, "{hp=10}".to_string()).unwrap();
+    match proto(s.inboxes[1].try_recv().unwrap()) {
+        (MessageType::Shard, 100, ProtoType::ShardMigrate(m)) => {
+            assert_eq!((m.vfd, m.from_shard, m.to_shard), (100, 0, 1));
+            assert_eq!(m.data, "{hp=10}");
+        }
+        other => panic!("unexpected: {other:?}"),
+    }
+    match proto(s.router.try_recv().unwrap()) {
+        (MessageType::Shard, 100, ProtoType::ShardMigrate(m)) => {
+            assert_eq!((m.from_shard, m.to_shard), (0, 1));
+            assert!(m.data.is_empty());
+        }
+        other => panic!("unexpected: {other:?}"),
+    }
+    assert_eq!(s.states[0].moved_to(100), Some(1));
+    assert!(s.states[0].migrate(101, 0, String::new()).is_err());
+
+    //fence 之后不再转发, 并通知路由
+    s.states[0].fence(100).unwrap();
+    assert_eq!(s.states[0].moved_to(100), None);
+    match proto(s.router.try_recv().unwrap()) {
+        (MessageType::Shard, 100, ProtoType::ShardFence(f)) => assert_eq!(f.shard, 0),
+        other => panic!("unexpected: {other:?}"),
+    }
+
+    //按场景查询分片
+    let scene = s.states[2].shard_of_scene(1001);
+    assert_eq!(scene, HashPolicy.pick(ShardKey::Scene(1001), 3));
+}
+
+//目标分片的通道满时, 转发的消息按顺序暂存, 全部交出之后才通知路由 fence
+#[test]
+fn forward_keeps_order_when_shard_full() {
+    let (senders, mut inboxes): (Vec<_>, Vec<_>) = (0..2).map(|_| mpsc::channel(4)).unzip();
+    let (router_tx, mut router) = mpsc::unbounded_channel();
+    let conf = ShardConf {
+        max_backlog: 4,
+        ..shard_conf(2)
+    };
+    let shard = ShardState::new(0, conf, senders, router_tx);
+    let item = |uid| Packet::Proto(ProtoType::Item(Item { uid, id: 1 }));
+
+    shard.migrate(100, 1, String::new()).unwrap();
+    router.try_recv().unwrap();
+    for uid in 0..7 {
+        shard.forward(1, MessageType::Tcp, 100, item(uid)).unwrap();
+    }
+    //暂存已满, 返回错误由调用方处理
+    assert!(shard.forward(1, MessageType::Tcp, 100, item(7)).is_err());
+    shard.fence(100).unwrap();
+    assert!(router.try_recv().is_err());
+
+    let mut got = vec![];
+    while got.len() < 8 {
+        match proto(inboxes[1].try_recv().unwrap()) {
+            (_, 100, ProtoType::ShardMigrate(_)) => got.push(u64::MAX),
+            (MessageType::Tcp, 100, ProtoType::Item(i)) => got.push(i.uid),
+            other => panic!("unexpected: {other:?}"),
+        }
+        shard.flush().unwrap();
+    }
+    assert_eq!(got, [u64::MAX, 0, 1, 2, 3, 4, 5, 6]);
+    match proto(router.try_recv().unwrap()) {
+        (MessageType::Shard, 100, ProtoType::ShardFence(f)) => assert_eq!(f.shard, 0),
+        other => panic!("unexpected: {other:?}"),
+    }
+}
+
+//rpc 的回复都由 0 号分片收到, 按 session 中的分片交给发出请求的分片
+#[tokio::test]
+async fn rpc_resp_returns_to_caller_shard() {
+    let dir = common::logic_dir(MAIN_LUA);
+    let conf = config(&format!(
+        "host_id = 1\nlog_level = 4\nlogic_path = {}\n",
+        dir.display()
+    ));
+
+    let mut s = shards(2);
+    let (rpc_tx, mut rpc_rx) = mpsc::channel(16);
+    let mut gs: Vec<GameState> = (0..2)
+        .map(|id| {
+            let log = format!("shard_rpc_{id}.log");
+            let mut gs = GameState::new(ServiceType::TCP, conf.clone(), 1, &log);
+            gs.set_rpc_sender(rpc_tx.clone());
+            gs.set_shard(s.states[id].clone());
+            gs
+        })
+        .collect();
+    let lua = |gs: &GameState, code: &str| {
+        let lua_state = gs.lua_state.as_ref().unwrap();
+        lua_state.context(|ctx| ctx.load(code).exec().unwrap());
+    };
+    let resps = |gs: &GameState| -> Vec<String> {
+        let lua_state = gs.lua_state.as_ref().unwrap();
+        lua_state.context(|ctx| ctx.load("return resps").eval().unwrap())
+    };
+
+    //两个分片使用相同的 session, 对端收到的 session 不同
+    for (id, gs) in gs.iter().enumerate() {
+        lua(
+            gs,
+            &format!("xlib.rpc_send(true, 1, '', 2, '', 7, 'from_{id}', {{}})"),
+        );
+    }
+    let mut sessions = vec![];
+    for _ in 0..2 {
+        match proto(rpc_rx.try_recv().unwrap()) {
+            (MessageType::Rpc, 2, ProtoType::RpcSend(p)) => sessions.push((p.func, p.session)),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+    assert_eq!(
+        sessions,
+        [
+            ("from_0".to_string(), 7),
+            ("from_1".to_string(), (1 << 48) + 7)
+        ]
+    );
+
+    //对端原样带回 session, 回复都到达 0 号分片
+    for (func, session) in sessions {
+        let resp = RpcResp {
+            from_host: 2,
+            session,
+            func,
+            ..Default::default()
+        };
+        gs[0]
+            .rpc_dispatch(MessageType::Rpc, 0, ProtoType::RpcResp(resp))
+            .unwrap();
+    }
+    assert_eq!(resps(&gs[0]), ["from_0,7"]);
+    let (msg_type, _, pto) = proto(s.inboxes[1].try_recv().unwrap());
+    assert_eq!(msg_type, MessageType::Shard);
+    gs[1].rpc_dispatch(msg_type, 0, pto).unwrap();
+    assert_eq!(resps(&gs[1]), ["from_1,7"]);
+    assert!(s.inboxes[0].try_recv().is_err());
+
+    //rpc 发件通道满时 xlib.rpc_send 返回 false, 由脚本稍后重试
+    lua(
+        &gs[0],
+        "for i = 1, 16 do assert(xlib.rpc_send(true, 1, '', 2, '', i, 'fill', {})) end
+         assert(not xlib.rpc_send(true, 1, '', 2, '', 17, 'fill', {}))
+         assert(not xlib.rpc_send(false, 1, '', 2, '', 17, 'fill', {}))",
+    );
+    rpc_rx.try_recv().unwrap();
+    lua(
+        &gs[0],
+        "assert(xlib.rpc_send(true, 1, '', 2, '', 17, 'fill', {}))",
+    );
+
+    //session 的高位留给分片, 脚本层使用时拒绝发送
+    while rpc_rx.try_recv().is_ok() {}
+    lua(
+        &gs[1],
+        "assert(not pcall(xlib.rpc_send, true, 1, '', 2, '', 1 << 48, 'big', {}))
+         assert(xlib.rpc_send(true, 1, '', 2, '', (1 << 48) - 1, 'max', {}))",
+    );
+    match proto(rpc_rx.try_recv().unwrap()) {
+        (MessageType::Rpc, 2, ProtoType::RpcSend(p)) => {
+            assert_eq!((p.func.as_str(), p.session), ("max", (2 << 48) - 1))
+        }
+        other => panic!("unexpected: {other:?}"),
+    }
+    assert!(rpc_rx.try_recv().is_err());
+}
@@ -0,0 +1,232 @@
+use cable::config::{Config, Schema};
+use cable::logger::build_logger;
+use cable::message::ServiceType;
+use cable::network::tcp::service as tcp_service;
+use cable::network::tls::{self, TlsAcceptor, TlsConnector};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+mod common;
+
+//本地生成的一套证书: CA 以及由它签发的服务端/客户端证书, 写入临时目录
+struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cable_tls_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pki = Pki { dir };
+        let (ca, ca_key) = pki.ca("ca");
+        pki.leaf("server", &ca, &ca_key);
+        pki.leaf("client", &ca, &ca_key);
+        pki
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_str().unwrap().to_string()
+    }
+
+    fn write(&self, name: &str, cert: &Certificate, key: &KeyPair) {
+        std::fs::write(self.path(&format!("{name}.pem")), cert.pem()).unwrap();
+        std::fs::write(self.path(&format!("{name}.key")), key.serialize_pem()).unwrap();
+    }
+
+    fn ca(&self, name: &str) -> (Certificate, KeyPair) {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        self.write(name, &cert, &key);
+        (cert, key)
+    }
+
+    fn leaf(&self, name: &str, ca: &Certificate, ca_key: &KeyPair) {
+        let mut params =
+            CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap();
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        self.write(name, &cert, &key);
+    }
+
+    fn acceptor(&self, mutual: bool) -> TlsAcceptor {
+        let ca = self.path("ca.pem");
+        tls::build_acceptor(
+            &self.path("server.pem"),
+            &self.path("server.key"),
+            mutual.then_some(ca.as_str()),
+        )
+        .unwrap()
+    }
+
+    fn connector(&self, with_identity: bool) -> TlsConnector {
+        let cert = self.path("client.pem");
+        let key = self.path("client.key");
+        tls::build_connector(
+            &self.path("ca.pem"),
+            with_identity.then_some((cert.as_str(), key.as_str())),
+        )
+        .unwrap()
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+//在本地回环地址上完成一次 tls 连接, 客户端发送 ping, 服务端回复 pong
+async fn exchange(acceptor: TlsAcceptor, connector: TlsConnector) -> cable::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+
+    let server = async {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = acceptor.accept(stream).await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").await?;
+        stream.flush().await?;
+        Ok::<(), cable::Error>(())
+    };
+    let client = async {
+        let stream = TcpStream::connect(&addr).await?;
+        let mut stream = connector.connect(tls::server_name(&addr)?, stream).await?;
+        stream.write_all(b"ping").await?;
+        stream.flush().await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+        Ok::<(), cable::Error>(())
+    };
+    let (server, client) = tokio::join!(server, client);
+    server?;
+    client
+}
+
+#[tokio::test]
+async fn server_auth_only() {
+    let pki = Pki::new("server_auth_only");
+    exchange(pki.acceptor(false), pki.connector(false))
+        .await
+        .unwrap();
+    //客户端出示证书也可以连接未开启双向 tls 的服务端
+    exchange(pki.acceptor(false), pki.connector(true))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn mutual_tls_accepts_cluster_cert() {
+    let pki = Pki::new("mutual_accept");
+    exchange(pki.acceptor(true), pki.connector(true))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn mutual_tls_rejects_client_without_cert() {
+    let pki = Pki::new("mutual_no_cert");
+    assert!(exchange(pki.acceptor(true), pki.connector(false))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn reject_cert_from_another_ca() {
+    let pki = Pki::new("other_ca_server");
+    let other = Pki::new("other_ca_client");
+
+    //服务端证书不是客户端信任的 CA 签发的
+    assert!(exchange(pki.acceptor(false), other.connector(false))
+        .await
+        .is_err());
+    //客户端证书不是集群 CA 签发的
+    let connector = tls::build_connector(
+        &pki.path("ca.pem"),
+        Some((&other.path("client.pem"), &other.path("client.key"))),
+    )
+    .unwrap();
+    assert!(exchange(pki.acceptor(true), connector).await.is_err());
+}
+
+#[test]
+fn bad_files() {
+    assert!(tls::build_acceptor("no_such_cert.pem", "no_such_key.pem", None).is_err());
+    assert!(tls::build_connector("no_such_ca.pem", None).is_err());
+}
+
+#[test]
+fn server_name_from_addr() {
+    let name = |addr: &str| tls::server_name(addr).unwrap();
+    assert!(matches!(name("127.0.0.1:8000"), ServerName::IpAddress(_)));
+    assert!(matches!(name("[::1]:8000"), ServerName::IpAddress(_)));
+    assert!(matches!(name("::1"), ServerName::IpAddress(_)));
+    assert!(matches!(name("localhost:8000"), ServerName::DnsName(_)));
+    assert!(matches!(name("game.example.com"), ServerName::DnsName(_)));
+    assert!(tls::server_name("bad name:8000").is_err());
+}
+
+//一个连接上来后不发起握手, 不影响其他连接完成握手并分配 vfd
+#[tokio::test]
+async fn slow_handshake_does_not_block_accept() {
+    let pki = Pki::new("slow_handshake");
+    common::workdir();
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = format!("127.0.0.1:{port}");
+    let mut conf = Config::default()
+        .with("tcp_tls", "true")
+        .with("certificate_file", &pki.path("server.pem"))
+        .with("privatekey_file", &pki.path("server.key"));
+    Schema::server().apply(&mut conf);
+    let (msg_tx, _msg_rx) = mpsc::channel(16);
+    let (chan_tx, mut chan_rx) = mpsc::channel(16);
+    let service = tcp_service::build(
+        ServiceType::TCP,
+        conf,
+        build_logger("tls_service.log"),
+        addr.clone(),
+        msg_tx,
+        chan_tx,
+    );
+    tokio::spawn(service.run());
+
+    //等待监听, 然后连上一个什么都不发的客户端
+    let silent = loop {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let stream = TcpStream::connect(&addr).await.unwrap();
+    let connector = pki.connector(false);
+    let _tls = connector
+        .connect(tls::server_name(&addr).unwrap(), stream)
+        .await
+        .unwrap();
+    let (vfd, _) = tokio::time::timeout(Duration::from_secs(2), chan_rx.recv())
+        .await
+        .expect("accept blocked by a slow handshake")
+        .unwrap();
+    assert!(vfd > 100);
+    drop(silent);
+}
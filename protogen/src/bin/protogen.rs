@@ -0,0 +1,64 @@
+use protogen::generator::{Generator, Lang};
+use std::env;
+use std::process;
+
+const USAGE: &str =
+    "usage: protogen -I <proto_dir>... -o <out_dir> [--ids <id_manifest>] [--lang rust,lua,ts]";
+
+fn main() {
+    let mut proto_dirs = vec![];
+    let mut out_dir = None;
+    let mut id_manifest = None;
+    let mut langs = vec![Lang::Rust, Lang::Lua, Lang::TypeScript];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| exit(&format!("missing value for {arg}")))
+        };
+        match arg.as_str() {
+            "-I" | "--proto-dir" => proto_dirs.push(value()),
+            "-o" | "--out-dir" => out_dir = Some(value()),
+            "--ids" => id_manifest = Some(value()),
+            "--lang" => {
+                langs = value()
+                    .split(',')
+                    .map(|s| s.parse().unwrap_or_else(|err| exit(&format!("{err}"))))
+                    .collect()
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ => exit(&format!("unknown argument: {arg}")),
+        }
+    }
+
+    let Some(out_dir) = out_dir else {
+        exit("missing -o <out_dir>");
+    };
+    let mut generator = Generator::new(out_dir);
+    for dir in proto_dirs {
+        generator.proto_dir(dir);
+    }
+    if let Some(path) = id_manifest {
+        generator.id_manifest(path);
+    }
+    for lang in langs {
+        generator.lang(lang);
+    }
+    match generator.generate() {
+        Ok(written) => {
+            for path in written {
+                println!("written: {}", path.display());
+            }
+        }
+        Err(err) => exit(&format!("{err}")),
+    }
+}
+
+fn exit(msg: &str) -> ! {
+    eprintln!("{msg}\n{USAGE}");
+    process::exit(1);
+}
@@ -0,0 +1,304 @@
+//协议生成工具, 从 proto 文件生成服务端的 rust 代码, 以及 lua 和 typescript 客户端使用的协议文件
+//
+//  Generator::new("src/output")
+//      .proto_dir("proto")
+//      .id_manifest("protoids.txt")
+//      .lang(Lang::Rust)
+//      .generate()?;
+//
+//  生成的 rust 代码与 lua table 互相转换时, repeated 字段是从 1 开始的 lua 数组, 可以直接用 # 和 ipairs
+//  旧版本的转换从 0 开始, 升级后脚本层按 t.list[0] 访问的代码要改为从 1 开始; 从 lua 转换时下标 0 的元素被忽略
+mod descriptor;
+mod error;
+mod lua;
+mod rust;
+mod typescript;
+
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+use descriptor::ProtoSet;
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, create_dir_all};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+//协议id的起始值
+const PROTO_ID_START: u32 = 100;
+
+//生成的目标语言
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    Rust,       //allprotos.rs, 包含 prost 结构, ProtoType 以及 lua table 的编解码
+    Lua,        //allprotos.lua, allenums.lua
+    TypeScript, //allprotos.ts
+}
+
+impl FromStr for Lang {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Lang> {
+        match s {
+            "rust" | "rs" => Ok(Lang::Rust),
+            "lua" => Ok(Lang::Lua),
+            "typescript" | "ts" => Ok(Lang::TypeScript),
+            _ => Err(format!("unknown lang: {s}").into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Generator {
+    proto_dirs: Vec<PathBuf>,
+    out_dir: PathBuf,
+    id_manifest: Option<PathBuf>,
+    langs: Vec<Lang>,
+    protoc: Option<PathBuf>,
+}
+
+impl Generator {
+    pub fn new<P: AsRef<Path>>(out_dir: P) -> Generator {
+        Generator {
+            proto_dirs: vec![],
+            out_dir: out_dir.as_ref().to_path_buf(),
+            id_manifest: None,
+            langs: vec![],
+            protoc: None,
+        }
+    }
+
+    //proto 文件目录, 同时作为 protoc 的 include 目录, 可以添加多个
+    pub fn proto_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.proto_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    //协议id清单, 已分配的id保持不变, 新增的协议追加在最大id之后
+    //不设置时, 所有协议按名字排序后从 100 开始分配id
+    pub fn id_manifest<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.id_manifest = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    //指定 protoc 的路径, 不设置时按 find_protoc 查找
+    pub fn protoc<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.protoc = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn lang(&mut self, lang: Lang) -> &mut Self {
+        if !self.langs.contains(&lang) {
+            self.langs.push(lang);
+        }
+        self
+    }
+
+    //生成所有目标文件, 返回内容有变化而被重写的文件
+    pub fn generate(&self) -> Result<Vec<PathBuf>> {
+        if self.proto_dirs.is_empty() {
+            return Err("no proto dir".into());
+        }
+        let fds = self.compile_descriptors()?;
+        let set = ProtoSet::collect(&fds)?;
+
+        let old_ids = match &self.id_manifest {
+            Some(path) if path.exists() => parse_manifest(&fs::read_to_string(path)?)?,
+            _ => HashMap::new(),
+        };
+        let name2id = assign_ids(&set.struct_names, &old_ids);
+        let version = proto_version(&name2id);
+
+        create_dir_all(&self.out_dir)?;
+        let mut written = vec![];
+        let mut write = |file_name: &str, contents: String| -> Result<()> {
+            let path = self.out_dir.join(file_name);
+            if write_if_changed(&path, &contents)? {
+                written.push(path);
+            }
+            Ok(())
+        };
+        for lang in &self.langs {
+            match lang {
+                Lang::Rust => {
+                    let modules = rust::generate_modules(&fds)?;
+                    let contents = rust::generate_allprotos(&set, &name2id, &version, &modules);
+                    write("allprotos.rs", rust::format(contents))?;
+                }
+                Lang::Lua => {
+                    let contents = lua::generate_allprotos_lua(&set.struct_names, &name2id);
+                    write("allprotos.lua", contents)?;
+                    write("allenums.lua", lua::generate_allenums_lua(&set.enums))?;
+                }
+                Lang::TypeScript => {
+                    let contents = typescript::generate_allprotos_ts(&set, &name2id, &version);
+                    write("allprotos.ts", contents)?;
+                }
+            }
+        }
+
+        if let Some(path) = &self.id_manifest {
+            if write_if_changed(path, &format_manifest(&name2id))? {
+                written.push(path.clone());
+            }
+        }
+        Ok(written)
+    }
+
+    //调用 protoc 一次编译所有 proto 文件, 得到所有文件的描述符
+    fn compile_descriptors(&self) -> Result<FileDescriptorSet> {
+        let mut protos = vec![];
+        for dir in &self.proto_dirs {
+            protos.extend(iterate_directory(dir, dir)?);
+        }
+        if protos.is_empty() {
+            return Err("no proto file found".into());
+        }
+
+        let tmp = tempfile::tempdir()?;
+        let descriptor_path = tmp.path().join("protos.bin");
+        let protoc = match &self.protoc {
+            Some(path) if path.is_file() => path.clone(),
+            Some(path) => return Err(format!("protoc not found: {}", path.display()).into()),
+            None => find_protoc()?,
+        };
+        let mut cmd = Command::new(protoc);
+        cmd.arg("--include_imports")
+            .arg("--include_source_info")
+            .arg("-o")
+            .arg(&descriptor_path);
+        for dir in &self.proto_dirs {
+            cmd.arg("-I").arg(dir);
+        }
+        if let Some(include) = env::var_os("PROTOC_INCLUDE").map(PathBuf::from) {
+            if !include.is_dir() {
+                return Err(format!("PROTOC_INCLUDE is not a dir: {}", include.display()).into());
+            }
+            cmd.arg("-I").arg(include);
+        }
+        cmd.args(&protos);
+
+        let output = cmd.output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("protoc failed: {stderr}").into());
+        }
+        let buf = fs::read(&descriptor_path)?;
+        Ok(FileDescriptorSet::decode(buf.as_slice())?)
+    }
+}
+
+//先用环境变量 PROTOC, 再在 PATH 中查找; 找不到时返回错误, 不会 panic
+pub fn find_protoc() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("PROTOC").map(PathBuf::from) {
+        if path.is_file() {
+            return Ok(path);
+        }
+        return Err(format!("protoc not found: PROTOC={}", path.display()).into());
+    }
+    env::var_os("PATH")
+        .and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join("protoc"))
+                .find(|path| path.is_file())
+        })
+        .ok_or_else(|| "protoc not found".into())
+}
+
+//返回相对于 root 的 proto 文件路径, 例如 login/c2s_login.proto
+fn iterate_directory(root: &Path, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut output = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            output.extend(iterate_directory(root, &path)?);
+        } else if path.extension().is_some_and(|ext| ext == "proto") {
+            let proto_short = path.strip_prefix(root).unwrap_or(&path);
+            output.push(proto_short.to_path_buf());
+        }
+    }
+    output.sort(); //保持有序
+    Ok(output)
+}
+
+//清单中已有的协议保留原来的id, 新协议按名字排序后依次分配, 已删除的协议不再保留
+fn assign_ids(struct_names: &[String], old_ids: &HashMap<String, u32>) -> HashMap<String, u32> {
+    let mut name2id = HashMap::new(); // [Item] = 101
+    let mut next_id = PROTO_ID_START;
+    for name in struct_names {
+        if let Some(&id) = old_ids.get(name) {
+            name2id.insert(name.to_owned(), id);
+            next_id = next_id.max(id + 1);
+        }
+    }
+    for name in struct_names {
+        if !name2id.contains_key(name) {
+            name2id.insert(name.to_owned(), next_id);
+            next_id += 1;
+        }
+    }
+    name2id
+}
+
+//协议版本号, 由所有协议的 id=>name 计算得出, 客户端与服务端以此判断协议是否一致
+fn proto_version(name2id: &HashMap<String, u32>) -> String {
+    let contents = manifest_lines(name2id).join("\n");
+    let hash = format!("{:x}", md5::compute(contents));
+    hash[(hash.len() - 8)..].to_owned()
+}
+
+//按id排序的 id=>name 列表
+fn manifest_lines(name2id: &HashMap<String, u32>) -> Vec<String> {
+    let mut ids: Vec<(&u32, &String)> = name2id.iter().map(|(name, id)| (id, name)).collect();
+    ids.sort();
+    ids.iter()
+        .map(|(id, name)| format!("{id}=>{name}"))
+        .collect()
+}
+
+fn format_manifest(name2id: &HashMap<String, u32>) -> String {
+    let mut lines = vec!["#协议id清单, 由 protogen 生成和维护, 已分配的id不会改变".to_owned()];
+    lines.extend(manifest_lines(name2id));
+    format!("{}\n", lines.join("\n"))
+}
+
+fn parse_manifest(contents: &str) -> Result<HashMap<String, u32>> {
+    let mut name2id = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, name) = line
+            .split_once("=>")
+            .ok_or_else(|| format!("invalid manifest line: {line}"))?;
+        let id: u32 = id
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid manifest line: {line}"))?;
+        if name2id.values().any(|&v| v == id) {
+            return Err(format!("duplicate id in manifest: {line}").into());
+        }
+        name2id.insert(name.trim().to_owned(), id);
+    }
+    Ok(name2id)
+}
+
+//内容没有变化时不写入, 避免触发不必要的重新编译
+//先写到同目录的临时文件再 rename, 其他进程不会读到写了一半的文件
+fn write_if_changed(path: &Path, contents: &str) -> Result<bool> {
+    if let Ok(old) = fs::read(path) {
+        if old == contents.as_bytes() {
+            return Ok(false);
+        }
+    }
+    let mut tmp: OsString = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(true)
+}
@@ -0,0 +1,277 @@
+use super::Result;
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, FileDescriptorSet};
+use std::collections::{BTreeMap, HashMap};
+
+//字段的值类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldKind {
+    Scalar(&'static str), //原生类型, 例如 u32, String, bool
+    Bytes,                //bytes, 在 lua 中是 string
+    Enum(String),         //枚举, 在 lua 中是整数, 值为 proto 的全名, 例如 .sample.SampleColor
+    Message(String),      //结构, 值为 proto 的全名, 例如 .embed.Item
+}
+
+//字段的组织方式
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldLabel {
+    Single,                    //普通字段
+    Optional,                  //proto3 optional 字段, Option<T>
+    Repeated,                  //数组, Vec<T>
+    Map(FieldKind, FieldKind), //map<k,v>, HashMap<K,V>
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldInfo {
+    pub name: String,     //rust 字段名, 例如 role_id, r#type
+    pub lua_name: String, //lua table 的 key, 与 rust 字段名一致, 但去掉 r# 前缀
+    pub kind: FieldKind,
+    pub label: FieldLabel,
+}
+
+#[derive(Debug, Clone)]
+pub struct OneofInfo {
+    pub name: String,                               //rust 字段名
+    pub enum_path: String,                          //oneof 生成的 rust 枚举路径
+    pub variants: Vec<(String, String, FieldKind)>, //(枚举成员名,lua key,值类型)
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageInfo {
+    pub name: String,      //rust 结构名
+    pub rust_path: String, //相对于 allprotos 模块的 rust 路径, 例如 sample::sample_constructs::Nested
+    pub top_level: bool,   //是否为包下的顶层结构, 只有顶层结构才分配协议id
+    pub is_map_entry: bool,
+    pub fields: Vec<FieldInfo>,
+    pub oneofs: Vec<OneofInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnumInfo {
+    pub full_name: String,          //proto 全名, 例如 sample.SampleColor
+    pub values: Vec<(String, i32)>, //(枚举值名,枚举值)
+}
+
+//从描述符中收集到的所有结构和枚举
+pub struct ProtoSet {
+    pub messages: BTreeMap<String, MessageInfo>, //[.sample.SampleConstructs] = MessageInfo
+    pub enums: Vec<EnumInfo>,
+    pub struct_names: Vec<String>, //所有顶层结构名, 按字母排序
+    pub unique_names: HashMap<String, String>, //[Item] = embed::Item
+}
+
+impl ProtoSet {
+    pub fn collect(fds: &FileDescriptorSet) -> Result<ProtoSet> {
+        //从描述符中收集所有结构和枚举, [.sample.SampleConstructs] = MessageInfo
+        let mut messages = BTreeMap::new();
+        let mut enums = Vec::new();
+        for file in &fds.file {
+            let package = file.package().to_owned();
+            let prefix = if package.is_empty() {
+                String::new()
+            } else {
+                format!(".{package}")
+            };
+            let rust_prefix: Vec<String> = package
+                .split('.')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_snake_case())
+                .collect();
+            for msg in &file.message_type {
+                collect_message(msg, &prefix, &rust_prefix, true, &mut messages, &mut enums)?;
+            }
+            for e in &file.enum_type {
+                collect_enum(e, &prefix, &mut enums);
+            }
+        }
+
+        let mut struct_names = vec![]; // {Item,Ivnentory,Player,...}
+        let mut unique_names = HashMap::new(); // [Item] = embed::Item,[OtherItem] = item::OtherItem,...
+        for info in messages.values() {
+            if info.top_level {
+                //所有顶层结构以结构名作为 ProtoType 的成员名, 所以必须唯一
+                if unique_names.contains_key(&info.name) {
+                    return Err(format!("duplicate message name: {}", info.name).into());
+                }
+                unique_names.insert(info.name.clone(), info.rust_path.clone());
+                struct_names.push(info.name.clone());
+            }
+        }
+        //按字母字典顺序排序,导出文件时，需要保持以字母排序的顺序，以便 git diff 看到明显的
+        struct_names.sort();
+
+        Ok(ProtoSet {
+            messages,
+            enums,
+            struct_names,
+            unique_names,
+        })
+    }
+}
+
+//与 prost-build 一致的字段命名规则
+pub fn to_rust_field(name: &str) -> String {
+    let ident = name.to_snake_case();
+    match ident.as_str() {
+        "as" | "break" | "const" | "continue" | "else" | "enum" | "false" | "fn" | "for" | "if"
+        | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref"
+        | "return" | "static" | "struct" | "trait" | "true" | "type" | "unsafe" | "use"
+        | "where" | "while" | "dyn" | "abstract" | "become" | "box" | "do" | "final" | "macro"
+        | "override" | "priv" | "typeof" | "unsized" | "virtual" | "yield" | "async" | "await"
+        | "try" => format!("r#{ident}"),
+        "self" | "super" | "extern" | "crate" => format!("{ident}_"),
+        _ => ident,
+    }
+}
+
+//与 prost-build 一致的类型命名规则
+fn to_rust_type(name: &str) -> String {
+    let ident = name.to_upper_camel_case();
+    if ident == "Self" {
+        format!("{ident}_")
+    } else {
+        ident
+    }
+}
+
+fn collect_enum(e: &EnumDescriptorProto, prefix: &str, enums: &mut Vec<EnumInfo>) {
+    let full_name = format!("{prefix}.{}", e.name());
+    let values = e
+        .value
+        .iter()
+        .map(|v| (v.name().to_owned(), v.number()))
+        .collect();
+    enums.push(EnumInfo {
+        full_name: full_name.trim_start_matches('.').to_owned(),
+        values,
+    });
+}
+
+//递归收集结构信息, 嵌套结构在 prost 中生成到以父结构命名的模块下
+fn collect_message(
+    msg: &DescriptorProto,
+    prefix: &str,
+    rust_prefix: &[String],
+    top_level: bool,
+    messages: &mut BTreeMap<String, MessageInfo>,
+    enums: &mut Vec<EnumInfo>,
+) -> Result<()> {
+    let full_name = format!("{prefix}.{}", msg.name());
+    let name = to_rust_type(msg.name());
+    let mut rust_path = rust_prefix.to_vec();
+    rust_path.push(name.clone());
+    let rust_path = rust_path.join("::");
+
+    //oneof 字段, proto3 optional 生成的合成 oneof 不算在内
+    let mut oneofs: Vec<OneofInfo> = msg
+        .oneof_decl
+        .iter()
+        .map(|o| {
+            let mut enum_path = rust_prefix.to_vec();
+            enum_path.push(msg.name().to_snake_case());
+            enum_path.push(to_rust_type(o.name()));
+            OneofInfo {
+                name: to_rust_field(o.name()),
+                enum_path: enum_path.join("::"),
+                variants: vec![],
+            }
+        })
+        .collect();
+
+    let mut fields = vec![];
+    for f in &msg.field {
+        let kind = field_kind(f.r#type(), f.type_name())?;
+        let rust_name = to_rust_field(f.name());
+        let lua_name = rust_name.trim_start_matches("r#").to_owned();
+        if f.proto3_optional() {
+            fields.push(FieldInfo {
+                name: rust_name,
+                lua_name,
+                kind,
+                label: FieldLabel::Optional,
+            });
+        } else if let Some(index) = f.oneof_index {
+            let oneof = oneofs.get_mut(index as usize).unwrap();
+            oneof
+                .variants
+                .push((to_rust_type(f.name()), lua_name, kind));
+        } else if f.label() == Label::Repeated {
+            let label = match &kind {
+                FieldKind::Message(type_name) => {
+                    //map<k,v> 在描述符中是一个 repeated 的 MapEntry 嵌套结构
+                    let entry = msg.nested_type.iter().find(|n| {
+                        n.options.as_ref().is_some_and(|o| o.map_entry())
+                            && type_name.ends_with(&format!(".{}", n.name()))
+                    });
+                    if let Some(entry) = entry {
+                        let key = entry.field.iter().find(|f| f.number() == 1).unwrap();
+                        let val = entry.field.iter().find(|f| f.number() == 2).unwrap();
+                        FieldLabel::Map(
+                            field_kind(key.r#type(), key.type_name())?,
+                            field_kind(val.r#type(), val.type_name())?,
+                        )
+                    } else {
+                        FieldLabel::Repeated
+                    }
+                }
+                _ => FieldLabel::Repeated,
+            };
+            fields.push(FieldInfo {
+                name: rust_name,
+                lua_name,
+                kind,
+                label,
+            });
+        } else {
+            fields.push(FieldInfo {
+                name: rust_name,
+                lua_name,
+                kind,
+                label: FieldLabel::Single,
+            });
+        }
+    }
+    oneofs.retain(|o| !o.variants.is_empty());
+
+    let is_map_entry = msg.options.as_ref().is_some_and(|o| o.map_entry());
+    messages.insert(
+        full_name.clone(),
+        MessageInfo {
+            name,
+            rust_path,
+            top_level,
+            is_map_entry,
+            fields,
+            oneofs,
+        },
+    );
+
+    let mut nested_prefix = rust_prefix.to_vec();
+    nested_prefix.push(msg.name().to_snake_case());
+    for nested in &msg.nested_type {
+        collect_message(nested, &full_name, &nested_prefix, false, messages, enums)?;
+    }
+    for e in &msg.enum_type {
+        collect_enum(e, &full_name, enums);
+    }
+    Ok(())
+}
+
+fn field_kind(ty: Type, type_name: &str) -> Result<FieldKind> {
+    let kind = match ty {
+        Type::Double => FieldKind::Scalar("f64"),
+        Type::Float => FieldKind::Scalar("f32"),
+        Type::Int32 | Type::Sint32 | Type::Sfixed32 => FieldKind::Scalar("i32"),
+        Type::Int64 | Type::Sint64 | Type::Sfixed64 => FieldKind::Scalar("i64"),
+        Type::Uint32 | Type::Fixed32 => FieldKind::Scalar("u32"),
+        Type::Uint64 | Type::Fixed64 => FieldKind::Scalar("u64"),
+        Type::Bool => FieldKind::Scalar("bool"),
+        Type::String => FieldKind::Scalar("String"),
+        Type::Bytes => FieldKind::Bytes,
+        Type::Enum => FieldKind::Enum(type_name.to_owned()),
+        Type::Message => FieldKind::Message(type_name.to_owned()),
+        Type::Group => return Err(format!("unsupport group type: {}", type_name).into()),
+    };
+    Ok(kind)
+}
@@ -0,0 +1,48 @@
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Message(String),
+    Io(io::Error),
+    Decode(prost::DecodeError),
+}
+
+impl From<String> for Error {
+    fn from(str: String) -> Self {
+        Error::Message(str)
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(str: &'a str) -> Self {
+        Error::Message(str.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(error: prost::DecodeError) -> Self {
+        Error::Decode(error)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Message(msg) => write!(f, "Message {{ {} }}", msg),
+            Error::Io(err) => {
+                write!(f, "IoError {{ {} }}", err)
+            }
+            Error::Decode(err) => {
+                write!(f, "DecodeError {{ {} }}", err)
+            }
+        }
+    }
+}
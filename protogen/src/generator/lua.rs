@@ -0,0 +1,35 @@
+use super::descriptor::EnumInfo;
+use std::collections::HashMap;
+
+//导出 .lua 文件, return { [proto_id] = proto_name,...}
+pub fn generate_allprotos_lua(struct_names: &[String], name2id: &HashMap<String, u32>) -> String {
+    let mut luaproto = vec![];
+    luaproto.push("return {".to_string());
+    for name in struct_names {
+        let id = name2id.get(name).unwrap();
+        let s = format!("\t[{id}]=\"{name}\",");
+        luaproto.push(s);
+    }
+    luaproto.push("}".to_string());
+    let pcontents = luaproto.join("\n");
+    format!("{pcontents}\n\n")
+}
+
+//导出枚举定义, return { ["sample.SampleColor"] = { SAMPLE_COLOR_NONE = 0,...},...}
+pub fn generate_allenums_lua(enums: &[EnumInfo]) -> String {
+    let mut enums = enums.to_vec();
+    enums.sort_by(|a, b| a.full_name.cmp(&b.full_name));
+
+    let mut luaenum = vec![];
+    luaenum.push("return {".to_string());
+    for e in enums {
+        luaenum.push(format!("\t[\"{}\"]={{", e.full_name));
+        for (name, value) in e.values {
+            luaenum.push(format!("\t\t{name}={value},"));
+        }
+        luaenum.push("\t},".to_string());
+    }
+    luaenum.push("}".to_string());
+    let pcontents = luaenum.join("\n");
+    format!("{pcontents}\n\n")
+}
@@ -0,0 +1,663 @@
+use super::descriptor::{
+    to_rust_field, FieldInfo, FieldKind, FieldLabel, MessageInfo, OneofInfo, ProtoSet,
+};
+use super::Result;
+use heck::ToSnakeCase;
+use prost_build::Module;
+use prost_types::FileDescriptorSet;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+//lua 数组转换为 repeated 字段时, 数组长度的上限
+const REPEATED_LEN_LIMIT: usize = 1000;
+
+//用 prost 生成每个 package 的结构定义, [sample] = "pub struct SampleConstructs {...}"
+pub fn generate_modules(fds: &FileDescriptorSet) -> Result<HashMap<String, String>> {
+    let requests = fds
+        .file
+        .iter()
+        .map(|file| {
+            let module = Module::from_protobuf_package_name(file.package());
+            (module, file.clone())
+        })
+        .collect();
+    let mut modules = prost_build::Config::new().generate(requests)?;
+
+    let mut packages = HashMap::new();
+    for file in &fds.file {
+        let module = Module::from_protobuf_package_name(file.package());
+        if let Some(contents) = modules.remove(&module) {
+            packages.insert(file.package().to_owned(), contents);
+        }
+    }
+    Ok(packages)
+}
+
+//生成 allprotos.rs, modules 为 prost 按 package 生成的代码, [sample] = "pub struct SampleConstructs {...}"
+pub fn generate_allprotos(
+    set: &ProtoSet,
+    name2id: &HashMap<String, u32>,
+    version: &str,
+    modules: &HashMap<String, String>,
+) -> String {
+    let struct_names = &set.struct_names;
+    let unique_names = &set.unique_names;
+    let mut out = String::new();
+
+    //文件头部
+    let header = format!(
+        "use ::prost::Message;
+use std::{{result::Result}};
+
+pub fn version() -> &'static str {{
+    \"{version}\"
+}}"
+    );
+    out.push_str(&header);
+    out.push_str("\n\n");
+
+    //把所有文件写入进来，代替 include! 宏
+    //没有 package 的协议写在顶层, 其余按 package 的层级生成嵌套模块, 例如 package a.b 生成 pub mod a { pub mod b {...} }
+    let mut packages: Vec<&String> = modules.keys().collect();
+    packages.sort(); //保持有序
+    let mut tree = ModTree::default();
+    for package in packages {
+        let path: Vec<String> = package
+            .split('.')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_snake_case())
+            .collect();
+        tree.insert(&path, modules[package].clone());
+    }
+    out.push_str(&tree.render());
+
+    //有 package 的顶层结构, 导出到 allprotos 模块下
+    let mut reexports: Vec<&String> = unique_names
+        .iter()
+        .filter(|(name, path)| *name != *path)
+        .map(|(_, path)| path)
+        .collect();
+    reexports.sort();
+    for path in reexports {
+        let str = format!("pub use {path};\n");
+        out.push_str(&str);
+    }
+    out.push_str("\n\n");
+
+    //定义枚举结构
+    /*
+    #[derive(Debug)]
+    pub enum ProtoType {
+        Item(Item),
+    }
+         */
+    let mut lines = vec![];
+    for name in struct_names {
+        let _id = name2id.get(name).unwrap();
+        let l = format!("\t{name}({name}),");
+        lines.push(l);
+    }
+
+    let pcontents = lines.join("\n");
+    let pstr = format!(
+        "#[derive(Debug)]
+pub enum ProtoType {{
+{pcontents}
+}}"
+    );
+    out.push_str(&pstr);
+    out.push_str("\n\n");
+
+    //实现枚举方法
+    /*
+    impl ProtoType {
+        pub fn inner_info(&self) -> (u32,&'static str) {
+            match self {
+                ProtoType::Item(_obj) => { (101,"Item") },
+            }
+        }
+    }
+         */
+
+    let mut lines = vec![];
+    let mut from_id = vec![];
+    let mut decode_from_lua = vec![];
+    let mut encode_to_lua = vec![];
+    for name in struct_names {
+        let id = name2id.get(name).unwrap();
+        let l = format!("\t\t\tProtoType::{name}(_obj) => {{ ({id},\"{name}\") }},");
+        lines.push(l);
+
+        let s = format!(
+            "{id} => {{
+            let obj = {name}::default();
+            Some(ProtoType::{name}(obj))
+        }},"
+        );
+        from_id.push(s);
+
+        let s = format!(
+            "ProtoType::{name}(obj) => {{
+            let obj = obj.from_lua_table(t)?;
+            Ok(ProtoType::{name}(obj))
+        }},"
+        );
+        decode_from_lua.push(s);
+
+        let s = format!("ProtoType::{name}(obj) => obj.to_lua_table(ctx),");
+        encode_to_lua.push(s);
+    }
+    from_id.push("_=>{None},".to_owned());
+
+    let pcontents = lines.join("\n");
+    let pcontents_from_id = from_id.join("\n");
+    let pcontents_decode_from_lua = decode_from_lua.join("\n");
+    let pcontents_encode_to_lua = encode_to_lua.join("\n");
+    let pstr = format!(
+        "impl ProtoType {{
+    pub fn inner_info(&self) -> (u32,&'static str) {{
+        match self {{
+{pcontents}
+        }}
+    }}
+
+    pub fn from_id(proto_id: i32) -> Option<ProtoType> {{
+        match proto_id {{
+{pcontents_from_id}
+        }}
+    }}
+
+    pub fn decode_from_lua(self, t: Table) -> rlua::Result<ProtoType> {{
+        match self {{
+    {pcontents_decode_from_lua}
+        }}
+    }}
+
+    pub fn encode_to_lua(self, ctx: Context) -> rlua::Result<Table> {{
+        match self {{
+    {pcontents_encode_to_lua}
+        }}
+    }}
+
+}}
+"
+    );
+    out.push_str(&pstr);
+    out.push_str("\n\n");
+
+    //decode函数
+    let mut lines = vec![];
+    for name in struct_names {
+        let id = name2id.get(name).unwrap();
+        let l = format!(
+            "{id} => {{
+    match {name}::decode(buf) {{
+        Ok(obj) => Ok(ProtoType::{name}(obj)),
+        Err(err) => Err(err.to_string()),
+    }}
+}},"
+        );
+        lines.push(l);
+    }
+
+    let pcontents = lines.join("\n");
+    let pstr = format!(
+        "pub fn decode(proto_id: u32,buf: &[u8]) -> Result<ProtoType,String> {{
+    match proto_id {{
+        {pcontents}
+        _ => Err(format!(\"[decode]: failed=true, proto_id={{}}\",proto_id))
+    }}
+}}\n"
+    );
+    out.push_str(&pstr);
+
+    //encode 函数
+    let mut lines = vec![];
+    for name in struct_names {
+        let l = format!(
+            "ProtoType::{name}(obj) => {{
+    let buff = obj.encode_to_vec();
+    Ok(buff)
+}},"
+        );
+        lines.push(l);
+    }
+
+    let pcontents = lines.join("\n");
+    let pstr = format!(
+        "pub fn encode(pto: ProtoType) -> Result<Vec<u8>,String> {{
+    match pto {{
+        {pcontents}
+    }}
+}}\n"
+    );
+    out.push_str(&pstr);
+
+    //按协议类型注册 rust 处理函数
+    out.push_str(&generate_handlers(struct_names, name2id));
+
+    //lua table 与结构的相互转换
+    out.push_str(&generate_lua_encode_decode(&set.messages));
+    out
+}
+
+//生成 ProtoMessage 实现和 ProtoHandlers 路由, 每个协议在 ProtoHandlers 中有一个对应类型的处理函数字段
+fn generate_handlers(struct_names: &[String], name2id: &HashMap<String, u32>) -> String {
+    let mut impls = vec![];
+    let mut fields = vec![];
+    let mut defaults = vec![];
+    let mut registered = vec![];
+    let mut arms = vec![];
+    for name in struct_names {
+        let id = name2id.get(name).unwrap();
+        let field = to_rust_field(name);
+        impls.push(format!(
+            "impl ProtoMessage for {name} {{
+    const PROTO_ID: u32 = {id};
+    const PROTO_NAME: &'static str = \"{name}\";
+
+    fn into_proto(self) -> ProtoType {{
+        ProtoType::{name}(self)
+    }}
+
+    fn set_handler<S>(handlers: &mut ProtoHandlers<S>, handler: fn(&mut S, u64, Self)) {{
+        handlers.{field} = Some(handler);
+    }}
+}}"
+        ));
+        fields.push(format!("    {field}: Option<fn(&mut S, u64, {name})>,"));
+        defaults.push(format!("            {field}: None,"));
+        registered.push(format!("            {id} => self.{field}.is_some(),"));
+        arms.push(format!(
+            "            ProtoType::{name}(obj) => match self.{field} {{
+                Some(handler) => {{
+                    handler(state, vfd, obj);
+                    None
+                }}
+                None => Some(ProtoType::{name}(obj)),
+            }},"
+        ));
+    }
+    let impls = impls.join("\n\n");
+    let fields = fields.join("\n");
+    let defaults = defaults.join("\n");
+    let registered = registered.join("\n");
+    let arms = arms.join("\n");
+    format!(
+        "
+//所有协议结构的公共接口
+pub trait ProtoMessage: Sized {{
+    const PROTO_ID: u32;
+    const PROTO_NAME: &'static str;
+
+    fn into_proto(self) -> ProtoType;
+
+    fn set_handler<S>(handlers: &mut ProtoHandlers<S>, handler: fn(&mut S, u64, Self));
+}}
+
+{impls}
+
+//按协议类型注册的 rust 处理函数, S 为处理函数的状态, 例如 GameState
+pub struct ProtoHandlers<S> {{
+{fields}
+    _state: std::marker::PhantomData<fn(&mut S)>,
+}}
+
+impl<S> Default for ProtoHandlers<S> {{
+    fn default() -> Self {{
+        ProtoHandlers {{
+{defaults}
+            _state: std::marker::PhantomData,
+        }}
+    }}
+}}
+
+impl<S> ProtoHandlers<S> {{
+    pub fn new() -> Self {{
+        Self::default()
+    }}
+
+    //注册一个协议的处理函数, 同一协议重复注册时覆盖之前的函数
+    pub fn register<T: ProtoMessage>(&mut self, handler: fn(&mut S, u64, T)) -> &mut Self {{
+        T::set_handler(self, handler);
+        self
+    }}
+
+    pub fn is_registered(&self, proto_id: u32) -> bool {{
+        match proto_id {{
+{registered}
+            _ => false,
+        }}
+    }}
+
+    //有注册处理函数时调用并返回 None, 否则原样返回协议, 由调用者继续处理
+    pub fn dispatch(&self, state: &mut S, vfd: u64, pto: ProtoType) -> Option<ProtoType> {{
+        match pto {{
+{arms}
+        }}
+    }}
+}}
+"
+    )
+}
+
+//按 package 层级组织的模块树
+#[derive(Default)]
+struct ModTree {
+    contents: String,
+    children: BTreeMap<String, ModTree>,
+}
+
+impl ModTree {
+    fn insert(&mut self, path: &[String], contents: String) {
+        match path.split_first() {
+            Some((first, rest)) => self
+                .children
+                .entry(first.clone())
+                .or_default()
+                .insert(rest, contents),
+            None => self.contents = contents,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut s = format!("{}\n", self.contents);
+        for (name, child) in &self.children {
+            s.push_str(&format!("pub mod {name} {{\n{}}}\n\n", child.render()));
+        }
+        s
+    }
+}
+
+fn generate_lua_encode_decode(messages: &BTreeMap<String, MessageInfo>) -> String {
+    let mut out = String::new();
+
+    //文件头部
+    let header = "\n\nuse rlua::{Context,Table};";
+    out.push_str(header);
+    out.push_str("\n\n");
+
+    /* 例子,
+    impl CFeedback {
+        pub fn to_lua_table(self,ctx: Context) -> rlua::Result<Table> {
+            let t: Table = ctx.create_table()?;
+            t.raw_set("id",self.id)?;
+            t.raw_set("msg",self.msg)?;
+            Ok(t)
+        }
+
+        pub fn from_lua_table(mut self, t: Table) -> rlua::Result<CFeedback> {
+            if let Some(v) = t.raw_get::<_, Option<u32>>("id")? {
+                self.id = v;
+            }
+            ...
+            Ok(self)
+        }
+    }
+    */
+    for info in messages.values() {
+        //map 的 MapEntry 结构只存在于描述符中, prost 不会为它生成结构
+        if info.is_map_entry {
+            continue;
+        }
+        let struct_name = &info.rust_path;
+        let mut to_block: Vec<String> = Vec::new();
+        let mut from_block: Vec<String> = Vec::new();
+        for field in &info.fields {
+            let (to, from) = gen_field(field, messages);
+            to_block.push(to);
+            from_block.push(from);
+        }
+        for oneof in &info.oneofs {
+            let (to, from) = gen_oneof(oneof, messages);
+            to_block.push(to);
+            from_block.push(from);
+        }
+
+        let s = if from_block.is_empty() {
+            //该协议无协议字段
+            format!(
+                "impl {struct_name} {{
+    pub fn to_lua_table(self,ctx: Context) -> rlua::Result<Table> {{
+        let t: Table = ctx.create_table()?;
+        Ok(t)
+    }}
+
+    pub fn from_lua_table(self, _t: Table) -> rlua::Result<{struct_name}> {{
+        Ok(self)
+    }}
+}}\n\n"
+            )
+        } else {
+            let to_block = to_block.join("\n");
+            let from_block = from_block.join("\n");
+            format!(
+                "impl {struct_name} {{
+    pub fn to_lua_table(self,ctx: Context) -> rlua::Result<Table> {{
+        let t: Table = ctx.create_table()?;
+        {to_block}
+        Ok(t)
+    }}
+
+    pub fn from_lua_table(mut self, t: Table) -> rlua::Result<{struct_name}> {{
+        {from_block}
+        Ok(self)
+    }}
+}}\n\n"
+            )
+        };
+        out.push_str(&s);
+    }
+    out.push_str("\n\n");
+    out
+}
+
+//生成一个字段的 (to_lua_table,from_lua_table) 代码片段
+fn gen_field(field: &FieldInfo, messages: &BTreeMap<String, MessageInfo>) -> (String, String) {
+    let name = &field.name;
+    let lua_name = &field.lua_name;
+    let kind = &field.kind;
+    let get_ty = lua_get_type(kind);
+    match &field.label {
+        FieldLabel::Single => match kind {
+            FieldKind::Message(_) => {
+                //结构字段在 prost 中是 Option<T>
+                let to = format!(
+                    "if let Some(v) = self.{name} {{
+                        t.raw_set(\"{lua_name}\", {})?;
+                    }}",
+                    to_lua_expr(kind, "v")
+                );
+                let from = format!(
+                    "if let Some(v) = t.raw_get::<_, Option<Table>>(\"{lua_name}\")? {{
+                        self.{name} = Some({});
+                    }} else {{
+                        self.{name} = None;
+                    }}",
+                    from_lua_expr(kind, "v", messages)
+                );
+                (to, from)
+            }
+            _ => {
+                let to = format!(
+                    "t.raw_set(\"{lua_name}\", {})?;",
+                    to_lua_expr(kind, &format!("self.{name}"))
+                );
+                //proto3 的字段都有默认值, lua 中不存在该 key 时保留默认值
+                let from = format!(
+                    "if let Some(v) = t.raw_get::<_, Option<{get_ty}>>(\"{lua_name}\")? {{
+                        self.{name} = {};
+                    }}",
+                    from_lua_expr(kind, "v", messages)
+                );
+                (to, from)
+            }
+        },
+        FieldLabel::Optional => {
+            let to = format!(
+                "if let Some(v) = self.{name} {{
+                    t.raw_set(\"{lua_name}\", {})?;
+                }}",
+                to_lua_expr(kind, "v")
+            );
+            let from = format!(
+                "if let Some(v) = t.raw_get::<_, Option<{get_ty}>>(\"{lua_name}\")? {{
+                    self.{name} = Some({});
+                }} else {{
+                    self.{name} = None;
+                }}",
+                from_lua_expr(kind, "v", messages)
+            );
+            (to, from)
+        }
+        FieldLabel::Repeated => {
+            //数组在 lua 中以 1 开始索引, 和旧版本从 0 开始不兼容, 见 generator.rs 的说明
+            let to = format!(
+                "let t_{lua_name}: Table = ctx.create_table()?;
+                for (index, v) in self.{name}.into_iter().enumerate() {{
+                    t_{lua_name}.raw_set(index + 1, {})?;
+                }}
+                t.raw_set(\"{lua_name}\", t_{lua_name})?;",
+                to_lua_expr(kind, "v")
+            );
+            let from = format!(
+                "if let Some(t_{lua_name}) = t.raw_get::<_, Option<Table>>(\"{lua_name}\")? {{
+                    let len = t_{lua_name}.raw_len();
+                    if len as usize >= {REPEATED_LEN_LIMIT} {{
+                        return Err(rlua::Error::RuntimeError(
+                            \"{lua_name} table len limit\".to_owned(),
+                        ));
+                    }}
+                    let mut {lua_name} = Vec::with_capacity(len as usize);
+                    for index in 1..=len {{
+                        let v: {get_ty} = t_{lua_name}.raw_get(index)?;
+                        {lua_name}.push({});
+                    }}
+                    self.{name} = {lua_name};
+                }}",
+                from_lua_expr(kind, "v", messages)
+            );
+            (to, from)
+        }
+        FieldLabel::Map(key_kind, val_kind) => {
+            let key_ty = lua_get_type(key_kind);
+            let val_ty = lua_get_type(val_kind);
+            let to = format!(
+                "let t_{lua_name}: Table = ctx.create_table()?;
+                for (k, v) in self.{name} {{
+                    t_{lua_name}.raw_set(k, {})?;
+                }}
+                t.raw_set(\"{lua_name}\", t_{lua_name})?;",
+                to_lua_expr(val_kind, "v")
+            );
+            let from = format!(
+                "if let Some(t_{lua_name}) = t.raw_get::<_, Option<Table>>(\"{lua_name}\")? {{
+                    for pair in t_{lua_name}.pairs::<{key_ty}, {val_ty}>() {{
+                        let (k, v) = pair?;
+                        self.{name}.insert(k, {});
+                    }}
+                }}",
+                from_lua_expr(val_kind, "v", messages)
+            );
+            (to, from)
+        }
+    }
+}
+
+//oneof 在 lua 中与普通字段一样, 以成员的字段名作为 key, 同一时间只有一个 key 存在
+fn gen_oneof(oneof: &OneofInfo, messages: &BTreeMap<String, MessageInfo>) -> (String, String) {
+    let name = &oneof.name;
+    let enum_path = &oneof.enum_path;
+
+    let mut to_arms = vec![];
+    let mut from_arms = vec![];
+    for (variant, lua_name, kind) in &oneof.variants {
+        to_arms.push(format!(
+            "Some({enum_path}::{variant}(v)) => {{
+                t.raw_set(\"{lua_name}\", {})?;
+            }}",
+            to_lua_expr(kind, "v")
+        ));
+        from_arms.push(format!(
+            "if let Some(v) = t.raw_get::<_, Option<{}>>(\"{lua_name}\")? {{
+                self.{name} = Some({enum_path}::{variant}({}));
+            }}",
+            lua_get_type(kind),
+            from_lua_expr(kind, "v", messages)
+        ));
+    }
+    let to = format!(
+        "match self.{name} {{
+            {}
+            None => {{}}
+        }}",
+        to_arms.join("\n")
+    );
+    let from = format!(
+        "{} else {{
+            self.{name} = None;
+        }}",
+        from_arms.join(" else ")
+    );
+    (to, from)
+}
+
+//从 lua 读取值时使用的 rust 类型
+fn lua_get_type(kind: &FieldKind) -> String {
+    match kind {
+        FieldKind::Scalar(ty) => ty.to_string(),
+        FieldKind::Bytes => "rlua::String".to_owned(),
+        FieldKind::Enum(_) => "i32".to_owned(),
+        FieldKind::Message(_) => "Table".to_owned(),
+    }
+}
+
+//rust 值转换为 lua 值的表达式
+fn to_lua_expr(kind: &FieldKind, var: &str) -> String {
+    match kind {
+        FieldKind::Scalar(_) | FieldKind::Enum(_) => var.to_owned(),
+        FieldKind::Bytes => format!("ctx.create_string(&{var})?"),
+        FieldKind::Message(_) => format!("{var}.to_lua_table(ctx)?"),
+    }
+}
+
+//lua 值转换为 rust 值的表达式
+fn from_lua_expr(kind: &FieldKind, var: &str, messages: &BTreeMap<String, MessageInfo>) -> String {
+    match kind {
+        FieldKind::Scalar(_) | FieldKind::Enum(_) => var.to_owned(),
+        FieldKind::Bytes => format!("{var}.as_bytes().to_vec()"),
+        FieldKind::Message(type_name) => {
+            let path = &messages.get(type_name).unwrap().rust_path;
+            format!("{path}::default().from_lua_table({var})?")
+        }
+    }
+}
+
+//用 rustfmt 格式化生成的代码, 只处理这一份内容, 不会改动工作区的其他文件
+//找不到 rustfmt 或格式化失败时, 保留未格式化的代码
+pub fn format(contents: String) -> String {
+    let child = Command::new("rustfmt")
+        .args(["--edition", "2021", "--emit", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return contents,
+    };
+
+    let mut stdin = child.stdin.take().unwrap();
+    let input = contents.clone();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output();
+    let written = writer.join().map(|res| res.is_ok()).unwrap_or(false);
+    match output {
+        Ok(output) if written && output.status.success() => {
+            String::from_utf8(output.stdout).unwrap_or(contents)
+        }
+        _ => contents,
+    }
+}
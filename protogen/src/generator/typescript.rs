@@ -0,0 +1,238 @@
+use super::descriptor::{FieldKind, FieldLabel, MessageInfo, ProtoSet};
+use heck::ToLowerCamelCase;
+use std::collections::{BTreeMap, HashMap};
+
+//typescript 的命名空间树, 层级与 proto 全名一致, 例如 sample.SampleConstructs.Nested
+#[derive(Default)]
+struct TsNamespace {
+    items: Vec<String>,
+    children: BTreeMap<String, TsNamespace>,
+}
+
+impl TsNamespace {
+    fn insert(&mut self, path: &[&str], item: String) {
+        match path.split_first() {
+            None => self.items.push(item),
+            Some((first, rest)) => self
+                .children
+                .entry(first.to_string())
+                .or_default()
+                .insert(rest, item),
+        }
+    }
+
+    fn render(&self, depth: usize) -> String {
+        let indent = "    ".repeat(depth);
+        let mut out = vec![];
+        for item in &self.items {
+            let lines: Vec<String> = item.lines().map(|l| format!("{indent}{l}")).collect();
+            out.push(lines.join("\n"));
+        }
+        for (name, child) in &self.children {
+            out.push(format!(
+                "{indent}export namespace {name} {{\n{}\n{indent}}}",
+                child.render(depth + 1)
+            ));
+        }
+        out.join("\n\n")
+    }
+}
+
+//typescript 中的值类型, 64 位整数使用 bigint 避免精度丢失
+fn to_ts_type(kind: &FieldKind) -> String {
+    match kind {
+        FieldKind::Scalar("i64") | FieldKind::Scalar("u64") => "bigint".to_owned(),
+        FieldKind::Scalar("bool") => "boolean".to_owned(),
+        FieldKind::Scalar("String") => "string".to_owned(),
+        FieldKind::Scalar(_) => "number".to_owned(),
+        FieldKind::Bytes => "Uint8Array".to_owned(),
+        FieldKind::Enum(type_name) | FieldKind::Message(type_name) => {
+            type_name.trim_start_matches('.').to_owned()
+        }
+    }
+}
+
+fn generate_ts_interface(name: &str, info: &MessageInfo) -> String {
+    let mut lines = vec![format!("export interface {name} {{")];
+    for field in &info.fields {
+        let fname = field.lua_name.to_lower_camel_case();
+        let ty = to_ts_type(&field.kind);
+        let line = match &field.label {
+            FieldLabel::Single => match field.kind {
+                FieldKind::Message(_) => format!("{fname}?: {ty};"),
+                _ => format!("{fname}: {ty};"),
+            },
+            FieldLabel::Optional => format!("{fname}?: {ty};"),
+            FieldLabel::Repeated => format!("{fname}: {ty}[];"),
+            FieldLabel::Map(key, val) => {
+                //js 对象的 key 只能是 string 或 number, 64 位整数和 bool 作为 string
+                let key_ty = match key {
+                    FieldKind::Scalar("i32") | FieldKind::Scalar("u32") => "number",
+                    _ => "string",
+                };
+                format!("{fname}: {{ [key: {key_ty}]: {} }};", to_ts_type(val))
+            }
+        };
+        lines.push(format!("    {line}"));
+    }
+    for oneof in &info.oneofs {
+        let oname = oneof.name.trim_start_matches("r#");
+        lines.push(format!("    //oneof {oname}, 最多只设置其中一个"));
+        for (_, key, kind) in &oneof.variants {
+            let fname = key.to_lower_camel_case();
+            lines.push(format!("    {fname}?: {};", to_ts_type(kind)));
+        }
+    }
+    lines.push("}".to_owned());
+    lines.join("\n")
+}
+
+//导出客户端使用的 typescript 协议文件: 协议id, 帧编解码, 以及所有结构的类型定义
+pub fn generate_allprotos_ts(
+    set: &ProtoSet,
+    name2id: &HashMap<String, u32>,
+    version: &str,
+) -> String {
+    let struct_names = &set.struct_names;
+    let messages = &set.messages;
+    let header = format!(
+        "//本文件由 protogen 自动生成, 请勿手动修改
+
+//协议版本号, 与服务端 allprotos::version() 一致
+export const PROTO_VERSION = \"{version}\";
+
+//帧头长度, 第 4 个字节为帧头版本, 均为小端
+//  v0: 4 字节协议id + 4 字节包体长度
+//  v1: 3 字节协议id + 1 字节版本 + 3 字节包体长度 + 1 字节 flags
+//flags 为 0 的帧总是写成 v0, 与服务端 Frame::header 一致
+export const PROTO_HEADER_LEN = 8;
+export const FRAME_VERSION_V0 = 0;
+export const FRAME_VERSION_V1 = 1;
+//包体长度上限, 与服务端 PROTO_BODY_MAX_LEN 一致
+export const PROTO_BODY_MAX_LEN = 10 * 1024 * 1024 - PROTO_HEADER_LEN;
+
+//flags 中的压缩标记, 与登录时 S2cLogin.compress 的算法标记相同
+//客户端只能在 compress 中声明自己能解压的算法, 不能解压时填 0, 服务端就不会发送压缩帧
+export const FLAG_LZ4 = 0x01;
+export const FLAG_ZSTD = 0x02;
+export const FLAG_COMPRESS_MASK = FLAG_LZ4 | FLAG_ZSTD;
+
+//flags 带压缩标记时 body 是压缩后的数据, 由调用者按 flags & FLAG_COMPRESS_MASK 解压后再解码
+export interface Frame {{
+    protoId: number;
+    flags: number;
+    body: Uint8Array;
+}}
+
+//编码一个帧, 格式与服务端 Frame::header 一致
+export function encodeFrame(protoId: number, body: Uint8Array, flags: number = 0): Uint8Array {{
+    if (body.length >= PROTO_BODY_MAX_LEN) {{
+        throw new Error(`body exceed PROTO_BODY_MAX_LEN: ${{body.length}}`);
+    }}
+    const buf = new Uint8Array(PROTO_HEADER_LEN + body.length);
+    const view = new DataView(buf.buffer);
+    view.setUint32(0, protoId, true);
+    view.setUint32(4, body.length, true);
+    if (flags !== 0) {{
+        buf[3] = FRAME_VERSION_V1;
+        buf[7] = flags;
+    }}
+    buf.set(body, PROTO_HEADER_LEN);
+    return buf;
+}}
+
+//解码一个帧, 数据不足一个完整的帧时返回 null
+//websocket 的每条二进制消息就是一个完整的帧
+export function decodeFrame(buf: Uint8Array): Frame | null {{
+    if (buf.length < PROTO_HEADER_LEN) {{
+        return null;
+    }}
+    const view = new DataView(buf.buffer, buf.byteOffset, buf.byteLength);
+    const version = buf[3];
+    if (version !== FRAME_VERSION_V0 && version !== FRAME_VERSION_V1) {{
+        throw new Error(`unknown frame version: ${{version}}`);
+    }}
+    //协议id和包体长度都小于 2^24, 只取低 3 个字节
+    const protoId = view.getUint32(0, true) & 0xffffff;
+    const v1 = version === FRAME_VERSION_V1;
+    const bodyLen = v1 ? view.getUint32(4, true) & 0xffffff : view.getUint32(4, true);
+    const flags = v1 ? buf[7] : 0;
+    if (bodyLen >= PROTO_BODY_MAX_LEN) {{
+        throw new Error(`body exceed PROTO_BODY_MAX_LEN: ${{bodyLen}}`);
+    }}
+    if (buf.length < PROTO_HEADER_LEN + bodyLen) {{
+        return null;
+    }}
+    return {{ protoId, flags, body: buf.subarray(PROTO_HEADER_LEN, PROTO_HEADER_LEN + bodyLen) }};
+}}
+
+//流式解码, 用于数据按字节流到达的场景, 例如 tcp
+export class FrameDecoder {{
+    private buf: Uint8Array = new Uint8Array(0);
+
+    push(chunk: Uint8Array): Frame[] {{
+        const merged = new Uint8Array(this.buf.length + chunk.length);
+        merged.set(this.buf, 0);
+        merged.set(chunk, this.buf.length);
+
+        const frames: Frame[] = [];
+        let offset = 0;
+        for (;;) {{
+            const frame = decodeFrame(merged.subarray(offset));
+            if (frame === null) {{
+                break;
+            }}
+            frames.push(frame);
+            offset += PROTO_HEADER_LEN + frame.body.length;
+        }}
+        this.buf = merged.slice(offset);
+        return frames;
+    }}
+}}"
+    );
+
+    //协议id, ProtoId[id] 可以反查协议名
+    let mut ids = vec!["export enum ProtoId {".to_owned()];
+    let mut id2type = vec!["//协议id与结构类型的对应关系".to_owned()];
+    id2type.push("export interface ProtoMessages {".to_owned());
+    let mut ts_paths = HashMap::new(); // [SampleConstructs] = sample.SampleConstructs
+    for (full_name, info) in messages {
+        if info.top_level {
+            ts_paths.insert(info.name.clone(), full_name.trim_start_matches('.'));
+        }
+    }
+    for name in struct_names {
+        let id = name2id.get(name).unwrap();
+        ids.push(format!("    {name} = {id},"));
+        let path = ts_paths.get(name).unwrap();
+        id2type.push(format!("    [ProtoId.{name}]: {path};"));
+    }
+    ids.push("}".to_owned());
+    id2type.push("}".to_owned());
+
+    //结构和枚举按 proto 的 package 和嵌套层级放到对应的命名空间下
+    let mut root = TsNamespace::default();
+    for (full_name, info) in messages {
+        if info.is_map_entry {
+            continue;
+        }
+        let path: Vec<&str> = full_name.trim_start_matches('.').split('.').collect();
+        let (name, parent) = path.split_last().unwrap();
+        root.insert(parent, generate_ts_interface(name, info));
+    }
+    let mut enums = set.enums.clone();
+    enums.sort_by(|a, b| a.full_name.cmp(&b.full_name));
+    for e in enums {
+        let path: Vec<&str> = e.full_name.split('.').collect();
+        let (name, parent) = path.split_last().unwrap();
+        let mut lines = vec![format!("export enum {name} {{")];
+        for (vname, value) in &e.values {
+            lines.push(format!("    {vname} = {value},"));
+        }
+        lines.push("}".to_owned());
+        root.insert(parent, lines.join("\n"));
+    }
+
+    let contents = [header, ids.join("\n"), id2type.join("\n"), root.render(0)].join("\n\n");
+    format!("{contents}\n")
+}
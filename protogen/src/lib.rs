@@ -1,2 +1,3 @@
 pub use ::prost::Message;
+pub mod generator;
 pub mod output;
@@ -0,0 +1,139 @@
+use protogen::generator::{find_protoc, Generator, Lang};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//运行时找不到 protoc 时使用编译时的 PROTOC, 都没有时跳过需要 protoc 的测试
+fn protoc() -> Option<PathBuf> {
+    let protoc = find_protoc().ok().or_else(|| {
+        option_env!("PROTOC")
+            .map(PathBuf::from)
+            .filter(|p| p.is_file())
+    });
+    if protoc.is_none() {
+        eprintln!("protoc not found, skipped");
+    }
+    protoc
+}
+
+fn write_proto(dir: &Path, file: &str, contents: &str) {
+    let path = dir.join(file);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, format!("syntax = \"proto3\";\n{contents}")).unwrap();
+}
+
+fn generator(protoc: &Path, proto_dir: &Path, out_dir: &Path) -> Generator {
+    let mut generator = Generator::new(out_dir);
+    generator
+        .protoc(protoc)
+        .proto_dir(proto_dir)
+        .id_manifest(out_dir.join("protoids.txt"))
+        .lang(Lang::Rust)
+        .lang(Lang::Lua)
+        .lang(Lang::TypeScript);
+    generator
+}
+
+#[test]
+fn generate_only_when_changed() {
+    let Some(protoc) = protoc() else {
+        return;
+    };
+    let proto_dir = tempfile::tempdir().unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    write_proto(
+        proto_dir.path(),
+        "login/c2s_login.proto",
+        "message C2sLogin { int32 ret = 1; }",
+    );
+    write_proto(
+        proto_dir.path(),
+        "login/s2c_login.proto",
+        "message S2cLogin { string account = 1; }",
+    );
+
+    let gen = generator(&protoc, proto_dir.path(), out_dir.path());
+    let written = gen.generate().unwrap();
+    let mut names: Vec<String> = written
+        .iter()
+        .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "allenums.lua",
+            "allprotos.lua",
+            "allprotos.rs",
+            "allprotos.ts",
+            "protoids.txt"
+        ]
+    );
+
+    //内容没有变化, 不会重写任何文件
+    assert!(gen.generate().unwrap().is_empty());
+}
+
+#[test]
+fn manifest_keeps_assigned_ids() {
+    let Some(protoc) = protoc() else {
+        return;
+    };
+    let proto_dir = tempfile::tempdir().unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    write_proto(proto_dir.path(), "b.proto", "message Bbb {}");
+    write_proto(proto_dir.path(), "c.proto", "message Ccc {}");
+
+    let gen = generator(&protoc, proto_dir.path(), out_dir.path());
+    gen.generate().unwrap();
+    let manifest = fs::read_to_string(out_dir.path().join("protoids.txt")).unwrap();
+    assert!(manifest.contains("100=>Bbb\n101=>Ccc\n"));
+
+    //按名字排序会排在最前面的新协议, 追加在最大id之后
+    write_proto(proto_dir.path(), "a.proto", "message Aaa {}");
+    gen.generate().unwrap();
+    let manifest = fs::read_to_string(out_dir.path().join("protoids.txt")).unwrap();
+    assert!(manifest.contains("100=>Bbb\n101=>Ccc\n102=>Aaa\n"));
+    let lua = fs::read_to_string(out_dir.path().join("allprotos.lua")).unwrap();
+    assert!(lua.contains("[102]=\"Aaa\""));
+}
+
+#[test]
+fn generate_selected_langs() {
+    let Some(protoc) = protoc() else {
+        return;
+    };
+    let proto_dir = tempfile::tempdir().unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    write_proto(proto_dir.path(), "a.proto", "message Aaa {}");
+
+    Generator::new(out_dir.path())
+        .protoc(&protoc)
+        .proto_dir(proto_dir.path())
+        .lang(Lang::TypeScript)
+        .generate()
+        .unwrap();
+    assert!(out_dir.path().join("allprotos.ts").exists());
+    assert!(!out_dir.path().join("allprotos.rs").exists());
+    assert!(!out_dir.path().join("allprotos.lua").exists());
+}
+
+#[test]
+fn missing_proto_dir() {
+    let out_dir = tempfile::tempdir().unwrap();
+    assert!(Generator::new(out_dir.path()).generate().is_err());
+}
+
+#[test]
+fn missing_protoc() {
+    let proto_dir = tempfile::tempdir().unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    write_proto(proto_dir.path(), "a.proto", "message Aaa {}");
+    let err = Generator::new(out_dir.path())
+        .protoc(proto_dir.path().join("protoc"))
+        .proto_dir(proto_dir.path())
+        .lang(Lang::Lua)
+        .generate()
+        .unwrap_err();
+    assert!(err.to_string().contains("protoc not found"));
+    assert!(!out_dir.path().join("allprotos.lua").exists());
+}
@@ -0,0 +1,67 @@
+use protogen::output::allprotos::*;
+
+#[derive(Default)]
+struct State {
+    logins: Vec<(u64, String)>,
+    feedbacks: u32,
+}
+
+fn on_login(state: &mut State, vfd: u64, msg: S2cLogin) {
+    state.logins.push((vfd, msg.account));
+}
+
+fn on_feedback(state: &mut State, _vfd: u64, _msg: C2sFeedback) {
+    state.feedbacks += 1;
+}
+
+#[test]
+fn dispatch_registered() {
+    let mut handlers = ProtoHandlers::new();
+    handlers.register(on_login).register(on_feedback);
+    assert!(handlers.is_registered(S2cLogin::PROTO_ID));
+    assert!(handlers.is_registered(C2sFeedback::PROTO_ID));
+    assert!(!handlers.is_registered(C2sLogin::PROTO_ID));
+
+    let mut state = State::default();
+    let login = S2cLogin {
+        account: "robot_1".to_string(),
+        ..Default::default()
+    };
+    assert!(handlers
+        .dispatch(&mut state, 7, login.into_proto())
+        .is_none());
+    assert!(handlers
+        .dispatch(&mut state, 7, C2sFeedback::default().into_proto())
+        .is_none());
+    assert_eq!(state.logins, vec![(7, "robot_1".to_string())]);
+    assert_eq!(state.feedbacks, 1);
+}
+
+#[test]
+fn dispatch_fallback() {
+    let handlers = ProtoHandlers::<State>::new();
+    let mut state = State::default();
+    let pto = C2sLogin {
+        ret: 1,
+        magic: 2,
+        compress: 0,
+    }
+    .into_proto();
+    match handlers.dispatch(&mut state, 1, pto) {
+        Some(ProtoType::C2sLogin(msg)) => assert_eq!(
+            msg,
+            C2sLogin {
+                ret: 1,
+                magic: 2,
+                compress: 0
+            }
+        ),
+        other => panic!("unexpected: {:?}", other),
+    }
+}
+
+#[test]
+fn proto_message_info() {
+    let pto = C2sKick::default().into_proto();
+    assert_eq!(pto.inner_info(), (C2sKick::PROTO_ID, C2sKick::PROTO_NAME));
+}
@@ -0,0 +1,203 @@
+//tests/proto 下的示例协议只在测试中生成, 见 build.rs
+#[allow(dead_code, clippy::wrong_self_convention)]
+mod allprotos {
+    include!(concat!(env!("OUT_DIR"), "/sample/allprotos.rs"));
+}
+
+use allprotos::sample::sample_constructs::{Kind, Nested, Payload};
+use allprotos::sample::SampleColor;
+use allprotos::*;
+use rlua::{Lua, Table};
+
+//rust -> lua -> rust, 并在转换为 lua table 后执行一段 lua 断言脚本
+fn roundtrip(obj: SampleConstructs, check: &str) -> SampleConstructs {
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        let t = obj.to_lua_table(ctx).unwrap();
+        ctx.globals().set("t", t.clone()).unwrap();
+        ctx.load(check).exec().unwrap();
+        SampleConstructs::default().from_lua_table(t).unwrap()
+    })
+}
+
+fn nested(id: u32, name: &str) -> Nested {
+    Nested {
+        id,
+        name: name.to_string(),
+    }
+}
+
+#[test]
+fn scalar_roundtrip() {
+    let obj = SampleConstructs {
+        ratio: 0.25,
+        flag: true,
+        delta: -42,
+        raw: vec![0, 1, 2, 255],
+        ..Default::default()
+    };
+    let res = roundtrip(
+        obj.clone(),
+        "assert(t.ratio == 0.25 and t.flag == true and t.delta == -42 and #t.raw == 4)",
+    );
+    assert_eq!(res, obj);
+}
+
+#[test]
+fn enum_roundtrip() {
+    let obj = SampleConstructs {
+        color: SampleColor::Blue as i32,
+        kind: Kind::Armor as i32,
+        colors: vec![SampleColor::Red as i32, SampleColor::None as i32],
+        ..Default::default()
+    };
+    let res = roundtrip(obj.clone(), "assert(t.color == 2 and t.kind == 2)");
+    assert_eq!(res, obj);
+    assert_eq!(res.color(), SampleColor::Blue);
+}
+
+#[test]
+fn nested_roundtrip() {
+    let obj = SampleConstructs {
+        nested: Some(nested(1, "sword")),
+        ..Default::default()
+    };
+    let res = roundtrip(obj.clone(), "assert(t.nested.name == 'sword')");
+    assert_eq!(res, obj);
+
+    //不存在的结构字段转换回来后为 None
+    let res = roundtrip(SampleConstructs::default(), "assert(t.nested == nil)");
+    assert_eq!(res.nested, None);
+}
+
+#[test]
+fn repeated_roundtrip() {
+    let obj = SampleConstructs {
+        nesteds: vec![nested(1, "a"), nested(2, "b"), nested(3, "c")],
+        tags: vec!["x".to_string(), "y".to_string()],
+        ..Default::default()
+    };
+    let res = roundtrip(
+        obj.clone(),
+        "assert(#t.nesteds == 3 and t.nesteds[1].name == 'a' and t.tags[2] == 'y')",
+    );
+    assert_eq!(res, obj);
+}
+
+//repeated 字段是从 1 开始的 lua 数组, 旧版本从 0 开始的下标不再使用
+#[test]
+fn repeated_is_one_based() {
+    let obj = SampleConstructs {
+        tags: vec!["x".to_string(), "y".to_string()],
+        ..Default::default()
+    };
+    roundtrip(
+        obj,
+        "assert(t.tags[0] == nil and t.tags[1] == 'x' and t.tags[2] == 'y' and t.tags[3] == nil)
+         local n = 0
+         for i, v in ipairs(t.tags) do n = i end
+         assert(n == 2)",
+    );
+
+    //按旧的约定从 0 开始填写时, 下标 0 的元素被忽略
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        let t: Table = ctx
+            .load("return { tags = { [0] = 'x', [1] = 'y' } }")
+            .eval()
+            .unwrap();
+        let res = SampleConstructs::default().from_lua_table(t).unwrap();
+        assert_eq!(res.tags, vec!["y".to_string()]);
+    });
+}
+
+#[test]
+fn repeated_len_limit() {
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        let t: Table = ctx
+            .load("local tags = {} for i = 1, 1000 do tags[i] = 'x' end return { tags = tags }")
+            .eval()
+            .unwrap();
+        assert!(SampleConstructs::default().from_lua_table(t).is_err());
+    });
+}
+
+#[test]
+fn map_roundtrip() {
+    let mut obj = SampleConstructs::default();
+    obj.counts.insert("gold".to_string(), 100);
+    obj.counts.insert("silver".to_string(), 7);
+    obj.items.insert(3, Item { uid: 1001, id: 3 });
+    obj.items.insert(9, Item { uid: 1002, id: 9 });
+    let res = roundtrip(
+        obj.clone(),
+        "assert(t.counts.gold == 100 and t.items[9].uid == 1002)",
+    );
+    assert_eq!(res, obj);
+}
+
+#[test]
+fn oneof_roundtrip() {
+    for payload in [
+        Payload::Text("hello".to_string()),
+        Payload::Number(u32::MAX as u64 + 1),
+        Payload::Detail(nested(5, "shield")),
+    ] {
+        let obj = SampleConstructs {
+            payload: Some(payload),
+            ..Default::default()
+        };
+        let res = roundtrip(
+            obj.clone(),
+            "local n = 0 for _, k in ipairs({'text','number','detail'}) do if t[k] ~= nil then n = n + 1 end end assert(n == 1)",
+        );
+        assert_eq!(res, obj);
+    }
+
+    let res = roundtrip(SampleConstructs::default(), "assert(t.text == nil)");
+    assert_eq!(res.payload, None);
+}
+
+#[test]
+fn optional_roundtrip() {
+    let obj = SampleConstructs {
+        limit: Some(0),
+        ..Default::default()
+    };
+    let res = roundtrip(obj.clone(), "assert(t.limit == 0)");
+    assert_eq!(res, obj);
+
+    let res = roundtrip(SampleConstructs::default(), "assert(t.limit == nil)");
+    assert_eq!(res.limit, None);
+}
+
+#[test]
+fn prototype_roundtrip() {
+    let mut obj = SampleConstructs {
+        kind: Kind::Weapon as i32,
+        payload: Some(Payload::Text("hi".to_string())),
+        ..Default::default()
+    };
+    obj.items.insert(1, Item { uid: 7, id: 1 });
+
+    let pto = ProtoType::SampleConstructs(obj.clone());
+    let (proto_id, name) = pto.inner_info();
+    assert_eq!(name, "SampleConstructs");
+
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        let t = pto.encode_to_lua(ctx).unwrap();
+        let pto = ProtoType::from_id(proto_id as i32).unwrap();
+        match pto.decode_from_lua(t).unwrap() {
+            ProtoType::SampleConstructs(res) => assert_eq!(res, obj),
+            other => panic!("wrong proto: {:?}", other),
+        }
+    });
+
+    let buf = encode(ProtoType::SampleConstructs(obj.clone())).unwrap();
+    match decode(proto_id, &buf).unwrap() {
+        ProtoType::SampleConstructs(res) => assert_eq!(res, obj),
+        other => panic!("wrong proto: {:?}", other),
+    }
+}
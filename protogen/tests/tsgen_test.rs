@@ -0,0 +1,42 @@
+use protogen::output::allprotos::*;
+
+const ALLPROTOS_TS: &str = include_str!("../src/output/allprotos.ts");
+
+#[test]
+fn ts_version_matches() {
+    let line = format!("export const PROTO_VERSION = \"{}\";", version());
+    assert!(ALLPROTOS_TS.contains(&line));
+}
+
+#[test]
+fn ts_proto_ids_match() {
+    //客户端的协议id表必须与服务端的 ProtoType 完全一致, 删除的协议会在清单中留下空缺的id
+    let mut count = 0;
+    for id in 100..1000 {
+        if let Some(pto) = ProtoType::from_id(id) {
+            let (proto_id, name) = pto.inner_info();
+            let line = format!("    {name} = {proto_id},");
+            assert!(ALLPROTOS_TS.contains(&line), "missing {line}");
+            count += 1;
+        }
+    }
+    let ts_ids = ALLPROTOS_TS
+        .split("export enum ProtoId {")
+        .nth(1)
+        .and_then(|s| s.split('}').next())
+        .unwrap();
+    assert_eq!(ts_ids.trim().lines().count(), count);
+}
+
+#[test]
+fn ts_frame_v1() {
+    //压缩帧使用 v1 帧头, 版本号和 flags 与服务端 frame.rs 一致
+    for line in [
+        "export const FRAME_VERSION_V1 = 1;",
+        "export const FLAG_LZ4 = 0x01;",
+        "export const FLAG_ZSTD = 0x02;",
+        "    flags: number;",
+    ] {
+        assert!(ALLPROTOS_TS.contains(line), "missing {line}");
+    }
+}
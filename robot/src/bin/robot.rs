@@ -1,14 +1,31 @@
-use cable::config::Config;
-use cable::logger::{init, LogLevel};
+use cable::cli::{self, Cli};
+use cable::logger::{self, init};
 use robot::services;
-use std::env;
+use std::{env, process};
 
 fn main() {
+    let cli = Cli::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}\n{}", cli::usage("robot"));
+        process::exit(2);
+    });
     let pwd = env::current_dir().unwrap();
-    let config_path = format!("{}/etc/sysconfig.conf", pwd.to_str().unwrap());
-    let conf = Config::new(&config_path).with("workdir", pwd.to_str().unwrap());
-    let log_level: LogLevel = conf.get_int("log_level").unwrap().into();
-    let log_chan_size = conf.get_int("log_chan_size").unwrap() as usize;
-    init(log_level, log_chan_size);
+    //配置文件 < 环境变量 < 命令行 --set k=v, 有错误时全部打印后退出
+    let loader = cli.loader(services::schema(), pwd.to_str().unwrap(), env::vars());
+    if let Some(code) = cli.run_offline("robot", env!("CARGO_PKG_VERSION"), &loader) {
+        process::exit(code);
+    }
+    let (conf, sys) = match loader.load() {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}: {err}", loader.path());
+            process::exit(1);
+        }
+    };
+    init(sys.log_level, sys.log_chan_size);
+    logger::set_log_format(sys.log_format);
+    logger::set_log_overflow(sys.log_overflow);
+    logger::set_host_id(sys.host_id);
     services::start(conf);
+    //退出前把队列中的日志都写到文件
+    logger::shutdown();
 }
@@ -1,4 +1,5 @@
-use cable::config::Config;
+use cable::config::schema::{Field, Kind, NON_NEGATIVE};
+use cable::config::{Config, Schema};
 use cable::info;
 use cable::logger::build_logger;
 use cable::message::ServiceType;
@@ -7,9 +8,18 @@ use tokio::sync::mpsc;
 
 pub mod client_hub;
 
+//机器人的配置: 公共配置之外有机器人数量, 以及加密时服务端的静态公钥
+pub fn schema() -> Schema {
+    Schema::common().with(&[
+        Field::new("robot_num", NON_NEGATIVE).default("10"),
+        Field::new("encrypt_server_key", Kind::Str),
+        Field::new("logic_path", Kind::Str).required(),
+    ])
+}
+
 pub fn start(conf: Config) {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let _ = rt.block_on(async move {
+    rt.block_on(async move {
         run_robot_server(conf).await;
     });
 }
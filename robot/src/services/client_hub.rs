@@ -1,6 +1,6 @@
 use cable::config::Config;
 use cable::logger::build_logger;
-use cable::message::{MessageType, ServiceType};
+use cable::message::{MessageType, Packet, ServiceType};
 use cable::modules::Module;
 use cable::network::tcp::service::{self as tcp_service};
 use cable::protos::*;
@@ -34,6 +34,13 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
             tm.spawn_smsender_chan(),
         );
 
+        //客户端与服务端共用 ConnReader/ConnWriter, is_encrypt 打开时连接建立后先完成握手
+        info!(
+            log,
+            "[tcp_client_hub]: is_encrypt={}",
+            tcp_client_srv.encrypt.is_some()
+        );
+
         let mut is_init = false;
         let mut connected_num = 0;
         loop {
@@ -42,17 +49,19 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                 res = smreceiver_chan.recv() => {
                     if let Some((vfd,sender)) = res {
                         gs.add_vfd(vfd,sender.clone());
-                        connected_num = connected_num + 1;
+                        connected_num += 1;
                         info!(log,"[tcp_client_hub]: new tcp client connection channel: vfd={}, connected_num={}",vfd,connected_num);
 
                         //:TODO:新连接发起第一个协议,登录 (或通过#[cfg()]配置 GameState 的 robot 方法)
-                        let mut s_login = S2cLogin::default();
-                        s_login.account = format!("robot_{connected_num}");
-                        s_login.passwd = "123456".to_string();
-                        s_login.version = version().to_string();
+                        let s_login = S2cLogin {
+                            account: format!("robot_{connected_num}"),
+                            passwd: "123456".to_string(),
+                            version: version().to_string(),
+                            compress: 0, //由网络层填写本端支持的压缩算法
+                        };
                         let pto = ProtoType::S2cLogin(s_login);
                         let (proto_id,_name) = pto.inner_info();
-                        if let Err(err) = sender.send((MessageType::Tcp,vfd,pto)).await {
+                        if let Err(err) = sender.send((MessageType::Tcp,vfd,Packet::Proto(pto))).await {
                             error!(log,"[tcp_client_hub]: send SLogin to connection failed: vfd={},proto_id={},err={:?}",vfd,proto_id,err);
                         }
                     } else {
@@ -61,13 +70,13 @@ pub fn start(conf: Config, mut tm: Module, all_srv_close_sender: Sender<()>) {
                     }
                 },
                 res = smreceiver.recv() => {
-                    if let Some((msg_type, session, pto)) = res {
-                        let (proto_id,_) = pto.inner_info();
+                    if let Some((msg_type, session, packet)) = res {
+                        let proto_id = packet.proto_id();
                         match msg_type {
                             MessageType::Tcp => {
                                 if gs.get_sender(session).is_some() {
                                     // :TODO: 通过#[cfg()]配置 GameState 的 robot 方法
-                                    if let Err(err) = gs.robot_dispatch(msg_type, session, pto) {
+                                    if let Err(err) = packet.into_proto().and_then(|pto| gs.robot_dispatch(msg_type, session, pto)) {
                                         info!(log,"[tcp_client_hub]: err={}, vfd={}", err, session);
                                     }
                                 } else {